@@ -26,6 +26,11 @@ impl Config {
         CONF.get_string(prop).ok()
     }
 
+    #[must_use]
+    pub fn get_float(prop: &str, default: f64) -> f64 {
+        CONF.get_float(prop).unwrap_or(default)
+    }
+
     pub fn get_int<T: Into<i64> + From<i64>>(prop: &str, default: T) -> T {
         CONF.get_int(prop).unwrap_or_else(|_| default.into()).into()
     }
@@ -1,4 +1,6 @@
-#[derive(PartialEq, Eq)]
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum AudioChannel {
     Pulse1,
     Pulse2,
@@ -6,3 +8,14 @@ pub enum AudioChannel {
     Noise,
     DMC,
 }
+
+// A snapshot of one channel for debugging. `length_counter` is the bytes left in the sample for
+// the DMC, and `volume` is what the channel's putting out right now: the envelope for pulse and
+// noise, the step of the triangle wave or the DMC's output level
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelDebug {
+    pub length_counter: u16,
+    pub period: u16,
+    pub volume: u8,
+    pub enabled: bool,
+}
@@ -1,9 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::Region;
+
+use super::base_channel::ChannelDebug;
 use super::frame_counter::IRQSignal;
 
 const PERIOD_LOOKUP: [u16; 16] = [
     428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
 ];
+const PAL_PERIOD_LOOKUP: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+fn period_lookup(region: Region) -> &'static [u16; 16] {
+    match region {
+        Region::Pal => &PAL_PERIOD_LOOKUP,
+        Region::Ntsc | Region::Dendy => &PERIOD_LOOKUP,
+    }
+}
 
+#[derive(Serialize, Deserialize)]
 pub struct DMC {
     /// Writeable values
     irq_enable: bool,
@@ -29,7 +45,11 @@ pub struct DMC {
 
     /// Misc flags
     silence_flag: bool,
+    // Set when a sample without looping runs out with IRQs enabled. $4010 with IRQs disabled or
+    // any write to $4015 clears it
+    irq_flag: bool,
     need_to_run: bool,
+    region: Region,
 }
 
 impl Default for DMC {
@@ -50,7 +70,9 @@ impl Default for DMC {
             output_level: 0,
             output_buffer: None,
             silence_flag: true,
+            irq_flag: false,
             need_to_run: false,
+            region: Region::Ntsc,
         }
     }
 }
@@ -60,11 +82,23 @@ impl DMC {
         DMC::default()
     }
 
+    pub fn with_region(region: Region) -> DMC {
+        DMC {
+            period: period_lookup(region)[0],
+            timer: period_lookup(region)[0],
+            region,
+            ..DMC::default()
+        }
+    }
+
     // RAM Writes --------------------------------------------------------------
     pub fn write_ctrl(&mut self, data: u8) {
         self.irq_enable = data >> 7 != 0;
-        self._loop = data >> 6 != 0;
-        self.period = PERIOD_LOOKUP[(data & 0x0f) as usize];
+        if !self.irq_enable {
+            self.irq_flag = false;
+        }
+        self._loop = data & 0x40 != 0;
+        self.period = period_lookup(self.region)[(data & 0x0f) as usize];
     }
 
     pub fn write_load(&mut self, data: u8) {
@@ -74,8 +108,9 @@ impl DMC {
     pub fn write_addr(&mut self, data: u8) {
         self.sample_addr = (data as u16) * 64 + 0xc000;
     }
+    // $4013 counts 16 byte blocks, so 0 is a 1 byte sample
     pub fn write_lc(&mut self, data: u8) {
-        self.sample_length = (data as u16) + 1;
+        self.sample_length = (data as u16) * 16 + 1;
     }
     // ------------------------------------------------------------------------
 
@@ -94,7 +129,7 @@ impl DMC {
                         self.output_level += 2;
                     }
                 } else if self.output_level >= 2 {
-                    self.output_level -= 1
+                    self.output_level -= 2
                 }
                 self.shift_register >>= 1;
             }
@@ -138,6 +173,7 @@ impl DMC {
                 if self._loop {
                     self.init_sample();
                 } else if self.irq_enable {
+                    self.irq_flag = true;
                     return IRQSignal::Set;
                 }
             }
@@ -152,6 +188,7 @@ impl DMC {
     }
 
     pub fn set_enabled(&mut self, enabled: bool, cpu_cycle: u64) {
+        self.irq_flag = false;
         if !enabled {
             self.bytes_remaining = 0;
             self.need_to_run = false;
@@ -178,6 +215,19 @@ impl DMC {
         (should_start_dmc_transfer, self.need_to_run)
     }
 
+    pub fn debug_state(&self) -> ChannelDebug {
+        ChannelDebug {
+            length_counter: self.bytes_remaining,
+            period: self.period,
+            volume: self.output_level,
+            enabled: self.bytes_remaining > 0,
+        }
+    }
+
+    pub const fn irq_flag(&self) -> bool {
+        self.irq_flag
+    }
+
     pub fn irq_pending(&self, cycles_to_run: u64) -> bool {
         if self.irq_enable && self.bytes_remaining > 0 {
             let cycles_to_empty =
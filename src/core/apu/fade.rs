@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+// Ramp length in APU cycles, roughly 4 output samples at 48kHz
+const FADE_CYCLES: f64 = 128.;
+
+// Smooths a channel appearing/disappearing from the mix. While it's off, the last level it
+// produced is held and faded out instead of dropping straight to 0
+#[derive(Default, Serialize, Deserialize)]
+pub struct Fade {
+    gain: f64,
+    held: f64,
+}
+
+impl Fade {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(&mut self, enabled: bool, out: f64) -> f64 {
+        if enabled {
+            self.held = out;
+            self.gain = (self.gain + 1. / FADE_CYCLES).min(1.);
+        } else {
+            self.gain = (self.gain - 1. / FADE_CYCLES).max(0.);
+        }
+        self.held * self.gain
+    }
+}
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::savestate::big_array;
+
+// The FDS's wavetable channel at $4040-$4097. So far only the registers that read back are kept,
+// the wave RAM and the gains, and nothing is synthesized, so disk games play without it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FdsAudio {
+    #[serde(with = "big_array")]
+    wave: [u8; 64],
+    // Wave RAM can only be written while $4089 bit 7 holds the channel
+    wave_write: bool,
+    volume_gain: u8,
+    mod_gain: u8,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self {
+            wave: [0; 64],
+            wave_write: false,
+            volume_gain: 0,
+            mod_gain: 0,
+        }
+    }
+}
+
+impl FdsAudio {
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040..=0x407F => self.wave[(addr - 0x4040) as usize],
+            0x4090 => self.volume_gain,
+            0x4092 => self.mod_gain,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write => self.wave[(addr - 0x4040) as usize] = val & 0x3f,
+            0x4080 => self.volume_gain = val & 0x3f,
+            0x4084 => self.mod_gain = val & 0x3f,
+            0x4089 => self.wave_write = val & 0x80 != 0,
+            _ => {}
+        }
+    }
+
+    pub fn output(&self) -> i32 {
+        0
+    }
+}
@@ -0,0 +1,79 @@
+use std::f64::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+// First-order RC filter
+#[derive(Clone, Serialize, Deserialize)]
+struct Filter {
+    kind: FilterKind,
+    alpha: f64,
+    prev_in: f64,
+    prev_out: f64,
+}
+
+impl Filter {
+    fn new(kind: FilterKind, sample_rate: f64, cutoff: f64) -> Self {
+        let rc = 1. / (2. * PI * cutoff);
+        let dt = 1. / sample_rate;
+        let alpha = match kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+        Self {
+            kind,
+            alpha,
+            prev_in: 0.,
+            prev_out: 0.,
+        }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        let out = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.prev_out + sample - self.prev_in),
+            FilterKind::LowPass => self.prev_out + self.alpha * (sample - self.prev_out),
+        };
+        self.prev_in = sample;
+        self.prev_out = out;
+        out
+    }
+}
+
+// What the NES does to its audio on the way out: two high-passes (90Hz, 440Hz) that remove the
+// DC offset, and a 14kHz low-pass
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FilterChain {
+    filters: [Filter; 3],
+}
+
+impl FilterChain {
+    #[must_use]
+    pub fn new(sample_rate: f64) -> Self {
+        Self {
+            filters: [
+                Filter::new(FilterKind::HighPass, sample_rate, 90.),
+                Filter::new(FilterKind::HighPass, sample_rate, 440.),
+                Filter::new(FilterKind::LowPass, sample_rate, 14000.),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, sample: f64) -> f64 {
+        self.filters
+            .iter_mut()
+            .fold(sample, |sample, filter| filter.process(sample))
+    }
+
+    // Filters a run of output samples in place
+    pub fn process_samples(&mut self, samples: &mut [i16]) {
+        for sample in samples {
+            let filtered = self.process(f64::from(*sample));
+            *sample = filtered.clamp(i16::MIN.into(), i16::MAX.into()) as i16;
+        }
+    }
+}
@@ -1,4 +1,8 @@
-#[derive(PartialEq, Eq, Copy, Clone)]
+use serde::{Deserialize, Serialize};
+
+use crate::core::Region;
+
+#[derive(PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
 pub enum Mode {
     FourStep = 0,
     FiveStep = 1,
@@ -21,6 +25,10 @@ const STEP_CYCLES: [[u16; 6]; 2] = [
     [7457, 14913, 22371, 29828, 29829, 29830],
     [7457, 14913, 22371, 29829, 37281, 37282],
 ];
+const PAL_STEP_CYCLES: [[u16; 6]; 2] = [
+    [8313, 16627, 24939, 33252, 33253, 33254],
+    [8313, 16627, 24939, 33253, 41565, 41566],
+];
 const FRAME_TYPES: [[FrameType; 6]; 2] = [
     [
         FrameType::QuarterFrame,
@@ -40,6 +48,7 @@ const FRAME_TYPES: [[FrameType; 6]; 2] = [
     ],
 ];
 
+#[derive(Serialize, Deserialize)]
 pub struct FrameCounter {
     previous_cycle: i32,
     pub step: usize,
@@ -47,6 +56,7 @@ pub struct FrameCounter {
     write_buffer: Option<u8>,
     write_delay: i8,
     block_tick: u8,
+    region: Region,
 }
 
 impl Default for FrameCounter {
@@ -58,11 +68,28 @@ impl Default for FrameCounter {
             write_delay: 3,
             block_tick: 0,
             write_buffer: None,
+            region: Region::Ntsc,
         }
     }
 }
 
 impl FrameCounter {
+    pub fn with_region(region: Region) -> Self {
+        Self {
+            region,
+            ..Self::default()
+        }
+    }
+
+    // Dendy uses the NTSC tables
+    fn step_cycle(&self) -> i32 {
+        let step_cycles = match self.region {
+            Region::Pal => &PAL_STEP_CYCLES,
+            Region::Ntsc | Region::Dendy => &STEP_CYCLES,
+        };
+        i32::from(step_cycles[self.mode as usize][self.step])
+    }
+
     pub fn clock(
         &mut self,
         inhibit_irq: bool,
@@ -72,9 +99,7 @@ impl FrameCounter {
         let cycles_ran;
         let mut signal = IRQSignal::None;
 
-        if self.previous_cycle + *cycles_to_run
-            >= i32::from(STEP_CYCLES[self.mode as usize][self.step])
-        {
+        if self.previous_cycle + *cycles_to_run >= self.step_cycle() {
             if !inhibit_irq && self.mode == Mode::FourStep && self.step >= 3 {
                 signal = IRQSignal::Set;
             }
@@ -85,13 +110,11 @@ impl FrameCounter {
                 self.block_tick = 2;
             }
 
-            cycles_ran =
-                if i32::from(STEP_CYCLES[self.mode as usize][self.step]) < self.previous_cycle {
-                    0
-                } else {
-                    (i32::from(STEP_CYCLES[self.mode as usize][self.step]) - self.previous_cycle)
-                        .unsigned_abs()
-                };
+            cycles_ran = if self.step_cycle() < self.previous_cycle {
+                0
+            } else {
+                (self.step_cycle() - self.previous_cycle).unsigned_abs()
+            };
 
             *cycles_to_run -= cycles_ran as i32;
 
@@ -139,8 +162,7 @@ impl FrameCounter {
     pub fn need_to_run(&self, cycles_to_run: u32) -> bool {
         self.write_buffer.is_some()
             || self.block_tick > 0
-            || (self.previous_cycle + cycles_to_run as i32)
-                >= i32::from(STEP_CYCLES[self.mode as usize][self.step]) - 1
+            || (self.previous_cycle + cycles_to_run as i32) >= self.step_cycle() - 1
     }
 
     pub fn write(&mut self, val: u8, cycle: usize) {
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 const LENGTH_LOOKUP: [u8; 0x20] = [
     0x0A, 0xFE, 0x14, 0x02, 0x28, 0x04, 0x50, 0x06, 0xA0, 0x08, 0x3C, 0x0A, 0x0E, 0x0C, 0x1A, 0x0E,
     0x0C, 0x10, 0x18, 0x12, 0x30, 0x14, 0x60, 0x16, 0xC0, 0x18, 0x48, 0x1A, 0x10, 0x1C, 0x20, 0x1E,
@@ -5,7 +7,7 @@ const LENGTH_LOOKUP: [u8; 0x20] = [
 
 pub struct NeedToRunFlag(pub Option<bool>);
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct LengthCounter {
     pub enabled: bool,
     pub counter: u8,
@@ -38,7 +40,12 @@ impl LengthCounter {
     }
 
     pub fn write_ctrl(&mut self, val: u8) -> NeedToRunFlag {
-        self.new_halt_val = (val >> 5) & 1 == 1;
+        self.set_halt((val >> 5) & 1 == 1)
+    }
+
+    // The halt bit isn't in the same place for every channel
+    pub fn set_halt(&mut self, halt: bool) -> NeedToRunFlag {
+        self.new_halt_val = halt;
         NeedToRunFlag(Some(true))
     }
 
@@ -1,32 +1,61 @@
 pub mod base_channel;
 pub mod dmc;
 pub mod envelope;
+pub mod fade;
+pub mod fds_audio;
+pub mod filter;
 pub mod frame_counter;
 pub mod length_counter;
 pub mod noise;
 pub mod pulse;
 pub mod sweep;
 pub mod triangle;
+pub mod vrc6_audio;
 
 use dmc::DMC;
+use fade::Fade;
+use filter::FilterChain;
 use frame_counter::FrameCounter;
 use noise::Noise;
 use pulse::Pulse;
 use triangle::Triangle;
 
+use serde::{Deserialize, Serialize};
+
+use crate::core::mappers::Mapper;
+use crate::core::Region;
 use crate::frontend::blip_buf::BlipBuf;
 
-use self::base_channel::AudioChannel;
+use self::base_channel::{AudioChannel, ChannelDebug};
 use self::frame_counter::{FrameType, IRQSignal};
 use self::length_counter::NeedToRunFlag;
 
+// The two interrupt flags the APU can be holding, as they'd read in $4015
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApuIrqState {
+    pub frame_irq: bool,
+    pub dmc_irq: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApuDebug {
+    pub pulse1: ChannelDebug,
+    pub pulse2: ChannelDebug,
+    pub triangle: ChannelDebug,
+    pub noise: ChannelDebug,
+    pub dmc: ChannelDebug,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct APU {
     pulse1: Pulse,
     pulse2: Pulse,
     triangle: Triangle,
-    _noise: Noise,
+    noise: Noise,
     pub dmc: DMC,
     frame_counter: FrameCounter,
+    // Host side, kept by the running APU when a save state is loaded
+    #[serde(skip, default = "detached_output_buffer")]
     pub output_buffer: BlipBuf<65536>,
     irq_pending: bool,
     irq_disabled: bool,
@@ -34,6 +63,35 @@ pub struct APU {
     need_to_run: bool,
     prev_cycle: usize,
     need_dmc_transfer: bool,
+    // Off by default: with it on, $4015 enable/disable and muting ramp instead of stepping
+    click_suppression: bool,
+    fades: [Fade; 5],
+    // Mixer-only mute per channel, indexed by AudioChannel. The channels keep running
+    channel_enabled: [bool; 5],
+    filters: FilterChain,
+    filters_enabled: bool,
+    clock_rate: f64,
+    // Host side like output_buffer, None for mono
+    #[serde(skip)]
+    stereo: Option<StereoOutput>,
+    // Sound chips registered with register_expansion, each asked for a level every APU cycle
+    #[serde(skip)]
+    expansions: Vec<Box<dyn Fn(u64) -> i32 + Send>>,
+    // How many of those, at the front, came from the cartridge's own sound chip
+    #[serde(skip)]
+    cartridge_expansions: usize,
+}
+
+// The right channel's buffer and filters. While stereo is on, output_buffer carries the left
+struct StereoOutput {
+    right_buffer: BlipBuf<65536>,
+    right_filters: FilterChain,
+    // Pulse 1 and pulse 2, from -1 (left) to 1 (right)
+    pan: [f64; 2],
+}
+
+fn detached_output_buffer() -> BlipBuf<65536> {
+    BlipBuf::new(APU::CLOCK_RATE, APU::DEFAULT_SAMPLE_RATE)
 }
 
 impl Default for APU {
@@ -43,28 +101,135 @@ impl Default for APU {
 }
 
 impl APU {
+    // NTSC. Use clock_rate() for the region the APU was built for
     pub const CLOCK_RATE: f64 = 1789772.7272;
-    const DEFAULT_SAMPLE_RATE: f64 = 48000.;
+    pub const DEFAULT_SAMPLE_RATE: f64 = 48000.;
 
     #[must_use]
     pub fn new() -> Self {
+        Self::with_region(Region::Ntsc)
+    }
+
+    #[must_use]
+    pub fn with_region(region: Region) -> Self {
+        let clock_rate = region.cpu_clock_rate();
         Self {
             pulse1: Pulse::new(AudioChannel::Pulse1),
             pulse2: Pulse::new(AudioChannel::Pulse2),
             triangle: Triangle::default(),
-            _noise: Noise::default(),
-            dmc: DMC::default(),
-            frame_counter: FrameCounter::default(),
-            output_buffer: BlipBuf::new(Self::CLOCK_RATE, Self::DEFAULT_SAMPLE_RATE),
+            noise: Noise::with_region(region),
+            dmc: DMC::with_region(region),
+            frame_counter: FrameCounter::with_region(region),
+            output_buffer: BlipBuf::new(clock_rate, Self::DEFAULT_SAMPLE_RATE),
             irq_pending: false,
             irq_disabled: false,
             cycle: 0,
             need_to_run: false,
             prev_cycle: 0,
             need_dmc_transfer: false,
+            click_suppression: false,
+            fades: [Fade::new(), Fade::new(), Fade::new(), Fade::new(), Fade::new()],
+            channel_enabled: [true; 5],
+            // Run on what comes out of the BlipBuf rather than every APU cycle, so at the host's
+            // rate
+            filters: FilterChain::new(Self::DEFAULT_SAMPLE_RATE),
+            filters_enabled: true,
+            clock_rate,
+            stereo: None,
+            expansions: Vec::new(),
+            cartridge_expansions: 0,
+        }
+    }
+
+    pub(crate) fn take_host_resources(&mut self, from: &mut APU) {
+        std::mem::swap(&mut self.output_buffer, &mut from.output_buffer);
+        std::mem::swap(&mut self.stereo, &mut from.stereo);
+        std::mem::swap(&mut self.expansions, &mut from.expansions);
+        self.cartridge_expansions = from.cartridge_expansions;
+    }
+
+    // Carries over what was registered with register_expansion, leaving the old cartridge's chip
+    // behind
+    pub(crate) fn take_expansions(&mut self, from: &mut APU) {
+        self.expansions.extend(from.expansions.drain(from.cartridge_expansions..));
+    }
+
+    // Hooks in the sound chip of a freshly plugged in cartridge, before anything else registers
+    pub(crate) fn register_cartridge_audio(&mut self, mapper: &dyn Mapper) {
+        mapper.register_audio(self);
+        self.cartridge_expansions = self.expansions.len();
+    }
+
+    pub fn clock_rate(&self) -> f64 {
+        self.clock_rate
+    }
+
+    // Resamples to a new host rate from here on. Anything not yet read out of the buffer was made
+    // for the old rate, so it's thrown away
+    pub fn set_output_sample_rate(&mut self, sample_rate: f64) {
+        self.output_buffer.set_rates(self.clock_rate, sample_rate);
+        self.output_buffer.clear();
+        self.filters = FilterChain::new(sample_rate);
+        if let Some(stereo) = &mut self.stereo {
+            stereo.right_buffer.set_rates(self.clock_rate, sample_rate);
+            stereo.right_buffer.clear();
+            stereo.right_filters = FilterChain::new(sample_rate);
+        }
+    }
+
+    // Like set_output_sample_rate but keeps what's buffered, for the small corrections rate
+    // control makes every frame
+    pub fn adjust_output_sample_rate(&mut self, sample_rate: f64) {
+        self.output_buffer.set_rates(self.clock_rate, sample_rate);
+        if let Some(stereo) = &mut self.stereo {
+            stereo.right_buffer.set_rates(self.clock_rate, sample_rate);
         }
     }
 
+    // Samples ready to be read with end_frame, for frontends that size their own
+    // buffers or track latency. Counts left/right pairs as one when stereo
+    pub fn samples_available(&self) -> usize {
+        self.output_buffer.samples_available()
+    }
+
+    // Pans pulse 1 and pulse 2 between -1 (left) and 1 (right), with the triangle, noise, DMC and
+    // cartridge audio in the middle. None goes back to mono. Whatever's buffered is dropped so
+    // the two sides start out in step
+    pub fn set_stereo(&mut self, pan: Option<[f64; 2]>) {
+        self.output_buffer.clear();
+        self.stereo = pan.map(|pan| StereoOutput {
+            right_buffer: self.output_buffer.clone(),
+            right_filters: self.filters.clone(),
+            pan: pan.map(|p| p.clamp(-1., 1.)),
+        });
+    }
+
+    #[must_use]
+    pub fn is_stereo(&self) -> bool {
+        self.stereo.is_some()
+    }
+
+    // Reads out the frame's samples, interleaved left then right when stereo
+    pub fn end_frame(&mut self, out: &mut Vec<i16>) {
+        let Some(stereo) = &mut self.stereo else {
+            let start = out.len();
+            self.output_buffer.end_frame(out);
+            if self.filters_enabled {
+                self.filters.process_samples(&mut out[start..]);
+            }
+            return;
+        };
+        let mut left = Vec::with_capacity(self.output_buffer.samples_available());
+        let mut right = Vec::with_capacity(left.capacity());
+        self.output_buffer.end_frame(&mut left);
+        stereo.right_buffer.end_frame(&mut right);
+        if self.filters_enabled {
+            self.filters.process_samples(&mut left);
+            stereo.right_filters.process_samples(&mut right);
+        }
+        out.extend(left.into_iter().zip(right).flat_map(|(l, r)| [l, r]));
+    }
+
     #[must_use]
     pub const fn read_status_trace(&self) -> u8 {
         let mut status = 0;
@@ -74,18 +239,51 @@ impl APU {
         if self.pulse2.length.counter > 0 {
             status |= 0x2;
         }
+        if self.triangle.length.counter > 0 {
+            status |= 0x4;
+        }
+        if self.noise.length.counter > 0 {
+            status |= 0x8;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
         if self.irq_pending {
             status |= 0x40;
         }
+        if self.dmc.irq_flag() {
+            status |= 0x80;
+        }
         status
     }
 
+    // Unlike reading $4015, this leaves the frame IRQ set
+    pub fn irq_state(&self) -> ApuIrqState {
+        ApuIrqState {
+            frame_irq: self.irq_pending,
+            dmc_irq: self.dmc.irq_flag(),
+        }
+    }
+
+    pub fn debug_state(&self) -> ApuDebug {
+        ApuDebug {
+            pulse1: self.pulse1.debug_state(),
+            pulse2: self.pulse2.debug_state(),
+            triangle: self.triangle.debug_state(),
+            noise: self.noise.debug_state(),
+            dmc: self.dmc.debug_state(),
+        }
+    }
+
     pub fn clock(&mut self) -> (bool, bool) {
         self.cycle += 1;
         // self.need_to_run();
+        // The first sample byte is fetched a few cycles after $4015 enables the DMC, before the
+        // output unit has asked for one
+        let (start_dmc_transfer, _) = self.dmc.need_to_run();
         self.run();
         self.output();
-        (self.irq_pending, self.need_dmc_transfer)
+        (self.irq_pending, self.need_dmc_transfer || start_dmc_transfer)
     }
 
     pub fn write_ctrl(&mut self, channel: &AudioChannel, val: u8) {
@@ -93,7 +291,8 @@ impl APU {
         match channel {
             AudioChannel::Pulse1 => flag = self.pulse1.write_ctrl(val),
             AudioChannel::Pulse2 => flag = self.pulse2.write_ctrl(val),
-            AudioChannel::Triangle => self.triangle.write_ctrl(val),
+            AudioChannel::Triangle => flag = self.triangle.write_ctrl(val),
+            AudioChannel::Noise => flag = self.noise.write_ctrl(val),
             _ => {}
         }
 
@@ -115,6 +314,7 @@ impl APU {
             AudioChannel::Pulse1 => self.pulse1.write_timer_lo(val),
             AudioChannel::Pulse2 => self.pulse2.write_timer_lo(val),
             AudioChannel::Triangle => self.triangle.write_timer_lo(val),
+            AudioChannel::Noise => self.noise.write_period(val),
             _ => {}
         }
     }
@@ -125,6 +325,7 @@ impl APU {
             AudioChannel::Pulse1 => flag = self.pulse1.write_timer_hi(val),
             AudioChannel::Pulse2 => flag = self.pulse2.write_timer_hi(val),
             AudioChannel::Triangle => flag = self.triangle.write_timer_hi(val),
+            AudioChannel::Noise => flag = self.noise.write_length(val),
             _ => {}
         }
 
@@ -141,9 +342,11 @@ impl APU {
                 self.pulse1.clock_quarter_frame();
                 self.pulse2.clock_quarter_frame();
                 self.triangle.clock_quarter_frame();
+                self.noise.clock_quarter_frame();
                 if typ == FrameType::HalfFrame {
                     self.pulse1.clock_length_counter();
                     self.pulse2.clock_length_counter();
+                    self.noise.clock_length_counter();
                     self.pulse1.clock_sweep();
                     self.pulse2.clock_sweep();
                     self.triangle.clock_half_frame();
@@ -163,10 +366,12 @@ impl APU {
             self.pulse1.reload_counter();
             self.pulse2.reload_counter();
             self.triangle.reload_counter();
+            self.noise.reload_counter();
 
             self.pulse1.clock(self.prev_cycle as u64);
             self.pulse2.clock(self.prev_cycle as u64);
             self.triangle.clock(self.prev_cycle as u64);
+            self.noise.clock(self.prev_cycle as u64);
             self.need_dmc_transfer = self.dmc.clock(self.prev_cycle as u64);
         }
     }
@@ -182,12 +387,20 @@ impl APU {
         if self.triangle.length.counter > 0 {
             status |= 0x04
         }
+        if self.noise.length.counter > 0 {
+            status |= 0x08
+        }
         if self.irq_pending {
             status |= 0x40;
         }
         if self.dmc.bytes_remaining > 0 {
             status |= 0x10;
         }
+        if self.dmc.irq_flag() {
+            status |= 0x80;
+        }
+        // Only the frame IRQ is acknowledged by reading, the DMC's stays until $4010 or $4015 is
+        // written
         self.irq_pending = false;
         (status, IRQSignal::Clear)
     }
@@ -216,9 +429,27 @@ impl APU {
         self.pulse1.set_enabled(val & 0x1 != 0);
         self.pulse2.set_enabled(val & 0x2 != 0);
         self.triangle.set_enabled(val & 0x4 != 0);
+        self.noise.set_enabled(val & 0x8 != 0);
         self.dmc.set_enabled(val & 0x10 != 0, cpu_cycle)
     }
 
+    pub fn set_channel_enabled(&mut self, channel: AudioChannel, enabled: bool) {
+        self.channel_enabled[channel as usize] = enabled;
+    }
+
+    #[must_use]
+    pub fn is_channel_enabled(&self, channel: AudioChannel) -> bool {
+        self.channel_enabled[channel as usize]
+    }
+
+    pub fn set_filters_enabled(&mut self, enabled: bool) {
+        self.filters_enabled = enabled;
+    }
+
+    pub fn set_click_suppression(&mut self, enabled: bool) {
+        self.click_suppression = enabled;
+    }
+
     pub fn write_frame_counter(&mut self, val: u8) -> IRQSignal {
         self.frame_counter.write(val, self.cycle);
         self.irq_disabled = val & 0x40 != 0;
@@ -229,17 +460,65 @@ impl APU {
         IRQSignal::None
     }
 
+    // Mixes in another sound chip, called with the APU cycle count each cycle and added to the
+    // 2A03's level, centered in stereo. A 2A03 pulse channel at full volume comes out at about
+    // 750 and all five channels together peak a little under 5000, so a chip whose loudest
+    // channel sits near 750 balances the way it does on hardware. Stays through save states
+    // and power cycles, but not loading another ROM
+    pub fn register_expansion<F: Fn(u64) -> i32 + Send + 'static>(&mut self, f: F) {
+        self.expansions.push(Box::new(f));
+    }
+
+    // Removes everything registered with register_expansion. The cartridge's own chip stays
+    pub fn clear_expansions(&mut self) {
+        self.expansions.truncate(self.cartridge_expansions);
+    }
+
     fn output(&mut self) {
-        let pulse1 = self.pulse1.output() as f64;
-        let pulse2 = self.pulse2.output() as f64;
-        let pulse_out = pulse1 + pulse2;
-        let square_volume = (477600. / (8128.0 / pulse_out + 100.0)) as i32;
+        let levels = [
+            self.pulse1.output() as f64,
+            self.pulse2.output() as f64,
+            self.triangle.output() as f64,
+            self.noise.output() as f64,
+            self.dmc.output() as f64,
+        ];
+        // Disabling the DMC in $4015 doesn't silence it, it just holds its level
+        let playing = [
+            self.pulse1.length.enabled,
+            self.pulse2.length.enabled,
+            self.triangle.length.enabled,
+            self.noise.length.enabled,
+            true,
+        ];
+        let [pulse1, pulse2, triangle, noise, dmc] = std::array::from_fn(|i| {
+            if self.click_suppression {
+                self.fades[i].apply(playing[i] && self.channel_enabled[i], levels[i])
+            } else if self.channel_enabled[i] {
+                levels[i]
+            } else {
+                0.
+            }
+        });
+
+        let square_volume = |pulse_out: f64| (477600. / (8128.0 / pulse_out + 100.0)) as i32;
 
-        let triangle = self.triangle.output() as f64;
-        let dmc = self.dmc.output() as f64;
-        let tnd_out = 3. * triangle + dmc;
-        let tnd_volume = (816850. / ((24329. / tnd_out) + 100.0)) as i32;
+        // Nonlinear TND group from the NESdev wiki, scaled to match the pulse group above
+        let tnd_out = triangle / 8227. + noise / 12241. + dmc / 22638.;
+        let tnd_volume = (159.79 * (477600. / 95.88) / (1. / tnd_out + 100.)) as i32;
+        let expansion: i32 = self.expansions.iter().map(|f| f(self.cycle as u64)).sum();
+        let centered = tnd_volume + expansion;
 
-        self.output_buffer.add_sample(square_volume + tnd_volume);
+        let Some(stereo) = &mut self.stereo else {
+            self.output_buffer.add_sample(square_volume(pulse1 + pulse2) + centered);
+            return;
+        };
+        // A pulse channel stays at full volume on the side it leans towards and fades out on the
+        // other, so a centered pan sounds the same as mono on both sides
+        let [left, right] = [-1., 1.].map(|side: f64| {
+            let [gain1, gain2] = stereo.pan.map(|pan| (1. + side * pan).min(1.));
+            square_volume(pulse1 * gain1 + pulse2 * gain2) + centered
+        });
+        self.output_buffer.add_sample(left);
+        stereo.right_buffer.add_sample(right);
     }
 }
@@ -1,2 +1,151 @@
-#[derive(Default)]
-pub struct Noise {}
+use serde::{Deserialize, Serialize};
+
+use crate::core::Region;
+
+use super::{
+    base_channel::ChannelDebug,
+    envelope::Envelope,
+    length_counter::{LengthCounter, NeedToRunFlag},
+};
+
+// Timer periods in CPU cycles
+const PERIOD_LOOKUP: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIOD_LOOKUP: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+fn period_lookup(region: Region) -> &'static [u16; 16] {
+    match region {
+        Region::Pal => &PAL_PERIOD_LOOKUP,
+        Region::Ntsc | Region::Dendy => &PERIOD_LOOKUP,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Noise {
+    pub length: LengthCounter,
+    envelope: Envelope,
+    shift_register: u16,
+    mode: bool,
+    period: u16,
+    timer: u16,
+    previous_cycle: u64,
+    region: Region,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Noise {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::with_region(Region::Ntsc)
+    }
+
+    #[must_use]
+    pub fn with_region(region: Region) -> Self {
+        Self {
+            length: LengthCounter::new(),
+            envelope: Envelope::new(),
+            // Loaded with 1 on power-up
+            shift_register: 1,
+            mode: false,
+            period: period_lookup(region)[0] - 1,
+            timer: 0,
+            previous_cycle: 0,
+            region,
+        }
+    }
+
+    #[must_use]
+    pub fn output(&self) -> u8 {
+        if self.shift_register & 0x01 == 0x01 {
+            return 0;
+        }
+        self.get_volume()
+    }
+
+    pub fn debug_state(&self) -> ChannelDebug {
+        ChannelDebug {
+            length_counter: u16::from(self.length.counter),
+            period: self.period,
+            volume: self.get_volume(),
+            enabled: self.length.enabled,
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_length_counter(&mut self) {
+        self.length.clock();
+    }
+
+    pub fn reload_counter(&mut self) {
+        self.length.reload();
+    }
+
+    pub fn write_ctrl(&mut self, val: u8) -> NeedToRunFlag {
+        let flag = self.length.write_ctrl(val);
+        self.envelope.write_ctrl(val);
+        flag
+    }
+
+    pub fn write_period(&mut self, val: u8) {
+        self.mode = val & 0x80 == 0x80;
+        self.period = period_lookup(self.region)[(val & 0x0F) as usize] - 1;
+    }
+
+    pub fn write_length(&mut self, val: u8) -> NeedToRunFlag {
+        self.envelope.reset = true;
+        if self.length.enabled {
+            self.length.load_value(val)
+        } else {
+            NeedToRunFlag(None)
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.length.enabled = enabled;
+        if !enabled {
+            self.length.counter = 0;
+        }
+    }
+
+    pub fn clock(&mut self, target_cycle: u64) {
+        let mut cycles_to_run = target_cycle - self.previous_cycle;
+        while cycles_to_run > u64::from(self.timer) {
+            cycles_to_run -= u64::from(self.timer) + 1;
+            self.previous_cycle += u64::from(self.timer) + 1;
+            self.timer = self.period;
+            self.shift();
+        }
+        self.timer -= cycles_to_run as u16;
+        self.previous_cycle = target_cycle;
+    }
+
+    // 15-bit LFSR. Mode 1 takes feedback from bit 6 instead of bit 1, giving a short, metallic
+    // 93-step sequence
+    fn shift(&mut self) {
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+        self.shift_register >>= 1;
+        self.shift_register |= feedback << 14;
+    }
+
+    fn get_volume(&self) -> u8 {
+        if self.length.counter > 0 {
+            if self.envelope.enabled {
+                return self.envelope.volume;
+            }
+            return self.envelope.constant_volume;
+        }
+        0
+    }
+}
@@ -1,5 +1,7 @@
+use serde::{Deserialize, Serialize};
+
 use super::{
-    base_channel::AudioChannel,
+    base_channel::{AudioChannel, ChannelDebug},
     envelope::Envelope,
     length_counter::{LengthCounter, NeedToRunFlag},
     sweep::Sweep,
@@ -11,6 +13,7 @@ const HALF: [u8; 8] = [0, 0, 0, 0, 1, 1, 1, 1];
 const QUARTER_NEG: [u8; 8] = [1, 1, 1, 1, 1, 1, 0, 0];
 const DUTY_CYCLES: [[u8; 8]; 4] = [EIGHTH, QUARTER, HALF, QUARTER_NEG];
 
+#[derive(Serialize, Deserialize)]
 pub struct Pulse {
     channel: AudioChannel,
     pub length: LengthCounter,
@@ -49,6 +52,15 @@ impl Pulse {
         DUTY_CYCLES[self.duty_cycle as usize][self.duty_counter as usize] * self.get_volume()
     }
 
+    pub fn debug_state(&self) -> ChannelDebug {
+        ChannelDebug {
+            length_counter: u16::from(self.length.counter),
+            period: self.real_period,
+            volume: self.get_volume(),
+            enabled: self.length.enabled,
+        }
+    }
+
     pub fn clock_quarter_frame(&mut self) {
         self.envelope.clock();
     }
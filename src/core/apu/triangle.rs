@@ -1,3 +1,6 @@
+use serde::{Deserialize, Serialize};
+
+use super::base_channel::ChannelDebug;
 use super::length_counter::{LengthCounter, NeedToRunFlag};
 
 const SEQUENCE: [u8; 32] = [
@@ -5,7 +8,7 @@ const SEQUENCE: [u8; 32] = [
     13, 14, 15,
 ];
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct LinearCounter {
     counter: u8,
     pub counter_reload: u8,
@@ -35,7 +38,7 @@ impl LinearCounter {
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize, Deserialize)]
 pub struct Triangle {
     pub length: LengthCounter,
     linear: LinearCounter,
@@ -64,6 +67,15 @@ impl Triangle {
         f32::from(SEQUENCE[self.seq_pos as usize])
     }
 
+    pub fn debug_state(&self) -> ChannelDebug {
+        ChannelDebug {
+            length_counter: u16::from(self.length.counter),
+            period: self.period,
+            volume: SEQUENCE[self.seq_pos as usize],
+            enabled: self.length.enabled,
+        }
+    }
+
     pub fn clock_quarter_frame(&mut self) {
         self.linear.clock();
     }
@@ -76,10 +88,10 @@ impl Triangle {
         self.length.reload();
     }
 
-    pub fn write_ctrl(&mut self, data: u8) {
+    pub fn write_ctrl(&mut self, data: u8) -> NeedToRunFlag {
         self.linear.control = (data >> 7) == 1;
-        self.linear.counter_reload = data & 0x8F;
-        self.length.write_ctrl(data >> 7);
+        self.linear.counter_reload = data & 0x7F;
+        self.length.set_halt(self.linear.control)
     }
 
     pub fn write_timer_lo(&mut self, data: u8) {
@@ -111,5 +123,8 @@ impl Triangle {
 
     pub fn set_enabled(&mut self, enabled: bool) {
         self.length.enabled = enabled;
+        if !enabled {
+            self.length.counter = 0;
+        }
     }
 }
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+
+// Mixer units per step of VRC6 output, so a pulse at full volume is about as loud as a 2A03 pulse
+// at full volume
+const MIX_SCALE: i32 = 50;
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    // Ignores the duty and outputs the volume constantly
+    ignore_duty: bool,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.volume = val & 0x0f;
+                self.duty = (val >> 4) & 0x07;
+                self.ignore_duty = val & 0x80 != 0;
+            }
+            1 => self.period = (self.period & 0x0f00) | val as u16,
+            2 => {
+                self.period = (self.period & 0x00ff) | ((val as u16 & 0x0f) << 8);
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0f;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.ignore_duty || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Vrc6Saw {
+    rate: u8,
+    period: u16,
+    enabled: bool,
+    timer: u16,
+    // Counts the 14 timer clocks of one ramp, the accumulator is added to on every other one
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => self.rate = val & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | val as u16,
+            2 => {
+                self.period = (self.period & 0x00ff) | ((val as u16 & 0x0f) << 8);
+                self.enabled = val & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if self.step & 0x01 == 0 {
+            self.accumulator = self.accumulator.wrapping_add(self.rate);
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+// The two pulse channels and sawtooth on Konami's VRC6, clocked once per CPU cycle by the mapper
+#[derive(Default, Clone, Serialize, Deserialize)]
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    halt: bool,
+    // Divides every period by 16 or 256, set through $9003 for speeding up the channels
+    shift: u8,
+}
+
+impl Vrc6Audio {
+    // `addr` with the A0/A1 lines already put in the VRC6a order
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x9003 => {
+                self.halt = val & 0x01 != 0;
+                self.shift = if val & 0x04 != 0 {
+                    8
+                } else if val & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0x9000..=0x9002 => self.pulse1.write(addr & 0x03, val),
+            0xA000..=0xA002 => self.pulse2.write(addr & 0x03, val),
+            0xB000..=0xB002 => self.saw.write(addr & 0x03, val),
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.shift);
+        self.pulse2.clock(self.shift);
+        self.saw.clock(self.shift);
+    }
+
+    pub fn output(&self) -> i32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        level as i32 * MIX_SCALE
+    }
+}
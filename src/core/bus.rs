@@ -1,10 +1,17 @@
 use std::sync::{Arc, Mutex};
 
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
 use crate::core::apu::base_channel::AudioChannel;
 use crate::core::apu::frame_counter::IRQSignal;
 use crate::core::apu::APU;
-use crate::core::joypad::Joypad;
-use crate::core::mappers::{MapperFactory, SharedMapper};
+use crate::core::cheats::Cheat;
+use crate::core::joypad::{Joypad, Player};
+use crate::core::mappers::{self, MapperFactory, Mirroring, SharedMapper};
+use crate::core::savestate::big_array;
+use crate::core::zapper::Zapper;
+use crate::core::Region;
 use crate::{core::ppu::PPU, ines_parser::NESFile};
 
 const RAM_SIZE: usize = 0x0800;
@@ -14,33 +21,196 @@ const PPU_REG_START: u16 = 0x2000;
 const PPU_REG_END: u16 = 0x3FFF;
 const APU_IO_START: u16 = 0x4000;
 const APU_IO_END: u16 = 0x401F;
+const OAM_DMA: u16 = 0x4014;
+// Everything from here up is on the cartridge, including expansion/register space at $4020-$5FFF
+const CART_START: u16 = 0x4020;
+const CART_END: u16 = 0xFFFF;
+
+// What CPU RAM, the nametables and OAM hold at power on. Hardware leaves them indeterminate, and
+// some games only work with, or only break with, a particular fill
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamInit {
+    #[default]
+    Zeros,
+    Ones,
+    Pattern(u8),
+    // Xorshift noise from a seed, the same every power on
+    Seeded(u64),
+}
 
+impl RamInit {
+    // ram_init is zeros, ones, pattern or seeded, with ram_init_value as the byte or the seed
+    pub fn from_config() -> Self {
+        let value = Config::get_int("ram_init_value", 0i64);
+        match Config::get_string_with_default("ram_init", "zeros").as_str() {
+            "ones" => RamInit::Ones,
+            "pattern" => RamInit::Pattern(value as u8),
+            "seeded" => RamInit::Seeded(value as u64),
+            _ => RamInit::Zeros,
+        }
+    }
+
+    pub fn bytes(self) -> impl Iterator<Item = u8> {
+        // Xorshift never leaves 0
+        let mut state = match self {
+            RamInit::Seeded(seed) => seed.max(1),
+            _ => 0,
+        };
+        std::iter::repeat_with(move || match self {
+            RamInit::Zeros => 0x00,
+            RamInit::Ones => 0xFF,
+            RamInit::Pattern(val) => val,
+            RamInit::Seeded(_) => {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            }
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Bus {
+    #[serde(with = "big_array")]
     cpu_ram: [u8; RAM_SIZE],
+    // Last value driven on the CPU data bus
+    open_bus: u8,
     pub ppu: PPU,
     pub apu: APU,
     pub joypad: Joypad,
+    pub joypad2: Joypad,
+    // Plugged into port 2 in place of joypad2. Host input like the mapper, so not saved
+    #[serde(skip)]
+    pub zapper: Option<Zapper>,
+    // Cartridge state is saved separately through the mapper
+    #[serde(skip, default = "mappers::unplugged")]
     pub mapper: SharedMapper,
+    #[serde(skip)]
+    mapper_clocked: bool,
+    region: Region,
+    // Game Genie codes patching cartridge reads. Host side, so they stay on across save states
+    #[serde(skip)]
+    cheats: Vec<Cheat>,
 }
 
 impl Bus {
     pub fn new(file: &NESFile) -> Bus {
-        let mapper = Arc::new(Mutex::new(MapperFactory::from_file(file)));
-        Bus {
+        Self::new_with_ram_init(file, RamInit::from_config())
+    }
+
+    pub fn new_with_ram_init(file: &NESFile, ram_init: RamInit) -> Bus {
+        let mapper = MapperFactory::from_file(file);
+        let mapper_clocked = mapper.is_cpu_clocked();
+        let mapper = Arc::new(Mutex::new(mapper));
+        let region = file.region();
+        let mut bus = Bus {
             cpu_ram: [0; RAM_SIZE],
+            open_bus: 0,
             mapper: mapper.clone(),
+            mapper_clocked,
             joypad: Joypad::default(),
+            joypad2: Joypad::default(),
+            zapper: None,
             ppu: PPU::new(mapper),
-            apu: APU::new(),
+            apu: APU::with_region(region),
+            region,
+            cheats: Vec::new(),
+        };
+        bus.ppu.set_region(region);
+        bus.apu.register_cartridge_audio(&**bus.mapper.lock().unwrap());
+
+        let mut bytes = ram_init.bytes();
+        bus.cpu_ram.fill_with(|| bytes.next().unwrap());
+        {
+            let mut mapper = bus.mapper.lock().unwrap();
+            for idx in 0..4 {
+                for addr in 0..0x400 {
+                    mapper.write_nametable_idx(idx, addr, bytes.next().unwrap());
+                }
+            }
+        }
+        bus.ppu.fill_oam(&mut bytes);
+        bus
+    }
+
+    pub(crate) fn take_host_resources(&mut self, from: &mut Bus) {
+        self.mapper = from.mapper.clone();
+        self.mapper_clocked = from.mapper_clocked;
+        self.zapper = from.zapper.take();
+        self.cheats = std::mem::take(&mut from.cheats);
+        self.ppu.take_host_resources(&mut from.ppu);
+        self.apu.take_host_resources(&mut from.apu);
+    }
+
+    pub fn joypad_for(&mut self, player: Player) -> &mut Joypad {
+        match player {
+            Player::One => &mut self.joypad,
+            Player::Two => &mut self.joypad2,
         }
     }
 
+    pub fn add_cheat(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cheats.clear();
+    }
+
+    pub fn cheats(&self) -> &[Cheat] {
+        &self.cheats
+    }
+
+    fn read_cart(&self, addr: u16) -> u8 {
+        let val = self.mapper.lock().unwrap().read(addr);
+        self.apply_cheats(addr, val)
+    }
+
+    fn apply_cheats(&self, addr: u16, val: u8) -> u8 {
+        // Skipped entirely when there aren't any, this is on every PRG-ROM read
+        if self.cheats.is_empty() {
+            return val;
+        }
+        self.cheats
+            .iter()
+            .fold(val, |patched, cheat| cheat.apply(addr, patched))
+    }
+
+    pub fn pull_zapper_trigger(&mut self) {
+        if let Some(zapper) = &mut self.zapper {
+            zapper.pull_trigger(self.ppu.frame_count);
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    // For debuggers and comparing runs. The 2K at $0000-$07FF, without the mirrors
+    pub fn cpu_ram(&self) -> &[u8; RAM_SIZE] {
+        &self.cpu_ram
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.ppu
+            .mirroring_override()
+            .unwrap_or_else(|| self.mapper.lock().unwrap().get_mirroring())
+    }
+
+    // Debug-only: forces the PPU to use this mirroring instead of the mapper's until reset to
+    // None. Frame hashes won't match while it's set
+    pub fn force_mirroring(&mut self, mirroring: Option<Mirroring>) {
+        self.ppu.set_mirroring_override(mirroring);
+    }
+
     pub fn read_trace(&self, addr: u16) -> u8 {
         match addr {
             RAM_START..=RAM_END => self.cpu_ram[(addr & 0x07FF) as usize],
             PPU_REG_START..=PPU_REG_END => self.ppu.read_ppudata_trace(addr as usize),
+            OAM_DMA => self.open_bus,
             APU_IO_START..=APU_IO_END => self.read_apu_trace(addr),
-            _ => self.mapper.lock().unwrap().read(addr),
+            CART_START..=CART_END => self.read_cart(addr),
         }
     }
 
@@ -48,8 +218,10 @@ impl Bus {
         let mapped_addr = (addr - APU_IO_START) % 0x1F;
         match mapped_addr {
             0x16 => self.joypad.read_trace(),
-            // Controller 2 data disabled -- always return 0
-            0x17 => 0,
+            0x17 => match &self.zapper {
+                Some(zapper) => zapper.read(&self.ppu),
+                None => self.joypad2.read_trace(),
+            },
             0x15 => self.apu.read_status_trace(),
             _ => self.ppu.open_bus,
         }
@@ -57,7 +229,7 @@ impl Bus {
 
     pub fn read_16_trace(&self, addr: u16) -> u16 {
         let low = self.read_trace(addr);
-        let high = self.read_trace(addr + 1);
+        let high = self.read_trace(addr.wrapping_add(1));
         (high as u16) << 8 | low as u16
     }
 
@@ -66,23 +238,32 @@ impl Bus {
         let val = match addr {
             RAM_START..=RAM_END => self.cpu_ram[(addr & 0x07FF) as usize],
             PPU_REG_START..=PPU_REG_END => self.execute_ppu_read(addr),
+            // $4014 is write-only and not driven by the PPU, so the CPU sees its own open bus
+            OAM_DMA => self.open_bus,
             APU_IO_START..=APU_IO_END => {
                 let ret = self.execute_apu_io_read(addr);
                 signal = ret.1;
                 ret.0
             }
-            _ => self.mapper.lock().unwrap().read(addr),
+            CART_START..=CART_END => {
+                let val = self.mapper.lock().unwrap().read_cpu(addr);
+                self.apply_cheats(addr, val)
+            }
         };
+        self.open_bus = val;
         (val, signal)
     }
 
     pub fn write(&mut self, addr: u16, data: u8, cpu_cycle: u64) -> IRQSignal {
         let mut signal = IRQSignal::None;
+        self.open_bus = data;
         match addr {
             RAM_START..=RAM_END => self.cpu_ram[(addr & 0x07FF) as usize] = data,
             PPU_REG_START..=PPU_REG_END => self.execute_ppu_write(addr, data),
             APU_IO_START..=APU_IO_END => signal = self.execute_apu_io_write(addr, data, cpu_cycle),
-            _ => self.mapper.lock().unwrap().write(addr, data),
+            CART_START..=CART_END => {
+                self.mapper.lock().unwrap().write_timed(addr, data, cpu_cycle);
+            }
         }
         signal
     }
@@ -92,8 +273,10 @@ impl Bus {
         let mut signal = IRQSignal::None;
         let val = match mapper_addr {
             0x16 => self.joypad.read(),
-            // Controller 2 data disabled -- always return 0
-            0x17 => 0,
+            0x17 => match &self.zapper {
+                Some(zapper) => zapper.read(&self.ppu),
+                None => self.joypad2.read(),
+            },
             0x15 => {
                 let ret = self.apu.read_status();
                 signal = ret.1;
@@ -120,14 +303,21 @@ impl Bus {
             0x09 => {}
             0x0A => self.apu.write_timer_lo(&AudioChannel::Triangle, data),
             0x0B => self.apu.write_timer_hi(&AudioChannel::Triangle, data),
-            0x0C..=0x0F => {}
+            0x0C => self.apu.write_ctrl(&AudioChannel::Noise, data),
+            0x0D => {}
+            0x0E => self.apu.write_timer_lo(&AudioChannel::Noise, data),
+            0x0F => self.apu.write_timer_hi(&AudioChannel::Noise, data),
             0x10 => self.apu.write_dmc_ctrl(data),
             0x11 => self.apu.write_dmc_load(data),
             0x12 => self.apu.write_dmc_addr(data),
             0x13 => self.apu.write_dmc_lc(data),
             0x14 => self.ppu.write_oamdma(data),
             0x15 => self.apu.write_status(data, cpu_cycle),
-            0x16 => self.joypad.write(data),
+            // The strobe line goes to both ports
+            0x16 => {
+                self.joypad.write(data);
+                self.joypad2.write(data);
+            }
             0x17 => signal = self.apu.write_frame_counter(data),
             _ => unreachable!(),
         }
@@ -157,7 +347,7 @@ impl Bus {
     }
 
     fn execute_ppu_write(&mut self, addr: u16, data: u8) {
-        if addr != 0x4014 {
+        if addr != OAM_DMA {
             self.ppu.set_open_bus(0xff, data);
         }
         let mapped_addr = (addr - PPU_REG_START) % 8;
@@ -178,6 +368,16 @@ impl Bus {
         self.ppu.nmi_generated = nmi;
     }
 
+    // Runs the mapper's CPU-cycle work. Returns whether the mapper is asserting IRQ
+    pub(crate) fn clock_mapper(&mut self) -> bool {
+        if !self.mapper_clocked {
+            return false;
+        }
+        let mut mapper = self.mapper.lock().unwrap();
+        mapper.clock_cpu();
+        mapper.irq_pending()
+    }
+
     pub(crate) fn run_to(&mut self, cyc: u64) {
         self.ppu.run_to(cyc);
     }
@@ -0,0 +1,60 @@
+// Game Genie letters, in the order of the nibble each one stands for
+const LETTERS: [u8; 16] = *b"APZLGITYEOXUKSVN";
+
+// A decoded Game Genie code. CPU reads from `addr` see `value` instead of what the cartridge
+// returns, as long as the cartridge returns `compare` when there is one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cheat {
+    pub addr: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl Cheat {
+    // 6 letter codes always substitute, 8 letter codes carry a compare value. Case doesn't matter
+    pub fn from_game_genie(code: &str) -> Option<Cheat> {
+        let n = code
+            .trim()
+            .bytes()
+            .map(|c| {
+                LETTERS
+                    .iter()
+                    .position(|&l| l == c.to_ascii_uppercase())
+                    .map(|n| n as u16)
+            })
+            .collect::<Option<Vec<u16>>>()?;
+        if n.len() != 6 && n.len() != 8 {
+            return None;
+        }
+
+        // The bits of the address and value are scattered across the letters
+        let addr = 0x8000
+            | (n[3] & 7) << 12
+            | (n[5] & 7) << 8
+            | (n[4] & 8) << 8
+            | (n[2] & 7) << 4
+            | (n[1] & 8) << 4
+            | (n[4] & 7)
+            | (n[3] & 8);
+        let value = (n[1] & 7) << 4 | (n[0] & 8) << 4 | (n[0] & 7);
+        let (value, compare) = if n.len() == 6 {
+            (value | (n[5] & 8), None)
+        } else {
+            let compare = (n[7] & 7) << 4 | (n[6] & 8) << 4 | (n[6] & 7) | (n[5] & 8);
+            (value | (n[7] & 8), Some(compare as u8))
+        };
+        Some(Cheat {
+            addr,
+            value: value as u8,
+            compare,
+        })
+    }
+
+    pub fn apply(&self, addr: u16, val: u8) -> u8 {
+        if addr == self.addr && self.compare.is_none_or(|compare| compare == val) {
+            self.value
+        } else {
+            val
+        }
+    }
+}
@@ -1,18 +1,224 @@
 use std::{
+    collections::VecDeque,
+    fmt,
     io::Write,
     path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
-use crate::{config::Config, ines_parser::NESFile};
-use super::{bus::Bus, cpu::CPU, frame::Frame, joypad::Buttons};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use crossbeam::channel::{self, Receiver, TryRecvError};
+use image::{save_buffer_with_format, ColorType, ImageFormat, ImageResult};
+use serde::{Deserialize, Serialize};
+use crate::{config::Config, frontend::wav::WavWriter, ines_parser::NESFile};
+use super::{
+    apu::APU,
+    bus::{Bus, RamInit},
+    cheats::Cheat,
+    cpu::{tracer::TraceFormat, CPU},
+    frame::Frame,
+    joypad::{Buttons, Player},
+    mappers::Mirroring,
+    rewind::RewindBuffer,
+    zapper::Zapper,
+};
+
+pub enum ConsoleMsg {
+    JoypadDown(Player, Buttons),
+    JoypadUp(Player, Buttons),
+    RunFrame,
+    // Emulated frames per RunFrame, fractional speeds skip some RunFrames instead. Has to be above
+    // 0, anything else is ignored
+    SetSpeed(f32),
+    Pause(bool),
+    Reset,
+    PowerCycle,
+    Rewind,
+    LoadRom(Box<NESFile>),
+    ConnectZapper(bool),
+    // Screen position the Zapper is pointed at, None when it's off screen
+    AimZapper(Option<(usize, usize)>),
+    PullZapperTrigger,
+}
+
+#[derive(Debug)]
+pub enum ConsoleError {
+    Io(std::io::Error),
+    InvalidState(String),
+    InvalidCheat(String),
+    NoDiskSide(u8),
+}
+
+impl fmt::Display for ConsoleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleError::Io(err) => write!(f, "{err}"),
+            ConsoleError::InvalidState(reason) => write!(f, "invalid save state: {reason}"),
+            ConsoleError::InvalidCheat(code) => write!(f, "not a Game Genie code: {code}"),
+            ConsoleError::NoDiskSide(side) => write!(f, "the disk image has no side {side}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for ConsoleError {
+    fn from(err: std::io::Error) -> Self {
+        ConsoleError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for ConsoleError {
+    fn from(err: bincode::Error) -> Self {
+        ConsoleError::InvalidState(err.to_string())
+    }
+}
+
+// Buttons that can be given auto-fire, in the order of Console::turbo
+const TURBO_BUTTONS: [Buttons; 2] = [Buttons::A, Buttons::B];
+
+// The furthest adjust_audio_rate strays from the device's rate, well under what's audible as a
+// change in pitch
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+const STATE_MAGIC: [u8; 4] = *b"RNST";
+// Bump whenever a serialized struct changes shape, older states won't load
+const STATE_VERSION: u32 = 5;
+
+#[derive(Serialize, Deserialize)]
+struct StateHeader {
+    magic: [u8; 4],
+    version: u32,
+    rom_hash: u64,
+}
+
+// Frames run per second of wall time, averaged over about a second
+struct FpsCounter {
+    since: Instant,
+    frames: u32,
+    fps: f64,
+}
+
+impl FpsCounter {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            frames: 0,
+            fps: 0.,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.frames += 1;
+        let elapsed = self.since.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.frames = 0;
+            self.since = Instant::now();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameMetrics {
+    // Over the last FrameTimer::WINDOW frames
+    pub average: Duration,
+    pub max: Duration,
+    pub missed_deadlines: u64,
+}
+
+// Wall time the emulation thread spends on each frame the frontend asks for, fast-forwarded
+// frames included, against how long it had before the next one was due
+pub struct FrameTimer {
+    times: VecDeque<Duration>,
+    missed_deadlines: u64,
+}
+
+impl Default for FrameTimer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FrameTimer {
+    pub const WINDOW: usize = 60;
+
+    pub fn new() -> Self {
+        Self {
+            times: VecDeque::with_capacity(Self::WINDOW),
+            missed_deadlines: 0,
+        }
+    }
+
+    pub fn record(&mut self, time: Duration, deadline: Duration) {
+        if self.times.len() == Self::WINDOW {
+            self.times.pop_front();
+        }
+        self.times.push_back(time);
+        if time > deadline {
+            self.missed_deadlines += 1;
+        }
+    }
+
+    pub fn metrics(&self) -> FrameMetrics {
+        let total: Duration = self.times.iter().sum();
+        FrameMetrics {
+            average: total / self.times.len().max(1) as u32,
+            max: self.times.iter().max().copied().unwrap_or_default(),
+            missed_deadlines: self.missed_deadlines,
+        }
+    }
+}
 
 pub struct Console {
     pub cpu: CPU,
     pub rom_hash: u64,
+    // Kept for power cycling
+    rom: NESFile,
+    ram_init: RamInit,
+    power_on_state: Vec<u8>,
+    sample_rate: f64,
+    // Behind its own lock so the UI can start/stop it while the emulation thread holds the console
+    recorder: Mutex<Option<WavWriter>>,
+    rewind: Option<RewindBuffer>,
+    paused: bool,
+    fps: FpsCounter,
+    frame_timer: FrameTimer,
+    // Auto-fire rate for A and B in presses per second, None while off
+    turbo: [Option<f64>; 2],
+    // What each player is physically holding, and for how many frames they've held A and B
+    held: [Buttons; 2],
+    turbo_frames: [[u32; 2]; 2],
 }
 
 impl Console {
     pub fn new(rom: NESFile) -> Self {
-        let mut cpu = CPU::new(Bus::new(&rom));
+        Self::new_with_ram_init(rom, RamInit::from_config())
+    }
+
+    pub fn new_with_ram_init(rom: NESFile, ram_init: RamInit) -> Self {
+        let cpu = Self::power_on(&rom, ram_init);
+
+        let mut console = Console {
+            cpu,
+            rom_hash: rom.hash,
+            rom,
+            ram_init,
+            power_on_state: Vec::new(),
+            sample_rate: APU::DEFAULT_SAMPLE_RATE,
+            recorder: Mutex::new(None),
+            rewind: None,
+            paused: false,
+            fps: FpsCounter::new(),
+            frame_timer: FrameTimer::new(),
+            turbo: [None; 2],
+            held: [Buttons::empty(); 2],
+            turbo_frames: [[0; 2]; 2],
+        };
+        console.power_on_state = console.save_state();
+        console
+    }
+
+    fn power_on(rom: &NESFile, ram_init: RamInit) -> CPU {
+        let mut cpu = CPU::new(Bus::new_with_ram_init(rom, ram_init));
 
         if Config::get_bool("enable_logging", false) {
             cpu.set_sink(Box::new(
@@ -23,31 +229,558 @@ impl Console {
                     .open(Config::get_string_with_default("logging_path", "log.log"))
                     .unwrap(),
             ));
-            cpu.enable_logging();
+            let format = Config::get_string_with_default("trace_format", "text");
+            cpu.enable_logging(TraceFormat::from_name(&format).unwrap_or_else(|| {
+                println!("Unknown trace_format {format}, using text");
+                TraceFormat::Text
+            }));
+        }
+        cpu.bus
+            .apu
+            .set_click_suppression(Config::get_bool("click_suppression", false));
+        if Config::get_bool("stereo", false) {
+            cpu.bus.apu.set_stereo(Some([
+                Config::get_float("pan_pulse1", -0.5),
+                Config::get_float("pan_pulse2", 0.5),
+            ]));
         }
         cpu.reset();
+        cpu
+    }
 
-        Console {
-            cpu,
-            rom_hash: rom.hash,
+    // Swaps the cartridge in place, keeping the audio stream and thread. Like a fresh Console,
+    // the incoming game's save is picked up if one exists
+    pub fn load_rom(&mut self, rom: NESFile) -> Result<(), ConsoleError> {
+        self.replace_cpu(Self::power_on(&rom, self.ram_init));
+        self.cpu.bus.clear_cheats();
+        self.cpu.bus.apu.clear_expansions();
+        self.rom_hash = rom.hash;
+        self.rom = rom;
+        self.power_on_state = self.save_state();
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+
+        self.load_default_save()?;
+        Ok(())
+    }
+
+    // Swaps in a freshly powered on console, keeping the host's audio rate, palette, Zapper,
+    // overclock and registered expansion audio
+    fn replace_cpu(&mut self, mut cpu: CPU) {
+        cpu.bus.apu.set_output_sample_rate(self.sample_rate);
+        cpu.bus.ppu.set_palette(self.cpu.bus.ppu.palette().clone());
+        cpu.bus
+            .ppu
+            .set_unlimited_sprites(self.cpu.bus.ppu.unlimited_sprites());
+        cpu.bus.zapper = self.cpu.bus.zapper.take();
+        cpu.set_overclock(self.cpu.overclock());
+        cpu.bus.apu.take_expansions(&mut self.cpu.bus.apu);
+        for &cheat in self.cpu.bus.cheats() {
+            cpu.bus.add_cheat(cheat);
+        }
+        self.cpu = cpu;
+    }
+
+    // Takes a 6 or 8 letter Game Genie code. Cheats last until the ROM is swapped out
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), ConsoleError> {
+        let cheat = Cheat::from_game_genie(code)
+            .ok_or_else(|| ConsoleError::InvalidCheat(code.to_string()))?;
+        self.cpu.bus.add_cheat(cheat);
+        Ok(())
+    }
+
+    // Resets the CPU only, so RAM, the PPU, APU and cartridge all carry on as they are
+    pub fn soft_reset(&mut self) {
+        self.cpu.soft_reset();
+    }
+
+    // Starts over from the ROM. Battery-backed RAM survives, as it would on hardware
+    pub fn power_cycle(&mut self) {
+        let save = self.cpu.bus.mapper.lock().unwrap().dump_save().to_vec();
+        self.replace_cpu(Self::power_on(&self.rom, self.ram_init));
+        self.cpu.bus.mapper.lock().unwrap().load_save(&save);
+        self.power_on_state = self.save_state();
+    }
+
+    // What the PPU is using for nametables right now, the forced mode if there is one
+    pub fn mirroring(&self) -> Mirroring {
+        self.cpu.bus.mirroring()
+    }
+
+    // Debug-only, see Bus::force_mirroring. Kept in save states, None goes back to the mapper's
+    pub fn force_mirroring(&mut self, mirroring: Option<Mirroring>) {
+        self.cpu.bus.force_mirroring(mirroring);
+    }
+
+    // Zero unless an .fds image is loaded. Side 0 is disk 1 side A, 1 is side B, and so on
+    pub fn disk_sides(&self) -> usize {
+        self.cpu.bus.mapper.lock().unwrap().disk_sides()
+    }
+
+    pub fn inserted_disk(&self) -> Option<usize> {
+        self.cpu.bus.mapper.lock().unwrap().inserted_disk()
+    }
+
+    // The drive sits empty for a moment before the new side goes in, like a real swap
+    pub fn insert_disk(&mut self, side: u8) -> Result<(), ConsoleError> {
+        let mut mapper = self.cpu.bus.mapper.lock().unwrap();
+        if side as usize >= mapper.disk_sides() {
+            return Err(ConsoleError::NoDiskSide(side));
+        }
+        mapper.insert_disk(Some(side as usize));
+        Ok(())
+    }
+
+    pub fn eject_disk(&mut self) {
+        self.cpu.bus.mapper.lock().unwrap().insert_disk(None);
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // How fast frames are actually being run by run_thread, fast-forward included
+    pub fn measured_fps(&self) -> f64 {
+        self.fps.fps
+    }
+
+    // Filled in by run_thread. A deadline is missed when a frame takes longer than target_fps
+    // allows, or than the region's frame rate without one
+    pub fn frame_metrics(&self) -> FrameMetrics {
+        self.frame_timer.metrics()
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f64) {
+        self.sample_rate = sample_rate;
+        self.cpu.bus.apu.set_output_sample_rate(sample_rate);
+    }
+
+    // Rate control for a host audio queue that's `fill` full, from 0 to 1. Resamples slightly
+    // fast while it's under half full and slightly slow while it's over, so the emulator and the
+    // sound card's clocks can't drift apart into underruns or dropped samples
+    pub fn adjust_audio_rate(&mut self, fill: f64) {
+        let ratio = 1. + (0.5 - fill.clamp(0., 1.)) * 2. * MAX_RATE_ADJUST;
+        self.cpu.bus.apu.adjust_output_sample_rate(self.sample_rate * ratio);
+    }
+
+    // Header, then the CPU (which owns the rest of the console), then the mapper's own state
+    pub fn save_state(&self) -> Vec<u8> {
+        let header = StateHeader {
+            magic: STATE_MAGIC,
+            version: STATE_VERSION,
+            rom_hash: self.rom_hash,
+        };
+        let mapper = self.cpu.bus.mapper.lock().unwrap().save_state();
+
+        let mut state = Vec::new();
+        bincode::serialize_into(&mut state, &header).unwrap();
+        bincode::serialize_into(&mut state, &self.cpu).unwrap();
+        bincode::serialize_into(&mut state, &mapper).unwrap();
+        state
+    }
+
+    // Leaves the console untouched if the state doesn't validate
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), ConsoleError> {
+        let mut reader = state;
+        let header: StateHeader = bincode::deserialize_from(&mut reader)?;
+        if header.magic != STATE_MAGIC {
+            return Err(ConsoleError::InvalidState("not a save state".to_string()));
+        }
+        if header.version != STATE_VERSION {
+            return Err(ConsoleError::InvalidState(format!(
+                "version {} isn't supported, expected {}",
+                header.version, STATE_VERSION
+            )));
+        }
+        if header.rom_hash != self.rom_hash {
+            return Err(ConsoleError::InvalidState(
+                "state was saved with a different ROM".to_string(),
+            ));
+        }
+
+        let cpu: CPU = bincode::deserialize_from(&mut reader)?;
+        let mapper: Vec<u8> = bincode::deserialize_from(&mut reader)?;
+        self.cpu.bus.mapper.lock().unwrap().load_state(&mapper)?;
+        self.cpu.restore(cpu);
+        Ok(())
+    }
+
+    // A save state taken right after the initial reset. A recording can embed it and playback can
+    // load_state it to start from the same point, whatever the host's ram_init is
+    pub fn power_on_state(&self) -> Vec<u8> {
+        self.power_on_state.clone()
+    }
+
+    pub fn run_thread(console: Arc<Mutex<Console>>, recv: Receiver<ConsoleMsg>) {
+        #[cfg(feature = "thread-priority")]
+        Self::configure_thread();
+
+        let device = cpal::default_host()
+            .default_output_device()
+            .expect("no audio output device");
+        let config = device.default_output_config().unwrap();
+        let sample_rate = config.sample_rate().0;
+        let channels = config.channels() as usize;
+        console.lock().unwrap().set_sample_rate(sample_rate as f64);
+
+        // Left and right, the same sample twice for mono. Bigger buffers ride out slow frames
+        // better at the cost of latency
+        let buffer_frames =
+            Config::get_int("audio_buffer_frames", 2048i64).clamp(256, 16384) as usize;
+        let (audio_send, audio_recv) = channel::bounded::<(i16, i16)>(buffer_frames);
+        let queued_audio = audio_recv.clone();
+        let stream_callback = move |data: &mut [i16], _: &cpal::OutputCallbackInfo| {
+            for frame in data.chunks_mut(channels) {
+                let (left, right) = audio_recv.try_recv().unwrap_or((0, 0));
+                if channels == 1 {
+                    frame[0] = ((left as i32 + right as i32) / 2) as i16;
+                } else {
+                    for (i, sample) in frame.iter_mut().enumerate() {
+                        *sample = if i % 2 == 0 { left } else { right };
+                    }
+                }
+            }
+        };
+        let stream = device
+            .build_output_stream(
+                &config.into(),
+                stream_callback,
+                |err| println!("Audio stream error: {err}"),
+                None,
+            )
+            .unwrap();
+        stream.play().unwrap();
+
+        // With target_fps set the thread keeps its own time and the frontend's RunFrames are
+        // ignored, 0 runs frames back to back. Unset, a frame runs whenever the frontend asks
+        let frame_period = Config::get_string("target_fps").map(|_| {
+            let fps = Config::get_float("target_fps", 0.);
+            if fps > 0. {
+                Duration::from_secs_f64(1. / fps)
+            } else {
+                Duration::ZERO
+            }
+        });
+        let mut next_frame = Instant::now();
+        // Nothing to pace while paused, so it waits on the frontend like it does without a target
+        let mut paused = false;
+
+        let mut speed = 1.0;
+        let mut frame_credit = 0.0;
+        loop {
+            let msg = match frame_period.filter(|_| !paused) {
+                None => match recv.recv() {
+                    Ok(msg) => msg,
+                    Err(_) => break,
+                },
+                Some(period) => match recv.try_recv() {
+                    Ok(ConsoleMsg::RunFrame) => continue,
+                    Ok(msg) => msg,
+                    Err(TryRecvError::Disconnected) => break,
+                    Err(TryRecvError::Empty) => {
+                        let now = Instant::now();
+                        match next_frame.checked_duration_since(now) {
+                            Some(wait) => {
+                                std::thread::sleep(wait);
+                                next_frame += period;
+                            }
+                            // Running behind, start counting again from here rather than
+                            // rushing through frames to catch up
+                            None => next_frame = now + period,
+                        }
+                        ConsoleMsg::RunFrame
+                    }
+                },
+            };
+            // Waiting on the frontend to let go of the console counts against the frame too
+            let started = Instant::now();
+            let mut console = console.lock().unwrap();
+            match msg {
+                // Headless stepping still works while paused, this only stops the frontend's frames
+                ConsoleMsg::RunFrame if console.is_paused() => {}
+                ConsoleMsg::RunFrame => {
+                    frame_credit += speed;
+                    let mut frames_run = 0;
+                    while frame_credit >= 1.0 {
+                        frame_credit -= 1.0;
+                        frames_run += 1;
+                        let samples = console.run_frame();
+                        console.fps.tick();
+                        // Audio at any other speed would need resampling, so it's muted instead
+                        if speed != 1.0 {
+                            continue;
+                        }
+                        console.record_samples(&samples);
+                        let frames: Vec<(i16, i16)> = if console.cpu.bus.apu.is_stereo() {
+                            samples.chunks_exact(2).map(|lr| (lr[0], lr[1])).collect()
+                        } else {
+                            samples.iter().map(|&s| (s, s)).collect()
+                        };
+                        for frame in frames {
+                            // Drop samples rather than block the emulator if the device falls behind
+                            let _ = audio_send.try_send(frame);
+                        }
+                        let fill = audio_send.len() as f64 / buffer_frames as f64;
+                        console.adjust_audio_rate(fill);
+                    }
+                    if frames_run > 0 {
+                        let deadline = frame_period.filter(|period| !period.is_zero());
+                        let deadline = deadline.unwrap_or_else(|| {
+                            Duration::from_secs_f64(1. / console.cpu.bus.region().frame_rate())
+                        });
+                        // Fast-forward runs several frames at once, each getting a frame's time
+                        console.frame_timer.record(started.elapsed(), deadline * frames_run);
+                    }
+                }
+                // 0 or less would never run a frame again, and infinity never finish one
+                ConsoleMsg::SetSpeed(new_speed) if new_speed > 0.0 && new_speed.is_finite() => {
+                    speed = new_speed;
+                    frame_credit = 0.0;
+                }
+                ConsoleMsg::SetSpeed(new_speed) => {
+                    println!("Ignoring speed {new_speed}, it has to be above 0");
+                }
+                ConsoleMsg::Pause(pause) => {
+                    paused = pause;
+                    console.set_paused(paused);
+                    // Cut the sound off now rather than playing out what's queued
+                    if paused {
+                        while queued_audio.try_recv().is_ok() {}
+                    }
+                }
+                ConsoleMsg::Reset => console.soft_reset(),
+                ConsoleMsg::PowerCycle => console.power_cycle(),
+                ConsoleMsg::Rewind => {
+                    console.rewind_step();
+                }
+                ConsoleMsg::JoypadDown(player, button) => console.set_joypad(player, button, true),
+                ConsoleMsg::JoypadUp(player, button) => console.set_joypad(player, button, false),
+                ConsoleMsg::LoadRom(rom) => {
+                    if let Err(err) = console.load_rom(*rom) {
+                        println!("Error while swapping ROM: {err}");
+                    }
+                }
+                ConsoleMsg::ConnectZapper(connected) => console.connect_zapper(connected),
+                ConsoleMsg::AimZapper(pos) => console.aim_zapper(pos),
+                ConsoleMsg::PullZapperTrigger => console.cpu.bus.pull_zapper_trigger(),
+            }
+        }
+    }
+
+    // Optional tuning for the emulation thread, e.g. to keep audio from crackling on busy hosts.
+    // Unset keys leave the OS defaults alone
+    #[cfg(feature = "thread-priority")]
+    fn configure_thread() {
+        use thread_priority::{set_current_thread_priority, ThreadPriority, ThreadPriorityValue};
+
+        let priority = Config::get_int("emu_thread_priority", -1);
+        if priority >= 0 {
+            let res = u8::try_from(priority)
+                .ok()
+                .and_then(|p| ThreadPriorityValue::try_from(p).ok())
+                .map(|p| set_current_thread_priority(ThreadPriority::Crossplatform(p)));
+            if !matches!(res, Some(Ok(()))) {
+                println!("Could not set emulation thread priority to {priority}");
+            }
+        }
+
+        let core = Config::get_int("emu_thread_core", -1);
+        if core >= 0 {
+            let pinned = core_affinity::get_core_ids()
+                .and_then(|ids| ids.into_iter().find(|id| id.id as i64 == core))
+                .is_some_and(core_affinity::set_for_current);
+            if !pinned {
+                println!("Could not pin emulation thread to core {core}");
+            }
         }
     }
 
     pub fn run_frame(&mut self) -> Vec<i16> {
+        if self.cpu.is_halted() {
+            return Vec::new();
+        }
+        self.apply_turbo();
         self.cpu.run_until_frame();
         let mut samples = Vec::with_capacity(1024);
-        self.cpu.bus.apu.output_buffer.end_frame(&mut samples);
+        self.cpu.bus.apu.end_frame(&mut samples);
+        self.capture_rewind_state();
         samples
     }
 
-    pub fn set_joypad(&mut self, button: Buttons, pressed: bool) {
-        self.cpu.bus.joypad.buttons.set(button, pressed);
+    // Keeps the last `seconds` of play, 0 turns rewind off. A state is captured after every
+    // frame: serializing it costs about as much as emulating a few scanlines, and only the bytes
+    // that changed since the previous frame are kept, typically a few KB but up to the ~300KB
+    // of a full state when the whole picture changes
+    pub fn enable_rewind(&mut self, seconds: u32) {
+        let frames = (f64::from(seconds) * self.cpu.bus.region().frame_rate()).round() as usize;
+        self.rewind = (frames > 0).then(|| RewindBuffer::new(frames));
+    }
+
+    // Restores the state from one frame back. Returns false once the buffer runs out
+    pub fn rewind_step(&mut self) -> bool {
+        let Some(mut rewind) = self.rewind.take() else {
+            return false;
+        };
+        let restored = match rewind.step_back() {
+            Some(state) => self.load_state(state).is_ok(),
+            None => false,
+        };
+        self.rewind = Some(rewind);
+        restored
+    }
+
+    pub fn rewind_memory_usage(&self) -> usize {
+        self.rewind.as_ref().map_or(0, RewindBuffer::memory_usage)
+    }
+
+    fn capture_rewind_state(&mut self) {
+        if self.rewind.is_some() {
+            let state = self.save_state();
+            if let Some(rewind) = &mut self.rewind {
+                rewind.push(state);
+            }
+        }
+    }
+
+    // Records at the output sample rate, so start after `run_thread` has negotiated it with the
+    // device. An existing recording is finished first
+    pub fn start_audio_recording(&self, path: PathBuf) -> std::io::Result<()> {
+        let channels = if self.cpu.bus.apu.is_stereo() { 2 } else { 1 };
+        let writer = WavWriter::create(path, self.sample_rate as u32, channels)?;
+        if let Some(prev) = self.recorder.lock().unwrap().replace(writer) {
+            prev.finish()?;
+        }
+        Ok(())
+    }
+
+    pub fn stop_audio_recording(&self) -> std::io::Result<()> {
+        match self.recorder.lock().unwrap().take() {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording_audio(&self) -> bool {
+        self.recorder.lock().unwrap().is_some()
+    }
+
+    fn record_samples(&self, samples: &[i16]) {
+        let mut recorder = self.recorder.lock().unwrap();
+        if let Some(writer) = recorder.as_mut() {
+            if let Err(err) = writer.write_samples(samples) {
+                println!("Error while recording audio, stopping: {err}");
+                *recorder = None;
+            }
+        }
+    }
+
+    // Headless stepping for tools and tests. The frame's audio is drained and dropped so the
+    // output buffer never fills up when nothing is listening
+    pub fn step_frame(&mut self) -> &Frame {
+        self.run_frame();
+        self.frame()
+    }
+
+    pub fn step_instruction(&mut self) {
+        self.cpu.run();
+    }
+
+    // Stops on the first instruction boundary at or after dot 1 of scanline 241, i.e. where the
+    // frame is complete and the NMI (if enabled) is about to be taken
+    pub fn step_to_vblank(&mut self) {
+        self.cpu.run_until_vblank();
+    }
+
+    pub fn set_joypad(&mut self, player: Player, button: Buttons, pressed: bool) {
+        let held = &mut self.held[player as usize];
+        let was_held = held.contains(button);
+        held.set(button, pressed);
+        // The frontend repeats presses for as long as a key is down, which mustn't undo auto-fire
+        let turbo = TURBO_BUTTONS
+            .iter()
+            .position(|&turbo_button| turbo_button == button)
+            .and_then(|i| self.turbo[i]);
+        if was_held && pressed && turbo.is_some() {
+            return;
+        }
+        self.cpu.bus.joypad_for(player).buttons.set(button, pressed);
+    }
+
+    // Auto-fire for A or B at `rate` presses per second, None to turn it off. Rounded to a whole
+    // number of frames per press, so 15 is 2 frames down and 2 up at 60Hz. Only fires while the
+    // button is held, starting with a press the frame it goes down
+    pub fn set_turbo(&mut self, button: Buttons, rate: Option<f64>) {
+        if let Some(i) = TURBO_BUTTONS.iter().position(|&turbo_button| turbo_button == button) {
+            self.turbo[i] = rate.filter(|&rate| rate > 0.);
+            // Turning it off leaves the button as the player is holding it
+            for (p, player) in [Player::One, Player::Two].into_iter().enumerate() {
+                self.turbo_frames[p][i] = 0;
+                let held = self.held[p].contains(button);
+                self.cpu.bus.joypad_for(player).buttons.set(button, held);
+            }
+        }
+    }
+
+    // Run at the start of every frame, before the game gets to read the controllers
+    fn apply_turbo(&mut self) {
+        let frame_rate = self.cpu.bus.region().frame_rate();
+        for (p, player) in [Player::One, Player::Two].into_iter().enumerate() {
+            for (i, &button) in TURBO_BUTTONS.iter().enumerate() {
+                let Some(rate) = self.turbo[i] else {
+                    continue;
+                };
+                let held = self.held[p].contains(button);
+                let period = ((frame_rate / rate).round() as u32).max(2);
+                let frames = &mut self.turbo_frames[p][i];
+                let pressed = held && *frames % period < period / 2;
+                *frames = if held { *frames + 1 } else { 0 };
+                self.cpu.bus.joypad_for(player).buttons.set(button, pressed);
+            }
+        }
+    }
+
+    // Swaps the second controller for a Zapper, or back
+    pub fn connect_zapper(&mut self, connected: bool) {
+        if connected != self.cpu.bus.zapper.is_some() {
+            self.cpu.bus.zapper = connected.then(Zapper::default);
+        }
+    }
+
+    pub fn aim_zapper(&mut self, pos: Option<(usize, usize)>) {
+        if let Some(zapper) = &mut self.cpu.bus.zapper {
+            zapper.aim(pos);
+        }
+    }
+
+    pub fn emulated_seconds(&self) -> f64 {
+        let (start, end) = self.cpu.bus.region().cpu_clock_counts();
+        let master_clocks_per_cpu_cycle = f64::from(start + end);
+        self.cpu.master_clock() as f64
+            / (self.cpu.bus.apu.clock_rate() * master_clocks_per_cpu_cycle)
     }
 
     pub fn frame(&self) -> &Frame {
         &self.cpu.bus.ppu.curr_frame
     }
 
+    // Always a PNG, whatever the extension. Whenever the emulation thread lets go of the console
+    // it's between frames, so a screenshot taken under the lock never catches one half drawn
+    pub fn screenshot(&self, path: PathBuf) -> ImageResult<()> {
+        save_buffer_with_format(
+            path,
+            &self.frame().image,
+            256,
+            240,
+            ColorType::Rgb8,
+            ImageFormat::Png,
+        )
+    }
+
     pub fn dump_save_to_path(&self, file: PathBuf) -> std::io::Result<()> {
         let mapper = self.cpu.bus.mapper.lock().unwrap();
         let save = mapper.dump_save();
@@ -61,15 +794,28 @@ impl Console {
     }
 
     pub fn dump_save(&self) -> std::io::Result<()> {
-        if let Some(save_dir_str) = Config::get_string("save_directory") {
-            let mut save_path = PathBuf::from(save_dir_str);
-            save_path.push(format!("{}.sav", self.rom_hash));
+        if let Some(save_path) = self.save_path() {
             println!("{}", save_path.to_str().unwrap());
             self.dump_save_to_path(save_path)?;
         }
         Ok(())
     }
 
+    pub fn save_path(&self) -> Option<PathBuf> {
+        Config::get_string("save_directory").map(|save_dir_str| {
+            let mut save_path = PathBuf::from(save_dir_str);
+            save_path.push(format!("{}.sav", self.rom_hash));
+            save_path
+        })
+    }
+
+    pub fn load_default_save(&self) -> std::io::Result<()> {
+        match self.save_path() {
+            Some(save_path) if save_path.exists() => self.load_save(save_path),
+            _ => Ok(()),
+        }
+    }
+
     pub fn load_save(&self, file: PathBuf) -> std::io::Result<()> {
         let save = std::fs::read(file)?;
         let mut mapper = self.cpu.bus.mapper.lock().unwrap();
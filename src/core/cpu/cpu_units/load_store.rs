@@ -7,6 +7,8 @@ pub trait LoadStore {
 
     fn lax(&mut self);
 
+    fn las(&mut self);
+
     fn lda(&mut self) {
         self.ld(Register::A);
     }
@@ -58,6 +60,13 @@ impl LoadStore for CPU {
         self.set_register(Register::A, val);
     }
 
+    fn las(&mut self) {
+        let val = self.get_operand_val() & self.sp;
+        self.sp = val;
+        self.set_register(Register::X, val);
+        self.set_register(Register::A, val);
+    }
+
     fn sax(&mut self) {
         self.memory_write(self.operand, self.acc & self.x);
     }
@@ -14,6 +14,10 @@ pub(crate) trait Shift: Logical + Arithmetic {
 
     fn sh(&mut self, reg: Register);
 
+    fn ahx(&mut self);
+
+    fn tas(&mut self);
+
     fn get_shift_val(&mut self, op: ShiftOp);
 
     fn rol(&mut self) {
@@ -128,9 +132,26 @@ impl Shift for CPU {
             Register::Y => self.y,
             _ => panic!("Invalid register"),
         };
+        self.sh_write(reg);
+    }
+
+    fn ahx(&mut self) {
+        self.sh_write(self.acc & self.x);
+    }
+
+    fn tas(&mut self) {
+        self.sp = self.acc & self.x;
+        self.sh_write(self.sp);
+    }
+}
+
+impl CPU {
+    // SHX, SHY, AHX and TAS all store `val` ANDed with the address's high byte plus one, and that
+    // result replaces the high byte of the address too
+    fn sh_write(&mut self, val: u8) {
         let addr_hi = (self.operand >> 8) as u8;
         let addr_lo = (self.operand & 0xFF) as u8;
-        let val = reg & (addr_hi + 1);
+        let val = val & addr_hi.wrapping_add(1);
         self.memory_write(((val as u16) << 8) | addr_lo as u16, val);
     }
 }
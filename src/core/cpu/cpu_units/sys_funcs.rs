@@ -8,6 +8,8 @@ pub trait SysFuncs {
     fn rti(&mut self);
 
     fn irq(&mut self);
+
+    fn jam(&mut self);
 }
 
 impl SysFuncs for CPU {
@@ -52,4 +54,11 @@ impl SysFuncs for CPU {
             self.pc = self.memory_read_word(0xfffe);
         }
     }
+
+    // The real CPU locks up until it's reset, leaving the PC on the opcode
+    fn jam(&mut self) {
+        self.pc = self.pc.wrapping_sub(1);
+        self.jammed = true;
+        self.halted = true;
+    }
 }
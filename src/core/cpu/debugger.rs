@@ -0,0 +1,81 @@
+// What made the debugger stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakEvent {
+    // About to execute the instruction at this address
+    Execute(u16),
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Registers {
+    pub pc: u16,
+    pub acc: u8,
+    pub x: u8,
+    pub y: u8,
+    pub sp: u8,
+    pub status: u8,
+    pub cycle: u64,
+}
+
+// Returns true to halt the CPU, false to keep going
+pub type BreakCallback = Box<dyn FnMut(BreakEvent, &Registers) -> bool + Send>;
+
+// Breakpoints and watches are kept sorted so checking one is a binary search, and the CPU only
+// looks when the list isn't empty
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<u16>,
+    watches: Vec<u16>,
+    callback: Option<BreakCallback>,
+}
+
+fn insert_sorted(list: &mut Vec<u16>, addr: u16) {
+    if let Err(i) = list.binary_search(&addr) {
+        list.insert(i, addr);
+    }
+}
+
+fn remove_sorted(list: &mut Vec<u16>, addr: u16) {
+    if let Ok(i) = list.binary_search(&addr) {
+        list.remove(i);
+    }
+}
+
+impl Debugger {
+    pub(super) fn add_breakpoint(&mut self, addr: u16) {
+        insert_sorted(&mut self.breakpoints, addr);
+    }
+
+    pub(super) fn remove_breakpoint(&mut self, addr: u16) {
+        remove_sorted(&mut self.breakpoints, addr);
+    }
+
+    pub(super) fn add_watch(&mut self, addr: u16) {
+        insert_sorted(&mut self.watches, addr);
+    }
+
+    pub(super) fn remove_watch(&mut self, addr: u16) {
+        remove_sorted(&mut self.watches, addr);
+    }
+
+    pub(super) fn set_callback(&mut self, callback: Option<BreakCallback>) {
+        self.callback = callback;
+    }
+
+    pub(super) fn is_breakpoint(&self, pc: u16) -> bool {
+        !self.breakpoints.is_empty() && self.breakpoints.binary_search(&pc).is_ok()
+    }
+
+    pub(super) fn is_watched(&self, addr: u16) -> bool {
+        !self.watches.is_empty() && self.watches.binary_search(&addr).is_ok()
+    }
+
+    // Without a callback every hit halts
+    pub(super) fn hit(&mut self, event: BreakEvent, regs: &Registers) -> bool {
+        match self.callback.as_mut() {
+            Some(callback) => callback(event, regs),
+            None => true,
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use core::panic;
 use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 use crate::config::Config;
 use crate::core::ppu::DMAFlag;
@@ -14,15 +16,18 @@ use self::{
         register_transfer::RegisterTransfer, shift::Shift, stack_ops::StackOps,
         sys_funcs::SysFuncs,
     },
+    debugger::{BreakCallback, BreakEvent, Debugger, Registers},
     op::OPS,
-    tracer::Loggable,
+    tracer::{Loggable, StringSink, TraceFormat},
 };
 
 mod cpu_units;
-mod op;
-mod tracer;
+pub mod debugger;
+pub mod op;
+pub mod tracer;
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     pub struct Status: u8 {
         const CARRY = 0x01;
         const ZERO = 0x02;
@@ -43,6 +48,7 @@ bitflags! {
 }
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     pub struct IRQSource: u8 {
         const EXT = 0x01;
         const FRAME_COUNTER = 0x02;
@@ -57,7 +63,7 @@ pub enum Register {
     P,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub enum AddressingMode {
     Immediate,
     ZeroPage,
@@ -77,6 +83,18 @@ pub enum AddressingMode {
     Indirect,
 }
 
+// What the CPU does when it fetches an opcode it doesn't implement
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum InvalidOpBehavior {
+    Panic,
+    // Prints the opcode and runs it as a one byte NOP
+    #[default]
+    NopAndLog,
+    // Stops with the PC on the opcode, the same way a breakpoint does
+    Halt,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct CPU {
     // Registers
     pub x: u8,
@@ -91,8 +109,11 @@ pub struct CPU {
     pub bus: Bus,
 
     // Logger
+    #[serde(skip, default = "default_sink")]
     pub sink: Box<dyn Write + Send>,
     logging_enabled: bool,
+    #[serde(skip)]
+    trace_format: TraceFormat,
 
     // Flags
     irq_flag: IRQSource,
@@ -118,10 +139,44 @@ pub struct CPU {
     need_dummy_read: bool,
     sprite_dma_offset: u8,
     dmc_dma_running: bool,
+
+    // Overclocking: extra cycles left to run with the PPU, APU and mapper held at the start of
+    // the post-render line, whether this cycle is one of them, and whether this frame's have run
+    overclock_cycles_left: u32,
+    frozen_cycle: bool,
+    overclocked_frame: bool,
+    // CPU speed multiplier, 1 for none. Set by the host like the debugger
+    #[serde(skip, default = "default_overclock")]
+    overclock: f64,
+
+    // Debugger
+    #[serde(skip)]
+    debugger: Debugger,
+    #[serde(skip)]
+    halted: bool,
+    // Lets the instruction a breakpoint stopped on run once it's resumed
+    #[serde(skip)]
+    skip_breakpoint: bool,
+    #[serde(skip)]
+    invalid_op_behavior: InvalidOpBehavior,
+    // Address and opcode that halted the CPU, if that's why it's halted
+    #[serde(skip)]
+    invalid_op: Option<(u16, u8)>,
+    #[serde(skip)]
+    jammed: bool,
+}
+
+fn default_sink() -> Box<dyn Write + Send> {
+    Box::new(io::sink())
+}
+
+fn default_overclock() -> f64 {
+    1.
 }
 
 impl CPU {
     pub fn new(bus: Bus) -> Self {
+        let (start_clock_count, end_clock_count) = bus.region().cpu_clock_counts();
         CPU {
             x: 0,
             y: 0,
@@ -132,11 +187,12 @@ impl CPU {
             status: Status::from_bits(0x04).unwrap(),
             sink: Box::new(io::sink()),
             logging_enabled: false,
+            trace_format: TraceFormat::Text,
             irq_flag: IRQSource::empty(),
             need_halt: false,
             run_irq: false,
-            start_clock_count: 6,
-            end_clock_count: 6,
+            start_clock_count,
+            end_clock_count,
             master_clock: 0,
             cycle_count: 0,
             ppu_offset: 0,
@@ -152,6 +208,16 @@ impl CPU {
             sprite_dma_offset: 0,
             irq_mask: 0,
             dmc_dma_running: false,
+            overclock_cycles_left: 0,
+            frozen_cycle: false,
+            overclocked_frame: false,
+            overclock: 1.,
+            debugger: Debugger::default(),
+            halted: false,
+            skip_breakpoint: false,
+            invalid_op_behavior: InvalidOpBehavior::default(),
+            invalid_op: None,
+            jammed: false,
         }
     }
 
@@ -183,6 +249,9 @@ impl CPU {
             IRQSignal::Clear => self.irq_flag.set(IRQSource::FRAME_COUNTER, false),
             IRQSignal::None => {}
         }
+        if self.debugger.is_watched(addr) {
+            self.debug_break(BreakEvent::Read(addr, ret.0));
+        }
         ret.0
     }
 
@@ -192,20 +261,115 @@ impl CPU {
             IRQSignal::Clear => self.irq_flag.set(IRQSource::FRAME_COUNTER, false),
             IRQSignal::None => {}
         }
+        if self.debugger.is_watched(addr) {
+            self.debug_break(BreakEvent::Write(addr, val));
+        }
+    }
+
+    pub fn set_pc_breakpoint(&mut self, addr: u16) {
+        self.debugger.add_breakpoint(addr);
+    }
+
+    pub fn clear_pc_breakpoint(&mut self, addr: u16) {
+        self.debugger.remove_breakpoint(addr);
+    }
+
+    // Breaks on any CPU read or write of `addr`, DMA included. The instruction that touched it
+    // still finishes before the CPU halts
+    pub fn set_mem_watch(&mut self, addr: u16) {
+        self.debugger.add_watch(addr);
+    }
+
+    pub fn clear_mem_watch(&mut self, addr: u16) {
+        self.debugger.remove_watch(addr);
+    }
+
+    // Called on every breakpoint or watch hit, returning true halts the CPU until resume(). With
+    // no callback set every hit halts
+    pub fn set_break_callback(&mut self, callback: Option<BreakCallback>) {
+        self.debugger.set_callback(callback);
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // Has no effect on a jammed CPU
+    pub fn resume(&mut self) {
+        if self.halted && !self.jammed {
+            self.halted = false;
+            self.skip_breakpoint = true;
+            self.invalid_op = None;
+        }
+    }
+
+    // Set after running one of the JAM opcodes, which also halts it until the next reset
+    pub fn is_jammed(&self) -> bool {
+        self.jammed
+    }
+
+    pub fn set_invalid_opcode_behavior(&mut self, behavior: InvalidOpBehavior) {
+        self.invalid_op_behavior = behavior;
+    }
+
+    // Runs the CPU this many times faster to cut down on slowdown, from 1 (off) up to 4. The
+    // extra cycles all go in one block at the start of the post-render line, after the picture is
+    // finished and before vblank's NMI, with the PPU, APU and mapper stopped for the whole block.
+    // Rendering, raster timing and sound come out the same as without it, and a game that was
+    // running behind gets more time to finish its frame. Games that count cycles to time
+    // something against the PPU outside of that block still see the normal speed
+    pub fn set_overclock(&mut self, multiplier: f64) {
+        self.overclock = multiplier.clamp(1., 4.);
+    }
+
+    pub fn overclock(&self) -> f64 {
+        self.overclock
+    }
+
+    // Set while halted on an invalid opcode. Resuming just runs into it again
+    pub fn invalid_opcode(&self) -> Option<(u16, u8)> {
+        self.invalid_op
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            acc: self.acc,
+            x: self.x,
+            y: self.y,
+            sp: self.sp,
+            status: self.status.bits(),
+            cycle: self.cycle_count,
+        }
+    }
+
+    fn debug_break(&mut self, event: BreakEvent) {
+        let regs = self.registers();
+        if self.debugger.hit(event, &regs) {
+            self.halted = true;
+        }
     }
 
     fn run_to(&mut self, cyc: u64) {
         self.bus.run_to(cyc);
     }
 
-    pub fn enable_logging(&mut self) {
+    pub fn enable_logging(&mut self, format: TraceFormat) {
         self.logging_enabled = true;
+        self.trace_format = format;
     }
 
     pub fn set_sink(&mut self, stream: Box<dyn Write + Send>) {
         self.sink = stream;
     }
 
+    // Routes the trace into a String that can be read back through the returned handle
+    pub fn set_string_sink(&mut self) -> Arc<Mutex<String>> {
+        let buf = Arc::new(Mutex::new(String::new()));
+        self.sink = Box::new(StringSink(buf.clone()));
+        buf
+    }
+
     fn process_pending_dma(&mut self, addr: u16) {
         self.poll_sprite_dma_flag();
         if self.need_halt {
@@ -226,7 +390,7 @@ impl CPU {
                         read_val = self.read(self.bus.apu.dmc.current_addr);
                         self.end_cpu_cycle(true);
                         if let IRQSignal::Set = self.bus.apu.dmc.set_dmc_read_buffer(read_val) {
-                            self.irq_flag = IRQSource::DMC
+                            self.irq_flag.set(IRQSource::DMC, true);
                         }
                         self.dmc_dma_running = false;
                     } else if self.sprite_dma_transfer {
@@ -505,10 +669,30 @@ impl CPU {
     }
 
     pub fn reset(&mut self) {
+        self.cycle_count = 0u64.wrapping_sub(1);
+        self.master_clock = (self.start_clock_count + self.end_clock_count) as u64;
+        self.ppu_offset = 1;
+        self.soft_reset();
+    }
+
+    // A power-on reset that starts at `pc` instead of the reset vector, for running code that
+    // isn't a whole cartridge
+    pub fn reset_to(&mut self, pc: u16) {
+        self.reset();
+        self.pc = pc;
+    }
+
+    // The reset button. Unlike at power on the clocks keep counting, since the PPU and APU aren't
+    // reset along with the CPU and have to stay in step with it
+    pub fn soft_reset(&mut self) {
         self.bus.set_nmi_generated(false);
         self.irq_flag = IRQSource::empty();
         self.need_halt = false;
         self.irq_mask = 0xff;
+        // Resetting is the way out of a JAM or an invalid opcode, a breakpoint halt is left alone
+        if self.invalid_op.take().is_some() | std::mem::take(&mut self.jammed) {
+            self.halted = false;
+        }
 
         self.pc = self.read(0xFFFC) as u16 | ((self.read(0xFFFD) as u16) << 8);
 
@@ -520,12 +704,6 @@ impl CPU {
 
         self.run_irq = false;
 
-        self.cycle_count = 0u64.wrapping_sub(1);
-        self.master_clock = 0;
-        self.ppu_offset = 1;
-
-        self.master_clock += 12;
-
         (0..8).for_each(|_| {
             self.start_cpu_cycle(true);
             self.end_cpu_cycle(true);
@@ -537,6 +715,12 @@ impl CPU {
     }
 
     fn start_cpu_cycle(&mut self, is_read: bool) {
+        self.frozen_cycle = self.overclock_cycles_left > 0;
+        if self.frozen_cycle {
+            self.overclock_cycles_left -= 1;
+            self.cycle_count = self.cycle_count.wrapping_add(1);
+            return;
+        }
         self.master_clock += if is_read {
             self.start_clock_count.wrapping_sub(1)
         } else {
@@ -545,10 +729,14 @@ impl CPU {
         self.cycle_count = self.cycle_count.wrapping_add(1);
         self.run_to(self.master_clock - self.ppu_offset as u64);
 
+        let mapper_irq = self.bus.clock_mapper();
+        self.irq_flag.set(IRQSource::EXT, mapper_irq);
         let (irq_pending, needs_dmc_transfer) = self.bus.apu.clock();
         if irq_pending {
             self.irq_flag.set(IRQSource::FRAME_COUNTER, true);
         }
+        // Writes to $4010 and $4015 drop the DMC's IRQ without going through here
+        self.irq_flag.set(IRQSource::DMC, self.bus.apu.dmc.irq_flag());
         if needs_dmc_transfer {
             self.start_dmc_transfer();
         }
@@ -561,12 +749,15 @@ impl CPU {
     }
 
     fn end_cpu_cycle(&mut self, is_read: bool) {
-        self.master_clock += if is_read {
-            self.end_clock_count.wrapping_add(1)
-        } else {
-            self.end_clock_count.wrapping_sub(1)
-        } as u64;
-        self.run_to(self.master_clock - self.ppu_offset as u64);
+        if !self.frozen_cycle {
+            self.master_clock += if is_read {
+                self.end_clock_count.wrapping_add(1)
+            } else {
+                self.end_clock_count.wrapping_sub(1)
+            } as u64;
+            self.run_to(self.master_clock - self.ppu_offset as u64);
+            self.start_overclock();
+        }
 
         self.prev_need_nmi = self.need_nmi;
 
@@ -580,6 +771,17 @@ impl CPU {
             && !self.status.contains(Status::INTERRUPT_DISABLE)
     }
 
+    fn start_overclock(&mut self) {
+        if self.bus.ppu.scanline != 240 {
+            self.overclocked_frame = false;
+        } else if !self.overclocked_frame && self.overclock > 1. {
+            let region = self.bus.region();
+            let frame_cycles = region.cpu_clock_rate() / region.frame_rate();
+            self.overclock_cycles_left = ((self.overclock - 1.) * frame_cycles) as u32;
+            self.overclocked_frame = true;
+        }
+    }
+
     fn get_op_code(&mut self) -> u8 {
         let op_code = self.memory_read(self.pc);
         self.pc += 1;
@@ -596,12 +798,32 @@ impl CPU {
         }
     }
 
+    // Replaces everything with a deserialized state, except what belongs to the host rather than
+    // the console: the log sink and format, debugger, overclock, cartridge, palette and audio
+    // output
+    pub(crate) fn restore(&mut self, mut state: CPU) {
+        std::mem::swap(&mut state.sink, &mut self.sink);
+        std::mem::swap(&mut state.debugger, &mut self.debugger);
+        state.overclock = self.overclock;
+        state.trace_format = self.trace_format;
+        state.bus.take_host_resources(&mut self.bus);
+        *self = state;
+    }
+
     pub fn get_frame_hash(&self) -> u64 {
         self.bus.ppu.curr_frame.get_hash()
     }
 
+    pub fn get_frame_hash_region(&self, x: usize, y: usize, w: usize, h: usize) -> u64 {
+        self.bus.ppu.curr_frame.get_hash_region(x, y, w, h)
+    }
+
+    pub fn master_clock(&self) -> u64 {
+        self.master_clock
+    }
+
     pub fn run_for_cycles(&mut self, cycles: u64) {
-        while cycles != self.cycle_count {
+        while cycles != self.cycle_count && !self.halted {
             self.run();
         }
     }
@@ -609,22 +831,72 @@ impl CPU {
     pub fn run_until_frame(&mut self) {
         let frame_num = self.bus.ppu.frame_count;
         while self.bus.ppu.frame_count == frame_num
+            && !self.halted
             && self.cycle_count < Config::get_int("max_cycles", i64::MAX) as u64
         {
             self.run();
         }
     }
 
-    fn run(&mut self) {
+    // Runs until the next instruction is the one at `target`, which is how a lot of test ROMs
+    // signal they're done. Gives up after `max_cycles` more cycles, at the max_cycles config key or
+    // when the CPU halts, returning whether it got there
+    pub fn run_until_pc(&mut self, target: u16, max_cycles: u64) -> bool {
+        let limit = self
+            .cycle_count
+            .saturating_add(max_cycles)
+            .min(Config::get_int("max_cycles", i64::MAX) as u64);
+        while self.pc != target && !self.halted && self.cycle_count < limit {
+            self.run();
+        }
+        self.pc == target
+    }
+
+    pub fn run_until_vblank(&mut self) {
+        let max_cycles = Config::get_int("max_cycles", i64::MAX) as u64;
+        // If we're already inside vblank, finish it so we stop at the start of the next one
+        while self.bus.ppu.in_vblank() && !self.halted && self.cycle_count < max_cycles {
+            self.run();
+        }
+        while !self.bus.ppu.in_vblank() && !self.halted && self.cycle_count < max_cycles {
+            self.run();
+        }
+    }
+
+    // Does nothing while halted by the debugger
+    pub fn run(&mut self) {
+        if self.halted {
+            return;
+        }
+        if !std::mem::take(&mut self.skip_breakpoint) && self.debugger.is_breakpoint(self.pc) {
+            self.debug_break(BreakEvent::Execute(self.pc));
+            if self.halted {
+                return;
+            }
+        }
         self.log();
         let opcode = self.get_op_code();
 
-        let searched_op = OPS.binary_search_by_key(&opcode, |op| op.hex);
-        let op = if searched_op.is_err() {
-            println!("Invalid opcode: {:02X}", opcode);
-            &OPS[OPS.binary_search_by_key(&"NOP", |op| op.name).unwrap()]
-        } else {
-            &OPS[OPS.binary_search_by_key(&opcode, |op| op.hex).unwrap()]
+        let op = match OPS.binary_search_by_key(&opcode, |op| op.hex) {
+            Ok(idx) => &OPS[idx],
+            Err(_) => {
+                let addr = self.pc.wrapping_sub(1);
+                match self.invalid_op_behavior {
+                    InvalidOpBehavior::Panic => {
+                        panic!("Invalid opcode {:02X} at {:04X}", opcode, addr)
+                    }
+                    InvalidOpBehavior::NopAndLog => {
+                        println!("Invalid opcode {:02X} at {:04X}", opcode, addr);
+                        &OPS[OPS.binary_search_by_key(&0xEA, |op| op.hex).unwrap()]
+                    }
+                    InvalidOpBehavior::Halt => {
+                        self.pc = addr;
+                        self.halted = true;
+                        self.invalid_op = Some((addr, opcode));
+                        return;
+                    }
+                }
+            }
         };
         self.instr_addr_mode = op.addressing_mode;
         self.operand = self.fetch_operand();
@@ -632,6 +904,7 @@ impl CPU {
         match op.name {
             "AND" => self.and(),
             "ADC" => self.adc(),
+            "*AHX" => self.ahx(),
             "*ANC" => self.anc(),
             "*ARR" => self.arr(),
             "ASL" => self.asl(),
@@ -663,8 +936,10 @@ impl CPU {
             "INX" => self.inx(),
             "INY" => self.iny(),
             "*ISB" => self.isb(),
+            "*JAM" => self.jam(),
             "JMP" => self.jmp(),
             "JSR" => self.jsr(),
+            "*LAS" => self.las(),
             "*LAX" => self.lax(),
             "LDA" => self.lda(),
             "LDX" => self.ldx(),
@@ -697,6 +972,7 @@ impl CPU {
             "STX" => self.stx(),
             "TAX" => self.tax(),
             "TAY" => self.tay(),
+            "*TAS" => self.tas(),
             "TSX" => self.tsx(),
             "TXA" => self.txa(),
             "TXS" => self.txs(),
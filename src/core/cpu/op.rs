@@ -27,6 +27,7 @@ lazy_static! {
     pub static ref OPS: Vec<Op<'static>> = vec![
         Op::new(0x00, "BRK", AddressingMode::Implicit, 7, 1),
         Op::new(0x01, "ORA", AddressingMode::IndexedIndirect, 6, 2),
+        Op::new(0x02, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x03, "*SLO", AddressingMode::IndexedIndirect, 8, 2),
         Op::new(0x04, "*NOP", AddressingMode::ZeroPage, 3, 2),
         Op::new(0x05, "ORA", AddressingMode::ZeroPage, 3, 2),
@@ -43,6 +44,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x10, "BPL", AddressingMode::Relative, 2, 2),
         Op::new(0x11, "ORA", AddressingMode::IndirectIndexed, 5, 2),
+        Op::new(0x12, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x13, "*SLO", AddressingMode::IndirectIndexedW, 8, 2),
         Op::new(0x14, "*NOP", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0x15, "ORA", AddressingMode::ZeroPageX, 4, 2),
@@ -59,6 +61,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x20, "JSR", AddressingMode::Absolute, 6, 3),
         Op::new(0x21, "AND", AddressingMode::IndexedIndirect, 6, 2),
+        Op::new(0x22, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x23, "*RLA", AddressingMode::IndexedIndirect, 8, 2),
         Op::new(0x24, "BIT", AddressingMode::ZeroPage, 3, 2),
         Op::new(0x25, "AND", AddressingMode::ZeroPage, 3, 2),
@@ -75,6 +78,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x30, "BMI", AddressingMode::Relative, 2, 2),
         Op::new(0x31, "AND", AddressingMode::IndirectIndexed, 5, 2),
+        Op::new(0x32, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x33, "*RLA", AddressingMode::IndirectIndexedW, 8, 2),
         Op::new(0x34, "*NOP", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0x35, "AND", AddressingMode::ZeroPageX, 4, 2),
@@ -91,6 +95,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x40, "RTI", AddressingMode::Implicit, 6, 1),
         Op::new(0x41, "EOR", AddressingMode::IndexedIndirect, 6, 2),
+        Op::new(0x42, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x43, "*SRE", AddressingMode::IndexedIndirect, 8, 2),
         Op::new(0x44, "*NOP", AddressingMode::ZeroPage, 3, 2),
         Op::new(0x45, "EOR", AddressingMode::ZeroPage, 3, 2),
@@ -107,6 +112,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x50, "BVC", AddressingMode::Relative, 2, 2),
         Op::new(0x51, "EOR", AddressingMode::IndirectIndexed, 5, 2),
+        Op::new(0x52, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x53, "*SRE", AddressingMode::IndirectIndexedW, 8, 2),
         Op::new(0x54, "*NOP", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0x55, "EOR", AddressingMode::ZeroPageX, 4, 2),
@@ -123,6 +129,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x60, "RTS", AddressingMode::Implicit, 6, 1),
         Op::new(0x61, "ADC", AddressingMode::IndexedIndirect, 6, 2),
+        Op::new(0x62, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x63, "*RRA", AddressingMode::IndexedIndirect, 8, 2),
         Op::new(0x64, "*NOP", AddressingMode::ZeroPage, 3, 2),
         Op::new(0x65, "ADC", AddressingMode::ZeroPage, 3, 2),
@@ -139,6 +146,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x70, "BVS", AddressingMode::Relative, 2, 2),
         Op::new(0x71, "ADC", AddressingMode::IndirectIndexed, 5, 2),
+        Op::new(0x72, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0x73, "*RRA", AddressingMode::IndirectIndexedW, 8, 2),
         Op::new(0x74, "*NOP", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0x75, "ADC", AddressingMode::ZeroPageX, 4, 2),
@@ -171,6 +179,8 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0x90, "BCC", AddressingMode::Relative, 2, 2),
         Op::new(0x91, "STA", AddressingMode::IndirectIndexedW, 6, 2),
+        Op::new(0x92, "*JAM", AddressingMode::Implicit, 2, 1),
+        Op::new(0x93, "*AHX", AddressingMode::IndirectIndexedW, 6, 2),
         Op::new(0x94, "STY", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0x95, "STA", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0x96, "STX", AddressingMode::ZeroPageY, 4, 2),
@@ -178,9 +188,11 @@ lazy_static! {
         Op::new(0x98, "TYA", AddressingMode::Implicit, 2, 1),
         Op::new(0x99, "STA", AddressingMode::AbsoluteYW, 5, 3),
         Op::new(0x9a, "TXS", AddressingMode::Implicit, 2, 1),
+        Op::new(0x9b, "*TAS", AddressingMode::AbsoluteYW, 5, 3),
         Op::new(0x9c, "*SHY", AddressingMode::AbsoluteXW, 5, 3),
         Op::new(0x9d, "STA", AddressingMode::AbsoluteXW, 5, 3),
         Op::new(0x9e, "*SHX", AddressingMode::AbsoluteYW, 5, 3),
+        Op::new(0x9f, "*AHX", AddressingMode::AbsoluteYW, 5, 3),
         // ---------------------------------------------------------------------------------------------
         Op::new(0xa0, "LDY", AddressingMode::Immediate, 2, 2),
         Op::new(0xa1, "LDA", AddressingMode::IndexedIndirect, 6, 2),
@@ -201,6 +213,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0xb0, "BCS", AddressingMode::Relative, 2, 2),
         Op::new(0xb1, "LDA", AddressingMode::IndirectIndexed, 5, 2),
+        Op::new(0xb2, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0xb3, "*LAX", AddressingMode::IndirectIndexed, 5, 2),
         Op::new(0xb4, "LDY", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0xb5, "LDA", AddressingMode::ZeroPageX, 4, 2),
@@ -209,6 +222,7 @@ lazy_static! {
         Op::new(0xb8, "CLV", AddressingMode::Implicit, 2, 1),
         Op::new(0xb9, "LDA", AddressingMode::AbsoluteY, 4, 3),
         Op::new(0xba, "TSX", AddressingMode::Implicit, 2, 1),
+        Op::new(0xbb, "*LAS", AddressingMode::AbsoluteY, 4, 3),
         Op::new(0xbc, "LDY", AddressingMode::AbsoluteX, 4, 3),
         Op::new(0xbd, "LDA", AddressingMode::AbsoluteX, 4, 3),
         Op::new(0xbe, "LDX", AddressingMode::AbsoluteY, 4, 3),
@@ -233,6 +247,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0xd0, "BNE", AddressingMode::Relative, 2, 2),
         Op::new(0xd1, "CMP", AddressingMode::IndirectIndexed, 5, 2),
+        Op::new(0xd2, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0xd3, "*DCP", AddressingMode::IndirectIndexedW, 8, 2),
         Op::new(0xd4, "*NOP", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0xd5, "CMP", AddressingMode::ZeroPageX, 4, 2),
@@ -266,6 +281,7 @@ lazy_static! {
         // ---------------------------------------------------------------------------------------------
         Op::new(0xf0, "BEQ", AddressingMode::Relative, 2, 2),
         Op::new(0xf1, "SBC", AddressingMode::IndirectIndexed, 5, 2),
+        Op::new(0xf2, "*JAM", AddressingMode::Implicit, 2, 1),
         Op::new(0xf3, "*ISB", AddressingMode::IndirectIndexedW, 8, 2),
         Op::new(0xf4, "*NOP", AddressingMode::ZeroPageX, 4, 2),
         Op::new(0xf5, "SBC", AddressingMode::ZeroPageX, 4, 2),
@@ -1,172 +1,250 @@
-use std::io::Write;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
 
 use super::{op::OPS, AddressingMode, CPU};
 
+// Trace sink that appends everything written into a shared String
+pub struct StringSink(pub Arc<Mutex<String>>);
+
+impl Write for StringSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap()
+            .push_str(&String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// What the trace looks like, from trace_format in config.toml
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceFormat {
+    // Lines laid out like nestest.log
+    #[default]
+    Text,
+    // One JSON object per instruction and line, for tools that would rather not parse columns
+    Json,
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "json" => Some(TraceFormat::Json),
+            _ => None,
+        }
+    }
+}
+
 pub trait Loggable {
     fn log(&mut self);
 }
 
 impl Loggable for CPU {
     fn log(&mut self) {
-        if self.logging_enabled {
-            let code = self.read_trace(self.pc);
-            let op = OPS[match OPS.binary_search_by_key(&code, |op| op.hex) {
-                Ok(i) => i,
-                Err(_) => panic!("Invalid opcode: {:02x}", code),
-            }];
-
-            let begin = self.pc;
-            let mut hex_dump = vec![];
-            hex_dump.push(code);
-
-            let (mem_addr, stored_value) = match op.addressing_mode {
-                AddressingMode::Immediate
-                | AddressingMode::Implicit
-                | AddressingMode::Accumulator
-                | AddressingMode::Relative
-                | AddressingMode::Indirect => (0, 0),
-                _ => {
-                    let addr = self
-                        .get_absolute_addr_trace(op.addressing_mode, begin + 1)
-                        .unwrap()
-                        .0;
-                    (addr, self.read_trace(addr))
-                }
-            };
-
-            let tmp = match op.size {
-                1 => match op.addressing_mode {
-                    AddressingMode::Accumulator => "A ".to_string(),
-                    _ => String::from(""),
-                },
-                2 => {
-                    let address: u8 = self.read_trace(begin + 1);
-                    hex_dump.push(address);
-
-                    match op.addressing_mode {
-                        AddressingMode::Immediate => format!("#${:02x}", address),
-                        AddressingMode::ZeroPage => {
-                            format!("${:02x} = {:02x}", mem_addr, self.read_trace(mem_addr))
-                        }
-                        AddressingMode::ZeroPageX => format!(
-                            "${:02x},X @ {:02x} = {:02x}",
-                            address, mem_addr, stored_value
-                        ),
-                        AddressingMode::ZeroPageY => format!(
-                            "${:02x},Y @ {:02x} = {:02x}",
-                            address, mem_addr, stored_value
-                        ),
-                        AddressingMode::IndexedIndirect => format!(
-                            "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
+        if !self.logging_enabled {
+            return;
+        }
+        match self.trace_format {
+            TraceFormat::Text => self.log_text(),
+            TraceFormat::Json => self.log_json(),
+        }
+    }
+}
+
+impl CPU {
+    fn log_text(&mut self) {
+        let begin = self.pc;
+        let (asm, next) = self.disassemble(begin);
+        let hex_str = (0..next.wrapping_sub(begin))
+            .map(|i| format!("{:02x}", self.read_trace(begin.wrapping_add(i))))
+            .collect::<Vec<String>>()
+            .join(" ");
+        // Official mnemonics are indented a space so they line up with unofficial ones' '*'
+        let indent = if asm.starts_with('*') { "" } else { " " };
+        let asm_str = format!("{:04x}  {:8} {}{}", begin, hex_str, indent, asm)
+            .trim()
+            .to_string();
+
+        let cycles = self.cycle_count;
+        let ppu_scanline = self.bus.ppu.scanline;
+        let ppu_cycle = self.bus.ppu.cycle;
+
+        let msg = format!(
+            "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3} CYC:{}",
+            asm_str,
+            self.acc,
+            self.x,
+            self.y,
+            self.status,
+            self.sp,
+            ppu_scanline,
+            ppu_cycle,
+            cycles
+        )
+        .to_uppercase();
+
+        writeln!(self.sink, "{}", msg).unwrap();
+    }
+
+    // Numbers are plain decimal, e.g. {"pc":49152,"opcode":76,"operands":[245,197],"a":0,...}
+    fn log_json(&mut self) {
+        let (asm, next) = self.disassemble(self.pc);
+        let operands = (1..next.wrapping_sub(self.pc))
+            .map(|i| self.read_trace(self.pc.wrapping_add(i)).to_string())
+            .collect::<Vec<String>>()
+            .join(",");
+        writeln!(
+            self.sink,
+            "{{\"pc\":{},\"opcode\":{},\"operands\":[{}],\"a\":{},\"x\":{},\"y\":{},\"p\":{},\
+             \"sp\":{},\"scanline\":{},\"dot\":{},\"cycle\":{},\"asm\":\"{}\"}}",
+            self.pc,
+            self.read_trace(self.pc),
+            operands,
+            self.acc,
+            self.x,
+            self.y,
+            self.status.bits(),
+            self.sp,
+            self.bus.ppu.scanline,
+            self.bus.ppu.cycle,
+            self.cycle_count,
+            asm
+        )
+        .unwrap();
+    }
+
+    // Formats the instruction at `addr` the way nestest.log does, e.g. "LDA ($80),Y = 0200 @ 0200
+    // = 5A", and returns it with the address of the next instruction. Operand values come from the
+    // current registers and memory, read without side effects. Bytes that aren't an opcode come
+    // out as ".DB $XX"
+    pub fn disassemble(&self, addr: u16) -> (String, u16) {
+        let code = self.read_trace(addr);
+        let Ok(i) = OPS.binary_search_by_key(&code, |op| op.hex) else {
+            return (format!(".DB ${:02X}", code), addr.wrapping_add(1));
+        };
+        let op = OPS[i];
+        let next = addr.wrapping_add(op.size);
+
+        let (mem_addr, stored_value) = match op.addressing_mode {
+            AddressingMode::Immediate
+            | AddressingMode::Implicit
+            | AddressingMode::Accumulator
+            | AddressingMode::Relative
+            | AddressingMode::Indirect => (0, 0),
+            _ => {
+                let operand_addr = self
+                    .get_absolute_addr_trace(op.addressing_mode, addr.wrapping_add(1))
+                    .unwrap()
+                    .0;
+                (operand_addr, self.read_trace(operand_addr))
+            }
+        };
+
+        let tmp = match op.size {
+            1 => match op.addressing_mode {
+                AddressingMode::Accumulator => "A ".to_string(),
+                _ => String::from(""),
+            },
+            2 => {
+                let address: u8 = self.read_trace(addr.wrapping_add(1));
+
+                match op.addressing_mode {
+                    AddressingMode::Immediate => format!("#${:02x}", address),
+                    AddressingMode::ZeroPage => {
+                        format!("${:02x} = {:02x}", mem_addr, stored_value)
+                    }
+                    AddressingMode::ZeroPageX => format!(
+                        "${:02x},X @ {:02x} = {:02x}",
+                        address, mem_addr, stored_value
+                    ),
+                    AddressingMode::ZeroPageY => format!(
+                        "${:02x},Y @ {:02x} = {:02x}",
+                        address, mem_addr, stored_value
+                    ),
+                    AddressingMode::IndexedIndirect => format!(
+                        "(${:02x},X) @ {:02x} = {:04x} = {:02x}",
+                        address,
+                        (address.wrapping_add(self.x)),
+                        mem_addr,
+                        stored_value
+                    ),
+                    AddressingMode::IndirectIndexed | AddressingMode::IndirectIndexedW => {
+                        format!(
+                            "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
                             address,
-                            (address.wrapping_add(self.x)),
+                            (mem_addr.wrapping_sub(self.y as u16)),
                             mem_addr,
                             stored_value
-                        ),
-                        AddressingMode::IndirectIndexed | AddressingMode::IndirectIndexedW => {
-                            format!(
-                                "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                                address,
-                                (mem_addr.wrapping_sub(self.y as u16)),
-                                mem_addr,
-                                stored_value
-                            )
-                        }
-                        AddressingMode::Implicit
-                        | AddressingMode::Accumulator
-                        | AddressingMode::Relative
-                        | AddressingMode::Indirect => {
-                            format!("${:04x}", (begin + 2).wrapping_add((address as i8) as u16))
-                        }
-                        _ => panic!(
-                            "Unexpected addressing mode: {:?} for opcode: {:?}",
-                            op.addressing_mode, op
-                        ),
+                        )
+                    }
+                    AddressingMode::Implicit
+                    | AddressingMode::Accumulator
+                    | AddressingMode::Relative
+                    | AddressingMode::Indirect => {
+                        format!("${:04x}", next.wrapping_add((address as i8) as u16))
                     }
+                    _ => panic!(
+                        "Unexpected addressing mode: {:?} for opcode: {:?}",
+                        op.addressing_mode, op
+                    ),
                 }
-                3 => {
-                    let address_lo = self.read_trace(begin + 1);
-                    let address_hi = self.read_trace(begin + 2);
-                    let address = (address_hi as u16) << 8 | (address_lo as u16);
-
-                    hex_dump.push(address_lo);
-                    hex_dump.push(address_hi);
-
-                    match op.addressing_mode {
-                        AddressingMode::Implicit
-                        | AddressingMode::Accumulator
-                        | AddressingMode::Relative
-                        | AddressingMode::Indirect => {
-                            if op.hex == 0x6c {
-                                //jmp indirect
-                                let jmp_addr = if address & 0x00FF == 0x00FF {
-                                    let lo = self.read_trace(address);
-                                    let hi = self.read_trace(address & 0xFF00);
-                                    (hi as u16) << 8 | (lo as u16)
-                                } else {
-                                    self.read_16_trace(address)
-                                };
-                                format!("(${:04x}) = {:04x}", address, jmp_addr)
-                            } else {
-                                format!("${:04x}", address)
-                            }
-                        }
-                        AddressingMode::Absolute => {
-                            if !op.name.starts_with('J') {
-                                format!("${:04x} = {:02x}", mem_addr, stored_value)
+            }
+            3 => {
+                let address = self.read_16_trace(addr.wrapping_add(1));
+
+                match op.addressing_mode {
+                    AddressingMode::Implicit
+                    | AddressingMode::Accumulator
+                    | AddressingMode::Relative
+                    | AddressingMode::Indirect => {
+                        if op.hex == 0x6c {
+                            //jmp indirect
+                            let jmp_addr = if address & 0x00FF == 0x00FF {
+                                let lo = self.read_trace(address);
+                                let hi = self.read_trace(address & 0xFF00);
+                                (hi as u16) << 8 | (lo as u16)
                             } else {
-                                format!("${:04x}", address)
-                            }
+                                self.read_16_trace(address)
+                            };
+                            format!("(${:04x}) = {:04x}", address, jmp_addr)
+                        } else {
+                            format!("${:04x}", address)
                         }
-                        AddressingMode::AbsoluteX | AddressingMode::AbsoluteXW => {
-                            format!(
-                                "${:04x},X @ {:04x} = {:02x}",
-                                address, mem_addr, stored_value
-                            )
-                        }
-                        AddressingMode::AbsoluteY | AddressingMode::AbsoluteYW => {
-                            format!(
-                                "${:04x},Y @ {:04x} = {:02x}",
-                                address, mem_addr, stored_value
-                            )
+                    }
+                    AddressingMode::Absolute => {
+                        if !op.name.starts_with('J') {
+                            format!("${:04x} = {:02x}", mem_addr, stored_value)
+                        } else {
+                            format!("${:04x}", address)
                         }
-                        _ => panic!(
-                            "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-                            op.addressing_mode, op.hex
-                        ),
                     }
+                    AddressingMode::AbsoluteX | AddressingMode::AbsoluteXW => {
+                        format!(
+                            "${:04x},X @ {:04x} = {:02x}",
+                            address, mem_addr, stored_value
+                        )
+                    }
+                    AddressingMode::AbsoluteY | AddressingMode::AbsoluteYW => {
+                        format!(
+                            "${:04x},Y @ {:04x} = {:02x}",
+                            address, mem_addr, stored_value
+                        )
+                    }
+                    _ => panic!(
+                        "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
+                        op.addressing_mode, op.hex
+                    ),
                 }
-                _ => String::from(""),
-            };
-
-            let hex_str = hex_dump
-                .iter()
-                .map(|z| format!("{:02x}", z))
-                .collect::<Vec<String>>()
-                .join(" ");
-            let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, op.name, tmp)
-                .trim()
-                .to_string();
-
-            let cycles = self.cycle_count;
-            let ppu_scanline = self.bus.ppu.scanline;
-            let ppu_cycle = self.bus.ppu.cycle;
-
-            let msg = format!(
-                "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3} CYC:{}",
-                asm_str,
-                self.acc,
-                self.x,
-                self.y,
-                self.status,
-                self.sp,
-                ppu_scanline,
-                ppu_cycle,
-                cycles
-            )
-            .to_uppercase();
-
-            writeln!(self.sink, "{}", msg).unwrap();
-        }
+            }
+            _ => String::from(""),
+        };
+
+        let asm = format!("{} {}", op.name, tmp).trim().to_uppercase();
+        (asm, next)
     }
 }
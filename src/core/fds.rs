@@ -0,0 +1,422 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::apu::fds_audio::FdsAudio;
+use crate::core::apu::APU;
+use crate::core::mappers::{Mapper, Mirroring};
+use crate::core::savestate::big_array_2d;
+use crate::ines_parser::ParseError;
+
+// fwNES header in front of most .fds images, with the number of sides after it
+const FDS_MAGIC: [u8; 4] = *b"FDS\x1a";
+// Every side starts with a disk info block, its block type of 1 and then this
+const DISK_VERIFICATION: &[u8; 14] = b"*NINTENDO-HVC*";
+pub const SIDE_SIZE: usize = 65500;
+pub const BIOS_SIZE: usize = 0x2000;
+
+// Gaps the drive reads between blocks, which .fds images leave out along with the CRCs
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+// CPU cycles for the head to get back to the start of the disk, and to pass over one byte
+const REWIND_CYCLES: u32 = 50000;
+const BYTE_CYCLES: u32 = 150;
+// About a second with no disk in the drive when swapping, or games don't notice the change
+const SWAP_CYCLES: u32 = 1_800_000;
+
+#[derive(Debug, Clone)]
+pub struct FdsImage {
+    pub sides: Vec<Vec<u8>>,
+    pub bios: Vec<u8>,
+}
+
+impl FdsImage {
+    // Either a fwNES header or a headerless image starting straight into the first side
+    pub fn is_fds(bytes: &[u8]) -> bool {
+        bytes.starts_with(&FDS_MAGIC)
+            || (bytes.first() == Some(&1) && bytes.get(1..15) == Some(DISK_VERIFICATION))
+    }
+
+    pub fn parse(bytes: &[u8], bios: Vec<u8>) -> Result<Self, ParseError> {
+        if bios.len() != BIOS_SIZE {
+            return Err(ParseError::BadFdsBios(bios.len()));
+        }
+        let data = if bytes.starts_with(&FDS_MAGIC) {
+            bytes.get(16..).unwrap_or_default()
+        } else {
+            bytes
+        };
+        let sides: Vec<Vec<u8>> = data.chunks_exact(SIDE_SIZE).map(<[u8]>::to_vec).collect();
+        if sides.is_empty()
+            || sides
+                .iter()
+                .any(|side| side[0] != 1 || &side[1..15] != DISK_VERIFICATION)
+        {
+            return Err(ParseError::BadFdsDisk);
+        }
+        Ok(FdsImage { sides, bios })
+    }
+}
+
+// Lays a side out the way the drive reads it: a lead-in gap, then each block behind a start mark
+// and followed by its CRC and another gap. Stops at the first byte that isn't a block type
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut out = vec![0; LEAD_IN_GAP];
+    let mut pos = 0;
+    let mut file_size = 0;
+    while pos < side.len() {
+        let len = match side[pos] {
+            1 => 56,
+            2 => 2,
+            3 => 16,
+            4 => 1 + file_size,
+            _ => break,
+        };
+        let Some(block) = side.get(pos..pos + len) else {
+            break;
+        };
+        // File headers carry the size of the data block after them
+        if block[0] == 3 {
+            file_size = u16::from_le_bytes([block[13], block[14]]) as usize;
+        }
+        out.push(0x80);
+        out.extend_from_slice(block);
+        // $4030 never reports a CRC error, so the BIOS takes whatever is here
+        out.extend([0, 0]);
+        out.extend([0; BLOCK_GAP]);
+        pos += len;
+    }
+    // Room after the last file for games that write new ones
+    out.resize(out.len().max(LEAD_IN_GAP + SIDE_SIZE), 0);
+    out
+}
+
+// The Famicom Disk System's RAM adapter, standing in for a cartridge: 32K of PRG-RAM, 8K of
+// CHR-RAM, the BIOS at $E000 and the drive and timer IRQ registers at $4020-$4033
+#[derive(Serialize, Deserialize)]
+pub struct FDS {
+    #[serde(skip)]
+    bios: Vec<u8>,
+    // With gaps added, and written to when games save
+    sides: Vec<Vec<u8>>,
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
+    mirroring: Mirroring,
+    disk_regs_enabled: bool,
+    sound_regs_enabled: bool,
+
+    irq_reload: u16,
+    irq_counter: u16,
+    irq_enabled: bool,
+    irq_repeat: bool,
+    timer_irq: bool,
+
+    side: Option<usize>,
+    // Goes in once swap_delay runs out
+    next_side: Option<usize>,
+    swap_delay: u32,
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    // $4025 bit 6, data only moves once this is set and the head is past a gap
+    transfer_enabled: bool,
+    disk_irq_enabled: bool,
+    disk_irq: bool,
+    transfer_complete: bool,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: u32,
+    read_data: u8,
+    write_data: u8,
+
+    audio: FdsAudio,
+    // The audio's latest output, read by the APU through register_audio
+    #[serde(skip)]
+    level: Arc<AtomicI32>,
+}
+
+impl FDS {
+    pub fn new(image: FdsImage) -> Self {
+        Self {
+            bios: image.bios,
+            sides: image.sides.iter().map(|side| add_gaps(side)).collect(),
+            prg_ram: vec![0; 0x8000],
+            chr_ram: vec![0; 0x2000],
+            nametables: [[0; 0x400]; 4],
+            mirroring: Mirroring::Horizontal,
+            disk_regs_enabled: true,
+            sound_regs_enabled: true,
+            irq_reload: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_repeat: false,
+            timer_irq: false,
+            side: Some(0),
+            next_side: Some(0),
+            swap_delay: 0,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            transfer_enabled: false,
+            disk_irq_enabled: false,
+            disk_irq: false,
+            transfer_complete: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            position: 0,
+            delay: 0,
+            read_data: 0,
+            write_data: 0,
+            audio: FdsAudio::default(),
+            level: Arc::default(),
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        if self.irq_counter == 0 {
+            self.timer_irq = true;
+            self.irq_counter = self.irq_reload;
+            if !self.irq_repeat {
+                self.irq_enabled = false;
+            }
+        } else {
+            self.irq_counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        if self.swap_delay > 0 {
+            self.swap_delay -= 1;
+            if self.swap_delay == 0 {
+                self.side = self.next_side;
+            }
+        }
+        let Some(side) = self.side.filter(|_| self.motor_on) else {
+            // The head goes back to the start whenever the disk stops
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = REWIND_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let disk = &mut self.sides[side];
+        if self.read_mode {
+            let data = disk[self.position];
+            let mut irq = self.disk_irq_enabled;
+            if !self.transfer_enabled {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // The start mark at the end of a gap comes through without an IRQ
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= irq;
+            }
+        } else {
+            // CRCs go out as zeroes, for the same reason add_gaps doesn't bother with them
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                self.disk_irq |= self.disk_irq_enabled;
+            }
+            disk[self.position] = if self.transfer_enabled { data } else { 0 };
+            self.gap_ended = false;
+        }
+
+        self.position += 1;
+        if self.position >= disk.len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+}
+
+impl Mapper for FDS {
+    fn get_mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.chr_ram[addr as usize]
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        let inserted = self.side.is_some();
+        match addr {
+            0x4030 => {
+                self.timer_irq as u8
+                    | (self.transfer_complete as u8) << 1
+                    | (self.end_of_head as u8) << 6
+            }
+            0x4031 => self.read_data,
+            // Bits set for no disk, not ready and write protected
+            0x4032 => {
+                !inserted as u8
+                    | ((!inserted || !self.scanning) as u8) << 1
+                    | (!inserted as u8) << 2
+            }
+            // Expansion port, with the top bit as a good battery
+            0x4033 => 0x80,
+            0x4040..=0x4097 if self.sound_regs_enabled => self.audio.read(addr),
+            0x6000..=0xDFFF => self.prg_ram[(addr - 0x6000) as usize],
+            0xE000..=0xFFFF => self.bios[(addr - 0xE000) as usize],
+            _ => 0,
+        }
+    }
+
+    fn read_cpu(&mut self, addr: u16) -> u8 {
+        let val = self.read(addr);
+        match addr {
+            0x4030 => {
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        val
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if !self.disk_regs_enabled && (0x4024..=0x4026).contains(&addr) {
+            return;
+        }
+        match addr {
+            0x4020 => self.irq_reload = (self.irq_reload & 0xff00) | data as u16,
+            0x4021 => self.irq_reload = (self.irq_reload & 0x00ff) | (data as u16) << 8,
+            0x4022 => {
+                self.irq_repeat = data & 0x01 != 0;
+                self.irq_enabled = data & 0x02 != 0 && self.disk_regs_enabled;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_regs_enabled = data & 0x01 != 0;
+                self.sound_regs_enabled = data & 0x02 != 0;
+                if !self.disk_regs_enabled {
+                    self.irq_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 => {
+                self.motor_on = data & 0x01 != 0;
+                self.reset_transfer = data & 0x02 != 0;
+                self.read_mode = data & 0x04 != 0;
+                self.mirroring = if data & 0x08 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.crc_control = data & 0x10 != 0;
+                self.transfer_enabled = data & 0x40 != 0;
+                self.disk_irq_enabled = data & 0x80 != 0;
+                self.disk_irq = false;
+            }
+            0x4040..=0x4097 if self.sound_regs_enabled => self.audio.write(addr, data),
+            0x6000..=0xDFFF => self.prg_ram[(addr - 0x6000) as usize] = data,
+            _ => {}
+        }
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, data: u8) {
+        self.chr_ram[addr as usize] = data;
+    }
+
+    fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8) {
+        self.nametables[idx][addr as usize] = val;
+    }
+
+    fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8 {
+        self.nametables[idx][addr as usize]
+    }
+
+    fn is_cpu_clocked(&self) -> bool {
+        true
+    }
+
+    fn clock_cpu(&mut self) {
+        self.clock_timer();
+        self.clock_drive();
+        self.level.store(self.audio.output(), Ordering::Relaxed);
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn register_audio(&self, apu: &mut APU) {
+        let level = self.level.clone();
+        apu.register_expansion(move |_| level.load(Ordering::Relaxed));
+    }
+
+    fn disk_sides(&self) -> usize {
+        self.sides.len()
+    }
+
+    fn inserted_disk(&self) -> Option<usize> {
+        self.next_side
+    }
+
+    fn insert_disk(&mut self, side: Option<usize>) {
+        self.side = None;
+        self.next_side = side;
+        self.swap_delay = if side.is_some() { SWAP_CYCLES } else { 0 };
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            bios: std::mem::take(&mut self.bios),
+            level: self.level.clone(),
+            ..state
+        };
+        Ok(())
+    }
+}
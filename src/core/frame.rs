@@ -1,12 +1,32 @@
 use std::collections::hash_map::DefaultHasher;
+use std::f32::consts::PI;
 use std::hash::{Hash, Hasher};
 use std::path::Path;
 use image::{ImageResult, Rgb, save_buffer};
 use image::ColorType;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy)]
+use crate::core::savestate::{big_array, big_array_2d};
+
+// Width of Frame::apply_ntsc's output, 7 pixels for every 3 NES pixels like nes_ntsc
+pub const NTSC_WIDTH: usize = 602;
+
+// The composite signal is sampled 12 times per color subcarrier cycle, which makes an NES pixel
+// 8 samples wide
+const SAMPLES_PER_CYCLE: usize = 12;
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = 256 * SAMPLES_PER_PIXEL;
+// How many samples luma and chroma are averaged over when decoding. A full cycle cancels out the
+// chroma in a flat area of color, and the longer chroma window gives the softer color edges of a
+// real TV
+const LUMA_WINDOW: usize = SAMPLES_PER_CYCLE;
+const CHROMA_WINDOW: usize = SAMPLES_PER_CYCLE * 2;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct Frame {
+    #[serde(with = "big_array")]
     pub image: [u8; 256 * 240 * 3],
+    #[serde(with = "big_array_2d")]
     pub is_zero: [[bool; 256]; 240],
 }
 
@@ -24,6 +44,11 @@ impl Frame {
         }
     }
 
+    // Width and height in pixels
+    pub const fn size() -> (usize, usize) {
+        (256, 240)
+    }
+
     pub fn set_pixel(&mut self, x: usize, y: usize, color: Rgb<u8>) {
         let index = (y * 256 + x) * 3;
         self.image[index] = color.0[0];
@@ -37,7 +62,169 @@ impl Frame {
         hasher.finish()
     }
 
+    // Hash of just the (x, y, width, height) rect, so a test can ignore changes elsewhere on the
+    // screen. The whole screen gives the same value as get_hash
+    pub fn get_hash_region(&self, x: usize, y: usize, w: usize, h: usize) -> u64 {
+        let (x, y) = (x.min(256), y.min(240));
+        let (region, _, _) = self.cropped(y, 240 - (y + h).min(240), x, 256 - (x + w).min(256));
+        let mut hasher = DefaultHasher::new();
+        region.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Hash with each (x, y, width, height) rect blanked out, for tests whose ROMs draw something
+    // volatile like a blinking cursor. An empty mask gives the same value as get_hash
+    pub fn hash_masked(&self, mask: &[(usize, usize, usize, usize)]) -> u64 {
+        let mut image = self.image;
+        for &(x, y, w, h) in mask {
+            for row in y..(y + h).min(240) {
+                let start = (row * 256 + x.min(256)) * 3;
+                let end = (row * 256 + (x + w).min(256)) * 3;
+                image[start..end].fill(0);
+            }
+        }
+        let mut hasher = DefaultHasher::new();
+        image.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // The image with an opaque alpha byte after every pixel, the layout most GPU textures and
+    // canvases take
+    pub fn to_rgba(&self) -> Vec<u8> {
+        let mut out = vec![0; 256 * 240 * 4];
+        self.write_rgba(&mut out);
+        out
+    }
+
+    // Same as to_rgba into a buffer the caller keeps, which has to hold at least 256 * 240 * 4
+    // bytes
+    pub fn write_rgba(&self, out: &mut [u8]) {
+        assert!(out.len() >= 256 * 240 * 4, "RGBA buffer of {} bytes is too small", out.len());
+        for (rgba, rgb) in out.chunks_exact_mut(4).zip(self.image.chunks_exact(3)) {
+            rgba[..3].copy_from_slice(rgb);
+            rgba[3] = 0xff;
+        }
+    }
+
+    // The pixels that differ from `prev`, as (y * 256 + x, RGB), for sending a frame to something
+    // that already has the previous one. Only the image is compared, not is_zero
+    pub fn diff(&self, prev: &Frame) -> Vec<(u32, [u8; 3])> {
+        let mut out = vec![];
+        self.diff_into(prev, &mut out);
+        out
+    }
+
+    // Same as diff but fills `out`, so a streamer can keep one buffer for every frame
+    pub fn diff_into(&self, prev: &Frame, out: &mut Vec<(u32, [u8; 3])>) {
+        out.clear();
+        let pixels = self.image.chunks_exact(3).zip(prev.image.chunks_exact(3));
+        for (i, (pixel, prev_pixel)) in pixels.enumerate() {
+            if pixel != prev_pixel {
+                out.push((i as u32, [pixel[0], pixel[1], pixel[2]]));
+            }
+        }
+    }
+
+    // Turns the frame diff was given as `prev` into the one it was called on. Indices off the
+    // screen are ignored
+    pub fn apply_diff(&mut self, diff: &[(u32, [u8; 3])]) {
+        for &(i, color) in diff {
+            let index = i as usize * 3;
+            if let Some(pixel) = self.image.get_mut(index..index + 3) {
+                pixel.copy_from_slice(&color);
+            }
+        }
+    }
+
+    // Encodes each line into a composite signal and decodes it again the way a TV would, giving
+    // the color fringing and dot crawl of the NTSC output. `phase` is the subcarrier phase of the
+    // first line in thirds of a cycle (PPU::color_phase). Output is RGB, NTSC_WIDTH x 240
+    pub fn apply_ntsc(&self, phase: u8) -> Vec<u8> {
+        let carrier: [(f32, f32); SAMPLES_PER_CYCLE] = std::array::from_fn(|i| {
+            let angle = 2. * PI * i as f32 / SAMPLES_PER_CYCLE as f32;
+            (angle.cos(), angle.sin())
+        });
+
+        let mut out = Vec::with_capacity(NTSC_WIDTH * 240 * 3);
+        // Running sums of the signal and its products with the carrier, so every window is an
+        // O(1) lookup
+        let mut luma = vec![0.; LINE_SAMPLES + 1];
+        let mut in_phase = vec![0.; LINE_SAMPLES + 1];
+        let mut quadrature = vec![0.; LINE_SAMPLES + 1];
+        for y in 0..240 {
+            // Each line starts a third of a cycle later than the one before
+            let line_phase = (phase as usize + y) % 3 * (SAMPLES_PER_CYCLE / 3);
+            for x in 0..256 {
+                let idx = (y * 256 + x) * 3;
+                let [r, g, b] = [0, 1, 2].map(|i| f32::from(self.image[idx + i]) / 255.);
+                let yy = 0.299 * r + 0.587 * g + 0.114 * b;
+                let ii = 0.596 * r - 0.274 * g - 0.322 * b;
+                let qq = 0.211 * r - 0.523 * g + 0.312 * b;
+                for s in 0..SAMPLES_PER_PIXEL {
+                    let t = x * SAMPLES_PER_PIXEL + s;
+                    let (cos, sin) = carrier[(t + line_phase) % SAMPLES_PER_CYCLE];
+                    let signal = yy + ii * cos + qq * sin;
+                    luma[t + 1] = luma[t] + signal;
+                    in_phase[t + 1] = in_phase[t] + 2. * signal * cos;
+                    quadrature[t + 1] = quadrature[t] + 2. * signal * sin;
+                }
+            }
+
+            let average = |sums: &[f32], center: usize, window: usize| {
+                let start = center.saturating_sub(window / 2).min(LINE_SAMPLES - window);
+                (sums[start + window] - sums[start]) / window as f32
+            };
+            for x in 0..NTSC_WIDTH {
+                let center = (2 * x + 1) * LINE_SAMPLES / (2 * NTSC_WIDTH);
+                let yy = average(&luma, center, LUMA_WINDOW);
+                let ii = average(&in_phase, center, CHROMA_WINDOW);
+                let qq = average(&quadrature, center, CHROMA_WINDOW);
+                let rgb = [
+                    yy + 0.956 * ii + 0.621 * qq,
+                    yy - 0.272 * ii - 0.647 * qq,
+                    yy - 1.106 * ii + 1.703 * qq,
+                ];
+                out.extend(rgb.map(|c| (c * 255.).round().clamp(0., 255.) as u8));
+            }
+        }
+        out
+    }
+
+    // The picture with `top`/`bottom` rows and `left`/`right` columns cut off, as RGB along with
+    // its width and height
+    pub fn cropped(
+        &self,
+        top: usize,
+        bottom: usize,
+        left: usize,
+        right: usize,
+    ) -> (Vec<u8>, usize, usize) {
+        crop(&self.image, 256, top, bottom, left, right)
+    }
+
     pub fn save_buffer(&self, path: impl AsRef<Path>) -> ImageResult<()> {
         save_buffer(path, &self.image, 256, 240, ColorType::Rgb8)
     }
 }
+
+// Crops an RGB image `width` pixels wide, e.g. the output of Frame::apply_ntsc. Crops bigger than
+// the image leave nothing
+pub fn crop(
+    image: &[u8],
+    width: usize,
+    top: usize,
+    bottom: usize,
+    left: usize,
+    right: usize,
+) -> (Vec<u8>, usize, usize) {
+    let height = image.len() / (width * 3);
+    let (top, left) = (top.min(height), left.min(width));
+    let cropped_height = height - top - bottom.min(height - top);
+    let cropped_width = width - left - right.min(width - left);
+    let mut out = Vec::with_capacity(cropped_width * cropped_height * 3);
+    for row in top..top + cropped_height {
+        let start = (row * width + left) * 3;
+        out.extend_from_slice(&image[start..start + cropped_width * 3]);
+    }
+    (out, cropped_width, cropped_height)
+}
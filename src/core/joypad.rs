@@ -1,6 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     pub struct Buttons: u8 {
         const A =       0b0000_0001;
         const B =       0b0000_0010;
@@ -13,6 +15,13 @@ bitflags! {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Player {
+    One,
+    Two,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct Joypad {
     is_strobe_on: bool,
     button_idx: u8,
@@ -37,11 +46,10 @@ impl Joypad {
         }
     }
 
+    // While the strobe is high the shift register keeps reloading, so every read is A. After
+    // all 8 buttons an official controller shifts in 1s
     pub fn read(&mut self) -> u8 {
-        if self.button_idx > 7 {
-            return 1;
-        }
-        let ret = (self.buttons.bits() & (1 << self.button_idx)) >> self.button_idx;
+        let ret = self.read_trace();
         if !self.is_strobe_on && self.button_idx <= 7 {
             self.button_idx += 1;
         }
@@ -52,6 +60,6 @@ impl Joypad {
         if self.button_idx > 7 {
             return 1;
         }
-        self.buttons.bits() & (1 << self.button_idx) >> self.button_idx
+        (self.buttons.bits() >> self.button_idx) & 1
     }
 }
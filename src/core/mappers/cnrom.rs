@@ -1,6 +1,11 @@
-use super::{Mapper, Mirroring};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+use crate::config::Config;
+use crate::core::savestate::big_array_2d;
+
+use super::{chr_rom_or_ram, prg_ram_with_trainer, CartridgeConfig, Mapper, Mirroring};
+
+#[derive(Clone, Serialize, Deserialize)]
 enum PRGRomMode {
     PRG16k,
     PRG32k,
@@ -8,27 +13,38 @@ enum PRGRomMode {
 
 const PAGE_SIZE: usize = 0x2000;
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CNROM {
     pub prg_ram: Vec<u8>,
+    #[serde(skip)]
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     has_chr_ram: bool,
+    has_battery: bool,
     prg_rom_mode: PRGRomMode,
-    mirroring: u8,
-    nametables: [[u8; 0x400]; 2],
+    mirroring: Mirroring,
+    // Only the first two are used unless the cart provides four-screen VRAM
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
     bank_select: u8,
+    chr_bank_count: usize,
+    // The board doesn't disable the ROM on writes, so the CPU and ROM both drive the data bus and
+    // the written value gets ANDed with the ROM byte at that address
+    bus_conflicts: bool,
 }
 
 impl CNROM {
-    pub fn new(
-        prg_rom: Vec<u8>,
-        chr_rom: Option<Vec<u8>>,
-        prg_ram_size: usize,
-        eeprom_size: usize,
-        has_battery: bool,
-        mirroring: u8,
-    ) -> Self {
+    pub fn new(cart: CartridgeConfig) -> Self {
+        let CartridgeConfig {
+            prg_rom,
+            chr_rom,
+            chr_ram_size,
+            prg_ram_size,
+            eeprom_size,
+            has_battery,
+            mirroring,
+            trainer,
+        } = cart;
         let mut prg_ram_size = prg_ram_size;
         if prg_ram_size == 0 && eeprom_size > 0 {
             prg_ram_size = eeprom_size;
@@ -36,33 +52,31 @@ impl CNROM {
             prg_ram_size = 0x2000;
         }
 
+        let has_chr_ram = chr_rom.is_none();
+        let chr_rom = chr_rom_or_ram(chr_rom, chr_ram_size);
         Self {
-            prg_ram: vec![0; prg_ram_size],
+            prg_ram: prg_ram_with_trainer(prg_ram_size, trainer),
             prg_rom_mode: if prg_rom.len() <= 16384 {
                 PRGRomMode::PRG16k
             } else {
                 PRGRomMode::PRG32k
             },
             prg_rom,
-            has_chr_ram: chr_rom.is_none(),
-            chr_rom: match chr_rom {
-                Some(chr_rom) => chr_rom,
-                None => vec![0; 8192],
-            },
+            has_chr_ram,
+            has_battery,
+            chr_bank_count: (chr_rom.len() / PAGE_SIZE).max(1),
+            chr_rom,
             mirroring,
-            nametables: [[0; 0x400]; 2],
+            nametables: [[0; 0x400]; 4],
             bank_select: 0,
+            bus_conflicts: Config::get_bool("cnrom_bus_conflicts", true),
         }
     }
 }
 
 impl Mapper for CNROM {
     fn get_mirroring(&self) -> Mirroring {
-        match self.mirroring {
-            0 => Mirroring::Horizontal,
-            1 => Mirroring::Vertical,
-            _ => unreachable!(),
-        }
+        self.mirroring
     }
 
     fn read_chr_rom(&self, addr: u16) -> u8 {
@@ -74,7 +88,7 @@ impl Mapper for CNROM {
         match addr {
             0x6000..=0x7FFF => {
                 if !self.prg_ram.is_empty() {
-                    self.prg_ram[(addr - 0x6000) as usize]
+                    self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()]
                 } else {
                     println!(
                         "Attempted to read from PRG RAM at {:#X} but no PRG RAM is present",
@@ -99,7 +113,8 @@ impl Mapper for CNROM {
         match addr {
             0x6000..=0x7FFF => {
                 if !self.prg_ram.is_empty() {
-                    self.prg_ram[(addr - 0x6000) as usize] = data;
+                    let len = self.prg_ram.len();
+                    self.prg_ram[(addr - 0x6000) as usize % len] = data;
                 } else {
                     println!(
                         "Attempted to write to PRG RAM at {:#X} but no PRG RAM is present",
@@ -108,7 +123,13 @@ impl Mapper for CNROM {
                 }
             }
             0x8000..=0xFFFF => {
-                self.bank_select = data;
+                let data = if self.bus_conflicts {
+                    data & self.read(addr)
+                } else {
+                    data
+                };
+                // Carts only decode as many bank bits as they have CHR for
+                self.bank_select = (data as usize % self.chr_bank_count) as u8;
             }
             _ => println!("Invalid address {:#X}", addr),
         }
@@ -120,6 +141,11 @@ impl Mapper for CNROM {
         }
     }
 
+    fn chr_bank_map(&self) -> [usize; 8] {
+        let bank_cnt = self.chr_rom.len() / 0x400;
+        std::array::from_fn(|i| (self.bank_select as usize * 8 + i) % bank_cnt)
+    }
+
     fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8) {
         self.nametables[idx][addr as usize] = val;
     }
@@ -127,4 +153,32 @@ impl Mapper for CNROM {
     fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8 {
         self.nametables[idx][addr as usize]
     }
+
+    fn dump_save(&self) -> &[u8] {
+        if self.has_battery {
+            self.prg_ram.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        if self.has_battery {
+            let len = data.len().min(self.prg_ram.len());
+            self.prg_ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            prg_rom: std::mem::take(&mut self.prg_rom),
+            ..state
+        };
+        Ok(())
+    }
 }
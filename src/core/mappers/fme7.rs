@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::savestate::big_array_2d;
+
+use super::{chr_rom_or_ram, prg_ram_with_trainer, CartridgeConfig, Mapper, Mirroring};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Irq {
+    counter: u16,
+    enabled: bool,
+    counter_enabled: bool,
+    pending: bool,
+}
+
+impl Irq {
+    fn clock(&mut self) {
+        if !self.counter_enabled {
+            return;
+        }
+        self.counter = self.counter.wrapping_sub(1);
+        if self.counter == 0xffff && self.enabled {
+            self.pending = true;
+        }
+    }
+}
+
+// Sunsoft FME-7 (mapper 69). All registers go through a command written to $8000 and a parameter
+// written to $A000
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FME7 {
+    pub prg_ram: Vec<u8>,
+    #[serde(skip)]
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    has_chr_ram: bool,
+    has_battery: bool,
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
+    command: u8,
+    chr_banks: [u8; 8],
+    // $6000-$7FFF, $8000, $A000, $C000
+    prg_banks: [u8; 4],
+    mirroring: Mirroring,
+    irq: Irq,
+}
+
+impl FME7 {
+    pub fn new(cart: CartridgeConfig) -> Self {
+        let CartridgeConfig {
+            prg_rom,
+            chr_rom,
+            chr_ram_size,
+            prg_ram_size,
+            eeprom_size,
+            has_battery,
+            mirroring,
+            trainer,
+        } = cart;
+        let mut prg_ram_size = prg_ram_size;
+        if prg_ram_size == 0 && eeprom_size > 0 {
+            prg_ram_size = eeprom_size;
+        } else if has_battery {
+            prg_ram_size = 0x2000;
+        }
+
+        let has_chr_ram = chr_rom.is_none();
+        Self {
+            prg_ram: prg_ram_with_trainer(prg_ram_size, trainer),
+            prg_rom,
+            chr_rom: chr_rom_or_ram(chr_rom, chr_ram_size),
+            has_chr_ram,
+            has_battery,
+            nametables: [[0; 0x400]; 4],
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring,
+            irq: Irq::default(),
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let bank = self.chr_banks[addr as usize / CHR_BANK_SIZE] as usize;
+        (bank * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr_rom.len()
+    }
+
+    // $6000-$7FFF maps PRG-RAM when bit 6 of its bank register is set, otherwise PRG-ROM
+    fn ram_selected(&self) -> bool {
+        self.prg_banks[0] & 0x40 != 0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.prg_banks[0] & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    fn ram_addr(&self, addr: u16) -> usize {
+        let bank = (self.prg_banks[0] & 0x3f) as usize;
+        (bank * PRG_BANK_SIZE + (addr - 0x6000) as usize) % self.prg_ram.len()
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => self.prg_banks[0] = data,
+            0x9..=0xB => self.prg_banks[self.command as usize - 0x8] = data & 0x3f,
+            0xC => {
+                self.mirroring = match data & 0x03 {
+                    0 => Mirroring::Vertical,
+                    1 => Mirroring::Horizontal,
+                    2 => Mirroring::SingleScreenA,
+                    _ => Mirroring::SingleScreenB,
+                }
+            }
+            0xD => {
+                self.irq.enabled = data & 0x01 != 0;
+                self.irq.counter_enabled = data & 0x80 != 0;
+                self.irq.pending = false;
+            }
+            0xE => self.irq.counter = (self.irq.counter & 0xff00) | data as u16,
+            0xF => self.irq.counter = (self.irq.counter & 0x00ff) | (data as u16) << 8,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Mapper for FME7 {
+    fn get_mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.chr_rom[self.chr_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, data: u8) {
+        if self.has_chr_ram {
+            let idx = self.chr_addr(addr);
+            self.chr_rom[idx] = data;
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x6000..=0x7FFF => {
+                if !self.ram_selected() {
+                    (self.prg_banks[0] & 0x3f) as usize
+                } else if self.ram_enabled() {
+                    return self.prg_ram[self.ram_addr(addr)];
+                } else {
+                    // Nothing drives the bus, so this is open bus: normally the high byte of the
+                    // address the CPU just fetched
+                    return (addr >> 8) as u8;
+                }
+            }
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x6000) / PRG_BANK_SIZE] as usize,
+            0xE000..=0xFFFF => self.prg_bank_count() - 1,
+            _ => {
+                println!("Invalid address {:#X}", addr);
+                return 0;
+            }
+        };
+        let bank = bank % self.prg_bank_count();
+        self.prg_rom[bank * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                if self.ram_selected() && self.ram_enabled() {
+                    let idx = self.ram_addr(addr);
+                    self.prg_ram[idx] = data;
+                }
+            }
+            0x8000..=0x9FFF => self.command = data & 0x0f,
+            0xA000..=0xBFFF => self.write_parameter(data),
+            // Sunsoft 5B audio registers, not emulated
+            0xC000..=0xFFFF => {}
+            _ => println!("Invalid address {:#X}", addr),
+        }
+    }
+
+    fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8) {
+        self.nametables[idx][addr as usize] = val;
+    }
+
+    fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8 {
+        self.nametables[idx][addr as usize]
+    }
+
+    fn chr_bank_map(&self) -> [usize; 8] {
+        let bank_cnt = self.chr_rom.len() / CHR_BANK_SIZE;
+        self.chr_banks.map(|bank| bank as usize % bank_cnt)
+    }
+
+    fn is_cpu_clocked(&self) -> bool {
+        true
+    }
+
+    fn clock_cpu(&mut self) {
+        self.irq.clock();
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn dump_save(&self) -> &[u8] {
+        if self.has_battery {
+            self.prg_ram.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        if self.has_battery {
+            let len = data.len().min(self.prg_ram.len());
+            self.prg_ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            prg_rom: std::mem::take(&mut self.prg_rom),
+            ..state
+        };
+        Ok(())
+    }
+}
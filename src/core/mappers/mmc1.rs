@@ -1,4 +1,10 @@
-use crate::core::mappers::{Mapper, Mirroring};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::core::mappers::{
+    chr_rom_or_ram, prg_ram_with_trainer, CartridgeConfig, Mapper, Mirroring,
+};
+use crate::core::savestate::big_array_2d;
 
 enum PRGMode {
     PRG16k,
@@ -22,6 +28,47 @@ enum SlotSelect {
     Slot1,
 }
 
+// Boards that use the MMC1's spare bits for something, named by the NES 2.0 submapper or else
+// guessed from the PRG-ROM and PRG-RAM sizes
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum Board {
+    #[default]
+    Standard,
+    // 512K of PRG-ROM, with bit 4 of the CHR bank picking the 256K half
+    Surom,
+    // 16K of PRG-RAM, with bit 3 of the CHR bank picking the 8K bank
+    Sorom,
+    // SUROM's PRG-ROM banking plus 32K of PRG-RAM, banked with bits 2-3 of the CHR bank
+    Sxrom,
+    // SEROM, SHROM and SH1ROM, a fixed 32K of PRG-ROM
+    Serom,
+    // The first MMC1 revision, which can't disable its PRG-RAM
+    Mmc1a,
+}
+
+impl Board {
+    fn from_submapper(submapper: u8) -> Option<Self> {
+        match submapper {
+            1 => Some(Self::Surom),
+            2 => Some(Self::Sorom),
+            3 => Some(Self::Mmc1a),
+            4 => Some(Self::Sxrom),
+            5 => Some(Self::Serom),
+            _ => None,
+        }
+    }
+
+    fn from_sizes(prg_rom_size: usize, prg_ram_size: usize) -> Self {
+        match (prg_rom_size > 0x40000, prg_ram_size) {
+            (_, 0x8000) => Self::Sxrom,
+            (true, _) => Self::Surom,
+            (false, 0x4000) => Self::Sorom,
+            _ => Self::Standard,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 struct State {
     control_reg: u8,
     chr_bank_0_reg: u8,
@@ -40,6 +87,7 @@ impl Default for State {
     }
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct MMC1 {
     // CPU BANKS -----------------------------------------------------------------------------------
     // $6000-7FFF: 8 KB PRG RAM bank (optional)
@@ -80,21 +128,45 @@ pub struct MMC1 {
     shift_count: u8,
     state: State,
     prg_ram: Vec<u8>,
+    #[serde(skip)]
     prg_rom: Vec<u8>,
     chr_rom: Vec<u8>,
     has_chr_ram: bool,
-    nametables: [[u8; 0x400]; 2],
+    // Extra two only reachable through a forced four-screen override
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
+    #[serde(skip)]
+    board: Board,
+    #[serde(skip)]
+    has_battery: bool,
+    // The serial port only takes the first of two writes on back to back CPU cycles, so the dummy
+    // write of a read-modify-write instruction is the one that counts
+    #[serde(skip)]
+    last_write_cycle: Option<u64>,
+    // Told about every access that goes nowhere, with the address and whether it was a write: WRAM
+    // while it's disabled or when there isn't any, and addresses below $6000. Off unless
+    // log_wram_access is set, as some games poll disabled WRAM constantly
+    #[serde(skip)]
+    wram_logger: Option<fn(u16, bool)>,
+}
+
+fn print_wram_access(addr: u16, write: bool) {
+    let access = if write { "write to" } else { "read from" };
+    println!("MMC1 ignored a {access} ${addr:04X}");
 }
 
 impl MMC1 {
-    pub fn new(
-        prg_rom: Vec<u8>,
-        chr_rom: Option<Vec<u8>>,
-        prg_ram_size: usize,
-        eeprom_size: usize,
-        has_battery: bool,
-        _mirroring: u8,
-    ) -> Self {
+    pub fn new(cart: CartridgeConfig) -> Self {
+        let CartridgeConfig {
+            prg_rom,
+            chr_rom,
+            chr_ram_size,
+            prg_ram_size,
+            eeprom_size,
+            has_battery,
+            mirroring: _,
+            trainer,
+        } = cart;
         let mut prg_ram_size = prg_ram_size;
         if prg_ram_size == 0 && eeprom_size > 0 {
             prg_ram_size = eeprom_size;
@@ -102,16 +174,32 @@ impl MMC1 {
             prg_ram_size = 0x2000;
         }
         let has_chr_ram = chr_rom.is_none();
+        let prg_ram = prg_ram_with_trainer(prg_ram_size, trainer);
 
         Self {
-            prg_ram: vec![0; prg_ram_size],
+            board: Board::from_sizes(prg_rom.len(), prg_ram.len()),
+            has_battery,
+            prg_ram,
             prg_rom,
-            chr_rom: chr_rom.unwrap_or_else(|| vec![0; 0x2000]),
+            chr_rom: chr_rom_or_ram(chr_rom, chr_ram_size),
             temp_reg: 0,
             has_chr_ram,
             shift_count: 0,
             state: State::default(),
-            nametables: [[0; 0x400]; 2],
+            nametables: [[0; 0x400]; 4],
+            last_write_cycle: None,
+            wram_logger: Config::get_bool("log_wram_access", false)
+                .then_some(print_wram_access as fn(u16, bool)),
+        }
+    }
+
+    pub fn set_wram_logger(&mut self, logger: Option<fn(u16, bool)>) {
+        self.wram_logger = logger;
+    }
+
+    fn log_ignored(&self, addr: u16, write: bool) {
+        if let Some(logger) = self.wram_logger {
+            logger(addr, write);
         }
     }
 
@@ -140,13 +228,33 @@ impl MMC1 {
     }
 
     fn get_wram_disable(&self) -> bool {
-        self.state.prg_bank_reg >> 4 == 1
+        self.board != Board::Mmc1a && self.state.prg_bank_reg >> 4 == 1
     }
 
     fn get_prg_bank(&self) -> usize {
         (self.state.prg_bank_reg & 0b1111) as usize
     }
 
+    // SUROM and SXROM have 512K of PRG-ROM, with bit 4 of the CHR bank picking which 256K half the
+    // PRG bank register switches within. Returned in 16K pages
+    fn get_prg_outer_bank(&self) -> usize {
+        match self.board {
+            Board::Surom | Board::Sxrom => (self.state.chr_bank_0_reg & 0x10) as usize,
+            _ => 0,
+        }
+    }
+
+    // SOROM (16K) and SXROM (32K) bank PRG-RAM through the CHR bank too, since they only have 8K of
+    // CHR-RAM and the upper bits are free
+    fn get_prg_ram_idx(&self, addr: u16) -> usize {
+        let bank = match self.board {
+            Board::Sorom => (self.state.chr_bank_0_reg >> 3) & 1,
+            Board::Sxrom => (self.state.chr_bank_0_reg >> 2) & 3,
+            _ => 0,
+        } as usize;
+        (bank * 0x2000 + (addr - 0x6000) as usize) % self.prg_ram.len()
+    }
+
     fn get_mut_ref_reg(&mut self, register: Register) -> &mut u8 {
         match register {
             Register::Control => &mut self.state.control_reg,
@@ -217,7 +325,7 @@ impl Mapper for MMC1 {
 
     fn read(&self, addr: u16) -> u8 {
         if self.get_wram_disable() && (0x6000..=0x7fff).contains(&addr) {
-            println!("WRAM disabled, cannot read from PRG");
+            self.log_ignored(addr, false);
             0
         } else {
             self.read_trace(addr)
@@ -226,25 +334,35 @@ impl Mapper for MMC1 {
 
     fn write(&mut self, addr: u16, data: u8) {
         if self.get_wram_disable() && (0x6000..=0x7fff).contains(&addr) {
-            println!("WRAM disabled, cannot write to PRG");
+            self.log_ignored(addr, true);
         } else {
             match addr {
                 0x6000..=0x7FFF => {
                     if !self.prg_ram.is_empty() {
-                        let idx = ((addr - 0x6000) as usize) % self.prg_ram.len();
+                        let idx = self.get_prg_ram_idx(addr);
                         self.prg_ram[idx] = data;
                     } else {
-                        println!("Attempted to write to non-existent PRG RAM");
+                        self.log_ignored(addr, true);
                     }
                 }
                 0x8000..=0x9FFF => self.write_reg(data, Register::Control),
                 0xA000..=0xBFFF => self.write_reg(data, Register::CHRBank0),
                 0xC000..=0xDFFF => self.write_reg(data, Register::CHRBank1),
                 0xE000..=0xFFFF => self.write_reg(data, Register::PRGBank),
-                _ => println!("Invalid write address: {:#X}", addr),
+                _ => self.log_ignored(addr, true),
+            }
+        }
+    }
+
+    fn write_timed(&mut self, addr: u16, data: u8, cpu_cycle: u64) {
+        if addr >= 0x8000 {
+            let consecutive = self.last_write_cycle == cpu_cycle.checked_sub(1);
+            self.last_write_cycle = Some(cpu_cycle);
+            if consecutive {
+                return;
             }
         }
-        // todo!("Implement consecutive write logic for MMC1");
+        self.write(addr, data);
     }
 
     fn write_chr_rom(&mut self, addr: u16, data: u8) {
@@ -271,6 +389,25 @@ impl Mapper for MMC1 {
         }
     }
 
+    fn chr_bank_map(&self) -> [usize; 8] {
+        let bank_cnt = self.chr_rom.len() / 0x400;
+        std::array::from_fn(|i| {
+            let bank = match self.get_chr_mode() {
+                CHRMode::CHR8k => (self.state.chr_bank_0_reg >> 1) as usize * 8 + i,
+                CHRMode::CHR4k if i < 4 => self.state.chr_bank_0_reg as usize * 4 + i,
+                CHRMode::CHR4k => self.state.chr_bank_1_reg as usize * 4 + (i - 4),
+            };
+            bank % bank_cnt
+        })
+    }
+
+    // Submapper 0 leaves it to the sizes, as iNES 1.0 has to
+    fn set_submapper(&mut self, submapper: u8) {
+        if let Some(board) = Board::from_submapper(submapper) {
+            self.board = board;
+        }
+    }
+
     fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8) {
         self.nametables[idx][addr as usize] = val;
     }
@@ -282,16 +419,18 @@ impl Mapper for MMC1 {
     fn read_trace(&self, addr: u16) -> u8 {
         if (0x6000..=0x7FFF).contains(&addr) {
             if !self.prg_ram.is_empty() {
-                let idx = (addr - 0x6000) as usize;
-                self.prg_ram[idx % self.prg_ram.len()]
+                self.prg_ram[self.get_prg_ram_idx(addr)]
             } else {
-                println!("Attempted to read from PRG RAM, but it is not mapped");
+                self.log_ignored(addr, false);
                 0
             }
+        } else if self.board == Board::Serom {
+            // SEROM, SHROM and SH1ROM have a fixed 32K of PRG-ROM and no PRG banking at all
+            self.prg_rom[(addr - 0x8000) as usize % self.prg_rom.len()]
         } else {
             match self.get_prg_mode() {
                 PRGMode::PRG32k => {
-                    let mut page = self.get_prg_bank() >> 1;
+                    let mut page = (self.get_prg_bank() | self.get_prg_outer_bank()) >> 1;
                     if page >= self.get_page_cnt() {
                         page &= self.get_page_cnt() - 1;
                     }
@@ -304,10 +443,11 @@ impl Mapper for MMC1 {
                         (_, 0x8000..=0xBFFF) => (self.get_prg_bank(), 0x8000),
                         (_, 0xC000..=0xFFFF) => (0x0F & (self.get_page_cnt() - 1), 0xC000),
                         _ => {
-                            println!("Invalid read address: {:#X}", addr);
+                            self.log_ignored(addr, false);
                             return 0;
                         }
                     };
+                    page |= self.get_prg_outer_bank();
                     if page >= self.get_page_cnt() {
                         page &= self.get_page_cnt() - 1;
                     }
@@ -318,11 +458,33 @@ impl Mapper for MMC1 {
     }
 
     fn dump_save(&self) -> &[u8] {
-        self.prg_ram.as_slice()
+        if self.has_battery {
+            self.prg_ram.as_slice()
+        } else {
+            &[]
+        }
     }
 
     fn load_save(&mut self, data: &[u8]) {
-        dbg!(data.len());
-        self.prg_ram = data.to_vec();
+        if self.has_battery {
+            let len = data.len().min(self.prg_ram.len());
+            self.prg_ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            prg_rom: std::mem::take(&mut self.prg_rom),
+            board: self.board,
+            has_battery: self.has_battery,
+            wram_logger: self.wram_logger,
+            ..state
+        };
+        Ok(())
     }
 }
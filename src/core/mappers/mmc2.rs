@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::savestate::big_array_2d;
+
+use super::{chr_rom_or_ram, prg_ram_with_trainer, CartridgeConfig, Mapper, Mirroring};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+enum Latch {
+    FD,
+    FE,
+}
+
+// Nintendo MMC2 (mapper 9), only used by Punch-Out!!. Each pattern table half has two CHR banks and
+// a latch picking between them, flipped by the PPU fetching tile $FD or $FE
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MMC2 {
+    pub prg_ram: Vec<u8>,
+    #[serde(skip)]
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    has_chr_ram: bool,
+    has_battery: bool,
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
+    prg_bank: u8,
+    // Indexed by pattern table half, then latch
+    chr_banks: [[u8; 2]; 2],
+    latches: [Latch; 2],
+    mirroring: Mirroring,
+}
+
+impl MMC2 {
+    pub fn new(cart: CartridgeConfig) -> Self {
+        let CartridgeConfig {
+            prg_rom,
+            chr_rom,
+            chr_ram_size,
+            prg_ram_size,
+            eeprom_size,
+            has_battery,
+            mirroring,
+            trainer,
+        } = cart;
+        let mut prg_ram_size = prg_ram_size;
+        if prg_ram_size == 0 && eeprom_size > 0 {
+            prg_ram_size = eeprom_size;
+        } else if has_battery {
+            prg_ram_size = 0x2000;
+        }
+
+        let has_chr_ram = chr_rom.is_none();
+        Self {
+            prg_ram: prg_ram_with_trainer(prg_ram_size, trainer),
+            prg_rom,
+            chr_rom: chr_rom_or_ram(chr_rom, chr_ram_size),
+            has_chr_ram,
+            has_battery,
+            nametables: [[0; 0x400]; 4],
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [Latch::FE; 2],
+            mirroring,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn chr_bank(&self, half: usize) -> usize {
+        let bank = self.chr_banks[half][self.latches[half] as usize];
+        bank as usize % (self.chr_rom.len() / CHR_BANK_SIZE).max(1)
+    }
+
+    fn chr_addr(&self, addr: u16) -> usize {
+        let half = addr as usize / CHR_BANK_SIZE;
+        (self.chr_bank(half) * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE) % self.chr_rom.len()
+    }
+}
+
+impl Mapper for MMC2 {
+    fn get_mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.chr_rom[self.chr_addr(addr)]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, data: u8) {
+        if self.has_chr_ram {
+            let idx = self.chr_addr(addr);
+            self.chr_rom[idx] = data;
+        }
+    }
+
+    fn notify_chr_fetch(&mut self, addr: u16) {
+        // The left half only reacts to the exact address, the right one to the whole tile row
+        // range. The fetch that trips the latch still comes from the old bank
+        match addr {
+            0x0FD8 => self.latches[0] = Latch::FD,
+            0x0FE8 => self.latches[0] = Latch::FE,
+            0x1FD8..=0x1FDF => self.latches[1] = Latch::FD,
+            0x1FE8..=0x1FEF => self.latches[1] = Latch::FE,
+            _ => {}
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x6000..=0x7FFF => {
+                if !self.prg_ram.is_empty() {
+                    return self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()];
+                }
+                println!(
+                    "Attempted to read from PRG RAM at {:#X} but no PRG RAM is present",
+                    addr
+                );
+                return 0;
+            }
+            0x8000..=0x9FFF => self.prg_bank as usize,
+            // The last three banks are fixed
+            0xA000..=0xFFFF => self.prg_bank_count() - 4 + (addr as usize - 0x8000) / PRG_BANK_SIZE,
+            _ => {
+                println!("Invalid address {:#X}", addr);
+                return 0;
+            }
+        };
+        let bank = bank % self.prg_bank_count();
+        self.prg_rom[bank * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                if !self.prg_ram.is_empty() {
+                    let len = self.prg_ram.len();
+                    self.prg_ram[(addr - 0x6000) as usize % len] = data;
+                } else {
+                    println!(
+                        "Attempted to write to PRG RAM at {:#X} but no PRG RAM is present",
+                        addr
+                    );
+                }
+            }
+            0x8000..=0x9FFF => {}
+            0xA000..=0xAFFF => self.prg_bank = data & 0x0f,
+            0xB000..=0xBFFF => self.chr_banks[0][Latch::FD as usize] = data & 0x1f,
+            0xC000..=0xCFFF => self.chr_banks[0][Latch::FE as usize] = data & 0x1f,
+            0xD000..=0xDFFF => self.chr_banks[1][Latch::FD as usize] = data & 0x1f,
+            0xE000..=0xEFFF => self.chr_banks[1][Latch::FE as usize] = data & 0x1f,
+            0xF000..=0xFFFF => {
+                self.mirroring = if data & 0x01 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+            }
+            _ => println!("Invalid address {:#X}", addr),
+        }
+    }
+
+    fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8) {
+        self.nametables[idx][addr as usize] = val;
+    }
+
+    fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8 {
+        self.nametables[idx][addr as usize]
+    }
+
+    fn chr_bank_map(&self) -> [usize; 8] {
+        std::array::from_fn(|i| self.chr_bank(i / 4) * 4 + i % 4)
+    }
+
+    fn dump_save(&self) -> &[u8] {
+        if self.has_battery {
+            self.prg_ram.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        if self.has_battery {
+            let len = data.len().min(self.prg_ram.len());
+            self.prg_ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            prg_rom: std::mem::take(&mut self.prg_rom),
+            ..state
+        };
+        Ok(())
+    }
+}
@@ -1,14 +1,34 @@
 use std::sync::{Arc, Mutex};
 
+use serde::{Deserialize, Serialize};
+
+use crate::core::apu::APU;
+use crate::core::fds::FDS;
 use crate::ines_parser::{Flags1Enum, NESFile};
 
-use self::{cnrom::CNROM, mmc1::MMC1, nrom::NROM};
+use self::{
+    axrom::AxROM,
+    cnrom::CNROM,
+    fme7::FME7,
+    mmc1::MMC1,
+    mmc2::MMC2,
+    nrom::NROM,
+    uxrom::UxROM,
+    vrc6::{VRC6a, VRC6b},
+};
 
+pub mod axrom;
 pub mod cnrom;
+pub mod fme7;
 pub mod mmc1;
+pub mod mmc2;
 pub mod nrom;
+pub mod uxrom;
+pub mod vrc6;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
 pub enum Mirroring {
+    #[default]
     Horizontal,
     Vertical,
     FourScreen,
@@ -16,43 +36,108 @@ pub enum Mirroring {
     SingleScreenB,
 }
 
+impl Mirroring {
+    // Physical nametable backing each of the four logical nametables at $2000/$2400/$2800/$2C00
+    pub fn nametable_idx(&self, i: u8) -> usize {
+        let mapping = match self {
+            Mirroring::Horizontal => [0, 0, 1, 1],
+            Mirroring::Vertical => [0, 1, 0, 1],
+            Mirroring::FourScreen => [0, 1, 2, 3],
+            Mirroring::SingleScreenA => [0, 0, 0, 0],
+            Mirroring::SingleScreenB => [1, 1, 1, 1],
+        };
+        mapping[i as usize]
+    }
+}
+
+// Trainers are meant to be loaded at $7000-$71FF, so a ROM with one gets at least 8K of PRG-RAM
+// even if the header doesn't ask for any
+pub fn prg_ram_with_trainer(prg_ram_size: usize, trainer: Option<[u8; 512]>) -> Vec<u8> {
+    let Some(trainer) = trainer else {
+        return vec![0; prg_ram_size];
+    };
+    let mut prg_ram = vec![0; prg_ram_size.max(0x2000)];
+    prg_ram[0x1000..0x1200].copy_from_slice(&trainer);
+    prg_ram
+}
+
+// Carts without CHR-ROM get as much CHR-RAM as the header asks for. None of the boards decode less
+// than 8K of it, so anything smaller is rounded up
+pub fn chr_rom_or_ram(chr_rom: Option<Vec<u8>>, chr_ram_size: usize) -> Vec<u8> {
+    chr_rom.unwrap_or_else(|| vec![0; chr_ram_size.max(0x2000)])
+}
+
+// Everything the header says about the board, handed to each mapper's constructor
+#[derive(Clone, Default)]
+pub struct CartridgeConfig {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Option<Vec<u8>>,
+    pub chr_ram_size: usize,
+    pub prg_ram_size: usize,
+    pub eeprom_size: usize,
+    pub has_battery: bool,
+    pub mirroring: Mirroring,
+    pub trainer: Option<[u8; 512]>,
+}
+
+impl CartridgeConfig {
+    pub fn from_file(file: &NESFile) -> Self {
+        Self {
+            prg_rom: file.prg_rom_area.clone(),
+            chr_rom: file.chr_rom_area.clone(),
+            // Battery backed CHR-RAM isn't saved, so it's treated like any other CHR-RAM
+            chr_ram_size: file.get_chr_ram_size() + file.get_chr_nvram_size(),
+            prg_ram_size: file.get_prg_ram_size(),
+            eeprom_size: file.get_eeprom_size(),
+            has_battery: file.header.flags1.get(Flags1Enum::BATTERY) != 0,
+            mirroring: file.mirroring(),
+            trainer: file.trainer,
+        }
+    }
+}
+
 pub type SharedMapper = Arc<Mutex<Box<dyn Mapper + Send>>>;
 
 pub struct MapperFactory;
 
 macro_rules! mappers {
-    ($file:expr, $( ($num:pat, $to_create:ty) ),*) => {
-        {
-            let mapper_num = $file.header.flags1.get(Flags1Enum::MAPPER_NUM);
-            let prg_ram_size = $file.get_prg_ram_size();
-            let eeprom_size = $file.get_eeprom_size();
-            let has_battery = $file.header.flags1.get(Flags1Enum::BATTERY) != 0;
-            let prg_rom_area = $file.prg_rom_area.clone();
-            let chr_rom_area = $file.chr_rom_area.clone();
-            let mirroring = $file.header.flags1.get(Flags1Enum::NAME_TABLE_MIRROR);
-
-            match mapper_num {
-                $(
-                    $num => Box::new(<$to_create>::new(
-                        prg_rom_area,
-                        chr_rom_area,
-                        prg_ram_size,
-                        eeprom_size,
-                        has_battery,
-                        mirroring,
-                    )),
-                )*
-                _ => panic!("Unsupported mapper {}", mapper_num)
+    ($( ($num:pat, $to_create:ty) ),*) => {
+        impl MapperFactory {
+            pub fn supports(mapper_num: u16) -> bool {
+                matches!(mapper_num, $( $num )|*)
+            }
+
+            pub fn from_file(file: &NESFile) -> Box<dyn Mapper + Send> {
+                // Disk images come through with mapper 20 in a made up header, but that number
+                // isn't in the list, so a cartridge claiming it still gets turned away
+                if let Some(image) = &file.fds {
+                    return Box::new(FDS::new(image.clone()));
+                }
+                let cart = CartridgeConfig::from_file(file);
+                let mut mapper: Box<dyn Mapper + Send> = match file.mapper_num() {
+                    $(
+                        $num => Box::new(<$to_create>::new(cart)),
+                    )*
+                    mapper_num => panic!("Unsupported mapper {}", mapper_num)
+                };
+                mapper.set_submapper(file.submapper_num());
+                mapper
             }
         }
     };
 }
 
-impl MapperFactory {
-    pub fn from_file(file: &NESFile) -> Box<dyn Mapper + Send> {
-        mappers!(file, (0, NROM), (1, MMC1), (3, CNROM))
-    }
-}
+mappers!(
+    (0, NROM),
+    (1, MMC1),
+    (2, UxROM),
+    (3, CNROM),
+    (7, AxROM),
+    (9, MMC2),
+    (24, VRC6a),
+    (26, VRC6b),
+    (69, FME7)
+);
 
 pub trait Mapper {
     fn get_mirroring(&self) -> Mirroring;
@@ -63,8 +148,21 @@ pub trait Mapper {
 
     fn write(&mut self, addr: u16, data: u8);
 
+    // CPU writes go through here, for mappers that care about when a write lands and not just what
+    // it is
+    fn write_timed(&mut self, addr: u16, data: u8, _cpu_cycle: u64) {
+        self.write(addr, data);
+    }
+
     fn write_chr_rom(&mut self, _addr: u16, _data: u8) {}
 
+    // Called after every pattern table fetch the PPU makes while rendering or through $2007, for
+    // mappers that switch banks based on what's being drawn
+    fn notify_chr_fetch(&mut self, _addr: u16) {}
+
+    // NES 2.0 submapper, for mappers whose boards need telling apart. Always 0 for iNES 1.0
+    fn set_submapper(&mut self, _submapper: u8) {}
+
     fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8);
 
     fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8;
@@ -84,6 +182,12 @@ pub trait Mapper {
         self.read(addr)
     }
 
+    // A read by the CPU itself, for registers that clear flags when read. Debuggers and tracing
+    // go through read/read_trace and leave them alone
+    fn read_cpu(&mut self, addr: u16) -> u8 {
+        self.read(addr)
+    }
+
     fn read_16_trace(&self, addr: u16) -> u16 {
         let low = self.read_trace(addr);
         let high = self.read_trace(addr + 1);
@@ -91,14 +195,7 @@ pub trait Mapper {
     }
 
     fn get_nametable_idx(&self, i: u8) -> usize {
-        let mapping = match self.get_mirroring() {
-            Mirroring::Horizontal => [0, 0, 1, 1],
-            Mirroring::Vertical => [0, 1, 0, 1],
-            Mirroring::FourScreen => [0, 1, 2, 3],
-            Mirroring::SingleScreenA => [0, 0, 0, 0],
-            Mirroring::SingleScreenB => [1, 1, 1, 1],
-        };
-        mapping[i as usize]
+        self.get_mirroring().nametable_idx(i)
     }
 
     fn write_nametable(&mut self, addr: u16, val: u8) {
@@ -122,9 +219,80 @@ pub trait Mapper {
         }
     }
 
+    // Physical 1K CHR bank backing each 1K window of $0000-$1FFF, for debug views
+    fn chr_bank_map(&self) -> [usize; 8] {
+        [0, 1, 2, 3, 4, 5, 6, 7]
+    }
+
+    // Mappers with IRQ counters or expansion audio return true to have clock_cpu called every
+    // CPU cycle. Checked once when the bus is built, everything else skips the per-cycle lock
+    fn is_cpu_clocked(&self) -> bool {
+        false
+    }
+
+    fn clock_cpu(&mut self) {}
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    // Boards with their own sound chip hook it in with APU::register_expansion
+    fn register_audio(&self, _apu: &mut APU) {}
+
+    // Famicom Disk System sides, counting each side of each disk. Zero for cartridges
+    fn disk_sides(&self) -> usize {
+        0
+    }
+
+    // The side in the drive, or about to be once a swap finishes
+    fn inserted_disk(&self) -> Option<usize> {
+        None
+    }
+
+    fn insert_disk(&mut self, _side: Option<usize>) {}
+
     fn dump_save(&self) -> &[u8] {
         &[]
     }
 
     fn load_save(&mut self, _data: &[u8]) {}
+
+    // Registers and RAM for a save state. PRG-ROM isn't included, so a state can only be loaded
+    // into a mapper built from the same ROM
+    fn save_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_state(&mut self, _state: &[u8]) -> bincode::Result<()> {
+        Ok(())
+    }
+}
+
+// Stands in for the cartridge in a deserialized PPU/Bus until the real one is swapped back in
+struct Unplugged;
+
+impl Mapper for Unplugged {
+    fn get_mirroring(&self) -> Mirroring {
+        Mirroring::Horizontal
+    }
+
+    fn read_chr_rom(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn read(&self, _addr: u16) -> u8 {
+        0
+    }
+
+    fn write(&mut self, _addr: u16, _data: u8) {}
+
+    fn write_nametable_idx(&mut self, _idx: usize, _addr: u16, _val: u8) {}
+
+    fn read_nametable_idx(&self, _idx: usize, _addr: u16) -> u8 {
+        0
+    }
+}
+
+pub(crate) fn unplugged() -> SharedMapper {
+    Arc::new(Mutex::new(Box::new(Unplugged)))
 }
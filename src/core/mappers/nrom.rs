@@ -1,31 +1,42 @@
-use super::{Mapper, Mirroring};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+use crate::core::savestate::big_array_2d;
+
+use super::{chr_rom_or_ram, prg_ram_with_trainer, CartridgeConfig, Mapper, Mirroring};
+
+#[derive(Clone, Serialize, Deserialize)]
 enum PRGRomMode {
     PRG16k,
     PRG32k,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct NROM {
     pub prg_ram: Vec<u8>,
+    #[serde(skip)]
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
     has_chr_ram: bool,
+    has_battery: bool,
     prg_rom_mode: PRGRomMode,
-    mirroring: u8,
-    nametables: [[u8; 0x400]; 2],
+    mirroring: Mirroring,
+    // Only the first two are used unless the cart provides four-screen VRAM
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
 }
 
 impl NROM {
-    pub fn new(
-        prg_rom: Vec<u8>,
-        chr_rom: Option<Vec<u8>>,
-        prg_ram_size: usize,
-        eeprom_size: usize,
-        has_battery: bool,
-        mirroring: u8,
-    ) -> Self {
+    pub fn new(cart: CartridgeConfig) -> Self {
+        let CartridgeConfig {
+            prg_rom,
+            chr_rom,
+            chr_ram_size,
+            prg_ram_size,
+            eeprom_size,
+            has_battery,
+            mirroring,
+            trainer,
+        } = cart;
         let mut prg_ram_size = prg_ram_size;
         if prg_ram_size == 0 && eeprom_size > 0 {
             prg_ram_size = eeprom_size;
@@ -34,7 +45,7 @@ impl NROM {
         }
 
         Self {
-            prg_ram: vec![0; prg_ram_size],
+            prg_ram: prg_ram_with_trainer(prg_ram_size, trainer),
             prg_rom_mode: if prg_rom.len() <= 16384 {
                 PRGRomMode::PRG16k
             } else {
@@ -42,23 +53,17 @@ impl NROM {
             },
             prg_rom,
             has_chr_ram: chr_rom.is_none(),
-            chr_rom: match chr_rom {
-                Some(chr_rom) => chr_rom,
-                None => vec![0; 8192],
-            },
+            has_battery,
+            chr_rom: chr_rom_or_ram(chr_rom, chr_ram_size),
             mirroring,
-            nametables: [[0; 0x400]; 2],
+            nametables: [[0; 0x400]; 4],
         }
     }
 }
 
 impl Mapper for NROM {
     fn get_mirroring(&self) -> Mirroring {
-        match self.mirroring {
-            0 => Mirroring::Horizontal,
-            1 => Mirroring::Vertical,
-            _ => unreachable!(),
-        }
+        self.mirroring
     }
 
     fn read_chr_rom(&self, addr: u16) -> u8 {
@@ -69,7 +74,7 @@ impl Mapper for NROM {
         match addr {
             0x6000..=0x7FFF => {
                 if !self.prg_ram.is_empty() {
-                    self.prg_ram[(addr - 0x6000) as usize]
+                    self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()]
                 } else {
                     println!(
                         "Attempted to read from PRG RAM at {:#X} but no PRG RAM is present",
@@ -93,7 +98,8 @@ impl Mapper for NROM {
     fn write(&mut self, addr: u16, data: u8) {
         if (0x6000..=0x7FFF).contains(&addr) {
             if !self.prg_ram.is_empty() {
-                self.prg_ram[(addr - 0x6000) as usize] = data;
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr - 0x6000) as usize % len] = data;
             } else {
                 println!(
                     "Attempted to write to PRG RAM at {:#X} but no PRG RAM is present",
@@ -116,4 +122,33 @@ impl Mapper for NROM {
     fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8 {
         self.nametables[idx][addr as usize]
     }
+
+    // Without a battery the PRG-RAM is just work RAM, so there's nothing to persist
+    fn dump_save(&self) -> &[u8] {
+        if self.has_battery {
+            self.prg_ram.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        if self.has_battery {
+            let len = data.len().min(self.prg_ram.len());
+            self.prg_ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            prg_rom: std::mem::take(&mut self.prg_rom),
+            ..state
+        };
+        Ok(())
+    }
 }
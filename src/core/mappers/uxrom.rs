@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+
+use crate::core::savestate::big_array_2d;
+
+use super::{chr_rom_or_ram, prg_ram_with_trainer, CartridgeConfig, Mapper, Mirroring};
+
+const PRG_BANK_SIZE: usize = 0x4000;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct UxROM {
+    pub prg_ram: Vec<u8>,
+    #[serde(skip)]
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    has_chr_ram: bool,
+    mirroring: Mirroring,
+    // Only the first two are used unless the cart provides four-screen VRAM
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
+    bank_select: u8,
+}
+
+impl UxROM {
+    pub fn new(cart: CartridgeConfig) -> Self {
+        let CartridgeConfig {
+            prg_rom,
+            chr_rom,
+            chr_ram_size,
+            prg_ram_size,
+            eeprom_size,
+            has_battery,
+            mirroring,
+            trainer,
+        } = cart;
+        let mut prg_ram_size = prg_ram_size;
+        if prg_ram_size == 0 && eeprom_size > 0 {
+            prg_ram_size = eeprom_size;
+        } else if has_battery {
+            prg_ram_size = 0x2000;
+        }
+
+        Self {
+            prg_ram: prg_ram_with_trainer(prg_ram_size, trainer),
+            prg_rom,
+            has_chr_ram: chr_rom.is_none(),
+            chr_rom: chr_rom_or_ram(chr_rom, chr_ram_size),
+            mirroring,
+            nametables: [[0; 0x400]; 4],
+            bank_select: 0,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+}
+
+impl Mapper for UxROM {
+    fn get_mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        self.chr_rom[addr as usize]
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7FFF => {
+                if !self.prg_ram.is_empty() {
+                    self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()]
+                } else {
+                    println!(
+                        "Attempted to read from PRG RAM at {:#X} but no PRG RAM is present",
+                        addr
+                    );
+                    0
+                }
+            }
+            // Switchable bank
+            0x8000..=0xBFFF => {
+                let bank = self.bank_select as usize % self.prg_bank_count();
+                self.prg_rom[bank * PRG_BANK_SIZE + (addr - 0x8000) as usize]
+            }
+            // Fixed to the last bank
+            0xC000..=0xFFFF => {
+                let bank = self.prg_bank_count() - 1;
+                self.prg_rom[bank * PRG_BANK_SIZE + (addr - 0xC000) as usize]
+            }
+            _ => {
+                println!("Invalid address {:#X}", addr);
+                0
+            }
+        }
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7FFF => {
+                if !self.prg_ram.is_empty() {
+                    let len = self.prg_ram.len();
+                    self.prg_ram[(addr - 0x6000) as usize % len] = data;
+                } else {
+                    println!(
+                        "Attempted to write to PRG RAM at {:#X} but no PRG RAM is present",
+                        addr
+                    );
+                }
+            }
+            0x8000..=0xFFFF => {
+                self.bank_select = data;
+            }
+            _ => println!("Invalid address {:#X}", addr),
+        }
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, data: u8) {
+        if self.has_chr_ram {
+            self.chr_rom[addr as usize] = data;
+        }
+    }
+
+    fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8) {
+        self.nametables[idx][addr as usize] = val;
+    }
+
+    fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8 {
+        self.nametables[idx][addr as usize]
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            prg_rom: std::mem::take(&mut self.prg_rom),
+            ..state
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::core::apu::vrc6_audio::Vrc6Audio;
+use crate::core::apu::APU;
+use crate::core::savestate::big_array_2d;
+
+use super::{chr_rom_or_ram, prg_ram_with_trainer, CartridgeConfig, Mapper, Mirroring};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+// CPU cycles per scanline, in thirds
+const SCANLINE_PRESCALER: i16 = 341;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Irq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    // Enable value restored when the IRQ is acknowledged
+    enable_after_ack: bool,
+    // Counts CPU cycles instead of scanlines
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl Irq {
+    fn write_control(&mut self, val: u8) {
+        self.enable_after_ack = val & 0x01 != 0;
+        self.enabled = val & 0x02 != 0;
+        self.cycle_mode = val & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = SCANLINE_PRESCALER;
+        }
+    }
+
+    fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    fn clock(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if !self.cycle_mode {
+            // Scanline mode approximates 113.667 CPU cycles per line
+            self.prescaler -= 3;
+            if self.prescaler > 0 {
+                return;
+            }
+            self.prescaler += SCANLINE_PRESCALER;
+        }
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+// Konami VRC6, mapper 24 (VRC6a) and 26 (VRC6b, which swaps the A0 and A1 lines)
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VRC6<const SWAP_LINES: bool> {
+    pub prg_ram: Vec<u8>,
+    #[serde(skip)]
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    has_chr_ram: bool,
+    has_battery: bool,
+    #[serde(with = "big_array_2d")]
+    nametables: [[u8; 0x400]; 4],
+    prg_bank_16k: u8,
+    prg_bank_8k: u8,
+    chr_banks: [u8; 8],
+    // $B003: CHR layout, mirroring and PRG-RAM enable
+    banking_mode: u8,
+    irq: Irq,
+    audio: Vrc6Audio,
+    // The audio's latest output, read by the APU through register_audio
+    #[serde(skip)]
+    level: Arc<AtomicI32>,
+}
+
+pub type VRC6a = VRC6<false>;
+pub type VRC6b = VRC6<true>;
+
+impl<const SWAP_LINES: bool> VRC6<SWAP_LINES> {
+    pub fn new(cart: CartridgeConfig) -> Self {
+        let CartridgeConfig {
+            prg_rom,
+            chr_rom,
+            chr_ram_size,
+            prg_ram_size,
+            eeprom_size,
+            has_battery,
+            mirroring: _,
+            trainer,
+        } = cart;
+        // Every VRC6 board has 8K of PRG-RAM, battery backed or not
+        let prg_ram_size = match (prg_ram_size, eeprom_size) {
+            (0, 0) => 0x2000,
+            (0, eeprom_size) => eeprom_size,
+            (prg_ram_size, _) => prg_ram_size,
+        };
+        let has_chr_ram = chr_rom.is_none();
+
+        Self {
+            prg_ram: prg_ram_with_trainer(prg_ram_size, trainer),
+            prg_rom,
+            chr_rom: chr_rom_or_ram(chr_rom, chr_ram_size),
+            has_chr_ram,
+            has_battery,
+            nametables: [[0; 0x400]; 4],
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [0; 8],
+            banking_mode: 0,
+            irq: Irq::default(),
+            audio: Vrc6Audio::default(),
+            level: Arc::default(),
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom.len() / PRG_BANK_SIZE
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.banking_mode & 0x80 != 0 && !self.prg_ram.is_empty()
+    }
+
+    // Register addresses as the VRC6a sees them
+    fn register(addr: u16) -> u16 {
+        if SWAP_LINES {
+            (addr & 0xf000) | (addr & 0x01) << 1 | (addr & 0x02) >> 1
+        } else {
+            addr & 0xf003
+        }
+    }
+
+    fn chr_bank(&self, window: usize) -> usize {
+        // With bit 5 set, 2K banks take their lowest bit from the address instead
+        let (mask, or) = if self.banking_mode & 0x20 != 0 {
+            (0xfe, 0x01)
+        } else {
+            (0xff, 0x00)
+        };
+        let two_k = |reg: usize, high: bool| {
+            let bank = self.chr_banks[reg];
+            if high {
+                bank | or
+            } else {
+                bank & mask
+            }
+        };
+        let bank = match (self.banking_mode & 0x03, window) {
+            (0, _) => self.chr_banks[window],
+            (1, _) => two_k(window / 2, window % 2 == 1),
+            (_, 0..=3) => self.chr_banks[window],
+            (_, _) => two_k(4 + (window - 4) / 2, window % 2 == 1),
+        };
+        bank as usize % (self.chr_rom.len() / CHR_BANK_SIZE)
+    }
+}
+
+impl<const SWAP_LINES: bool> Mapper for VRC6<SWAP_LINES> {
+    fn get_mirroring(&self) -> Mirroring {
+        // Games only use the CIRAM layouts, not CHR-ROM nametables (bit 4)
+        match self.banking_mode & 0x2f {
+            0x20 | 0x27 => Mirroring::Vertical,
+            0x23 | 0x24 => Mirroring::Horizontal,
+            0x28 | 0x2f => Mirroring::SingleScreenA,
+            0x2b | 0x2c => Mirroring::SingleScreenB,
+            _ => match self.banking_mode & 0x0c {
+                0x00 => Mirroring::Vertical,
+                0x04 => Mirroring::Horizontal,
+                0x08 => Mirroring::SingleScreenA,
+                _ => Mirroring::SingleScreenB,
+            },
+        }
+    }
+
+    fn read_chr_rom(&self, addr: u16) -> u8 {
+        let window = addr as usize / CHR_BANK_SIZE;
+        self.chr_rom[self.chr_bank(window) * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE]
+    }
+
+    fn write_chr_rom(&mut self, addr: u16, data: u8) {
+        if self.has_chr_ram {
+            let window = addr as usize / CHR_BANK_SIZE;
+            let idx = self.chr_bank(window) * CHR_BANK_SIZE + addr as usize % CHR_BANK_SIZE;
+            self.chr_rom[idx] = data;
+        }
+    }
+
+    fn read(&self, addr: u16) -> u8 {
+        let bank = match addr {
+            0x6000..=0x7FFF => {
+                if self.prg_ram_enabled() {
+                    return self.prg_ram[(addr - 0x6000) as usize % self.prg_ram.len()];
+                }
+                return 0;
+            }
+            0x8000..=0xBFFF => self.prg_bank_16k as usize * 2 + (addr as usize - 0x8000) / 0x2000,
+            0xC000..=0xDFFF => self.prg_bank_8k as usize,
+            0xE000..=0xFFFF => self.prg_bank_count() - 1,
+            _ => {
+                println!("Invalid address {:#X}", addr);
+                return 0;
+            }
+        };
+        let bank = bank % self.prg_bank_count();
+        self.prg_rom[bank * PRG_BANK_SIZE + addr as usize % PRG_BANK_SIZE]
+    }
+
+    fn write(&mut self, addr: u16, data: u8) {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            if self.prg_ram_enabled() {
+                let len = self.prg_ram.len();
+                self.prg_ram[(addr - 0x6000) as usize % len] = data;
+            }
+            return;
+        }
+
+        let reg = Self::register(addr);
+        match reg {
+            0x8000..=0x8003 => self.prg_bank_16k = data & 0x0f,
+            0x9000..=0xB002 => self.audio.write(reg, data),
+            0xB003 => self.banking_mode = data,
+            0xC000..=0xC003 => self.prg_bank_8k = data & 0x1f,
+            0xD000..=0xD003 => self.chr_banks[(reg & 0x03) as usize] = data,
+            0xE000..=0xE003 => self.chr_banks[4 + (reg & 0x03) as usize] = data,
+            0xF000 => self.irq.latch = data,
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            0xF003 => {}
+            _ => println!("Invalid address {:#X}", addr),
+        }
+    }
+
+    fn write_nametable_idx(&mut self, idx: usize, addr: u16, val: u8) {
+        self.nametables[idx][addr as usize] = val;
+    }
+
+    fn read_nametable_idx(&self, idx: usize, addr: u16) -> u8 {
+        self.nametables[idx][addr as usize]
+    }
+
+    fn chr_bank_map(&self) -> [usize; 8] {
+        std::array::from_fn(|i| self.chr_bank(i))
+    }
+
+    fn is_cpu_clocked(&self) -> bool {
+        true
+    }
+
+    fn clock_cpu(&mut self) {
+        self.irq.clock();
+        self.audio.clock();
+        self.level.store(self.audio.output(), Ordering::Relaxed);
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq.pending
+    }
+
+    fn register_audio(&self, apu: &mut APU) {
+        let level = self.level.clone();
+        apu.register_expansion(move |_| level.load(Ordering::Relaxed));
+    }
+
+    fn dump_save(&self) -> &[u8] {
+        if self.has_battery {
+            self.prg_ram.as_slice()
+        } else {
+            &[]
+        }
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        if self.has_battery {
+            let len = data.len().min(self.prg_ram.len());
+            self.prg_ram[..len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    fn save_state(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    fn load_state(&mut self, state: &[u8]) -> bincode::Result<()> {
+        let state: Self = bincode::deserialize(state)?;
+        *self = Self {
+            prg_rom: std::mem::take(&mut self.prg_rom),
+            level: self.level.clone(),
+            ..state
+        };
+        Ok(())
+    }
+}
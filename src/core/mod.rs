@@ -1,8 +1,81 @@
+use serde::{Deserialize, Serialize};
+
 pub mod apu;
 pub mod bus;
+pub mod cheats;
 pub mod console;
 pub mod cpu;
+pub mod fds;
 pub mod frame;
 pub mod joypad;
 pub mod mappers;
 pub mod ppu;
+pub mod rewind;
+mod savestate;
+pub mod zapper;
+
+// Console timing from the header. Multi-region carts run as NTSC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn from_timing(timing: u8) -> Self {
+        match timing {
+            1 => Region::Pal,
+            3 => Region::Dendy,
+            _ => Region::Ntsc,
+        }
+    }
+
+    // Master clock ticks in the first and second half of a CPU cycle
+    pub fn cpu_clock_counts(&self) -> (u8, u8) {
+        match self {
+            Region::Ntsc => (6, 6),
+            Region::Pal => (8, 8),
+            Region::Dendy => (7, 8),
+        }
+    }
+
+    // Master clock ticks per PPU dot
+    pub fn ppu_divider(&self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    pub fn cpu_clock_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 1789772.7272,
+            Region::Pal => 1662607.0,
+            Region::Dendy => 1773447.5,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        match self {
+            Region::Ntsc => 60.0988,
+            Region::Pal | Region::Dendy => 50.0070,
+        }
+    }
+
+    pub fn last_scanline(&self) -> i16 {
+        match self {
+            Region::Ntsc => 260,
+            Region::Pal | Region::Dendy => 310,
+        }
+    }
+
+    // Dendy keeps NTSC's 20 vblank lines and puts its 50 extra lines before them instead
+    pub fn vblank_scanline(&self) -> i16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+}
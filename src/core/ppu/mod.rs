@@ -1,18 +1,59 @@
+use image::Rgb;
+use serde::{Deserialize, Serialize};
+
 use crate::core::frame::Frame;
-use crate::core::mappers::SharedMapper;
+use crate::core::mappers::{self, Mirroring, SharedMapper};
 use crate::core::ppu::palettes::Palette;
+use crate::core::savestate::big_array;
+use crate::core::Region;
 
 use self::registers::{control::Control, mask::Mask, status::Status};
 
 pub mod palettes;
 mod registers;
 
+// Channels not selected by an emphasis bit are dimmed by roughly this factor
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+#[derive(Serialize, Deserialize)]
 pub enum DMAFlag {
     Enabled(u8),
     Disabled,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+// One OAM entry, decoded for debugging
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteInfo {
+    pub index: u8,
+    pub x: u8,
+    // As stored in OAM, one less than the first scanline the sprite is drawn on
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    // Drawn on the current scanline
+    pub in_range: bool,
+}
+
+impl SpriteInfo {
+    // 4-7, the sprite palettes
+    pub fn palette(&self) -> u8 {
+        (self.attributes & 0x03) + 4
+    }
+
+    pub fn behind_background(&self) -> bool {
+        self.attributes & 0x20 != 0
+    }
+
+    pub fn flip_horizontal(&self) -> bool {
+        self.attributes & 0x40 != 0
+    }
+
+    pub fn flip_vertical(&self) -> bool {
+        self.attributes & 0x80 != 0
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 struct Tile {
     palette_offset: u32,
     tile_addr: u16,
@@ -21,7 +62,7 @@ struct Tile {
     offset_y: u8,
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 struct Sprite {
     offset_y: u8,
     tile_addr: u16,
@@ -34,6 +75,18 @@ struct Sprite {
     high_byte: u8,
 }
 
+impl Sprite {
+    // 2-bit color `shift` pixels in from the sprite's left edge
+    fn pixel(&self, shift: i32) -> u8 {
+        if self.flip_horizontal {
+            ((self.low_byte >> shift) & 0x01) | ((self.high_byte >> shift) & 0x01) << 1
+        } else {
+            ((self.low_byte << shift) & 0x80) >> 7 | ((self.high_byte << shift) & 0x80) >> 6
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct PPU {
     // PPU Registers
     ctrl: Control,
@@ -43,6 +96,7 @@ pub struct PPU {
 
     // Contains all 64 sprites in OAM
     sprite_ram_addr: u32,
+    #[serde(with = "big_array")]
     sprite_ram: [u8; 0x100],
     // Contains the 8 sprites that will be drawn on the next scanline
     sprite_tiles: [Sprite; 8],
@@ -50,10 +104,13 @@ pub struct PPU {
     pub(crate) cycle: u64,
     pub(crate) scanline: i16,
     palette: [u8; 0x0020],
+    #[serde(skip, default = "Palette::blank")]
     colors: Palette,
-    pub curr_frame: Frame,
+    // Boxed so that moving the PPU around (e.g. loading a save state) doesn't copy the image
+    pub curr_frame: Box<Frame>,
 
     pub nmi_generated: bool,
+    #[serde(skip, default = "mappers::unplugged")]
     mapper: SharedMapper,
 
     // Represents the first cycle a BG pixel or sprite can be draw. Modified by mask and enable
@@ -68,6 +125,7 @@ pub struct PPU {
 
     // Buffer containing info on if the dot on this scanline contains a sprite. Cycles 0-256 involve
     // OAM read and sprite eval, before sprite fetches for next scanline
+    #[serde(with = "big_array")]
     has_sprite: [bool; 257],
 
     sprite_count: u8,
@@ -109,10 +167,42 @@ pub struct PPU {
     master_clock: u64,
     pub open_bus: u8,
     pub sprite_dma_transfer: DMAFlag,
+
+    // Debug-only layer overrides, independent of $2001 and sprite 0 hit. Hiding a layer changes
+    // the output frame, so frame hashes won't match while either is off
+    debug_show_bg: bool,
+    debug_show_sprites: bool,
+
+    // Test-only. Turning this off keeps every frame the same length, which is not what an NTSC
+    // PPU does, so anything timing-sensitive will drift from hardware. PAL and Dendy never skip
+    skip_odd_frame_cycle: bool,
+    region: Region,
+
+    sprite_0_hit_scanline: Option<i16>,
+    // NTSC color subcarrier phase at the start of line 0, in thirds of a cycle
+    color_phase: u8,
+
+    // Debug-only, replaces the mapper's mirroring for every nametable access. Changes frame
+    // hashes for any game that doesn't already use the forced mode
+    mirroring_override: Option<Mirroring>,
+
+    // Draws every sprite on a line instead of the first 8, to get rid of flicker. Evaluation,
+    // overflow and sprite 0 hit still behave as on hardware, the extras are only drawn. Host side
+    // like the palette
+    #[serde(skip)]
+    unlimited_sprites: bool,
+    // In-range sprites past the first 8, in OAM order, for the next line
+    #[serde(skip)]
+    extra_sprites: Vec<Sprite>,
 }
 
 impl PPU {
     pub fn new(mapper: SharedMapper) -> PPU {
+        Self::with_palette(mapper, Palette::default())
+    }
+
+    // For embedders that can't read the palette from disk
+    pub fn with_palette(mapper: SharedMapper, palette: Palette) -> PPU {
         PPU {
             ctrl: Control::new(),
             status_flags: Status::new(),
@@ -126,10 +216,10 @@ impl PPU {
                 0x04, 0x2C, 0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14, 0x08, 0x3A, 0x00, 0x02,
                 0x00, 0x20, 0x2C, 0x08,
             ],
-            colors: Palette::default(),
+            colors: palette,
             cycle: 0,
             scanline: 0,
-            curr_frame: Frame::new(),
+            curr_frame: Box::new(Frame::new()),
             nmi_generated: false,
             mapper,
             minimum_draw_bg_cycle: 0,
@@ -159,6 +249,7 @@ impl PPU {
             last_visible_sprite_addr: 0,
             sprite_0_visible: false,
             frame_count: 1,
+            color_phase: 0,
             prev_rendering_enabled: false,
             rendering_enabled: false,
             need_state_update: false,
@@ -170,9 +261,53 @@ impl PPU {
             master_clock: 0,
             open_bus: 0,
             sprite_dma_transfer: DMAFlag::Disabled,
+            debug_show_bg: true,
+            debug_show_sprites: true,
+            skip_odd_frame_cycle: true,
+            region: Region::Ntsc,
+            sprite_0_hit_scanline: None,
+            mirroring_override: None,
+            unlimited_sprites: false,
+            extra_sprites: Vec::new(),
         }
     }
 
+    pub fn set_layer_mask(&mut self, show_bg: bool, show_sprites: bool) {
+        self.debug_show_bg = show_bg;
+        self.debug_show_sprites = show_sprites;
+    }
+
+    pub fn mirroring_override(&self) -> Option<Mirroring> {
+        self.mirroring_override
+    }
+
+    pub fn set_mirroring_override(&mut self, mirroring: Option<Mirroring>) {
+        self.mirroring_override = mirroring;
+    }
+
+    pub fn set_unlimited_sprites(&mut self, unlimited: bool) {
+        self.unlimited_sprites = unlimited;
+    }
+
+    pub fn unlimited_sprites(&self) -> bool {
+        self.unlimited_sprites
+    }
+
+    pub fn set_skip_odd_frame_cycle(&mut self, skip: bool) {
+        self.skip_odd_frame_cycle = skip;
+    }
+
+    // What a deserialized PPU doesn't carry: the cartridge and the host's palette
+    pub(crate) fn take_host_resources(&mut self, from: &mut PPU) {
+        self.mapper = from.mapper.clone();
+        std::mem::swap(&mut self.colors, &mut from.colors);
+        self.unlimited_sprites = from.unlimited_sprites;
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
     fn update_video_ram_addr(&mut self) {
         if self.scanline >= 240 || !self.is_rendering_enabled() {
             self.vram_addr = (self.vram_addr
@@ -278,9 +413,10 @@ impl PPU {
 
     pub fn run_to(&mut self, cycle: u64) -> bool {
         let mut new_frame = false;
-        while self.master_clock + 4 <= cycle {
+        let divider = self.region.ppu_divider();
+        while self.master_clock + divider <= cycle {
             new_frame |= self.run();
-            self.master_clock += 4;
+            self.master_clock += divider;
         }
         new_frame
     }
@@ -289,7 +425,7 @@ impl PPU {
         if self.cycle > 339 {
             self.cycle = 0;
             self.scanline += 1;
-            if self.scanline > 260 {
+            if self.scanline > self.region.last_scanline() {
                 self.scanline = -1;
                 self.sprite_count = 0;
                 self.update_minimum_draw_cycles();
@@ -297,7 +433,11 @@ impl PPU {
             if self.scanline == -1 {
                 self.status_flags.set(Status::SPRITE_OVERFLOW, false);
                 self.status_flags.set(Status::SPRITE_ZERO_HIT, false);
-                self.curr_frame = Frame::new();
+                self.sprite_0_hit_scanline = None;
+                *self.curr_frame = Frame::new();
+            } else if self.scanline == 0 {
+                // A dot is 4 master clocks and a subcarrier cycle 6, so 2/3 of a cycle per dot
+                self.color_phase = ((self.master_clock / 2) % 3) as u8;
             } else if self.scanline == 240 {
                 self.set_bus_address(self.vram_addr);
                 self.frame_count += 1;
@@ -307,7 +447,7 @@ impl PPU {
             self.cycle += 1;
             if self.scanline < 240 {
                 self.process_scanline();
-            } else if self.cycle == 1 && self.scanline == 241 {
+            } else if self.cycle == 1 && self.scanline == self.region.vblank_scanline() {
                 if !self.prevent_vbl_flag {
                     self.status_flags.set(Status::VBLANK, true);
                     if self.ctrl.contains(Control::NMI) {
@@ -340,7 +480,40 @@ impl PPU {
         let background_priority = attr & 0x20 == 0x20;
         let horizontal_mirror = attr & 0x40 == 0x40;
         let vertical_mirror = attr & 0x80 == 0x80;
+        let (tile_addr, line_offset) =
+            self.sprite_pattern_addr(sprite_y, tile_idx, vertical_mirror);
+
+        if self.sprite_index < self.sprite_count && sprite_y < 240 {
+            let low_byte = self.read_vram(tile_addr);
+            let high_byte = self.read_vram(tile_addr + 8);
+            let info = &mut self.sprite_tiles[self.sprite_index as usize];
+            info.priority = background_priority;
+            info.flip_horizontal = horizontal_mirror;
+            info.flip_vertical = vertical_mirror;
+            info.palette_offset = (((attr & 0x03) << 2) | 0x10) as u32;
+            info.low_byte = low_byte;
+            info.high_byte = high_byte;
+            info.tile_addr = tile_addr;
+            info.offset_y = line_offset;
+            info.sprite_x = sprite_x;
+            if self.scanline >= 0 {
+                self.mark_sprite_dots(sprite_x);
+            }
+        }
+
+        self.sprite_index += 1;
+    }
 
+    fn mark_sprite_dots(&mut self, sprite_x: u8) {
+        let mut i = 0;
+        while i < 8 && (sprite_x as u16 + i + 1) < 257 {
+            self.has_sprite[(sprite_x as u16 + i + 1) as usize] = true;
+            i += 1;
+        }
+    }
+
+    // Pattern table address of the row of a sprite that's on the next line, and that row
+    fn sprite_pattern_addr(&self, sprite_y: u8, tile_idx: u8, vertical_mirror: bool) -> (u16, u8) {
         let line_offset = if vertical_mirror {
             ((if self.ctrl.contains(Control::SPRITE_SIZE) {
                 15
@@ -368,51 +541,102 @@ impl PPU {
                     0x0000
                 }) + line_offset as u16)
         };
+        (tile_addr, line_offset)
+    }
 
-        if self.sprite_index < self.sprite_count && sprite_y < 240 {
-            let low_byte = self.read_vram(tile_addr);
-            let high_byte = self.read_vram(tile_addr + 8);
-            let info = &mut self.sprite_tiles[self.sprite_index as usize];
-            info.priority = background_priority;
-            info.flip_horizontal = horizontal_mirror;
-            info.flip_vertical = vertical_mirror;
-            info.palette_offset = (((attr & 0x03) << 2) | 0x10) as u32;
-            info.low_byte = low_byte;
-            info.high_byte = high_byte;
-            info.tile_addr = tile_addr;
-            info.offset_y = line_offset;
-            info.sprite_x = sprite_x;
-            if self.scanline >= 0 {
-                let mut i = 0;
-                while i < 8 && (sprite_x as u16 + i + 1) < 257 {
-                    self.has_sprite[(sprite_x as u16 + i + 1) as usize] = true;
-                    i += 1;
-                }
-            }
+    // Hardware stops at 8, so there are only extras to find when secondary OAM filled up. Their
+    // pattern reads skip read_vram, the PPU never makes them so mappers mustn't see them
+    fn load_extra_sprites(&mut self) {
+        if self.sprite_count < 8 {
+            return;
+        }
+        let height = if self.ctrl.contains(Control::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        };
+        let in_range: Vec<[u8; 4]> = self
+            .sprite_ram
+            .chunks_exact(4)
+            .filter(|data| {
+                data[0] < 240 && (data[0] as i16..data[0] as i16 + height).contains(&self.scanline)
+            })
+            .skip(8)
+            .map(|data| data.try_into().unwrap())
+            .collect();
+        for [sprite_y, tile_idx, attr, sprite_x] in in_range {
+            let (tile_addr, line_offset) =
+                self.sprite_pattern_addr(sprite_y, tile_idx, attr & 0x80 != 0);
+            let (low_byte, high_byte) = {
+                let mapper = self.mapper.lock().unwrap();
+                (mapper.read_chr_rom(tile_addr), mapper.read_chr_rom(tile_addr + 8))
+            };
+            self.extra_sprites.push(Sprite {
+                offset_y: line_offset,
+                tile_addr,
+                palette_offset: (((attr & 0x03) << 2) | 0x10) as u32,
+                priority: attr & 0x20 != 0,
+                flip_horizontal: attr & 0x40 != 0,
+                flip_vertical: attr & 0x80 != 0,
+                sprite_x,
+                low_byte,
+                high_byte,
+            });
+            self.mark_sprite_dots(sprite_x);
         }
-
-        self.sprite_index += 1;
     }
 
     fn draw_pixel(&mut self) {
         if self.is_rendering_enabled() || ((self.vram_addr & 0x3f00) != 0x3f00) {
             let pixel_color = self.get_pixel_color();
-            let color = self.colors.system_palette[(self.palette[(if pixel_color & 0x03 > 0 {
-                pixel_color
-            } else {
-                0
-            }) as usize]) as usize];
+            let color = self.get_output_color(
+                self.palette[(if pixel_color & 0x03 > 0 {
+                    pixel_color
+                } else {
+                    0
+                }) as usize],
+            );
             self.curr_frame
                 .set_pixel((self.cycle - 1) as usize, self.scanline as usize, color);
         } else {
-            self.curr_frame.set_pixel(
-                (self.cycle - 1) as usize,
-                self.scanline as usize,
-                self.colors.system_palette[self.palette[(self.vram_addr & 0x1f) as usize] as usize],
-            );
+            let color = self.get_output_color(self.palette[(self.vram_addr & 0x1f) as usize]);
+            self.curr_frame
+                .set_pixel((self.cycle - 1) as usize, self.scanline as usize, color);
         }
     }
 
+    // Greyscale forces the palette index into the grey column before the lookup, while emphasis
+    // attenuates the resulting RGB, so both can apply at once. Both come from the mask as it is
+    // at this dot, so a $2001 write mid-scanline splits the line where it lands
+    fn get_output_color(&self, palette_idx: u8) -> Rgb<u8> {
+        let palette_idx = if self.mask.contains(Mask::GREYSCALE) {
+            palette_idx & 0x30
+        } else {
+            palette_idx
+        };
+        let color = self.colors.system_palette[palette_idx as usize];
+
+        let (red, green) = (
+            self.mask.contains(Mask::EMPHASIZE_RED),
+            self.mask.contains(Mask::EMPHASIZE_GREEN),
+        );
+        // The PAL and Dendy PPUs have the red and green emphasis bits swapped
+        let emphasis = match self.region {
+            Region::Ntsc => [red, green, self.mask.contains(Mask::EMPHASIZE_BLUE)],
+            Region::Pal | Region::Dendy => [green, red, self.mask.contains(Mask::EMPHASIZE_BLUE)],
+        };
+        if !emphasis.contains(&true) {
+            return color;
+        }
+        let mut out = color;
+        for (channel, val) in out.0.iter_mut().enumerate() {
+            if !emphasis[channel] {
+                *val = (*val as f32 * EMPHASIS_ATTENUATION) as u8;
+            }
+        }
+        out
+    }
+
     fn shift_tile_registers(&mut self) {
         self.low_bit_shift <<= 1;
         self.high_bit_shift <<= 1;
@@ -567,6 +791,7 @@ impl PPU {
             if self.cycle == 257 {
                 self.sprite_index = 0;
                 self.has_sprite = [false; 257];
+                self.extra_sprites.clear();
                 if self.prev_rendering_enabled {
                     self.vram_addr = (self.vram_addr & !0x041f) | (self.temp_vram_addr & 0x041f);
                 }
@@ -583,6 +808,10 @@ impl PPU {
                     self.read_vram(self.get_attribute_addr());
                 }
 
+                if self.cycle == 320 && self.unlimited_sprites && self.scanline >= 0 {
+                    self.load_extra_sprites();
+                }
+
                 if self.scanline == -1 && self.cycle >= 280 && self.cycle <= 304 {
                     self.vram_addr = (self.vram_addr & !0x7be0) | (self.temp_vram_addr & 0x7be0);
                 }
@@ -603,7 +832,12 @@ impl PPU {
             }
         } else if (self.cycle == 337 || self.cycle == 339) && self.is_rendering_enabled() {
             self.read_vram(self.get_nametable_addr());
-            if self.scanline == -1 && self.cycle == 339 && (self.frame_count % 2 == 1) {
+            if self.scanline == -1
+                && self.cycle == 339
+                && self.skip_odd_frame_cycle
+                && self.region == Region::Ntsc
+                && (self.frame_count % 2 == 1)
+            {
                 self.cycle = 340;
             }
         }
@@ -748,7 +982,7 @@ impl PPU {
             0x2005 => self.x_scroll,
             0x2006 => self.temp_vram_addr as u8,
             0x2007 => self.memory_read_buffer,
-            0x2008..=0x2fff => self.mapper.lock().unwrap().read_nametable(addr as u16),
+            0x2008..=0x2fff => self.read_nametable(addr as u16),
             0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => self.palette[addr - 0x3f10],
             0x3f00..=0x3fff => self.palette[(addr - 0x3f00) % 0x20],
             _ => panic!("Invalid address {:#X}", addr),
@@ -765,38 +999,43 @@ impl PPU {
             let or_1 = ((self.low_bit_shift << (offset as u16)) & 0x8000) >> 15;
             let or_2 = ((self.high_bit_shift << (offset as u16)) & 0x8000) >> 14;
             sprite_bg_color = (or_1 | or_2) as u8;
-            background_color = sprite_bg_color;
+            if self.debug_show_bg {
+                background_color = sprite_bg_color;
+            }
         }
         if self.has_sprite[self.cycle as usize]
             && self.cycle > self.minimum_draw_sprite_cycle as u64
         {
-            for i in 0..self.sprite_count {
-                let shift = self.cycle as i32 - self.sprite_tiles[i as usize].sprite_x as i32 - 1;
-                if (0..8).contains(&shift) {
-                    let sprite = self.sprite_tiles[i as usize];
-                    let sprite_color = if self.sprite_tiles[i as usize].flip_horizontal {
-                        ((sprite.low_byte >> shift) & 0x01)
-                            | ((sprite.high_byte >> shift) & 0x01) << 1
-                    } else {
-                        ((sprite.low_byte << shift) & 0x80) >> 7
-                            | ((sprite.high_byte << shift) & 0x80) >> 6
-                    };
-                    if sprite_color != 0 {
-                        if i == 0
-                            && sprite_bg_color != 0
-                            && self.sprite_0_visible
-                            && self.cycle != 256
-                            && self.mask.contains(Mask::SHOW_BACKGROUND)
-                            && !self.status_flags.contains(Status::SPRITE_ZERO_HIT)
-                            && self.cycle > self.minimum_draw_sprite_cycle as u64
-                        {
-                            self.status_flags.set(Status::SPRITE_ZERO_HIT, true);
-                        }
-                        if background_color == 0 || !self.sprite_tiles[i as usize].priority {
-                            return (sprite.palette_offset + sprite_color as u32) as u8;
-                        }
-                        break;
+            // The first opaque sprite wins, extras come after the 8 hardware would draw
+            let opaque = self.sprite_tiles[..self.sprite_count as usize]
+                .iter()
+                .chain(&self.extra_sprites)
+                .enumerate()
+                .find_map(|(i, sprite)| {
+                    let shift = self.cycle as i32 - sprite.sprite_x as i32 - 1;
+                    if !(0..8).contains(&shift) {
+                        return None;
                     }
+                    let sprite_color = sprite.pixel(shift);
+                    (sprite_color != 0).then_some((i, *sprite, sprite_color))
+                });
+            if let Some((i, sprite, sprite_color)) = opaque {
+                // Sprite 0 hit can land on cycles 1-255 (x 0-254) of a visible scanline, or 9-255
+                // while either layer is clipped out of the left 8 pixels. The minimum draw cycles
+                // above handle the clipping, a clipped background pixel reads as transparent and
+                // a clipped sprite pixel is never looked at. x=255 never hits
+                if i == 0
+                    && sprite_bg_color != 0
+                    && self.sprite_0_visible
+                    && self.cycle != 256
+                    && self.mask.contains(Mask::SHOW_BACKGROUND)
+                    && !self.status_flags.contains(Status::SPRITE_ZERO_HIT)
+                {
+                    self.status_flags.set(Status::SPRITE_ZERO_HIT, true);
+                    self.sprite_0_hit_scanline = Some(self.scanline);
+                }
+                if self.debug_show_sprites && (background_color == 0 || !sprite.priority) {
+                    return (sprite.palette_offset + sprite_color as u32) as u8;
                 }
             }
         }
@@ -834,7 +1073,7 @@ impl PPU {
         self.status = self.status_flags.bits() & 0xe0;
         self.status_flags.set(Status::VBLANK, false);
         self.nmi_generated = false;
-        if self.scanline == 241 && self.cycle == 0 {
+        if self.scanline == self.region.vblank_scanline() && self.cycle == 0 {
             self.prevent_vbl_flag = true;
         }
     }
@@ -857,12 +1096,34 @@ impl PPU {
         self.set_bus_address(addr);
         match addr {
             0x0000..=0x1fff => self.mapper.lock().unwrap().write_chr_rom(addr, val),
-            0x2000..=0x3eff => self.mapper.lock().unwrap().write_nametable(addr, val),
+            0x2000..=0x3eff => self.write_nametable(addr, val),
             0x3f00..=0x3fff => self.write_palette_ram(addr, val),
             _ => panic!("Invalid address {:#X}", addr),
         }
     }
 
+    fn read_nametable(&self, addr: u16) -> u8 {
+        let mapper = self.mapper.lock().unwrap();
+        match self.mirroring_override {
+            Some(mirroring) => {
+                let idx = mirroring.nametable_idx(((addr - 0x2000) / 0x400) as u8);
+                mapper.read_nametable_idx(idx, addr % 0x400)
+            }
+            None => mapper.read_nametable(addr),
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, val: u8) {
+        let mut mapper = self.mapper.lock().unwrap();
+        match self.mirroring_override {
+            Some(mirroring) => {
+                let idx = mirroring.nametable_idx(((addr & 0x0fff) / 0x400) as u8);
+                mapper.write_nametable_idx(idx, addr % 0x400, val);
+            }
+            None => mapper.write_nametable(addr, val),
+        }
+    }
+
     fn write_palette_ram(&mut self, addr: u16, val: u8) {
         let addr = addr & 0x1f;
         let val = val & 0x3f;
@@ -905,6 +1166,8 @@ impl PPU {
                 }) as u8;
                 self.secondary_oam_addr =
                     ((self.cycle.wrapping_sub(257)) / 8 * 4 + (step as u64)) as u32;
+                // Sprite fetches read secondary OAM, so that's what's on the OAM data bus here
+                self.oam_copy_buffer = self.secondary_sprite_ram[self.secondary_oam_addr as usize];
             }
             self.oam_copy_buffer
         } else {
@@ -912,6 +1175,162 @@ impl PPU {
         }
     }
 
+    pub fn scanline(&self) -> i16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u64 {
+        self.cycle
+    }
+
+    // Scanline of this frame's sprite 0 hit, reset along with the frame on the pre-render line
+    pub fn sprite_0_hit_scanline(&self) -> Option<i16> {
+        self.sprite_0_hit_scanline
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.colors
+    }
+
+    // Takes effect from the next pixel drawn
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.colors = palette;
+    }
+
+    pub(crate) fn fill_oam(&mut self, bytes: &mut impl Iterator<Item = u8>) {
+        self.sprite_ram.fill_with(|| bytes.next().unwrap());
+    }
+
+    pub fn dump_oam(&self) -> [u8; 0x100] {
+        self.sprite_ram
+    }
+
+    // All 64 sprites in OAM order. Only reads, so it's fine to call mid-frame
+    pub fn sprites(&self) -> Vec<SpriteInfo> {
+        let height = if self.ctrl.contains(Control::SPRITE_SIZE) {
+            16
+        } else {
+            8
+        };
+        self.sprite_ram
+            .chunks_exact(4)
+            .enumerate()
+            .map(|(index, data)| {
+                let top = data[0] as i16 + 1;
+                SpriteInfo {
+                    index: index as u8,
+                    x: data[3],
+                    y: data[0],
+                    tile: data[1],
+                    attributes: data[2],
+                    in_range: (0..240).contains(&self.scanline)
+                        && (top..top + height).contains(&self.scanline),
+                }
+            })
+            .collect()
+    }
+
+    // Pattern table 0 or 1 as a 128x128 RGB image of 16x16 tiles, colored with one of the eight
+    // palettes in palette RAM (0-3 background, 4-7 sprites). Reads CHR through the mapper as it's
+    // banked right now
+    pub fn pattern_table_image(&self, table: usize, palette: u8) -> Vec<u8> {
+        let colors = self.palette_colors(palette);
+        let mut image = vec![0; 128 * 128 * 3];
+        for tile in 0..256 {
+            for row in 0..8 {
+                let pixels = self.tile_row((table & 0x01) * 0x1000, tile, row);
+                for (col, value) in pixels.into_iter().enumerate() {
+                    let (x, y) = (tile % 16 * 8 + col, tile / 16 * 8 + row);
+                    let index = (y * 128 + x) * 3;
+                    image[index..index + 3].copy_from_slice(&colors[value as usize].0);
+                }
+            }
+        }
+        image
+    }
+
+    // All four nametables as a 512x480 RGB image, laid out like the PPU addresses them so the
+    // mirroring shows. Tiles come from the pattern table PPUCTRL has selected for the background
+    pub fn nametable_image(&self) -> Vec<u8> {
+        let table = if self.ctrl.contains(Control::BACKGROUND_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0
+        };
+        let palettes: [[Rgb<u8>; 4]; 4] = std::array::from_fn(|i| self.palette_colors(i as u8));
+        let mut image = vec![0; 512 * 480 * 3];
+        for nametable in 0..4u16 {
+            let base = 0x2000 + nametable * 0x400;
+            let (left, top) = ((nametable as usize & 1) * 256, (nametable as usize >> 1) * 240);
+            for tile_y in 0..30 {
+                for tile_x in 0..32 {
+                    let tile = self.read_nametable(base + tile_y * 32 + tile_x) as usize;
+                    let attribute =
+                        self.read_nametable(base + 0x3c0 + tile_y / 4 * 8 + tile_x / 4);
+                    let shift = (tile_y & 0x02) << 1 | (tile_x & 0x02);
+                    let colors = &palettes[((attribute >> shift) & 0x03) as usize];
+                    for row in 0..8 {
+                        let pixels = self.tile_row(table, tile, row);
+                        for (col, value) in pixels.into_iter().enumerate() {
+                            let x = left + tile_x as usize * 8 + col;
+                            let y = top + tile_y as usize * 8 + row;
+                            let index = (y * 512 + x) * 3;
+                            image[index..index + 3].copy_from_slice(&colors[value as usize].0);
+                        }
+                    }
+                }
+            }
+        }
+        image
+    }
+
+    // Top left of the screen in nametable_image's coordinates, from the scroll in the temporary
+    // VRAM address, which is what the next frame starts rendering from
+    pub fn scroll_position(&self) -> (usize, usize) {
+        let t = self.temp_vram_addr as usize;
+        let x = (t >> 10 & 0x01) * 256 + (t & 0x1f) * 8 + self.x_scroll as usize;
+        let y = (t >> 11 & 0x01) * 240 + (t >> 5 & 0x1f) * 8 + (t >> 12 & 0x07);
+        (x, y)
+    }
+
+    // $2000-$2FFF as the PPU addresses it, so mirrored nametables show up twice
+    pub fn dump_nametables(&self) -> [u8; 0x1000] {
+        std::array::from_fn(|i| self.read_nametable(0x2000 + i as u16))
+    }
+
+    pub fn dump_palette(&self) -> [u8; 32] {
+        self.palette
+    }
+
+    fn palette_colors(&self, palette: u8) -> [Rgb<u8>; 4] {
+        std::array::from_fn(|i| {
+            let addr = if i == 0 {
+                0x3f00
+            } else {
+                0x3f00 + (palette as usize & 0x07) * 4 + i
+            };
+            self.colors.system_palette[(self.read_ppudata_trace(addr) & 0x3f) as usize]
+        })
+    }
+
+    // The 2-bit color of each pixel in one row of a tile
+    fn tile_row(&self, table: usize, tile: usize, row: usize) -> [u8; 8] {
+        let low = self.read_ppudata_trace(table + tile * 16 + row);
+        let high = self.read_ppudata_trace(table + tile * 16 + row + 8);
+        std::array::from_fn(|col| (low >> (7 - col)) & 0x01 | ((high >> (7 - col)) & 0x01) << 1)
+    }
+
+    // For Frame::apply_ntsc, changes from frame to frame which is what makes the dots crawl
+    pub fn color_phase(&self) -> u8 {
+        self.color_phase
+    }
+
+    // Vblank starts at dot 1 of scanline 241 (291 on Dendy) and lasts until the pre-render line
+    pub fn in_vblank(&self) -> bool {
+        let vblank_scanline = self.region.vblank_scanline();
+        self.scanline > vblank_scanline || (self.scanline == vblank_scanline && self.cycle >= 1)
+    }
+
     pub fn write_oamdma(&mut self, data: u8) {
         self.sprite_dma_transfer = DMAFlag::Enabled(data);
     }
@@ -919,9 +1338,14 @@ impl PPU {
     fn read_vram(&mut self, addr: u16) -> u8 {
         self.set_bus_address(addr);
         match addr {
-            0x0000..=0x1fff => self.mapper.lock().unwrap().read_chr_rom(addr),
-            0x2000..=0x2fff => self.mapper.lock().unwrap().read_nametable(addr),
-            0x3000..=0x3fff => self.mapper.lock().unwrap().read_nametable(addr - 0x1000),
+            0x0000..=0x1fff => {
+                let mut mapper = self.mapper.lock().unwrap();
+                let val = mapper.read_chr_rom(addr);
+                mapper.notify_chr_fetch(addr);
+                val
+            }
+            0x2000..=0x2fff => self.read_nametable(addr),
+            0x3000..=0x3fff => self.read_nametable(addr - 0x1000),
             _ => panic!("Invalid address {:#X}", addr),
         }
     }
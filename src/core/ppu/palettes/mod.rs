@@ -1,35 +1,51 @@
-use std::{fs::File, io::Read};
+use std::{fs, io, path::Path};
 
 use image::Rgb;
-use itertools::Itertools;
 
+// 64 colors, RGB
+const PALETTE_FILE_LEN: usize = 0x40 * 3;
+
+#[derive(Clone)]
 pub struct Palette {
     pub system_palette: [Rgb<u8>; 0x40],
 }
 
 impl Default for Palette {
     fn default() -> Self {
-        Self::from_file("src/core/ppu/palettes/ntscpalette.pal")
+        Self::from_file("src/core/ppu/palettes/ntscpalette.pal").unwrap()
     }
 }
 
 impl Palette {
-    pub fn from_file(path: &str) -> Palette {
-        let file = File::open(path).unwrap();
-        let reader = file.bytes();
+    // Placeholder until a real palette is swapped in
+    pub(crate) fn blank() -> Palette {
         Palette {
-            system_palette: reader
-                .chunks(3)
-                .into_iter()
-                .map(|mut chunk| {
-                    let r = chunk.next().unwrap().unwrap();
-                    let g = chunk.next().unwrap().unwrap();
-                    let b = chunk.next().unwrap().unwrap();
-                    Rgb([r, g, b])
-                })
-                .collect::<Vec<Rgb<u8>>>()
-                .try_into()
-                .unwrap(),
+            system_palette: [Rgb([0, 0, 0]); 0x40],
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Palette> {
+        let bytes = fs::read(path)?;
+        Self::from_bytes(&bytes).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "palette files are {PALETTE_FILE_LEN} bytes (64 RGB colors), this one is {}",
+                    bytes.len()
+                ),
+            )
+        })
+    }
+
+    // None unless it's exactly 64 RGB colors
+    pub fn from_bytes(bytes: &[u8]) -> Option<Palette> {
+        if bytes.len() != PALETTE_FILE_LEN {
+            return None;
+        }
+        let mut system_palette = [Rgb([0, 0, 0]); 0x40];
+        for (color, rgb) in system_palette.iter_mut().zip(bytes.chunks_exact(3)) {
+            *color = Rgb([rgb[0], rgb[1], rgb[2]]);
         }
+        Some(Palette { system_palette })
     }
 }
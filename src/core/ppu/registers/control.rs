@@ -1,6 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     pub struct Control : u8 {
         const NAMETABLE_1 =             0b0000_0001;
         const NAMETABLE_2 =             0b0000_0010;
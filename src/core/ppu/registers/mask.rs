@@ -1,6 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     pub struct Mask : u8 {
         const GREYSCALE = 1 << 0;
         const SHOW_LEFT_BACKGROUND = 1 << 1;
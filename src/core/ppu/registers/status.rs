@@ -1,6 +1,8 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 
 bitflags! {
+    #[derive(Serialize, Deserialize)]
     pub struct Status  : u8 {
         const VBLANK = 1 << 7;
         const SPRITE_ZERO_HIT = 1 << 6;
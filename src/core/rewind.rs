@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+
+// Runs of unchanged bytes shorter than this are folded into the surrounding span, since every
+// span costs an offset and an allocation of its own
+const MIN_GAP: usize = 16;
+
+// The bytes of an older state that differ from the newer state it was diffed against
+struct Delta {
+    len: usize,
+    spans: Vec<(usize, Vec<u8>)>,
+}
+
+impl Delta {
+    fn between(newer: &[u8], older: &[u8]) -> Self {
+        let differs = |i: usize| newer.get(i) != Some(&older[i]);
+        let mut spans = Vec::new();
+        let mut i = 0;
+        while i < older.len() {
+            if !differs(i) {
+                i += 1;
+                continue;
+            }
+            let start = i;
+            let mut end = i + 1;
+            i += 1;
+            while i < older.len() && i - end < MIN_GAP {
+                if differs(i) {
+                    end = i + 1;
+                }
+                i += 1;
+            }
+            spans.push((start, older[start..end].to_vec()));
+        }
+        Delta {
+            len: older.len(),
+            spans,
+        }
+    }
+
+    fn apply(&self, newer: &mut Vec<u8>) {
+        newer.resize(self.len, 0);
+        for (offset, bytes) in &self.spans {
+            newer[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.spans.iter().map(|(_, bytes)| bytes.len()).sum()
+    }
+}
+
+// Keeps the newest state in full and every older one as a delta back from its successor, so
+// evicting the oldest state is just dropping the front delta
+pub struct RewindBuffer {
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Delta>,
+}
+
+impl RewindBuffer {
+    pub fn new(capacity: usize) -> Self {
+        RewindBuffer {
+            capacity: capacity.max(1),
+            newest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(prev) = self.newest.take() {
+            self.deltas.push_back(Delta::between(&state, &prev));
+            while self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+        self.newest = Some(state);
+    }
+
+    // Drops the newest state and returns the one before it, which becomes the newest
+    pub fn step_back(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let newest = self.newest.as_mut()?;
+        delta.apply(newest);
+        Some(newest)
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.deltas.len() + usize::from(self.newest.is_some())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    // Bytes of state data held, not counting allocation overhead
+    pub fn memory_usage(&self) -> usize {
+        self.newest.as_ref().map_or(0, Vec::len)
+            + self.deltas.iter().map(Delta::size).sum::<usize>()
+    }
+}
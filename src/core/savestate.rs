@@ -0,0 +1,60 @@
+// serde only implements arrays of up to 32 elements, so bigger ones go through these as sequences
+pub(crate) mod big_array {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S, T, const N: usize>(arr: &[T; N], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        serializer.collect_seq(arr)
+    }
+
+    pub fn deserialize<'de, D, T, const N: usize>(deserializer: D) -> Result<[T; N], D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        let len = items.len();
+        items
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"an array of the same size"))
+    }
+}
+
+pub(crate) mod big_array_2d {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S, T, const N: usize, const M: usize>(
+        arr: &[[T; N]; M],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        serializer.collect_seq(arr.iter().flatten())
+    }
+
+    pub fn deserialize<'de, D, T, const N: usize, const M: usize>(
+        deserializer: D,
+    ) -> Result<[[T; N]; M], D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de> + Copy + Default,
+    {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        if items.len() != N * M {
+            return Err(D::Error::invalid_length(
+                items.len(),
+                &"an array of the same size",
+            ));
+        }
+        let mut arr = [[T::default(); N]; M];
+        for (slot, item) in arr.iter_mut().flatten().zip(items) {
+            *slot = item;
+        }
+        Ok(arr)
+    }
+}
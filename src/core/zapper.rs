@@ -0,0 +1,60 @@
+use crate::core::ppu::PPU;
+
+// How long the photodiode keeps reporting light after the beam passes the spot it's aimed at
+const SENSE_SCANLINES: i16 = 20;
+// A pull reads as the trigger being held for about 100ms, after which it reads released again
+// until the next pull
+const TRIGGER_FRAMES: usize = 6;
+// Luma a pixel needs to register as light
+const BRIGHTNESS_THRESHOLD: u32 = 0x80;
+
+// NES Zapper on controller port 2. Reads come back on $4017 with bit 3 clear while light is
+// sensed and bit 4 set while the trigger is pulled
+#[derive(Default, Clone)]
+pub struct Zapper {
+    // Screen position it's pointed at, None when it's pointed away from the screen
+    aim: Option<(usize, usize)>,
+    // PPU frame the trigger was last pulled on
+    pulled_on: Option<usize>,
+}
+
+impl Zapper {
+    pub fn aim(&mut self, pos: Option<(usize, usize)>) {
+        self.aim = pos.filter(|&(x, y)| x < 256 && y < 240);
+    }
+
+    pub fn pull_trigger(&mut self, frame: usize) {
+        self.pulled_on = Some(frame);
+    }
+
+    fn trigger_pulled(&self, frame: usize) -> bool {
+        self.pulled_on
+            .is_some_and(|pulled_on| frame.wrapping_sub(pulled_on) < TRIGGER_FRAMES)
+    }
+
+    // The frame is cleared on the pre-render line, so a pixel only shows up bright once the PPU
+    // has drawn it this frame
+    fn light_sensed(&self, ppu: &PPU) -> bool {
+        let Some((x, y)) = self.aim else {
+            return false;
+        };
+        let since_drawn = ppu.scanline() - y as i16;
+        if !(0..SENSE_SCANLINES).contains(&since_drawn) {
+            return false;
+        }
+        let idx = (y * 256 + x) * 3;
+        let [r, g, b] = [0, 1, 2].map(|i| ppu.curr_frame.image[idx + i] as u32);
+        (r * 299 + g * 587 + b * 114) / 1000 >= BRIGHTNESS_THRESHOLD
+    }
+
+    pub fn read(&self, ppu: &PPU) -> u8 {
+        let mut val = 0;
+        if !self.light_sensed(ppu) {
+            val |= 0x08;
+        }
+        if self.trigger_pulled(ppu.frame_count) {
+            val |= 0x10;
+        }
+        val
+    }
+}
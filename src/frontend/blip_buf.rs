@@ -3,13 +3,15 @@
     blip_buf 1.1.0. http://www.slack.net/~ant/ by Shay Green.
 */
 
+#[derive(Clone)]
 pub struct BlipBuf<const S: usize> {
     factor: u64,
     offset: u64,
     available: u64,
     integrator: i32,
 
-    buf: [i32; S],
+    // On the heap, it's big enough to overflow a test thread's stack as the APU gets moved around
+    buf: Box<[i32; S]>,
     last_sample: i32,
     time: u64,
 }
@@ -39,7 +41,7 @@ impl<const S: usize> BlipBuf<S> {
             offset: (Self::TIME_UNIT / Self::BLIP_MAX_RATIO) / 2,
             available: 0,
             integrator: 0,
-            buf: [0; S],
+            buf: vec![0; S].into_boxed_slice().try_into().unwrap(),
             last_sample: 0,
             time: 0,
         }
@@ -50,6 +52,21 @@ impl<const S: usize> BlipBuf<S> {
         self.factor = f64::ceil(factor) as u64;
     }
 
+    // Drops everything buffered, as if freshly created
+    pub fn clear(&mut self) {
+        self.offset = (Self::TIME_UNIT / Self::BLIP_MAX_RATIO) / 2;
+        self.available = 0;
+        self.integrator = 0;
+        self.buf.fill(0);
+        self.last_sample = 0;
+        self.time = 0;
+    }
+
+    // Samples the next end_frame will hand out for the clocks added so far
+    pub fn samples_available(&self) -> usize {
+        (self.available + ((self.time * self.factor + self.offset) >> Self::TIME_BITS)) as usize
+    }
+
     const BL_STEP: [[i16; 8]; 33] = [
         [43, -115, 350, -488, 1136, -914, 5861, 21022],
         [44, -118, 348, -473, 1076, -799, 5274, 21001],
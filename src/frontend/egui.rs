@@ -1,58 +1,176 @@
 use crate::config::Config;
-use crate::core::console::Console;
-use crate::core::frame::Frame;
-use crate::core::joypad::Buttons;
+use crate::core::console::{Console, ConsoleMsg};
+use crate::core::cpu::InvalidOpBehavior;
+use crate::core::frame::{self, NTSC_WIDTH};
+use crate::core::joypad::{Buttons, Player};
+use crate::core::mappers::Mirroring;
+use crate::core::ppu::palettes::Palette;
+use crate::frontend::gamepad::Gamepads;
 use crate::ines_parser::NESFile;
 use crossbeam::channel::{self, Sender};
-use eframe::egui::{self, menu, CentralPanel, ColorImage, Key, TopBottomPanel, Ui};
+use eframe::egui::{
+    self, menu, CentralPanel, Color32, ColorImage, Key, Stroke, TopBottomPanel, Ui,
+};
 use eframe::epaint::ImageData;
 use eframe::App;
-use lazy_static::lazy_static;
-use rfd::FileDialog;
+use rfd::{FileDialog, MessageDialog, MessageLevel};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-
-lazy_static! {
-    static ref KEY_MAP: HashMap<Key, Buttons> = [
-        (Key::W, Buttons::UP),
-        (Key::S, Buttons::DOWN),
-        (Key::D, Buttons::RIGHT),
-        (Key::A, Buttons::LEFT),
-        (Key::U, Buttons::SELECT),
-        (Key::I, Buttons::START),
-        (Key::K, Buttons::A),
-        (Key::J, Buttons::B),
-    ]
-    .iter()
-    .fold(HashMap::new(), |mut acc, (key, button)| {
-        acc.insert(*key, *button);
-        acc
-    });
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Config key and default binding for each button
+const DEFAULT_BINDINGS: [(&str, Key, Buttons); 8] = [
+    ("button_up", Key::W, Buttons::UP),
+    ("button_down", Key::S, Buttons::DOWN),
+    ("button_right", Key::D, Buttons::RIGHT),
+    ("button_left", Key::A, Buttons::LEFT),
+    ("button_select", Key::U, Buttons::SELECT),
+    ("button_start", Key::I, Buttons::START),
+    ("button_a", Key::K, Buttons::A),
+    ("button_b", Key::J, Buttons::B),
+];
+
+// Everything a button can be bound to, by egui's name for it. Tab and Backspace are left out
+// since they're taken by fast-forward and rewind
+#[rustfmt::skip]
+const BINDABLE_KEYS: [Key; 42] = [
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K,
+    Key::L, Key::M, Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V,
+    Key::W, Key::X, Key::Y, Key::Z,
+    Key::Num0, Key::Num1, Key::Num2, Key::Num3, Key::Num4, Key::Num5, Key::Num6, Key::Num7,
+    Key::Num8, Key::Num9,
+    Key::ArrowUp, Key::ArrowDown, Key::ArrowLeft, Key::ArrowRight, Key::Space, Key::Enter,
+];
+
+fn parse_key(name: &str) -> Option<Key> {
+    BINDABLE_KEYS
+        .into_iter()
+        .find(|key| key.name().eq_ignore_ascii_case(name))
+}
+
+const FAST_FORWARD_KEY: Key = Key::Tab;
+const REWIND_KEY: Key = Key::Backspace;
+const SCREENSHOT_KEY: Key = Key::F12;
+const RESET_KEY: Key = Key::F5;
+
+// Config keys for how many pixels are cropped off each edge, in the order Frame::cropped takes
+// them
+const OVERSCAN_KEYS: [&str; 4] = [
+    "overscan_top",
+    "overscan_bottom",
+    "overscan_left",
+    "overscan_right",
+];
+const MAX_OVERSCAN: i64 = 64;
+
+// What the memory viewer is showing, with the address its first byte sits at
+#[derive(Default, Clone, Copy, PartialEq)]
+enum MemoryView {
+    #[default]
+    CpuRam,
+    Nametables,
+    Palette,
 }
-impl From<Frame> for ImageData {
-    fn from(value: Frame) -> Self {
-        ColorImage::from_rgb([256, 240], &value.image).into()
+
+impl MemoryView {
+    fn base_addr(self) -> usize {
+        match self {
+            MemoryView::CpuRam => 0x0000,
+            MemoryView::Nametables => 0x2000,
+            MemoryView::Palette => 0x3f00,
+        }
     }
 }
 
-pub enum ConsoleMsg {
-    JoypadDown(Buttons),
-    JoypadUp(Buttons),
-    RunFrame,
+// A headerless ROM waiting on the user to say how to load it. CHR-ROM, if any, is taken off the
+// end of the file
+struct RawRomDialog {
+    path: PathBuf,
+    bytes: Vec<u8>,
+    mapper: u16,
+    mirroring: Mirroring,
+    chr_banks: usize,
+}
+
+#[derive(Default)]
+enum FastForwardMode {
+    #[default]
+    Hold,
+    Toggle,
+}
+
+impl FastForwardMode {
+    fn from_config() -> Self {
+        match Config::get_string_with_default("fast_forward_mode", "hold").as_str() {
+            "hold" => FastForwardMode::Hold,
+            "toggle" => FastForwardMode::Toggle,
+            other => {
+                println!("Unknown fast_forward_mode {other}, using hold");
+                FastForwardMode::Hold
+            }
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct EGuiApp {
-    console: Option<Arc<Mutex<Console<'static>>>>,
+    console: Option<Arc<Mutex<Console>>>,
+    key_map: HashMap<Key, Buttons>,
+    gamepads: Option<Gamepads>,
     channel: Option<Sender<ConsoleMsg>>,
+    show_sprite_0_hit: bool,
+    show_chr_viewer: bool,
+    show_nametable_viewer: bool,
+    show_sprite_list: bool,
+    show_memory_viewer: bool,
+    show_irq_status: bool,
+    show_frame_metrics: bool,
+    memory_view: MemoryView,
+    // Palette the CHR viewer colors tiles with, 0-3 background and 4-7 sprites
+    chr_palette: u8,
+    ntsc_filter: bool,
+    unlimited_sprites: bool,
+    overclock: bool,
+    turbo_a: bool,
+    turbo_b: bool,
+    fast_forward_mode: FastForwardMode,
+    fast_forward: bool,
+    rewinding: bool,
+    speed: f32,
+    // Last speed the console thread was told about
+    sent_speed: f32,
+    // Palette picked before any ROM was loaded, applied to the first console
+    palette: Option<Palette>,
+    breakpoint_input: String,
+    halt_on_invalid_op: bool,
+    // Zapper in port 2, aimed with the mouse and fired with a left click
+    zapper: bool,
+    overscan: [usize; 4],
+    show_cheats: bool,
+    cheat_input: String,
+    raw_rom: Option<RawRomDialog>,
 }
 
 impl App for EGuiApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         if let Some(channel) = &self.channel {
-            channel.send(ConsoleMsg::RunFrame).unwrap();
+            let speed = if self.fast_forward {
+                // Below 1x it would slow the game down instead, or at 0 stop it altogether
+                Config::get_int("fast_forward_speed", 4i64).max(1) as f32
+            } else {
+                self.speed
+            };
+            if speed != self.sent_speed {
+                channel.send(ConsoleMsg::SetSpeed(speed)).unwrap();
+                self.sent_speed = speed;
+            }
+
+            if self.rewinding {
+                channel.send(ConsoleMsg::Rewind).unwrap();
+            } else {
+                channel.send(ConsoleMsg::RunFrame).unwrap();
+            }
         }
 
         // Draw
@@ -62,14 +180,36 @@ impl App for EGuiApp {
                 menu::bar(ui, |ui| {
                     if ui.button("Load ROM").clicked() {
                         if let Some(path) = FileDialog::new().pick_file() {
-                            let file = NESFile::new(path);
-                            let hash = file.hash;
-                            self.load(file);
-                            if let Some(save_dir_str) = Config::get_string("save_directory") {
-                                let mut save_path = PathBuf::from(save_dir_str);
-                                save_path.push(format!("{}.sav", hash));
-                                if save_path.exists() {
-                                    self.load_save(save_path).unwrap();
+                            match NESFile::new(path.clone()) {
+                                Ok(rom) => self.load(rom),
+                                Err(err) => {
+                                    MessageDialog::new()
+                                        .set_level(MessageLevel::Error)
+                                        .set_title("Could not load ROM")
+                                        .set_description(format!("{}: {err}", path.display()))
+                                        .show();
+                                }
+                            }
+                        }
+                    }
+                    if ui.button("Load Raw ROM").clicked() {
+                        if let Some(path) = FileDialog::new().pick_file() {
+                            match std::fs::read(&path) {
+                                Ok(bytes) => {
+                                    self.raw_rom = Some(RawRomDialog {
+                                        path,
+                                        bytes,
+                                        mapper: 0,
+                                        mirroring: Mirroring::Horizontal,
+                                        chr_banks: 0,
+                                    })
+                                }
+                                Err(err) => {
+                                    MessageDialog::new()
+                                        .set_level(MessageLevel::Error)
+                                        .set_title("Could not load ROM")
+                                        .set_description(format!("{}: {err}", path.display()))
+                                        .show();
                                 }
                             }
                         }
@@ -82,9 +222,139 @@ impl App for EGuiApp {
                     if ui.button("Save game").clicked() {
                         self.save_game().unwrap();
                     }
+                    if ui.button("Load palette...").clicked() {
+                        if let Some(path) =
+                            FileDialog::new().add_filter("Palette", &["pal"]).pick_file()
+                        {
+                            self.load_palette(path);
+                        }
+                    }
+                    if ui.button("Screenshot").clicked() {
+                        self.screenshot();
+                    }
+                    if let Some(console) = &self.console {
+                        let (recording, fps) = {
+                            let console = console.lock().unwrap();
+                            (console.is_recording_audio(), console.measured_fps())
+                        };
+                        let mut failed = None;
+                        if recording {
+                            if ui.button("Stop recording").clicked() {
+                                if let Err(err) = console.lock().unwrap().stop_audio_recording() {
+                                    failed = Some(("Could not finish recording", err.to_string()));
+                                }
+                            }
+                        } else if ui.button("Record audio").clicked() {
+                            // Not holding the lock while the dialog is up, which would stop the
+                            // emulator until it closed
+                            if let Some(path) = FileDialog::new()
+                                .add_filter("WAV", &["wav"])
+                                .save_file()
+                            {
+                                let console = console.lock().unwrap();
+                                if let Err(err) = console.start_audio_recording(path.clone()) {
+                                    let description = format!("{}: {err}", path.display());
+                                    failed = Some(("Could not record audio", description));
+                                }
+                            }
+                        }
+                        if let Some((title, description)) = failed {
+                            MessageDialog::new()
+                                .set_level(MessageLevel::Error)
+                                .set_title(title)
+                                .set_description(description)
+                                .show();
+                        }
+                        ui.label(format!("{fps:.1} FPS"));
+                    }
+                    if let Some(channel) = &self.channel {
+                        let paused = self
+                            .console
+                            .as_ref()
+                            .is_some_and(|console| console.lock().unwrap().is_paused());
+                        if ui.button(if paused { "Resume" } else { "Pause" }).clicked() {
+                            channel.send(ConsoleMsg::Pause(!paused)).unwrap();
+                        }
+                        if ui.button("Reset").clicked() {
+                            channel.send(ConsoleMsg::Reset).unwrap();
+                        }
+                        if ui.button("Power cycle").clicked() {
+                            channel.send(ConsoleMsg::PowerCycle).unwrap();
+                        }
+                    }
+                    // Rewinds for as long as it's held down
+                    let rewind = ui.button("Rewind");
+                    self.rewinding = rewind.is_pointer_button_down_on()
+                        || ctx.input(|i| i.key_down(REWIND_KEY));
+                    ui.add(egui::Slider::new(&mut self.speed, 0.25..=4.0).text("Speed"));
+                    ui.checkbox(&mut self.ntsc_filter, "NTSC filter");
+                    if ui
+                        .checkbox(&mut self.unlimited_sprites, "No sprite limit")
+                        .changed()
+                    {
+                        if let Some(console) = &self.console {
+                            let mut console = console.lock().unwrap();
+                            console.cpu.bus.ppu.set_unlimited_sprites(self.unlimited_sprites);
+                        }
+                    }
+                    if ui.checkbox(&mut self.overclock, "Overclock").changed() {
+                        if let Some(console) = &self.console {
+                            let mut console = console.lock().unwrap();
+                            console.cpu.set_overclock(self.overclock_multiplier());
+                        }
+                    }
+                    let turbo_a = ui.checkbox(&mut self.turbo_a, "Turbo A").changed();
+                    let turbo_b = ui.checkbox(&mut self.turbo_b, "Turbo B").changed();
+                    if turbo_a || turbo_b {
+                        if let Some(console) = &self.console {
+                            self.apply_turbo(&mut console.lock().unwrap());
+                        }
+                    }
+                    if ui.checkbox(&mut self.zapper, "Zapper").changed() {
+                        if let Some(channel) = &self.channel {
+                            channel.send(ConsoleMsg::ConnectZapper(self.zapper)).unwrap();
+                        }
+                    }
+                    ui.checkbox(&mut self.show_cheats, "Cheats");
+                    self.show_disk_menu(ui);
+                    ui.menu_button("Debug", |ui| {
+                        ui.checkbox(&mut self.show_sprite_0_hit, "Sprite 0 hit line");
+                        ui.checkbox(&mut self.show_chr_viewer, "CHR viewer");
+                        ui.checkbox(&mut self.show_nametable_viewer, "Nametable viewer");
+                        ui.checkbox(&mut self.show_sprite_list, "Sprites");
+                        ui.checkbox(&mut self.show_memory_viewer, "Memory viewer");
+                        ui.checkbox(&mut self.show_irq_status, "APU IRQ status");
+                        ui.checkbox(&mut self.show_frame_metrics, "Frame timing");
+                        if ui
+                            .checkbox(&mut self.halt_on_invalid_op, "Halt on invalid opcodes")
+                            .changed()
+                        {
+                            if let Some(console) = &self.console {
+                                let mut console = console.lock().unwrap();
+                                console.cpu.set_invalid_opcode_behavior(self.invalid_op_behavior());
+                            }
+                        }
+                        ui.horizontal(|ui| {
+                            ui.label("Break at $");
+                            ui.text_edit_singleline(&mut self.breakpoint_input);
+                            if ui.button("Add").clicked() {
+                                self.add_breakpoint();
+                            }
+                        });
+                    });
                 });
             });
 
+            self.show_halted(ctx);
+            self.show_irq_status(ctx);
+            self.show_frame_metrics(ctx);
+            self.show_chr_viewer(ctx);
+            self.show_nametable_viewer(ctx);
+            self.show_sprite_list(ctx);
+            self.show_memory_viewer(ctx);
+            self.show_cheats(ctx);
+            self.show_raw_rom_dialog(ctx);
+
             CentralPanel::default().show(ctx, |ui| self.show_texture(ui));
             self.handle_keyevent(ctx);
         });
@@ -96,12 +366,85 @@ impl EGuiApp {
         Self {
             channel: None,
             console: None,
+            key_map: Self::key_map_from_config(),
+            gamepads: Gamepads::new(),
+            show_sprite_0_hit: false,
+            show_chr_viewer: false,
+            show_nametable_viewer: false,
+            show_sprite_list: false,
+            show_memory_viewer: false,
+            show_irq_status: false,
+            show_frame_metrics: false,
+            memory_view: MemoryView::default(),
+            chr_palette: 0,
+            ntsc_filter: Config::get_bool("ntsc_filter", false),
+            unlimited_sprites: Config::get_bool("unlimited_sprites", false),
+            overclock: Config::get_float("overclock", 1.) > 1.,
+            turbo_a: false,
+            turbo_b: false,
+            fast_forward_mode: FastForwardMode::from_config(),
+            fast_forward: false,
+            rewinding: false,
+            speed: 1.0,
+            sent_speed: 1.0,
+            palette: None,
+            breakpoint_input: String::new(),
+            halt_on_invalid_op: false,
+            zapper: false,
+            overscan: OVERSCAN_KEYS
+                .map(|key| Config::get_int(key, 0i64).clamp(0, MAX_OVERSCAN) as usize),
+            show_cheats: false,
+            cheat_input: String::new(),
+            raw_rom: None,
         }
     }
 
+    // Any binding that's missing or unparseable keeps its default. If two buttons end up on the
+    // same key, all of them go back to the defaults
+    fn key_map_from_config() -> HashMap<Key, Buttons> {
+        let mut key_map = HashMap::new();
+        for (name, default, button) in DEFAULT_BINDINGS {
+            let key = match Config::get_string(name) {
+                Some(value) => parse_key(&value).unwrap_or_else(|| {
+                    println!("Unknown key {value} for {name}, using {}", default.name());
+                    default
+                }),
+                None => default,
+            };
+            if let Some(other) = key_map.insert(key, button) {
+                println!(
+                    "{} is bound to both {other:?} and {button:?}, using the default bindings",
+                    key.name()
+                );
+                return DEFAULT_BINDINGS
+                    .iter()
+                    .map(|(_, key, button)| (*key, *button))
+                    .collect();
+            }
+        }
+        key_map
+    }
+
     fn load(&mut self, rom: NESFile) {
+        // Reuse the running console and its audio stream if there is one
+        if let Some(channel) = &self.channel {
+            channel.send(ConsoleMsg::LoadRom(Box::new(rom))).unwrap();
+            return;
+        }
+
         let (send, recv) = channel::bounded::<ConsoleMsg>(1024);
-        let console = Arc::new(Mutex::new(Console::new(rom)));
+        let mut console = Console::new(rom);
+        console.load_default_save().unwrap();
+        if let Some(palette) = self.palette.take() {
+            console.cpu.bus.ppu.set_palette(palette);
+        }
+        console.enable_rewind(Config::get_int("rewind_seconds", 10i64) as u32);
+        console.cpu.set_invalid_opcode_behavior(self.invalid_op_behavior());
+        console.connect_zapper(self.zapper);
+        console.cpu.bus.ppu.set_unlimited_sprites(self.unlimited_sprites);
+        console.cpu.set_overclock(self.overclock_multiplier());
+        self.apply_turbo(&mut console);
+        let console = Arc::new(Mutex::new(console));
         self.channel = Some(send);
         self.console = Some(console.clone());
 
@@ -126,29 +469,538 @@ impl EGuiApp {
         Ok(())
     }
 
+    fn load_palette(&mut self, path: PathBuf) {
+        match Palette::from_file(&path) {
+            Ok(palette) => match &self.console {
+                Some(console) => console.lock().unwrap().cpu.bus.ppu.set_palette(palette),
+                None => self.palette = Some(palette),
+            },
+            Err(err) => {
+                MessageDialog::new()
+                    .set_level(MessageLevel::Error)
+                    .set_title("Could not load palette")
+                    .set_description(format!("{}: {err}", path.display()))
+                    .show();
+            }
+        }
+    }
+
+    // Rates are presses per second, from turbo_a and turbo_b in config.toml
+    fn apply_turbo(&self, console: &mut Console) {
+        let rate = |enabled: bool, key| enabled.then(|| Config::get_float(key, 15.));
+        console.set_turbo(Buttons::A, rate(self.turbo_a, "turbo_a"));
+        console.set_turbo(Buttons::B, rate(self.turbo_b, "turbo_b"));
+    }
+
+    // The checkbox turns on whatever overclock config.toml asks for, or double speed if it
+    // doesn't ask for any
+    fn overclock_multiplier(&self) -> f64 {
+        if !self.overclock {
+            return 1.;
+        }
+        let multiplier = Config::get_float("overclock", 2.);
+        if multiplier > 1. {
+            multiplier
+        } else {
+            2.
+        }
+    }
+
+    fn invalid_op_behavior(&self) -> InvalidOpBehavior {
+        if self.halt_on_invalid_op {
+            InvalidOpBehavior::Halt
+        } else {
+            InvalidOpBehavior::NopAndLog
+        }
+    }
+
+    fn add_breakpoint(&mut self) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        match u16::from_str_radix(self.breakpoint_input.trim(), 16) {
+            Ok(addr) => {
+                console.lock().unwrap().cpu.set_pc_breakpoint(addr);
+                self.breakpoint_input.clear();
+            }
+            Err(_) => println!("Not a hex address: {}", self.breakpoint_input),
+        }
+    }
+
+    // Shows the registers and a way out while a breakpoint, JAM or invalid opcode has the CPU
+    // halted
+    fn show_halted(&self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        let mut console = console.lock().unwrap();
+        if !console.cpu.is_halted() {
+            return;
+        }
+        let regs = console.cpu.registers();
+        TopBottomPanel::bottom("debugger").show(ctx, |ui| {
+            if console.cpu.is_jammed() {
+                ui.colored_label(Color32::RED, "CPU jammed, reset to continue");
+            }
+            if let Some((addr, opcode)) = console.cpu.invalid_opcode() {
+                ui.colored_label(
+                    Color32::RED,
+                    format!("Invalid opcode {opcode:02X} at {addr:04X}, reset to continue"),
+                );
+            }
+            ui.horizontal(|ui| {
+                ui.monospace(format!(
+                    "Halted at {:04X}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
+                    regs.pc, regs.acc, regs.x, regs.y, regs.status, regs.sp, regs.cycle
+                ));
+                if ui.button("Continue").clicked() {
+                    console.cpu.resume();
+                }
+                if ui.button("Clear breakpoint").clicked() {
+                    console.cpu.clear_pc_breakpoint(regs.pc);
+                    console.cpu.resume();
+                }
+            });
+        });
+    }
+
+    // Only there for .fds images, one button per side of each disk
+    fn show_disk_menu(&self, ui: &mut egui::Ui) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        let sides = console.lock().unwrap().disk_sides();
+        if sides == 0 {
+            return;
+        }
+        ui.menu_button("Disk", |ui| {
+            let mut console = console.lock().unwrap();
+            let inserted = console.inserted_disk();
+            for side in 0..sides {
+                let name = format!("Disk {} Side {}", side / 2 + 1, ["A", "B"][side % 2]);
+                if ui.radio(inserted == Some(side), name).clicked() {
+                    console.insert_disk(side as u8).unwrap();
+                }
+            }
+            if ui.button("Eject").clicked() {
+                console.eject_disk();
+            }
+        });
+    }
+
+    fn show_irq_status(&self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        if !self.show_irq_status {
+            return;
+        }
+        let irq = console.lock().unwrap().cpu.bus.apu.irq_state();
+        TopBottomPanel::bottom("status").show(ctx, |ui| {
+            ui.horizontal(|ui| {
+                for (name, pending) in [("Frame IRQ", irq.frame_irq), ("DMC IRQ", irq.dmc_irq)] {
+                    let color = if pending { Color32::RED } else { Color32::GRAY };
+                    ui.colored_label(color, name);
+                }
+            });
+        });
+    }
+
+    // Drawn over the corner of the game rather than in a window, so it's there while playing
+    fn show_frame_metrics(&self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        if !self.show_frame_metrics {
+            return;
+        }
+        let metrics = console.lock().unwrap().frame_metrics();
+        egui::Area::new("frame_metrics")
+            .anchor(egui::Align2::RIGHT_TOP, [-8., 40.])
+            .show(ctx, |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    let ms = |time: Duration| time.as_secs_f64() * 1000.;
+                    ui.label(format!("Frame time {:.2} ms", ms(metrics.average)));
+                    ui.label(format!("Max {:.2} ms", ms(metrics.max)));
+                    let color = if metrics.missed_deadlines > 0 {
+                        Color32::RED
+                    } else {
+                        Color32::GRAY
+                    };
+                    ui.colored_label(color, format!("Missed {}", metrics.missed_deadlines));
+                });
+            });
+    }
+
+    fn show_chr_viewer(&mut self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        let palette = &mut self.chr_palette;
+        egui::Window::new("CHR viewer")
+            .open(&mut self.show_chr_viewer)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.add(egui::Slider::new(palette, 0..=7).text("Palette"));
+                // Redrawn every frame so CHR-RAM writes and bank switches show up straight away
+                let console = console.lock().unwrap();
+                ui.horizontal(|ui| {
+                    for table in 0..2 {
+                        let image = console.cpu.bus.ppu.pattern_table_image(table, *palette);
+                        let texture = ui.ctx().load_texture(
+                            format!("CHR {table}"),
+                            ColorImage::from_rgb([128, 128], &image),
+                            egui::TextureOptions::NEAREST,
+                        );
+                        ui.image((texture.id(), egui::Vec2::new(256., 256.)));
+                    }
+                });
+            });
+    }
+
+    fn show_nametable_viewer(&mut self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        egui::Window::new("Nametable viewer")
+            .open(&mut self.show_nametable_viewer)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let console = console.lock().unwrap();
+                let ppu = &console.cpu.bus.ppu;
+                let texture = ui.ctx().load_texture(
+                    "Nametables",
+                    ColorImage::from_rgb([512, 480], &ppu.nametable_image()),
+                    egui::TextureOptions::NEAREST,
+                );
+                let rect = ui
+                    .image((texture.id(), egui::Vec2::new(512., 480.)))
+                    .rect;
+
+                // The visible screen, wrapping around the edges like the scroll does
+                let (x, y) = ppu.scroll_position();
+                let painter = ui.painter_at(rect);
+                for (dx, dy) in [(0., 0.), (-512., 0.), (0., -480.), (-512., -480.)] {
+                    let min = rect.min + egui::Vec2::new(x as f32 + dx, y as f32 + dy);
+                    painter.rect_stroke(
+                        egui::Rect::from_min_size(min, egui::Vec2::new(256., 240.)),
+                        0.,
+                        Stroke::new(1., Color32::RED),
+                    );
+                }
+            });
+    }
+
+    fn show_sprite_list(&mut self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        egui::Window::new("Sprites")
+            .open(&mut self.show_sprite_list)
+            .show(ctx, |ui| {
+                let sprites = console.lock().unwrap().cpu.bus.ppu.sprites();
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    egui::Grid::new("sprite list").striped(true).show(ui, |ui| {
+                        for header in ["#", "X", "Y", "Tile", "Palette", "Flip", "Behind BG"] {
+                            ui.strong(header);
+                        }
+                        ui.end_row();
+                        for sprite in sprites {
+                            // Sprites on the scanline the PPU is at are highlighted
+                            let color = if sprite.in_range {
+                                Color32::YELLOW
+                            } else {
+                                ui.visuals().text_color()
+                            };
+                            let flip = match (sprite.flip_horizontal(), sprite.flip_vertical()) {
+                                (false, false) => "",
+                                (true, false) => "H",
+                                (false, true) => "V",
+                                (true, true) => "HV",
+                            };
+                            for cell in [
+                                sprite.index.to_string(),
+                                sprite.x.to_string(),
+                                sprite.y.to_string(),
+                                format!("${:02X}", sprite.tile),
+                                sprite.palette().to_string(),
+                                flip.to_string(),
+                                if sprite.behind_background() { "yes" } else { "" }.to_string(),
+                            ] {
+                                ui.colored_label(color, cell);
+                            }
+                            ui.end_row();
+                        }
+                    });
+                });
+            });
+    }
+
+    fn show_memory_viewer(&mut self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        let view = &mut self.memory_view;
+        egui::Window::new("Memory viewer")
+            .open(&mut self.show_memory_viewer)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.radio_value(view, MemoryView::CpuRam, "CPU RAM");
+                    ui.radio_value(view, MemoryView::Nametables, "Nametables");
+                    ui.radio_value(view, MemoryView::Palette, "Palette");
+                });
+                // Snapshotted every frame, the console lock isn't held while drawing
+                let bytes = {
+                    let console = console.lock().unwrap();
+                    match view {
+                        MemoryView::CpuRam => console.cpu.bus.cpu_ram().to_vec(),
+                        MemoryView::Nametables => console.cpu.bus.ppu.dump_nametables().to_vec(),
+                        MemoryView::Palette => console.cpu.bus.ppu.dump_palette().to_vec(),
+                    }
+                };
+                let base = view.base_addr();
+                let row_height = ui.text_style_height(&egui::TextStyle::Monospace);
+                egui::ScrollArea::vertical().show_rows(
+                    ui,
+                    row_height,
+                    bytes.len() / 16,
+                    |ui, rows| {
+                        for row in rows {
+                            let line = bytes[row * 16..row * 16 + 16]
+                                .iter()
+                                .map(|b| format!("{b:02X}"))
+                                .collect::<Vec<_>>()
+                                .join(" ");
+                            ui.monospace(format!("{:04X}: {line}", base + row * 16));
+                        }
+                    },
+                );
+            });
+    }
+
+    fn show_cheats(&mut self, ctx: &egui::Context) {
+        let Some(console) = &self.console else {
+            return;
+        };
+        let input = &mut self.cheat_input;
+        let mut error = None;
+        egui::Window::new("Cheats")
+            .open(&mut self.show_cheats)
+            .resizable(false)
+            .show(ctx, |ui| {
+                let mut console = console.lock().unwrap();
+                ui.horizontal(|ui| {
+                    ui.label("Game Genie code");
+                    ui.text_edit_singleline(input);
+                    if ui.button("Add").clicked() {
+                        match console.add_cheat(input) {
+                            Ok(()) => input.clear(),
+                            Err(err) => error = Some(err),
+                        }
+                    }
+                });
+                for cheat in console.cpu.bus.cheats() {
+                    let line = match cheat.compare {
+                        Some(compare) => format!(
+                            "${:04X} = ${:02X} if ${compare:02X}",
+                            cheat.addr, cheat.value
+                        ),
+                        None => format!("${:04X} = ${:02X}", cheat.addr, cheat.value),
+                    };
+                    ui.monospace(line);
+                }
+                if ui.button("Clear").clicked() {
+                    console.cpu.bus.clear_cheats();
+                }
+            });
+        // Shown once the console is unlocked, the dialog blocks until it's dismissed
+        if let Some(err) = error {
+            MessageDialog::new()
+                .set_level(MessageLevel::Error)
+                .set_title("Could not add cheat")
+                .set_description(err.to_string())
+                .show();
+        }
+    }
+
+    fn show_raw_rom_dialog(&mut self, ctx: &egui::Context) {
+        let Some(raw) = &mut self.raw_rom else {
+            return;
+        };
+        let mut open = true;
+        let mut load = false;
+        egui::Window::new("Load Raw ROM")
+            .open(&mut open)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.label(raw.path.display().to_string());
+                ui.horizontal(|ui| {
+                    ui.label("Mapper");
+                    ui.add(egui::DragValue::new(&mut raw.mapper).clamp_range(0..=4095));
+                });
+                egui::ComboBox::from_label("Mirroring")
+                    .selected_text(format!("{:?}", raw.mirroring))
+                    .show_ui(ui, |ui| {
+                        for mirroring in [
+                            Mirroring::Horizontal,
+                            Mirroring::Vertical,
+                            Mirroring::FourScreen,
+                        ] {
+                            ui.selectable_value(
+                                &mut raw.mirroring,
+                                mirroring,
+                                format!("{mirroring:?}"),
+                            );
+                        }
+                    });
+                ui.horizontal(|ui| {
+                    ui.label("CHR-ROM 8K banks");
+                    let max_banks = raw.bytes.len() / 0x2000;
+                    ui.add(egui::DragValue::new(&mut raw.chr_banks).clamp_range(0..=max_banks));
+                });
+                load = ui.button("Load").clicked();
+            });
+        if !open {
+            self.raw_rom = None;
+        }
+        if !load {
+            return;
+        }
+
+        let raw = self.raw_rom.take().unwrap();
+        let mut prg = raw.bytes;
+        let chr = (raw.chr_banks > 0).then(|| prg.split_off(prg.len() - raw.chr_banks * 0x2000));
+        match NESFile::from_raw(prg, chr, raw.mapper, raw.mirroring) {
+            Ok(rom) => self.load(rom),
+            Err(err) => {
+                MessageDialog::new()
+                    .set_level(MessageLevel::Error)
+                    .set_title("Could not load ROM")
+                    .set_description(format!("{}: {err}", raw.path.display()))
+                    .show();
+            }
+        }
+    }
+
+    fn screenshot(&self) {
+        if let Some(console) = &self.console {
+            let dir = PathBuf::from(Config::get_string_with_default(
+                "screenshot_directory",
+                "./screenshots/",
+            ));
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            let path = dir.join(format!("{timestamp}.png"));
+            let res = std::fs::create_dir_all(&dir)
+                .map_err(image::ImageError::from)
+                .and_then(|()| console.lock().unwrap().screenshot(path.clone()));
+            match res {
+                Ok(()) => println!("Saved screenshot to {}", path.display()),
+                Err(err) => println!("Could not save screenshot: {err}"),
+            }
+        }
+    }
+
     fn show_texture(&self, ui: &mut Ui) {
+        let mut zapper = None;
         if let Some(console) = &self.console {
             let console = console.lock().unwrap();
-            let texture =
-                ui.ctx()
-                    .load_texture("NES", console.cpu.bus.ppu.curr_frame, Default::default());
-            let image = egui::Image::new((texture.id(), texture.size_vec2()))
+            let frame = &console.cpu.bus.ppu.curr_frame;
+            let [top, bottom, left, right] = self.overscan;
+            let (image, width, height) = if self.ntsc_filter {
+                let filtered = frame.apply_ntsc(console.cpu.bus.ppu.color_phase());
+                let widen = |pixels: usize| pixels * NTSC_WIDTH / 256;
+                frame::crop(&filtered, NTSC_WIDTH, top, bottom, widen(left), widen(right))
+            } else {
+                frame.cropped(top, bottom, left, right)
+            };
+            let image_data: ImageData = ColorImage::from_rgb([width, height], &image).into();
+            let texture = ui
+                .ctx()
+                .load_texture("NES", image_data, Default::default());
+            // The filtered image is wider, but it's still shown at the NES's aspect ratio
+            let shown = egui::Vec2::new((256 - left - right) as f32, (240 - top - bottom) as f32);
+            let image = egui::Image::new((texture.id(), shown))
                 .maintain_aspect_ratio(true)
-                .fit_to_fraction(egui::Vec2::new(1., 1.));
-            ui.add_sized(ui.available_size(), image);
+                .fit_to_fraction(egui::Vec2::new(1., 1.))
+                .sense(egui::Sense::click());
+            let response = ui.add_sized(ui.available_size(), image);
+            let rect = response.rect;
+            if self.zapper {
+                let aim = response.hover_pos().map(|pos| {
+                    let x = (pos.x - rect.left()) * shown.x / rect.width();
+                    let y = (pos.y - rect.top()) * shown.y / rect.height();
+                    (x as usize + left, y as usize + top)
+                });
+                zapper = Some((aim, response.clicked()));
+            }
+
+            if self.show_sprite_0_hit {
+                if let Some(scanline) = console.cpu.bus.ppu.sprite_0_hit_scanline() {
+                    let row = scanline as f32 - top as f32 + 0.5;
+                    if (0. ..shown.y).contains(&row) {
+                        let y = rect.top() + row * rect.height() / shown.y;
+                        ui.painter()
+                            .hline(rect.x_range(), y, Stroke::new(1., Color32::RED));
+                    }
+                }
+            }
+        }
+
+        if let (Some((aim, fired)), Some(channel)) = (zapper, &self.channel) {
+            channel.send(ConsoleMsg::AimZapper(aim)).unwrap();
+            if fired {
+                channel.send(ConsoleMsg::PullZapperTrigger).unwrap();
+            }
         }
     }
 
     fn handle_keyevent(&mut self, ctx: &eframe::egui::Context) {
+        match self.fast_forward_mode {
+            FastForwardMode::Hold => {
+                self.fast_forward = ctx.input(|i| i.key_down(FAST_FORWARD_KEY))
+            }
+            FastForwardMode::Toggle => {
+                if ctx.input(|i| i.key_pressed(FAST_FORWARD_KEY)) {
+                    self.fast_forward = !self.fast_forward;
+                }
+            }
+        }
+
+        if ctx.input(|i| i.key_pressed(SCREENSHOT_KEY)) {
+            self.screenshot();
+        }
+
         if let Some(channel) = &self.channel {
+            if ctx.input(|i| i.key_pressed(RESET_KEY)) {
+                channel.send(ConsoleMsg::Reset).unwrap();
+            }
             let keys_down = ctx.input(|i| i.keys_down.clone());
-            KEY_MAP.iter().for_each(|(key, button)| {
-                if keys_down.contains(key) {
-                    channel.try_send(ConsoleMsg::JoypadDown(*button)).unwrap();
-                } else {
-                    channel.send(ConsoleMsg::JoypadUp(*button)).unwrap();
+            let mut player1 = self
+                .key_map
+                .iter()
+                .filter(|(key, _)| keys_down.contains(key))
+                .fold(Buttons::empty(), |acc, (_, button)| acc | *button);
+            let mut player2 = Buttons::empty();
+            // The first pad is merged with the keyboard, the second one is player 2
+            if let Some(gamepads) = &mut self.gamepads {
+                let [pad1, pad2] = gamepads.poll();
+                player1 |= pad1;
+                player2 = pad2;
+            }
+
+            for (player, held) in [(Player::One, player1), (Player::Two, player2)] {
+                for (_, _, button) in DEFAULT_BINDINGS {
+                    let msg = if held.contains(button) {
+                        ConsoleMsg::JoypadDown(player, button)
+                    } else {
+                        ConsoleMsg::JoypadUp(player, button)
+                    };
+                    channel.send(msg).unwrap();
                 }
-            });
+            }
         }
     }
 }
@@ -0,0 +1,65 @@
+use gilrs::{Axis, Button, Gamepad, Gilrs};
+
+use crate::config::Config;
+use crate::core::joypad::Buttons;
+
+const BUTTON_MAP: [(Button, Buttons); 8] = [
+    (Button::DPadUp, Buttons::UP),
+    (Button::DPadDown, Buttons::DOWN),
+    (Button::DPadLeft, Buttons::LEFT),
+    (Button::DPadRight, Buttons::RIGHT),
+    (Button::Select, Buttons::SELECT),
+    (Button::Start, Buttons::START),
+    // Same positions as on the NES pad, B on the left and A on the right
+    (Button::East, Buttons::A),
+    (Button::South, Buttons::B),
+];
+
+pub struct Gamepads {
+    gilrs: Gilrs,
+    deadzone: f32,
+}
+
+impl Gamepads {
+    // None if the platform's gamepad backend isn't available, in which case it's keyboard only
+    pub fn new() -> Option<Self> {
+        match Gilrs::new() {
+            Ok(gilrs) => Some(Gamepads {
+                gilrs,
+                deadzone: Config::get_float("gamepad_deadzone", 0.5) as f32,
+            }),
+            Err(err) => {
+                println!("Gamepad support unavailable: {err}");
+                None
+            }
+        }
+    }
+
+    // Buttons held on the first two connected pads, for player 1 and player 2
+    pub fn poll(&mut self) -> [Buttons; 2] {
+        // gilrs only updates its gamepad state as events are taken off the queue
+        while self.gilrs.next_event().is_some() {}
+
+        let mut held = [Buttons::empty(); 2];
+        for ((_, gamepad), buttons) in self.gilrs.gamepads().zip(held.iter_mut()) {
+            *buttons = self.held_buttons(&gamepad);
+        }
+        held
+    }
+
+    fn held_buttons(&self, gamepad: &Gamepad) -> Buttons {
+        let mut buttons = Buttons::empty();
+        for (button, nes_button) in BUTTON_MAP {
+            buttons.set(nes_button, gamepad.is_pressed(button));
+        }
+
+        // The left stick doubles as the d-pad
+        let x = gamepad.value(Axis::LeftStickX);
+        let y = gamepad.value(Axis::LeftStickY);
+        buttons.set(Buttons::LEFT, buttons.contains(Buttons::LEFT) || x < -self.deadzone);
+        buttons.set(Buttons::RIGHT, buttons.contains(Buttons::RIGHT) || x > self.deadzone);
+        buttons.set(Buttons::UP, buttons.contains(Buttons::UP) || y > self.deadzone);
+        buttons.set(Buttons::DOWN, buttons.contains(Buttons::DOWN) || y < -self.deadzone);
+        buttons
+    }
+}
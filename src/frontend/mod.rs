@@ -1,2 +1,4 @@
 pub mod blip_buf;
 pub mod egui;
+pub mod gamepad;
+pub mod wav;
@@ -0,0 +1,77 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+// 16-bit PCM, with stereo samples interleaved left then right. The header goes out with the first
+// sample, with placeholder sizes that are patched in by `finish`
+pub struct WavWriter {
+    file: BufWriter<File>,
+    sample_rate: u32,
+    channels: u16,
+    data_len: u32,
+    header_written: bool,
+}
+
+impl WavWriter {
+    const HEADER_LEN: u32 = 44;
+    const RIFF_SIZE_POS: u64 = 4;
+    const DATA_SIZE_POS: u64 = 40;
+
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            sample_rate,
+            channels,
+            data_len: 0,
+            header_written: false,
+        })
+    }
+
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        if !self.header_written {
+            self.write_header()?;
+        }
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = self.data_len.saturating_add(samples.len() as u32 * 2);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.write_header()?;
+        }
+        self.file.seek(SeekFrom::Start(Self::RIFF_SIZE_POS))?;
+        self.file
+            .write_all(&(Self::HEADER_LEN - 8 + self.data_len).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(Self::DATA_SIZE_POS))?;
+        self.file.write_all(&self.data_len.to_le_bytes())?;
+        self.file.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let channels = self.channels;
+        let bits_per_sample = 16u16;
+        let block_align = channels * bits_per_sample / 8;
+
+        self.file.write_all(b"RIFF")?;
+        self.file.write_all(&0u32.to_le_bytes())?;
+        self.file.write_all(b"WAVE")?;
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        self.file.write_all(&1u16.to_le_bytes())?; // PCM
+        self.file.write_all(&channels.to_le_bytes())?;
+        self.file.write_all(&self.sample_rate.to_le_bytes())?;
+        self.file
+            .write_all(&(self.sample_rate * block_align as u32).to_le_bytes())?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&bits_per_sample.to_le_bytes())?;
+        self.file.write_all(b"data")?;
+        self.file.write_all(&0u32.to_le_bytes())?;
+        self.header_written = true;
+        Ok(())
+    }
+}
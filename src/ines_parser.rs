@@ -1,12 +1,73 @@
 #![allow(non_camel_case_types, clippy::upper_case_acronyms)]
 
 use std::{
+    fmt,
     hash::{DefaultHasher, Hash, Hasher},
     path::PathBuf,
 };
 
+use crate::{
+    config::Config,
+    core::{
+        fds::FdsImage,
+        mappers::{MapperFactory, Mirroring},
+        Region,
+    },
+};
+
 const NES_MAGIC: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
 
+#[derive(Debug)]
+pub enum ParseError {
+    MissingFile(std::io::Error),
+    BadMagic,
+    TruncatedPrg { expected: usize, found: usize },
+    TruncatedChr { expected: usize, found: usize },
+    UnsupportedMapper(u16),
+    // A headerless dump whose PRG (16K) or CHR (8K) size isn't a whole number of banks
+    UnalignedRawRom { prg: usize, chr: usize },
+    // .fds images need the disk system's BIOS, which has to come from a separate file
+    MissingFdsBios(std::io::Error),
+    BadFdsBios(usize),
+    // Not a whole number of 65500 byte sides, or a side without the disk info block
+    BadFdsDisk,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::MissingFile(err) => write!(f, "{err}"),
+            ParseError::BadMagic => write!(f, "not an iNES or NES 2.0 file"),
+            ParseError::TruncatedPrg { expected, found } => write!(
+                f,
+                "PRG-ROM is truncated: header claims {expected} bytes but only {found} are present"
+            ),
+            ParseError::TruncatedChr { expected, found } => write!(
+                f,
+                "CHR-ROM is truncated: header claims {expected} bytes but only {found} are present"
+            ),
+            ParseError::UnsupportedMapper(mapper_num) => {
+                write!(f, "unsupported mapper {mapper_num}")
+            }
+            ParseError::UnalignedRawRom { prg, chr } => write!(
+                f,
+                "{prg} bytes of PRG-ROM and {chr} of CHR-ROM aren't whole 16K and 8K banks"
+            ),
+            ParseError::MissingFdsBios(err) => write!(f, "couldn't read the FDS BIOS: {err}"),
+            ParseError::BadFdsBios(size) => {
+                write!(f, "the FDS BIOS should be 8192 bytes, found {size}")
+            }
+            ParseError::BadFdsDisk => write!(f, "not a valid Famicom Disk System image"),
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(err: std::io::Error) -> Self {
+        ParseError::MissingFile(err)
+    }
+}
+
 pub enum NameTableMirrorType {
     HORIZONTAL_OR_MAPPER,
     VERTICAL,
@@ -87,6 +148,8 @@ struct ROMSizeMSB(u8);
 enum ROMSizeMSBEnum {
     PRG,
     CHR,
+    // iNES 1.0 only, where byte 9 is the TV system instead
+    INES_TV_SYSTEM,
 }
 
 impl ROMSizeMSB {
@@ -94,6 +157,7 @@ impl ROMSizeMSB {
         match arg {
             ROMSizeMSBEnum::PRG => self.0 & 0x0f,
             ROMSizeMSBEnum::CHR => (self.0 & 0xf0) >> 4,
+            ROMSizeMSBEnum::INES_TV_SYSTEM => self.0 & 0x01,
         }
     }
 }
@@ -192,38 +256,45 @@ enum ConsoleType {
     _Unused(u8),
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RomFormat {
+    INes,
+    Nes2,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct Header {
     _magic: [u8; 4],
     prg_rom_size_lsb: u8,
     chr_rom_size_lsb: u8,
     pub flags1: Flags1,
-    _flags2: Flags2,
-    _mapper_msb: MapperMSB,
+    pub flags2: Flags2,
+    mapper_msb: MapperMSB,
     rom_size_msb: ROMSizeMSB,
     prg_ram_eeprom_size: PRGRAMEEPROMSize,
-    _chr_ram_size: CHRRAMSize,
-    _timing: Timing,
+    chr_ram_size: CHRRAMSize,
+    timing: Timing,
     _console_type: ConsoleType,
     _misc_roms: MiscROMs,
     _default_expansion_device: DefaultExpansionDevice,
 }
 
 impl Header {
-    pub fn new(bytes: [u8; 16]) -> Self {
-        assert_eq!(bytes[0..4], NES_MAGIC);
-        // assert!(bytes[7] & 0x0c == 0x08);
-        Header {
+    pub fn new(bytes: [u8; 16]) -> Result<Self, ParseError> {
+        if bytes[0..4] != NES_MAGIC {
+            return Err(ParseError::BadMagic);
+        }
+        Ok(Header {
             _magic: NES_MAGIC,
             prg_rom_size_lsb: bytes[4],
             chr_rom_size_lsb: bytes[5],
             flags1: Flags1(bytes[6]),
-            _flags2: Flags2(bytes[7]),
-            _mapper_msb: MapperMSB(bytes[8]),
+            flags2: Flags2(bytes[7]),
+            mapper_msb: MapperMSB(bytes[8]),
             rom_size_msb: ROMSizeMSB(bytes[9]),
             prg_ram_eeprom_size: PRGRAMEEPROMSize(bytes[10]),
-            _chr_ram_size: CHRRAMSize(bytes[11]),
-            _timing: Timing(bytes[12]),
+            chr_ram_size: CHRRAMSize(bytes[11]),
+            timing: Timing(bytes[12]),
             _console_type: match bytes[7] & 0x03 {
                 1 => ConsoleType::VsSystemType(VsSystemType(bytes[13])),
                 3 => ConsoleType::Extended(ExtendedConsoleType(bytes[13])),
@@ -231,28 +302,48 @@ impl Header {
             },
             _misc_roms: MiscROMs(bytes[14]),
             _default_expansion_device: DefaultExpansionDevice(bytes[15]),
+        })
+    }
+
+    // Bytes 8-15 only mean something in NES 2.0. iNES 1.0 dumps often have the ripper's name or
+    // other junk there, so none of it can be trusted unless flags 7 has the NES 2.0 identifier
+    pub fn format(&self) -> RomFormat {
+        if self.flags2.get(Flags2Enum::MAGIC) == 2 {
+            RomFormat::Nes2
+        } else {
+            RomFormat::INes
         }
     }
 }
 
-pub fn get_prg_rom_size(header: Header) -> usize {
-    if header.rom_size_msb.get(ROMSizeMSBEnum::PRG) == 0xF {
-        (2_u32.pow((header.prg_rom_size_lsb & 0xFC) as u32)
-            * ((header.prg_rom_size_lsb & 0x03) * 2 + 1) as u32) as usize
+// An MSB nibble of $F switches the LSB to exponent-multiplier notation, 2^E * (MM * 2 + 1) bytes
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0xF {
+        (1_usize << (lsb >> 2)).saturating_mul((lsb & 0x03) as usize * 2 + 1)
     } else {
-        let lsb = header.prg_rom_size_lsb as u16;
-        let msb = header.rom_size_msb.get(ROMSizeMSBEnum::PRG) as u16;
-        (msb << 8 | lsb) as usize * 16384
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+pub fn get_prg_rom_size(header: Header) -> usize {
+    match header.format() {
+        RomFormat::INes => header.prg_rom_size_lsb as usize * 16384,
+        RomFormat::Nes2 => nes2_rom_size(
+            header.prg_rom_size_lsb,
+            header.rom_size_msb.get(ROMSizeMSBEnum::PRG),
+            16384,
+        ),
     }
 }
 
 pub fn get_chr_rom_size(header: Header) -> usize {
-    let msb = header.rom_size_msb.get(ROMSizeMSBEnum::CHR) as usize;
-    let lsb = header.chr_rom_size_lsb as usize;
-    if header.rom_size_msb.get(ROMSizeMSBEnum::CHR) == 0xF {
-        (2_u32.pow((lsb & 0xFC) as u32) * ((msb & 0x03) * 2 + 1) as u32) as usize
-    } else {
-        (lsb | (msb << 8)) * 8192
+    match header.format() {
+        RomFormat::INes => header.chr_rom_size_lsb as usize * 8192,
+        RomFormat::Nes2 => nes2_rom_size(
+            header.chr_rom_size_lsb,
+            header.rom_size_msb.get(ROMSizeMSBEnum::CHR),
+            8192,
+        ),
     }
 }
 
@@ -273,33 +364,85 @@ pub struct NESFile {
     // Misc ROM Area
     pub misc_rom_area: Option<Vec<u8>>,
 
+    // Famicom Disk System image, in place of PRG and CHR
+    pub fds: Option<FdsImage>,
+
     pub hash: u64,
 }
 
 impl NESFile {
-    pub fn new(file_path: PathBuf) -> Self {
-        let bytes = std::fs::read(file_path).unwrap();
+    pub fn new(file_path: PathBuf) -> Result<Self, ParseError> {
+        let bytes = std::fs::read(file_path)?;
+        if FdsImage::is_fds(&bytes) {
+            let bios_path = Config::get_string_with_default("fds_bios", "disksys.rom");
+            let bios = std::fs::read(bios_path).map_err(ParseError::MissingFdsBios)?;
+            return Self::from_fds(&bytes, bios);
+        }
+        Self::from_bytes(bytes, Config::get_bool("pad_truncated_chr", false))
+    }
+
+    // With pad_truncated_chr, a CHR-ROM area cut short by a damaged dump is zero-filled up to the
+    // size the header claims instead of failing to load
+    pub fn from_bytes(bytes: Vec<u8>, pad_truncated_chr: bool) -> Result<Self, ParseError> {
         let file_size = bytes.len();
 
-        let header = Header::new(bytes[..16].try_into().unwrap());
+        let header_bytes = bytes.get(..16).ok_or(ParseError::BadMagic)?;
+        let header = Header::new(header_bytes.try_into().unwrap())?;
+        let prg_rom_size = get_prg_rom_size(header);
         let (trainer, prg_rom_pos) = match header.flags1.get(Flags1Enum::TRAINER) {
-            1 => (Some(bytes[16..528].try_into().unwrap()), 528),
-            _ => (None, 16),
+            1 => {
+                // The trainer sits in front of PRG-ROM, so a file cut short there has none of it
+                let trainer = bytes.get(16..528).ok_or(ParseError::TruncatedPrg {
+                    expected: prg_rom_size,
+                    found: 0,
+                })?;
+                (Some(trainer.try_into().unwrap()), 528)
+            }
+            _ => (None, 16_usize),
         };
 
-        let prg_rom_size = get_prg_rom_size(header);
-        let prg_rom_area = bytes[prg_rom_pos..prg_rom_pos + prg_rom_size].to_vec();
+        // NES 2.0's exponent sizes go up to usize::MAX, which no file can hold
+        let prg_rom_area = prg_rom_pos
+            .checked_add(prg_rom_size)
+            .and_then(|prg_rom_end| bytes.get(prg_rom_pos..prg_rom_end))
+            .ok_or(ParseError::TruncatedPrg {
+                expected: prg_rom_size,
+                found: file_size - prg_rom_pos,
+            })?
+            .to_vec();
 
         let chr_rom_pos = prg_rom_pos + prg_rom_size;
         let chr_rom_size = get_chr_rom_size(header);
 
+        let chr_rom_end = chr_rom_pos
+            .checked_add(chr_rom_size)
+            .ok_or(ParseError::TruncatedChr {
+                expected: chr_rom_size,
+                found: file_size - chr_rom_pos,
+            })?
+            .min(file_size);
         let chr_rom_area = if chr_rom_size > 0 {
-            Some(bytes[chr_rom_pos..chr_rom_pos + chr_rom_size].to_vec())
+            let mut chr_rom_area = bytes[chr_rom_pos..chr_rom_end].to_vec();
+            if chr_rom_area.len() < chr_rom_size {
+                if !pad_truncated_chr {
+                    return Err(ParseError::TruncatedChr {
+                        expected: chr_rom_size,
+                        found: chr_rom_area.len(),
+                    });
+                }
+                println!(
+                    "CHR-ROM is truncated, padding {} bytes to {}",
+                    chr_rom_area.len(),
+                    chr_rom_size
+                );
+                chr_rom_area.resize(chr_rom_size, 0);
+            }
+            Some(chr_rom_area)
         } else {
             None
         };
 
-        let misc_rom_pos = chr_rom_pos + chr_rom_size;
+        let misc_rom_pos = chr_rom_end;
         let misc_rom_area = if misc_rom_pos < file_size {
             Some(bytes[misc_rom_pos..file_size].to_vec())
         } else {
@@ -309,17 +452,146 @@ impl NESFile {
         let mut hasher = DefaultHasher::new();
         bytes.hash(&mut hasher);
 
-        NESFile {
+        let file = NESFile {
             header,
             trainer,
             prg_rom_area,
             chr_rom_area,
             misc_rom_area,
+            fds: None,
+            hash: hasher.finish(),
+        };
+        if !MapperFactory::supports(file.mapper_num()) {
+            return Err(ParseError::UnsupportedMapper(file.mapper_num()));
+        }
+        Ok(file)
+    }
+
+    // A headerless dump, given a NES 2.0 header with 8K of PRG-RAM, and 8K of CHR-RAM when there's
+    // no CHR-ROM. A header can't ask for single screen mirroring, so that falls back to horizontal
+    // for mappers that don't set it themselves
+    pub fn from_raw(
+        prg: Vec<u8>,
+        chr: Option<Vec<u8>>,
+        mapper: u16,
+        mirroring: Mirroring,
+    ) -> Result<Self, ParseError> {
+        let chr = chr.unwrap_or_default();
+        let (prg_banks, chr_banks) = (prg.len() / 0x4000, chr.len() / 0x2000);
+        if prg.is_empty()
+            || !prg.len().is_multiple_of(0x4000)
+            || !chr.len().is_multiple_of(0x2000)
+        {
+            return Err(ParseError::UnalignedRawRom {
+                prg: prg.len(),
+                chr: chr.len(),
+            });
+        }
+
+        let mirroring_flags = match mirroring {
+            Mirroring::Vertical => 0x01,
+            Mirroring::FourScreen => 0x08,
+            Mirroring::Horizontal | Mirroring::SingleScreenA | Mirroring::SingleScreenB => 0x00,
+        };
+        let mut bytes = vec![
+            NES_MAGIC[0],
+            NES_MAGIC[1],
+            NES_MAGIC[2],
+            NES_MAGIC[3],
+            prg_banks as u8,
+            chr_banks as u8,
+            (mapper as u8 & 0x0f) << 4 | mirroring_flags,
+            mapper as u8 & 0xf0 | 0x08,
+            (mapper >> 8) as u8 & 0x0f,
+            ((chr_banks >> 8) as u8 & 0x0f) << 4 | (prg_banks >> 8) as u8 & 0x0f,
+            0x07,
+            if chr.is_empty() { 0x07 } else { 0x00 },
+            0,
+            0,
+            0,
+            0,
+        ];
+        bytes.extend(prg);
+        bytes.extend(chr);
+        Self::from_bytes(bytes, false)
+    }
+
+    // A disk image and BIOS, under a NES 2.0 header for mapper 20 with no PRG or CHR so that the
+    // rest of the loading code has something to look at
+    pub fn from_fds(bytes: &[u8], bios: Vec<u8>) -> Result<Self, ParseError> {
+        let image = FdsImage::parse(bytes, bios)?;
+        let mut header = [0; 16];
+        header[0..4].copy_from_slice(&NES_MAGIC);
+        header[6] = 0x40;
+        header[7] = 0x18;
+
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+
+        Ok(NESFile {
+            header: Header::new(header)?,
+            trainer: None,
+            prg_rom_area: Vec::new(),
+            chr_rom_area: None,
+            misc_rom_area: None,
+            fds: Some(image),
             hash: hasher.finish(),
+        })
+    }
+
+    pub fn format(&self) -> RomFormat {
+        self.header.format()
+    }
+
+    pub fn submapper_num(&self) -> u8 {
+        match self.format() {
+            RomFormat::INes => 0,
+            RomFormat::Nes2 => self.header.mapper_msb.get(MapperMSBEnum::SUBMAPPER_NUM),
+        }
+    }
+
+    // Low nibble from flags 6, middle from flags 7, and for NES 2.0 the high nibble from byte 8
+    pub fn mapper_num(&self) -> u16 {
+        let msb = match self.format() {
+            RomFormat::INes => 0,
+            RomFormat::Nes2 => self.header.mapper_msb.get(MapperMSBEnum::MAPPER_NUM) as u16,
+        };
+        msb << 8
+            | (self.header.flags2.get(Flags2Enum::MAPPER_NUM) as u16) << 4
+            | self.header.flags1.get(Flags1Enum::MAPPER_NUM) as u16
+    }
+
+    // Hardwired mirroring from flags 6. Mappers with their own mirroring control ignore this
+    pub fn mirroring(&self) -> Mirroring {
+        if self.header.flags1.get(Flags1Enum::FOUR_SCREEN_MODE) == 1 {
+            Mirroring::FourScreen
+        } else if self.header.flags1.get(Flags1Enum::NAME_TABLE_MIRROR) == 1 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
+    // NES 2.0 has the timing in byte 12, iNES 1.0 just a rarely set PAL bit in byte 9
+    pub fn region(&self) -> Region {
+        if self.format() == RomFormat::Nes2 {
+            Region::from_timing(self.header.timing.get())
+        } else if self
+            .header
+            .rom_size_msb
+            .get(ROMSizeMSBEnum::INES_TV_SYSTEM)
+            == 1
+        {
+            Region::Pal
+        } else {
+            Region::Ntsc
         }
     }
 
     pub fn get_prg_ram_size(&self) -> usize {
+        if self.format() == RomFormat::INes {
+            return 0;
+        }
         let shift_count = self
             .header
             .prg_ram_eeprom_size
@@ -330,7 +602,33 @@ impl NESFile {
         64 << shift_count
     }
 
+    // iNES 1.0 has no field for it, so 8K is assumed whenever there's no CHR-ROM
+    pub fn get_chr_ram_size(&self) -> usize {
+        if self.format() == RomFormat::INes {
+            return if self.chr_rom_area.is_none() { 0x2000 } else { 0 };
+        }
+        let shift_count = self.header.chr_ram_size.get(CHRRAMSizeEnum::CHR_RAM_SIZE);
+        if shift_count == 0 {
+            return 0;
+        }
+        64 << shift_count
+    }
+
+    pub fn get_chr_nvram_size(&self) -> usize {
+        if self.format() == RomFormat::INes {
+            return 0;
+        }
+        let shift_count = self.header.chr_ram_size.get(CHRRAMSizeEnum::CHR_NVRAM_SIZE);
+        if shift_count == 0 {
+            return 0;
+        }
+        64 << shift_count
+    }
+
     pub fn get_eeprom_size(&self) -> usize {
+        if self.format() == RomFormat::INes {
+            return 0;
+        }
         let shift_count = self
             .header
             .prg_ram_eeprom_size
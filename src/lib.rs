@@ -14,3 +14,38 @@ pub mod config;
 pub mod core;
 pub mod frontend;
 pub mod ines_parser;
+
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::core::{bus::Bus, cpu::CPU};
+use crate::ines_parser::{NESFile, ParseError};
+
+// Runs a ROM with no frontend for a number of frames and returns the hash of the last one. Stops
+// early once the CPU halts or hits the max_cycles config key
+pub fn run_headless(rom_path: &str, frames: u64) -> Result<u64, ParseError> {
+    let rom = NESFile::new(Path::new(rom_path).to_path_buf())?;
+    let mut cpu = CPU::new(Bus::new(&rom));
+    cpu.reset();
+    for _ in 0..frames {
+        cpu.run_until_frame();
+    }
+    Ok(cpu.get_frame_hash())
+}
+
+// Times `frames` frames run back to back with nothing drawn, played or waited on, for profiling
+// the core. Loading the ROM isn't counted. Samples are still mixed and thrown away, since that's
+// part of what the APU costs
+pub fn benchmark(rom_path: &str, frames: u64) -> Result<Duration, ParseError> {
+    let rom = NESFile::new(Path::new(rom_path).to_path_buf())?;
+    let mut cpu = CPU::new(Bus::new(&rom));
+    cpu.reset();
+    let mut samples = Vec::new();
+    let start = Instant::now();
+    for _ in 0..frames {
+        cpu.run_until_frame();
+        cpu.bus.apu.end_frame(&mut samples);
+        samples.clear();
+    }
+    Ok(start.elapsed())
+}
@@ -4,14 +4,7 @@ macro_rules! integration_tests {
             #[test]
             fn $name() {
                 let (file, frames, hash) = $value;
-                let rom = NESFile::new(Path::new(file).to_path_buf());
-                let bus = Bus::new(&rom);
-                let mut cpu = CPU::new(bus);
-                cpu.reset();
-                for _ in 0..frames {
-                    cpu.run_until_frame();
-                }
-                let actual = cpu.get_frame_hash();
+                let actual = nes::run_headless(file, frames).unwrap();
                 assert_eq!(actual, hash, "Actual hash was {}", actual);
             }
         )*
@@ -19,14 +12,58 @@ macro_rules! integration_tests {
 }
 
 mod tests {
-    use nes::core::bus::Bus;
-    use nes::core::cpu::CPU;
-    use nes::ines_parser::NESFile;
+    use image::Rgb;
+    use nes::core::apu::base_channel::{AudioChannel, ChannelDebug};
+    use nes::core::apu::dmc::DMC;
+    use nes::core::apu::filter::FilterChain;
+    use nes::core::apu::{ApuIrqState, APU};
+    use nes::core::bus::{Bus, RamInit};
+    use nes::core::cheats::Cheat;
+    use nes::core::console::{Console, ConsoleError, FrameMetrics, FrameTimer};
+    use nes::core::cpu::debugger::{BreakEvent, Registers};
+    use nes::core::cpu::op::OPS;
+    use nes::core::cpu::tracer::TraceFormat;
+    use nes::core::cpu::{AddressingMode, InvalidOpBehavior, CPU};
+    use nes::core::fds::{FdsImage, BIOS_SIZE, SIDE_SIZE};
+    use nes::core::frame::{Frame, NTSC_WIDTH};
+    use nes::core::joypad::{Buttons, Player};
+    use nes::core::mappers::cnrom::CNROM;
+    use nes::core::mappers::fme7::FME7;
+    use nes::core::mappers::mmc1::MMC1;
+    use nes::core::mappers::mmc2::MMC2;
+    use nes::core::mappers::nrom::NROM;
+    use nes::core::mappers::vrc6::{VRC6a, VRC6b};
+    use nes::core::mappers::{CartridgeConfig, Mapper, MapperFactory, Mirroring};
+    use nes::core::ppu::palettes::Palette;
+    use nes::core::ppu::PPU;
+    use nes::core::zapper::Zapper;
+    use nes::core::Region;
+    use nes::frontend::wav::WavWriter;
+    use nes::ines_parser::{NESFile, ParseError, RomFormat};
+    use std::collections::HashSet;
+    use std::f64::consts::PI;
     use std::path::Path;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn new_ppu(file: &str) -> PPU {
+        let rom = NESFile::new(Path::new(file).to_path_buf()).unwrap();
+        PPU::new(Arc::new(Mutex::new(MapperFactory::from_file(&rom))))
+    }
+
+    // Runs the PPU in small steps so delayed register updates (e.g. $2006) take effect
+    fn step_ppu(ppu: &mut PPU, clock: &mut u64, master_clocks: u64) {
+        *clock += master_clocks;
+        ppu.run_to(*clock);
+    }
 
     integration_tests! {
         // CPU TESTS -------------------------------------------------------------------------------
         instr_test_v5: ("tests/instr_test-v5/all_instrs.nes", 2398, 13190525789780138270);
+        // The singles that cover LAS/TAS/AHX ($BB, $9B, $9F) and AHX ($93)
+        instr_test_v5_abs_xy: ("tests/instr_test-v5/rom_singles/07-abs_xy.nes", 365, 15475618005286250385);
+        instr_test_v5_ind_y: ("tests/instr_test-v5/rom_singles/09-ind_y.nes", 137, 12022834498478185321);
         cpu_dummy_writes_oam: ("tests/cpu_dummy_writes/cpu_dummy_writes_oam.nes", 329, 18226267073703253929);
         cpu_dummy_writes_ppumem: ("tests/cpu_dummy_writes/cpu_dummy_writes_ppumem.nes", 234, 17557076518018075713);
         cpu_exec_space_ppuio: ("tests/cpu_exec_space/test_cpu_exec_space_ppuio.nes", 43, 7085559936242306659);
@@ -40,6 +77,7 @@ mod tests {
         ppu_vbl_nmi: ("tests/ppu_vbl_nmi/ppu_vbl_nmi.nes", 1624, 3000831971158866996);
         ppu_read_buffer: ("tests/ppu_read_buffer/test_ppu_read_buffer.nes", 1269, 10957719060148031592);
         oam_stress: ("tests/oam_stress/oam_stress.nes", 1703, 60536158850127617);
+        oam_read: ("tests/oam_read/oam_read.nes", 30, 8751641062757564021);
 
         sprite_hit_basics: ("tests/sprite_hit_tests_2005.10.05/01.basics.nes", 32, 4669044134520954011);
         sprite_hit_alignment: ("tests/sprite_hit_tests_2005.10.05/02.alignment.nes", 31, 4554223117083026616);
@@ -65,10 +103,23 @@ mod tests {
         m1_p128k_c128k_s8k: ("tests/holy-mapperel/M1_P128K_C128K_S8K.nes", 83, 1836053688703264546);
         m1_p128k_c128k_w8k: ("tests/holy-mapperel/M1_P128K_C128K_W8K.nes", 83, 1836053688703264546);
         m1_p128k_cr8k: ("tests/holy-mapperel/M1_P128K_CR8K.nes", 78, 993067101538369690);
+        m1_p512k_cr8k_s8k: ("tests/holy-mapperel/M1_P512K_CR8K_S8K.nes", 156, 464504638001816925);
+        m1_p512k_cr8k_s32k: ("tests/holy-mapperel/M1_P512K_CR8K_S32K.nes", 381, 17607016021228182763);
+
+        m2_p128k_cr8k_v: ("tests/holy-mapperel/M2_P128K_CR8K_V.nes", 78, 14670854750832270444);
 
         m3_p32k_c32k_h: ("tests/holy-mapperel/M3_P32K_C32K_H.nes", 6, 12112331729405102634);
 
+        m7_p128k_cr8k: ("tests/holy-mapperel/M7_P128K_CR8K.nes", 78, 8521262014816800142);
+
+        m9_p128k_c64k: ("tests/holy-mapperel/M9_P128K_C64K.nes", 6, 176226381917582622);
+
+        m69_p128k_c64k_s8k: ("tests/holy-mapperel/M69_P128K_C64K_S8K.nes", 83, 7219651142909354205);
+        m69_p128k_c64k_w8k: ("tests/holy-mapperel/M69_P128K_C64K_W8K.nes", 83, 7219651142909354205);
+
         // APU TESTS -------------------------------------------------------------------------------
+        // These print a result code and nothing else, so every one that passes shows the same "$01"
+        // screen
         len_ctr: ("tests/blargg_apu_2005.07.30/01.len_ctr.nes", 26, 3301376315147960416);
         len_table: ("tests/blargg_apu_2005.07.30/02.len_table.nes", 12, 3301376315147960416);
         irq_flag: ("tests/blargg_apu_2005.07.30/03.irq_flag.nes", 17, 3301376315147960416);
@@ -82,6 +133,2839 @@ mod tests {
         len_reload_timing: ("tests/blargg_apu_2005.07.30/11.len_reload_timing.nes", 17, 3301376315147960416);
     }
 
+    #[test]
+    fn greyscale_with_emphasis() {
+        let mut ppu = new_ppu("tests/holy-mapperel/M0_P32K_C8K_V.nes");
+        let mut clock = 0;
+
+        // Backdrop = $16 (red), then point v away from the palette so it's drawn normally
+        ppu.write_ppuaddr(0x3f);
+        ppu.write_ppuaddr(0x00);
+        step_ppu(&mut ppu, &mut clock, 40);
+        ppu.write_ppudata(0x16);
+        ppu.write_ppuaddr(0x20);
+        ppu.write_ppuaddr(0x00);
+        step_ppu(&mut ppu, &mut clock, 40);
+
+        // Greyscale + red emphasis
+        ppu.write_ppumask(0x21);
+        step_ppu(&mut ppu, &mut clock, 341 * 240 * 4);
+
+        let grey = Palette::default().system_palette[0x10].0;
+        let idx = (100 * 256 + 100) * 3;
+        let actual = &ppu.curr_frame.image[idx..idx + 3];
+        let expected = [
+            grey[0],
+            (grey[1] as f32 * 0.746) as u8,
+            (grey[2] as f32 * 0.746) as u8,
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn mid_frame_emphasis_split() {
+        #[rustfmt::skip]
+        let mut program = vec![
+            0x2c, 0x02, 0x20, 0x10, 0xfb, // Wait out two vblanks for the PPU to warm up
+            0x2c, 0x02, 0x20, 0x10, 0xfb,
+            0xa9, 0x3f, 0x8d, 0x06, 0x20, // Backdrop = $16
+            0xa9, 0x00, 0x8d, 0x06, 0x20,
+            0xa9, 0x16, 0x8d, 0x07, 0x20,
+            0xa9, 0x20, 0x8d, 0x06, 0x20, // v away from the palette
+            0xa9, 0x00, 0x8d, 0x06, 0x20,
+            0x2c, 0x02, 0x20, 0x10, 0xfb, // $8023: wait for vblank
+            0xa9, 0x00, 0x8d, 0x01, 0x20, // No emphasis for the top of the frame
+            0xa0, 0x0b,                   // About 11 * 1284 cycles, to around scanline 100
+            0xa2, 0x00, 0xca, 0xd0, 0xfd,
+            0x88, 0xd0, 0xf8,
+            0xa9, 0x20, 0x8d, 0x01, 0x20, // Red emphasis for the rest of it
+            0x4c, 0x23, 0x80,
+        ];
+        let run = |program: &[u8]| {
+            let mut cpu = CPU::new(Bus::new(&nrom_with_program(program)));
+            cpu.reset();
+            for _ in 0..5 {
+                cpu.run_until_frame();
+            }
+            cpu.bus.ppu.curr_frame.clone()
+        };
+        let split = run(&program);
+        let pixel = |frame: &Frame, y: usize| {
+            let idx = (y * 256 + 128) * 3;
+            frame.image[idx..idx + 3].to_vec()
+        };
+        let row = (0..240).find(|&y| pixel(&split, y) != pixel(&split, 0)).unwrap();
+        assert!((80..130).contains(&row), "{row}");
+        assert!((row..240).all(|y| pixel(&split, y) == pixel(&split, 239)));
+
+        // Against the same program writing 0, only the part below the write changes
+        program[0x38] = 0x00;
+        let plain = run(&program);
+        assert_ne!(split.get_hash(), plain.get_hash());
+        assert_eq!(
+            split.get_hash_region(0, 0, 256, row - 1),
+            plain.get_hash_region(0, 0, 256, row - 1)
+        );
+        assert_ne!(
+            split.get_hash_region(0, row + 1, 256, 240 - row - 1),
+            plain.get_hash_region(0, row + 1, 256, 240 - row - 1)
+        );
+    }
+
+    #[test]
+    fn op_cycles_match_table() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        for op in OPS.iter() {
+            let mut cpu = CPU::new(Bus::new(&rom));
+            cpu.reset();
+
+            // Run each op from RAM with X = Y = 0 and operands that never cross a page
+            let start = 0x0200;
+            let operand: [u8; 2] = match op.addressing_mode {
+                AddressingMode::ZeroPage
+                | AddressingMode::ZeroPageX
+                | AddressingMode::ZeroPageY
+                | AddressingMode::IndexedIndirect
+                | AddressingMode::IndirectIndexed
+                | AddressingMode::IndirectIndexedW => [0x10, 0x00],
+                AddressingMode::Relative => [0x10, 0x00],
+                _ => [0x00, 0x03],
+            };
+            cpu.bus.write(start, op.hex, 0);
+            cpu.bus.write(start + 1, operand[0], 0);
+            cpu.bus.write(start + 2, operand[1], 0);
+            cpu.pc = start;
+
+            let before = cpu.cycle_count;
+            cpu.run();
+            let measured = cpu.cycle_count - before;
+
+            // Taken branches cost one more cycle, plus another if the target is on a new page
+            let mut expected = op.cycles as u64;
+            if let AddressingMode::Relative = op.addressing_mode {
+                let next = start + 2;
+                if cpu.pc != next {
+                    expected += 1 + u64::from(cpu.pc & 0xff00 != next & 0xff00);
+                }
+            }
+            assert_eq!(
+                measured, expected,
+                "{} ({:02x}) took {} cycles, expected {}",
+                op.name, op.hex, measured, expected
+            );
+        }
+    }
+
+    #[test]
+    fn oam_dma_read_is_open_bus() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+
+        // Leave something different on the PPU's latch so it can't be mistaken for the CPU's bus
+        bus.write(0x2003, 0xa5, 0);
+        bus.write(0x4014, 0x02, 0);
+        assert_eq!(bus.read(0x4014).0, 0x02);
+        assert_eq!(bus.read_trace(0x4014), 0x02);
+    }
+
+    #[test]
+    fn both_controllers_read_independently() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+        bus.joypad_for(Player::One).buttons = Buttons::A | Buttons::START;
+        bus.joypad_for(Player::Two).buttons = Buttons::B | Buttons::LEFT | Buttons::RIGHT;
+
+        bus.write(0x4016, 1, 0);
+        bus.write(0x4016, 0, 0);
+        let mut player1 = 0u8;
+        let mut player2 = 0u8;
+        for i in 0..8 {
+            player1 |= (bus.read(0x4016).0 & 1) << i;
+            player2 |= (bus.read(0x4017).0 & 1) << i;
+        }
+        assert_eq!(player1, (Buttons::A | Buttons::START).bits());
+        assert_eq!(player2, (Buttons::B | Buttons::LEFT | Buttons::RIGHT).bits());
+    }
+
+    #[test]
+    fn joypad_strobe_and_extra_reads() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+        bus.joypad_for(Player::One).buttons = Buttons::B | Buttons::RIGHT;
+
+        // The 8 buttons in order, then 1s from the 9th read on. Peeking doesn't shift anything
+        bus.write(0x4016, 1, 0);
+        bus.write(0x4016, 0, 0);
+        let mut reads = vec![];
+        for _ in 0..10 {
+            let peeked = bus.read_trace(0x4016) & 1;
+            let read = bus.read(0x4016).0 & 1;
+            assert_eq!(peeked, read);
+            reads.push(read);
+        }
+        assert_eq!(reads, [0, 1, 0, 0, 0, 0, 0, 1, 1, 1]);
+
+        // With the strobe held high every read is A
+        bus.joypad_for(Player::One).buttons = Buttons::A;
+        bus.write(0x4016, 1, 0);
+        assert!((0..10).all(|_| bus.read(0x4016).0 & 1 == 1));
+        bus.joypad_for(Player::One).buttons = Buttons::B;
+        assert!((0..10).all(|_| bus.read(0x4016).0 & 1 == 0));
+        // and dropping it starts from A again however many reads came before
+        bus.write(0x4016, 0, 0);
+        assert_eq!(bus.read(0x4016).0 & 1, 0);
+        assert_eq!(bus.read(0x4016).0 & 1, 1);
+    }
+
+    // Cartridge with a single register at $5000, like the expansion registers MMC5 and friends have
+    struct ExpansionMapper {
+        reg: u8,
+    }
+
+    impl Mapper for ExpansionMapper {
+        fn get_mirroring(&self) -> Mirroring {
+            Mirroring::Vertical
+        }
+
+        fn read_chr_rom(&self, _addr: u16) -> u8 {
+            0
+        }
+
+        fn read(&self, addr: u16) -> u8 {
+            match addr {
+                0x5000 => self.reg,
+                _ => 0,
+            }
+        }
+
+        fn write(&mut self, addr: u16, data: u8) {
+            if addr == 0x5000 {
+                self.reg = data;
+            }
+        }
+
+        fn write_nametable_idx(&mut self, _idx: usize, _addr: u16, _val: u8) {}
+
+        fn read_nametable_idx(&self, _idx: usize, _addr: u16) -> u8 {
+            0
+        }
+    }
+
+    #[test]
+    fn mapper_sees_expansion_area() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+        bus.mapper = Arc::new(Mutex::new(Box::new(ExpansionMapper { reg: 0 })));
+
+        bus.write(0x5000, 0x42, 0);
+        assert_eq!(bus.read(0x5000).0, 0x42);
+        assert_eq!(bus.read_trace(0x5000), 0x42);
+        assert_eq!(bus.read(0x4020).0, 0);
+    }
+
+    #[test]
+    fn force_mirroring() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+        let mut clock = 0;
+        assert_eq!(bus.mirroring(), Mirroring::Vertical);
+
+        bus.force_mirroring(Some(Mirroring::Horizontal));
+        assert_eq!(bus.mirroring(), Mirroring::Horizontal);
+        bus.ppu.write_ppuaddr(0x20);
+        bus.ppu.write_ppuaddr(0x00);
+        step_ppu(&mut bus.ppu, &mut clock, 40);
+        bus.ppu.write_ppudata(0x5a);
+        assert_eq!(bus.ppu.read_ppudata_trace(0x2400), 0x5a);
+        assert_eq!(bus.ppu.read_ppudata_trace(0x2800), 0x00);
+
+        bus.force_mirroring(None);
+        assert_eq!(bus.mirroring(), Mirroring::Vertical);
+        assert_eq!(bus.ppu.read_ppudata_trace(0x2800), 0x5a);
+
+        let mut console = Console::new(rom);
+        console.force_mirroring(Some(Mirroring::SingleScreenB));
+        assert_eq!(console.mirroring(), Mirroring::SingleScreenB);
+        console.force_mirroring(None);
+        assert_eq!(console.mirroring(), Mirroring::Vertical);
+    }
+
+    #[test]
+    fn palette_read_open_bus() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+        let mut clock = 0;
+        let mut set_addr = |bus: &mut Bus, addr: u16| {
+            bus.write(0x2006, (addr >> 8) as u8, 0);
+            bus.write(0x2006, addr as u8, 0);
+            step_ppu(&mut bus.ppu, &mut clock, 40);
+        };
+
+        // $3F00 reads fill the buffer from the nametable underneath it, at $2F00
+        set_addr(&mut bus, 0x2f00);
+        bus.write(0x2007, 0x77, 0);
+        set_addr(&mut bus, 0x3f00);
+        bus.write(0x2007, 0x16, 0);
+        set_addr(&mut bus, 0x3f00);
+
+        bus.write(0x2003, 0xa5, 0);
+        assert_eq!(bus.read(0x2007).0, 0x16 | (0xa5 & 0xc0));
+        assert_eq!(bus.ppu.read_ppudata_trace(0x2007), 0x77);
+    }
+
+    #[test]
+    fn hash_region() {
+        let mut a = Frame::new();
+        a.set_pixel(10, 10, Rgb([1, 2, 3]));
+        let mut b = a;
+        b.set_pixel(100, 50, Rgb([255, 255, 255]));
+
+        assert_eq!(a.get_hash_region(0, 0, 256, 240), a.get_hash());
+        assert_eq!(a.get_hash_region(0, 0, 100, 240), b.get_hash_region(0, 0, 100, 240));
+        assert_ne!(a.get_hash_region(96, 48, 8, 8), b.get_hash_region(96, 48, 8, 8));
+        // Off the edge of the screen is clipped off
+        assert_eq!(a.get_hash_region(200, 0, 100, 300), a.get_hash_region(200, 0, 56, 240));
+
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..10 {
+            cpu.run_until_frame();
+        }
+        assert_eq!(cpu.get_frame_hash_region(0, 0, 256, 240), cpu.get_frame_hash());
+    }
+
+    #[test]
+    fn hash_masked_ignores_region() {
+        let mut a = Frame::new();
+        a.set_pixel(10, 10, Rgb([1, 2, 3]));
+        let mut b = a;
+        b.set_pixel(100, 50, Rgb([255, 255, 255]));
+
+        assert_eq!(a.hash_masked(&[]), a.get_hash());
+        assert_ne!(a.get_hash(), b.get_hash());
+        assert_eq!(a.hash_masked(&[(96, 48, 8, 8)]), b.hash_masked(&[(96, 48, 8, 8)]));
+        assert_ne!(a.hash_masked(&[(0, 0, 8, 8)]), b.hash_masked(&[(0, 0, 8, 8)]));
+    }
+
+    #[test]
+    fn frame_to_rgba() {
+        let mut frame = Frame::new();
+        frame.set_pixel(0, 0, Rgb([1, 2, 3]));
+        frame.set_pixel(255, 239, Rgb([4, 5, 6]));
+        let (width, height) = Frame::size();
+
+        let rgba = frame.to_rgba();
+        assert_eq!(rgba.len(), width * height * 4);
+        assert_eq!(rgba[..8], [1, 2, 3, 0xff, 0, 0, 0, 0xff]);
+        assert_eq!(rgba[rgba.len() - 4..], [4, 5, 6, 0xff]);
+        let rgb: Vec<u8> = rgba.chunks_exact(4).flat_map(|pixel| pixel[..3].to_vec()).collect();
+        assert_eq!(rgb, frame.image);
+
+        // Anything past the picture in a bigger buffer is left alone
+        let mut buffer = vec![7; width * height * 4 + 2];
+        frame.write_rgba(&mut buffer);
+        assert_eq!(buffer[..width * height * 4], rgba);
+        assert_eq!(buffer[width * height * 4..], [7, 7]);
+    }
+
+    #[test]
+    fn frame_diff_round_trip() {
+        let mut a = Frame::new();
+        a.set_pixel(10, 10, Rgb([1, 2, 3]));
+        let mut b = a;
+        b.set_pixel(100, 50, Rgb([255, 255, 255]));
+        b.set_pixel(255, 239, Rgb([0, 0, 9]));
+
+        assert!(a.diff(&a).is_empty());
+        assert_eq!(b.diff(&a), [(50 * 256 + 100, [255; 3]), (239 * 256 + 255, [0, 0, 9])]);
+        let mut c = a;
+        c.apply_diff(&b.diff(&a));
+        assert_eq!(c.image, b.image);
+
+        // Between real frames, reusing the one buffer
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        let mut remote = Frame::new();
+        let mut diff = vec![];
+        for _ in 0..10 {
+            cpu.run_until_frame();
+            cpu.bus.ppu.curr_frame.diff_into(&remote, &mut diff);
+            remote.apply_diff(&diff);
+            assert_eq!(remote.image, cpu.bus.ppu.curr_frame.image);
+        }
+        // Off the screen is dropped rather than panicking
+        remote.apply_diff(&[(256 * 240, [1; 3])]);
+        assert_eq!(remote.image, cpu.bus.ppu.curr_frame.image);
+    }
+
+    // 16K PRG, header claims 8K CHR but only 4K follows
+    fn truncated_chr_rom() -> Vec<u8> {
+        let mut bytes = vec![0x4e, 0x45, 0x53, 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(vec![0xea; 0x4000]);
+        bytes.extend(vec![0x55; 0x1000]);
+        bytes
+    }
+
+    #[test]
+    fn truncated_chr_is_padded() {
+        let rom = NESFile::from_bytes(truncated_chr_rom(), true).unwrap();
+        let chr = rom.chr_rom_area.unwrap();
+        assert_eq!(chr.len(), 0x2000);
+        assert!(chr[..0x1000].iter().all(|&b| b == 0x55));
+        assert!(chr[0x1000..].iter().all(|&b| b == 0));
+        assert!(rom.misc_rom_area.is_none());
+    }
+
+    #[test]
+    fn truncated_chr_is_rejected() {
+        let err = NESFile::from_bytes(truncated_chr_rom(), false).unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::TruncatedChr {
+                expected: 0x2000,
+                found: 0x1000
+            }
+        ));
+    }
+
+    #[test]
+    fn bad_roms_are_rejected() {
+        let err = NESFile::new(Path::new("tests/missing.nes").to_path_buf()).unwrap_err();
+        assert!(matches!(err, ParseError::MissingFile(_)));
+        assert!(nes::run_headless("tests/missing.nes", 1).is_err());
+        assert!(nes::benchmark("tests/missing.nes", 1).is_err());
+
+        let err = NESFile::from_bytes(b"not a rom".to_vec(), false).unwrap_err();
+        assert!(matches!(err, ParseError::BadMagic));
+        let mut bytes = truncated_chr_rom();
+        bytes[0] = b'n';
+        assert!(matches!(NESFile::from_bytes(bytes, false), Err(ParseError::BadMagic)));
+
+        let mut bytes = truncated_chr_rom();
+        bytes.truncate(0x2010);
+        let err = NESFile::from_bytes(bytes, false).unwrap_err();
+        assert!(matches!(
+            err,
+            ParseError::TruncatedPrg {
+                expected: 0x4000,
+                found: 0x2000
+            }
+        ));
+
+        // Mapper 4 (MMC3) isn't implemented
+        let mut bytes = truncated_chr_rom();
+        bytes[6] = 0x40;
+        let err = NESFile::from_bytes(bytes, true).unwrap_err();
+        assert!(matches!(err, ParseError::UnsupportedMapper(4)));
+        assert_eq!(err.to_string(), "unsupported mapper 4");
+    }
+
+    #[test]
+    fn oversized_nes2_sizes_are_rejected() {
+        // Exponent-multiplier sizes that come out bigger than the address space
+        let mut prg = b"NES\x1a".to_vec();
+        prg.extend([0xfd, 0x01, 0x00, 0x08, 0x00, 0x0f, 0, 0, 0, 0, 0, 0]);
+        let err = NESFile::from_bytes(prg, true).unwrap_err();
+        assert!(matches!(err, ParseError::TruncatedPrg { expected: usize::MAX, found: 0 }));
+
+        let mut chr = b"NES\x1a".to_vec();
+        chr.extend([0x01, 0xfd, 0x00, 0x08, 0x00, 0xf0, 0, 0, 0, 0, 0, 0]);
+        chr.extend([0xea; 0x4000]);
+        for pad_truncated_chr in [false, true] {
+            let err = NESFile::from_bytes(chr.clone(), pad_truncated_chr).unwrap_err();
+            assert!(matches!(err, ParseError::TruncatedChr { expected: usize::MAX, found: 0 }));
+        }
+    }
+
+    #[test]
+    fn ines_and_nes2_sizes() {
+        let path = "tests/instr_test-v5/all_instrs.nes";
+        let rom = NESFile::new(Path::new(path).to_path_buf()).unwrap();
+        assert_eq!(rom.format(), RomFormat::INes);
+        assert_eq!(rom.prg_rom_area.len(), 0x40000);
+
+        // Junk where NES 2.0 keeps the size MSBs and submapper is ignored
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[8..16].copy_from_slice(b"DiskDude");
+        let rom = NESFile::from_bytes(bytes, false).unwrap();
+        assert_eq!(rom.format(), RomFormat::INes);
+        assert_eq!(rom.prg_rom_area.len(), 0x40000);
+        assert_eq!(rom.submapper_num(), 0);
+        assert_eq!(rom.get_prg_ram_size(), 0);
+
+        let path = "tests/holy-mapperel/M1_P128K_C32K_W8K.nes";
+        let rom = NESFile::new(Path::new(path).to_path_buf()).unwrap();
+        assert_eq!(rom.format(), RomFormat::Nes2);
+        assert_eq!(rom.prg_rom_area.len(), 0x20000);
+        assert_eq!(rom.get_prg_ram_size(), 0x2000);
+        assert_eq!(rom.chr_rom_area.unwrap().len(), 0x8000);
+    }
+
+    #[test]
+    fn fds_disk_drive() {
+        // One side holding just the disk info block and a file count of 0
+        let mut side = vec![0; SIDE_SIZE];
+        side[0] = 1;
+        side[1..15].copy_from_slice(b"*NINTENDO-HVC*");
+        side[56] = 2;
+        let mut bytes = b"FDS\x1a\x01".to_vec();
+        bytes.resize(16, 0);
+        bytes.extend(&side);
+        let mut bios = vec![0; BIOS_SIZE];
+        bios[0] = 0x4C;
+
+        assert!(FdsImage::is_fds(&bytes) && FdsImage::is_fds(&side));
+        assert!(matches!(
+            NESFile::from_fds(&bytes, vec![0; 0x1000]),
+            Err(ParseError::BadFdsBios(0x1000))
+        ));
+        assert!(matches!(
+            NESFile::from_fds(&bytes[..0x1000], bios.clone()),
+            Err(ParseError::BadFdsDisk)
+        ));
+        let file = NESFile::from_fds(&bytes, bios).unwrap();
+        assert_eq!(file.mapper_num(), 20);
+        assert_eq!(file.fds.as_ref().unwrap().sides.len(), 1);
+
+        let mut fds = MapperFactory::from_file(&file);
+        assert_eq!(fds.disk_sides(), 1);
+        assert_eq!(fds.read(0xE000), 0x4C);
+        fds.write(0x6000, 0x12);
+        assert_eq!(fds.read(0x6000), 0x12);
+
+        // Timer IRQ after reload + 1 cycles, acknowledged by reading $4030
+        fds.write(0x4020, 3);
+        fds.write(0x4021, 0);
+        fds.write(0x4022, 0x02);
+        for _ in 0..3 {
+            fds.clock_cpu();
+        }
+        assert!(!fds.irq_pending());
+        fds.clock_cpu();
+        assert!(fds.irq_pending());
+        assert_eq!(fds.read_cpu(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq_pending());
+
+        // Motor on, reading with IRQs. The start mark comes through quietly, then the block
+        assert_eq!(fds.read(0x4032) & 0x03, 0x02);
+        fds.write(0x4025, 0xC5);
+        let next_byte = |fds: &mut Box<dyn Mapper + Send>| {
+            while !fds.irq_pending() {
+                fds.clock_cpu();
+            }
+            assert_eq!(fds.read(0x4030) & 0x02, 0x02);
+            fds.read_cpu(0x4031)
+        };
+        assert_eq!(next_byte(&mut fds), 0x01);
+        assert_eq!(next_byte(&mut fds), b'*');
+        assert!(!fds.irq_pending());
+        assert_eq!(fds.read(0x4032) & 0x03, 0x00);
+
+        // The drive stays empty for a while after a swap
+        fds.insert_disk(Some(0));
+        assert_eq!(fds.inserted_disk(), Some(0));
+        assert_eq!(fds.read(0x4032) & 0x07, 0x07);
+        for _ in 0..2_000_000 {
+            fds.clock_cpu();
+        }
+        assert_eq!(fds.read(0x4032) & 0x01, 0x00);
+
+        let mut console = Console::new(file);
+        assert_eq!(console.disk_sides(), 1);
+        assert!(console.insert_disk(0).is_ok());
+        assert!(matches!(console.insert_disk(1), Err(ConsoleError::NoDiskSide(1))));
+    }
+
+    #[test]
+    fn chr_ram_sizes() {
+        let files = std::fs::read_dir("tests/holy-mapperel").unwrap();
+        let mut checked = 0;
+        for path in files.map(|entry| entry.unwrap().path()) {
+            // The CHR-RAM size is in the name, e.g. M0_P32K_CR32K_V.nes
+            let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+            let Some(size) = name.split('_').find_map(|part| part.strip_prefix("CR")) else {
+                continue;
+            };
+            let size = size.trim_end_matches('K').parse::<usize>().unwrap() * 0x400;
+            let rom = match NESFile::new(path) {
+                Ok(rom) => rom,
+                Err(ParseError::UnsupportedMapper(_)) => continue,
+                Err(err) => panic!("{name}: {err}"),
+            };
+            assert!(rom.chr_rom_area.is_none(), "{name}");
+            assert_eq!(rom.get_chr_ram_size(), size, "{name}");
+            assert_eq!(rom.get_chr_nvram_size(), 0, "{name}");
+
+            let mut mapper = MapperFactory::from_file(&rom);
+            mapper.write_chr_rom(0x1234, 0x56);
+            assert_eq!(mapper.read_chr_rom(0x1234), 0x56, "{name}");
+            checked += 1;
+        }
+        assert_eq!(checked, 7);
+
+        let nrom = NROM::new(CartridgeConfig {
+            prg_rom: vec![0; 0x8000],
+            chr_ram_size: 0x8000,
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+        assert_eq!(nrom.chr_rom.len(), 0x8000);
+        let nrom = NROM::new(CartridgeConfig {
+            prg_rom: vec![0; 0x8000],
+            chr_ram_size: 0x400,
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+        assert_eq!(nrom.chr_rom.len(), 0x2000);
+
+        // iNES 1.0 has nowhere to put it, so CHR-RAM is only assumed without CHR-ROM
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        assert_eq!(rom.get_chr_ram_size(), 0);
+        let mut bytes = std::fs::read("tests/nestest/nestest.nes").unwrap();
+        bytes[5] = 0;
+        bytes.truncate(16 + 0x4000);
+        let rom = NESFile::from_bytes(bytes, false).unwrap();
+        assert_eq!(rom.get_chr_ram_size(), 0x2000);
+    }
+
+    // 16K PRG, 8K CHR cartridge with the mapper number spread across flags 6, 7 and byte 8
+    fn rom_with_mapper(mapper_num: u16, nes2: bool) -> Result<NESFile, ParseError> {
+        let flags6 = (mapper_num as u8 & 0x0f) << 4;
+        let flags7 = (mapper_num as u8 & 0xf0) | if nes2 { 0x08 } else { 0 };
+        let byte8 = (mapper_num >> 8) as u8;
+        let mut bytes = vec![0x4e, 0x45, 0x53, 0x1a, 1, 1, flags6, flags7, byte8];
+        bytes.resize(16, 0);
+        bytes.extend(vec![0; 0x6000]);
+        NESFile::from_bytes(bytes, false)
+    }
+
+    #[test]
+    fn mapper_numbers() {
+        for mapper_num in [0, 1, 7, 9, 24, 26, 69] {
+            for nes2 in [false, true] {
+                assert_eq!(rom_with_mapper(mapper_num, nes2).unwrap().mapper_num(), mapper_num);
+            }
+        }
+
+        // Mapper 69 plus a high nibble only exists in NES 2.0, iNES 1.0 ignores byte 8
+        assert_eq!(rom_with_mapper(0x145, false).unwrap().mapper_num(), 69);
+        assert!(matches!(
+            rom_with_mapper(0x145, true),
+            Err(ParseError::UnsupportedMapper(0x145))
+        ));
+        assert!(matches!(rom_with_mapper(4, true), Err(ParseError::UnsupportedMapper(4))));
+    }
+
+    // Clocks the APU for a number of NTSC frames, collecting its output
+    fn run_apu(bus: &mut Bus, frames: usize) -> Vec<i16> {
+        let mut samples = vec![];
+        for _ in 0..frames {
+            for _ in 0..29781 {
+                bus.apu.clock();
+            }
+            bus.apu.output_buffer.end_frame(&mut samples);
+        }
+        samples
+    }
+
+    #[test]
+    fn benchmark_runs_frames() {
+        // Long enough that the audio would overflow if it weren't being drained
+        let elapsed = nes::benchmark("tests/nestest/nestest.nes", 300).unwrap();
+        assert!(elapsed > Duration::ZERO);
+        assert!(nes::benchmark("tests/nestest/nestest.nes", 0).unwrap() < elapsed);
+    }
+
+    #[test]
+    fn frame_timer_metrics() {
+        let mut timer = FrameTimer::new();
+        assert_eq!(timer.metrics(), FrameMetrics::default());
+
+        let ms = Duration::from_millis;
+        let deadline = ms(16);
+        timer.record(ms(20), deadline);
+        for _ in 0..FrameTimer::WINDOW - 1 {
+            timer.record(ms(10), deadline);
+        }
+        let metrics = timer.metrics();
+        assert_eq!(metrics.max, ms(20));
+        assert_eq!(metrics.average, (ms(20) + ms(10) * 59) / 60);
+        assert_eq!(metrics.missed_deadlines, 1);
+
+        // The slow frame drops out of the window, but stays counted as missed
+        timer.record(ms(16), deadline);
+        let metrics = timer.metrics();
+        assert_eq!(metrics.max, ms(16));
+        assert_eq!(metrics.missed_deadlines, 1);
+    }
+
+    #[test]
+    fn registered_expansion_audio() {
+        let run_frame = |apu: &mut APU| {
+            let mut samples = vec![];
+            for _ in 0..29781 {
+                apu.clock();
+            }
+            apu.end_frame(&mut samples);
+            samples
+        };
+        let swing = |samples: &[i16]| {
+            *samples.iter().max().unwrap() as i32 - *samples.iter().min().unwrap() as i32
+        };
+
+        let mut apu = APU::new();
+        run_frame(&mut apu);
+        let silent = run_frame(&mut apu);
+        let last_cycle = Arc::new(AtomicU64::new(0));
+        let seen = last_cycle.clone();
+        // Square wave at about the level of a full volume 2A03 pulse
+        apu.register_expansion(move |cycle| {
+            seen.store(cycle, Ordering::Relaxed);
+            if (cycle / 2000).is_multiple_of(2) {
+                750
+            } else {
+                0
+            }
+        });
+        let loud = run_frame(&mut apu);
+        assert_eq!(last_cycle.load(Ordering::Relaxed), 3 * 29781);
+        assert!(swing(&loud) > swing(&silent) + 500);
+
+        apu.clear_expansions();
+        run_frame(&mut apu);
+        assert!(swing(&run_frame(&mut apu)) < 100);
+    }
+
+    #[test]
+    fn output_sample_rate_change() {
+        let mut apu = APU::new();
+        let run_frame = |apu: &mut APU| {
+            for _ in 0..29781 {
+                apu.clock();
+            }
+            let expected = apu.samples_available();
+            let mut samples = vec![];
+            apu.output_buffer.end_frame(&mut samples);
+            assert_eq!(samples.len(), expected);
+            assert_eq!(apu.samples_available(), 0);
+            samples.len()
+        };
+        assert!((798..=800).contains(&run_frame(&mut apu)));
+
+        // Half a frame at the old rate is dropped
+        for _ in 0..15000 {
+            apu.clock();
+        }
+        assert!(apu.samples_available() > 0);
+        apu.set_output_sample_rate(44100.);
+        assert_eq!(apu.samples_available(), 0);
+        assert!((733..=735).contains(&run_frame(&mut apu)));
+        assert!((733..=735).contains(&run_frame(&mut apu)));
+    }
+
+    #[test]
+    fn pulse_length_counter_clocks_on_half_frames() {
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut bus = Bus::new(&rom);
+        bus.write(0x4015, 0x03, 0);
+        // Constant volume 5 on both, but pulse 2 has its length counter halted. A length of 10
+        bus.write(0x4000, 0x15, 0);
+        bus.write(0x4002, 0x80, 0);
+        bus.write(0x4003, 0x01, 0);
+        bus.write(0x4004, 0x35, 0);
+        bus.write(0x4007, 0x00, 0);
+        bus.apu.clock();
+        let state = bus.apu.debug_state();
+        assert_eq!(
+            state.pulse1,
+            ChannelDebug {
+                length_counter: 10,
+                period: 0x180,
+                volume: 5,
+                enabled: true,
+            }
+        );
+        assert_eq!(state.pulse2.length_counter, 10);
+        assert!(!state.triangle.enabled);
+
+        // Two half frames per frame in the 4 step sequence, 14913 cycles apart
+        let mut decrements = vec![];
+        let mut length = 10;
+        for cycle in 0..29781 * 3 {
+            bus.apu.clock();
+            let state = bus.apu.debug_state();
+            assert_eq!(state.pulse2.length_counter, 10);
+            if state.pulse1.length_counter != length {
+                assert_eq!(state.pulse1.length_counter, length - 1);
+                length -= 1;
+                decrements.push(cycle);
+            }
+        }
+        assert_eq!(decrements.len(), 5);
+        for pair in decrements.windows(2) {
+            assert!((14912..=14918).contains(&(pair[1] - pair[0])), "{decrements:?}");
+        }
+
+        bus.write(0x4015, 0x00, 0);
+        assert_eq!(bus.apu.debug_state().pulse1.length_counter, 0);
+        assert!(!bus.apu.debug_state().pulse1.enabled);
+    }
+
+    #[test]
+    fn triangle_is_mixed() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+
+        // Halted with the shortest length (10 half frames), so it only keeps playing if the halt
+        // flag in $4008 bit 7 is honored
+        bus.write(0x4015, 0x04, 0);
+        bus.write(0x4008, 0xff, 0);
+        bus.write(0x400a, 0x40, 0);
+        bus.write(0x400b, 0x00, 0);
+
+        let samples = run_apu(&mut bus, 8);
+        let tail = &samples[samples.len() / 2..];
+        assert!(tail.iter().any(|&s| s != tail[0]));
+        assert_eq!(bus.read(0x4015).0 & 0x04, 0x04);
+
+        bus.write(0x4015, 0x00, 0);
+        assert_eq!(bus.read(0x4015).0 & 0x04, 0x00);
+    }
+
+    #[test]
+    fn noise_is_mixed() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+
+        // Constant volume 15, halted, in both normal and short mode
+        bus.write(0x4015, 0x08, 0);
+        bus.write(0x400c, 0x3f, 0);
+        bus.write(0x400f, 0x00, 0);
+        for period in [0x04, 0x84] {
+            bus.write(0x400e, period, 0);
+            let samples = run_apu(&mut bus, 4);
+            let tail = &samples[samples.len() / 2..];
+            assert!(tail.iter().any(|&s| s != tail[0]));
+            assert_eq!(bus.read(0x4015).0 & 0x08, 0x08);
+        }
+
+        bus.write(0x4015, 0x00, 0);
+        assert_eq!(bus.read(0x4015).0 & 0x08, 0x00);
+    }
+
+    #[test]
+    fn dmc_is_mixed() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+
+        // Play the start of PRG-ROM as a DPCM sample at the fastest rate, then spin
+        #[rustfmt::skip]
+        let program = [
+            0xa9, 0x0f, 0x8d, 0x10, 0x40, // LDA #$0F, STA $4010
+            0xa9, 0x00, 0x8d, 0x12, 0x40, // LDA #$00, STA $4012 ($C000)
+            0xa9, 0xff, 0x8d, 0x13, 0x40, // LDA #$FF, STA $4013 (4081 bytes)
+            0xa9, 0x10, 0x8d, 0x15, 0x40, // LDA #$10, STA $4015
+            0x4c, 0x14, 0x02,             // JMP $0214
+        ];
+        for (i, byte) in program.iter().enumerate() {
+            cpu.bus.write(0x0200 + i as u16, *byte, 0);
+        }
+        cpu.pc = 0x0200;
+
+        let mut samples = vec![];
+        let mut levels = vec![];
+        for _ in 0..4 {
+            cpu.run_until_frame();
+            cpu.bus.apu.output_buffer.end_frame(&mut samples);
+            levels.push(cpu.bus.apu.dmc.output_level);
+        }
+        assert!(levels.iter().any(|&l| l != levels[0]));
+        assert!(samples.iter().any(|&s| s != samples[0]));
+    }
+
+    #[test]
+    fn muted_channel_is_silent() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+
+        // Constant volume, halted squares at two different pitches
+        let setup = |enable: u8| {
+            let mut bus = Bus::new(&rom);
+            bus.write(0x4015, enable, 0);
+            bus.write(0x4000, 0xbf, 0);
+            bus.write(0x4002, 0x80, 0);
+            bus.write(0x4003, 0x00, 0);
+            bus.write(0x4004, 0xbf, 0);
+            bus.write(0x4006, 0x40, 0);
+            bus.write(0x4007, 0x01, 0);
+            bus
+        };
+
+        let mut both = setup(0x03);
+        both.apu.set_channel_enabled(AudioChannel::Pulse1, false);
+        assert!(!both.apu.is_channel_enabled(AudioChannel::Pulse1));
+        let mut pulse2_only = setup(0x02);
+
+        let samples = run_apu(&mut both, 4);
+        assert!(samples.iter().any(|&s| s != samples[0]));
+        assert_eq!(samples, run_apu(&mut pulse2_only, 4));
+        // Muting is mixer-only, pulse 1 is still running
+        assert_eq!(both.read(0x4015).0 & 0x01, 0x01);
+    }
+
+    #[test]
+    fn audio_filters_remove_dc_and_bass() {
+        let rate = APU::DEFAULT_SAMPLE_RATE;
+        // A steady level gets through at first, then is pulled back to nothing
+        let mut filters = FilterChain::new(rate);
+        let mut dc = vec![1000i16; rate as usize];
+        filters.process_samples(&mut dc);
+        assert!(dc[0] > 500, "{}", dc[0]);
+        assert!(dc[rate as usize / 10..].iter().all(|s| s.abs() <= 1));
+
+        // Peak of a sine once the filters have settled
+        let amplitude = |freq: f64| {
+            let mut filters = FilterChain::new(rate);
+            let mut sine: Vec<i16> = (0..rate as usize)
+                .map(|i| (10000. * (2. * PI * freq * i as f64 / rate).sin()) as i16)
+                .collect();
+            filters.process_samples(&mut sine);
+            sine[rate as usize / 2..].iter().map(|s| s.abs()).max().unwrap()
+        };
+        let (bass, mid, treble) = (amplitude(20.), amplitude(2000.), amplitude(16000.));
+        assert!(bass < 500, "20Hz came out at {bass}");
+        assert!(mid > 9000, "2kHz came out at {mid}");
+        assert!(treble < mid * 3 / 4, "16kHz came out at {treble}");
+
+        // Through the APU, a jump in the DMC's level dies away within a few ms rather than the
+        // BlipBuf's own much slower drift back to zero
+        let step = |filtered: bool| {
+            let mut apu = APU::new();
+            apu.set_filters_enabled(filtered);
+            apu.write_dmc_load(0x7f);
+            for _ in 0..29781 {
+                apu.clock();
+            }
+            let mut samples = vec![];
+            apu.end_frame(&mut samples);
+            (*samples.iter().max().unwrap(), samples[240])
+        };
+        let (peak, after_5ms) = step(false);
+        assert!(peak > 2000 && after_5ms > peak / 2);
+        let (peak, after_5ms) = step(true);
+        assert!(peak > 2000 && after_5ms.abs() < peak / 20);
+    }
+
+    #[test]
+    fn stereo_pans_pulse_channels() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+
+        // Pulse 1 only, as a constant volume square
+        let setup = |pan: Option<[f64; 2]>| {
+            let mut bus = Bus::new(&rom);
+            bus.apu.set_filters_enabled(false);
+            bus.apu.set_stereo(pan);
+            bus.write(0x4015, 0x01, 0);
+            bus.write(0x4000, 0xbf, 0);
+            bus.write(0x4002, 0x80, 0);
+            bus.write(0x4003, 0x00, 0);
+            bus
+        };
+        let run_frames = |bus: &mut Bus, frames: usize| {
+            let mut samples = vec![];
+            for _ in 0..frames {
+                for _ in 0..29781 {
+                    bus.apu.clock();
+                }
+                bus.apu.end_frame(&mut samples);
+            }
+            samples
+        };
+
+        let mut mono = setup(None);
+        assert!(!mono.apu.is_stereo());
+        let mono_samples = run_frames(&mut mono, 4);
+
+        let mut stereo = setup(Some([-1., 1.]));
+        assert!(stereo.apu.is_stereo());
+        let samples = run_frames(&mut stereo, 4);
+        assert_eq!(samples.len(), mono_samples.len() * 2);
+        let left: Vec<i16> = samples.iter().step_by(2).copied().collect();
+        let right: Vec<i16> = samples.iter().skip(1).step_by(2).copied().collect();
+        // Hard left keeps pulse 1 at full volume on the left and out of the right entirely
+        assert_eq!(left, mono_samples);
+        let mut muted = setup(None);
+        muted.apu.set_channel_enabled(AudioChannel::Pulse1, false);
+        assert_eq!(right, run_frames(&mut muted, 4));
+        assert_ne!(left, right);
+
+        // Centered sounds the same on both sides as mono does
+        let mut centered = setup(Some([0., 0.]));
+        let samples = run_frames(&mut centered, 4);
+        assert!(samples.chunks_exact(2).all(|lr| lr[0] == lr[1]));
+        assert_eq!(samples.iter().step_by(2).copied().collect::<Vec<_>>(), mono_samples);
+    }
+
+    #[test]
+    fn wav_recording_patches_sizes() {
+        let path = std::env::temp_dir().join("runes_wav_recording_test.wav");
+        let mut writer = WavWriter::create(&path, 44100, 1).unwrap();
+        writer.write_samples(&[0, 1, -1]).unwrap();
+        writer.write_samples(&[i16::MAX, i16::MIN]).unwrap();
+        writer.finish().unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(24), 44100);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(40), 10);
+        assert_eq!(u32_at(4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[44..], &[0, 0, 1, 0, 0xFF, 0xFF, 0xFF, 0x7F, 0x00, 0x80]);
+    }
+
+    #[test]
+    fn raw_rom_loads_like_headered() {
+        let path = "tests/nestest/nestest.nes";
+        let headered = NESFile::new(Path::new(path).to_path_buf()).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        let raw = NESFile::from_raw(
+            bytes[16..16 + 0x4000].to_vec(),
+            Some(bytes[16 + 0x4000..].to_vec()),
+            0,
+            Mirroring::Horizontal,
+        )
+        .unwrap();
+        assert_eq!(raw.format(), RomFormat::Nes2);
+        assert_eq!(raw.mapper_num(), 0);
+        assert_eq!(raw.mirroring(), Mirroring::Horizontal);
+        assert_eq!(raw.prg_rom_area, headered.prg_rom_area);
+        assert_eq!(raw.chr_rom_area, headered.chr_rom_area);
+
+        let mut expected = Console::new(headered);
+        let mut console = Console::new(raw);
+        for _ in 0..30 {
+            assert_eq!(
+                console.step_frame().get_hash(),
+                expected.step_frame().get_hash()
+            );
+        }
+
+        // No CHR-ROM means CHR-RAM, and a mapper number past 255 needs the NES 2.0 byte
+        let raw = NESFile::from_raw(vec![0; 0x4000], None, 0, Mirroring::FourScreen).unwrap();
+        assert_eq!(raw.chr_rom_area, None);
+        assert_eq!(raw.mirroring(), Mirroring::FourScreen);
+        let err = NESFile::from_raw(vec![0; 0x4000], None, 0x104, Mirroring::Horizontal);
+        assert!(matches!(err, Err(ParseError::UnsupportedMapper(0x104))));
+        let err = NESFile::from_raw(vec![0; 0x5000], None, 0, Mirroring::Horizontal);
+        assert!(matches!(
+            err,
+            Err(ParseError::UnalignedRawRom {
+                prg: 0x5000,
+                chr: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn unlimited_sprites() {
+        // JMP $C000 forever, with ten solid sprites side by side on the same lines
+        let mut prg = vec![0; 0x4000];
+        prg[..3].copy_from_slice(&[0x4c, 0x00, 0xc0]);
+        prg[0x3ffd] = 0xc0;
+        let rom = NESFile::from_raw(prg, None, 0, Mirroring::Horizontal).unwrap();
+        let drawn = |unlimited: bool| {
+            let mut console = Console::new(rom.clone());
+            console.cpu.bus.ppu.set_unlimited_sprites(unlimited);
+            for i in 0..8 {
+                console.cpu.bus.mapper.lock().unwrap().write_chr_rom(0x10 + i, 0xff);
+            }
+            console.cpu.bus.write(0x2003, 0x00, 0);
+            for i in 0..64 {
+                let sprite = if i < 10 { [100, 1, 0, i * 16] } else { [0xff; 4] };
+                for byte in sprite {
+                    console.cpu.bus.write(0x2004, byte, 0);
+                }
+            }
+            console.cpu.bus.write(0x2001, 0x14, 0);
+            console.step_frame();
+            let frame = console.step_frame();
+            let pixel = |x: usize| &frame.image[(104 * 256 + x) * 3..(104 * 256 + x) * 3 + 3];
+            (0..10).map(|i| pixel(i * 16 + 4) != pixel(i * 16 + 12)).collect::<Vec<_>>()
+        };
+
+        let mut expected = vec![true; 8];
+        expected.extend([false, false]);
+        assert_eq!(drawn(false), expected);
+        assert_eq!(drawn(true), vec![true; 10]);
+    }
+
+    #[test]
+    fn sprite_0_hit_edges() {
+        // Tile 0 is solid, so the whole background and sprite 0 are opaque
+        let mut prg = vec![0; 0x4000];
+        prg[..3].copy_from_slice(&[0x4c, 0x00, 0xc0]);
+        prg[0x3ffd] = 0xc0;
+        let rom = NESFile::from_raw(prg, None, 0, Mirroring::Horizontal).unwrap();
+        let hit = |x: u8, mask: u8| {
+            let mut console = Console::new(rom.clone());
+            for i in 0..8 {
+                console.cpu.bus.mapper.lock().unwrap().write_chr_rom(i, 0xff);
+            }
+            console.cpu.bus.write(0x2003, 0x00, 0);
+            for byte in [100, 0, 0, x] {
+                console.cpu.bus.write(0x2004, byte, 0);
+            }
+            console.cpu.bus.write(0x2001, mask, 0);
+            console.step_frame();
+            console.step_frame();
+            console.cpu.bus.ppu.sprite_0_hit_scanline()
+        };
+
+        assert_eq!(hit(0, 0x1e), Some(101));
+        assert_eq!(hit(254, 0x1e), Some(101));
+        assert_eq!(hit(255, 0x1e), None);
+        // Sprite 0 entirely within the left 8 pixels with either layer clipped there
+        assert_eq!(hit(0, 0x1c), None);
+        assert_eq!(hit(0, 0x1a), None);
+        assert_eq!(hit(0, 0x18), None);
+        assert_eq!(hit(1, 0x1a), Some(101));
+        assert_eq!(hit(0, 0x16), None);
+        assert_eq!(hit(0, 0x0e), None);
+    }
+
+    #[test]
+    fn console_step_frame() {
+        let rom = NESFile::new(
+            Path::new("tests/sprite_hit_tests_2005.10.05/01.basics.nes").to_path_buf(),
+        )
+        .unwrap();
+        let mut console = Console::new(rom);
+        for _ in 0..31 {
+            console.step_frame();
+        }
+        assert_eq!(console.step_frame().get_hash(), 4669044134520954011);
+
+        let pc = console.cpu.pc;
+        let master_clock = console.cpu.master_clock();
+        console.step_instruction();
+        assert_ne!(console.cpu.pc, pc);
+        assert!(console.cpu.master_clock() > master_clock);
+    }
+
+    #[test]
+    fn console_step_to_vblank() {
+        let rom = NESFile::new(
+            Path::new("tests/sprite_hit_tests_2005.10.05/01.basics.nes").to_path_buf(),
+        )
+        .unwrap();
+        let mut console = Console::new(rom);
+        let mut last_clock = None;
+        for _ in 0..5 {
+            console.step_to_vblank();
+            let ppu = &console.cpu.bus.ppu;
+            assert_eq!(ppu.scanline(), 241);
+            // Only as far past dot 1 as the instruction that crossed it
+            assert!((1..30).contains(&ppu.dot()), "dot {}", ppu.dot());
+
+            // Already in vblank, so the next call waits for the following frame
+            let clock = console.cpu.master_clock();
+            if let Some(last_clock) = last_clock {
+                let frame = 341 * 262 * 4;
+                assert!((frame - 100..frame + 100).contains(&(clock - last_clock)));
+            }
+            last_clock = Some(clock);
+        }
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let file = "tests/holy-mapperel/M1_P128K_C32K_S8K.nes";
+        let mut console = Console::new(NESFile::new(Path::new(file).to_path_buf()).unwrap());
+        for _ in 0..40 {
+            console.step_frame();
+        }
+        let state = console.save_state();
+        for _ in 0..41 {
+            console.step_frame();
+        }
+        let expected = console.step_frame().get_hash();
+        assert_eq!(expected, 14698170090460665526);
+
+        console.load_state(&state).unwrap();
+        for _ in 0..41 {
+            console.step_frame();
+        }
+        assert_eq!(console.step_frame().get_hash(), expected);
+
+        // States only load into a console running the same ROM
+        let other = "tests/sprite_hit_tests_2005.10.05/01.basics.nes";
+        let mut other = Console::new(NESFile::new(Path::new(other).to_path_buf()).unwrap());
+        assert!(other.load_state(&state).is_err());
+        assert!(other.load_state(&state[..8]).is_err());
+    }
+
+    #[test]
+    fn power_on_state_replays_across_ram_init() {
+        let file = "tests/sprite_hit_tests_2005.10.05/01.basics.nes";
+        let rom = NESFile::new(Path::new(file).to_path_buf()).unwrap();
+        let mut recorded = Console::new_with_ram_init(rom.clone(), RamInit::Seeded(1234));
+        let mut playback = Console::new_with_ram_init(rom, RamInit::Ones);
+        // RAM, the nametables and OAM all start out differently
+        let state = recorded.power_on_state();
+        assert_ne!(state, playback.power_on_state());
+
+        playback.load_state(&state).unwrap();
+        for _ in 0..10 {
+            assert_eq!(playback.step_frame().get_hash(), recorded.step_frame().get_hash());
+        }
+        assert_eq!(playback.save_state(), recorded.save_state());
+    }
+
+    #[test]
+    fn rewind_steps_back_through_frames() {
+        let file = "tests/holy-mapperel/M1_P128K_C32K_S8K.nes";
+        let mut console = Console::new(NESFile::new(Path::new(file).to_path_buf()).unwrap());
+        assert!(!console.rewind_step());
+
+        console.enable_rewind(1);
+        let hashes: Vec<u64> = (0..100).map(|_| console.step_frame().get_hash()).collect();
+
+        // One second of NTSC is 60 frames, so the current one plus 59 to step back through
+        for hash in hashes[40..99].iter().rev() {
+            assert!(console.rewind_step());
+            assert_eq!(console.frame().get_hash(), *hash);
+        }
+        assert!(!console.rewind_step());
+
+        // Play resumes from the rewound point
+        for _ in 0..41 {
+            console.step_frame();
+        }
+        assert_eq!(console.step_frame().get_hash(), hashes[82]);
+    }
+
+    #[test]
+    fn console_reset_and_power_cycle() {
+        let rom = NESFile::new(
+            Path::new("tests/sprite_hit_tests_2005.10.05/01.basics.nes").to_path_buf(),
+        )
+        .unwrap();
+        let mut console = Console::new(rom);
+        for _ in 0..20 {
+            console.step_frame();
+        }
+        console.power_cycle();
+        for _ in 0..31 {
+            console.step_frame();
+        }
+        assert_eq!(console.step_frame().get_hash(), 4669044134520954011);
+
+        // The test reruns from the reset vector and ends on the same screen
+        let reset_vector = console.cpu.bus.read_16_trace(0xfffc);
+        console.soft_reset();
+        assert_eq!(console.cpu.pc, reset_vector);
+        let master_clock = console.cpu.master_clock();
+        for _ in 0..40 {
+            console.step_frame();
+        }
+        assert!(console.cpu.master_clock() > master_clock);
+        assert_eq!(console.frame().get_hash(), 4669044134520954011);
+    }
+
+    #[test]
+    fn console_screenshot() {
+        let rom = NESFile::new(
+            Path::new("tests/sprite_hit_tests_2005.10.05/01.basics.nes").to_path_buf(),
+        )
+        .unwrap();
+        let mut console = Console::new(rom);
+        for _ in 0..32 {
+            console.step_frame();
+        }
+        let path = std::env::temp_dir().join("runes_screenshot.png");
+        console.screenshot(path.clone()).unwrap();
+
+        let png = image::open(&path).unwrap().into_rgb8();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(png.dimensions(), (256, 240));
+        assert_eq!(png.as_raw().as_slice(), console.frame().image.as_slice());
+    }
+
+    #[test]
+    fn ntsc_filter() {
+        // A flat color comes back out as itself
+        let mut frame = Frame::new();
+        for px in frame.image.chunks_mut(3) {
+            px.copy_from_slice(&[200, 80, 40]);
+        }
+        for phase in 0..3 {
+            let out = frame.apply_ntsc(phase);
+            assert_eq!(out.len(), NTSC_WIDTH * 240 * 3);
+            for px in out.chunks(3) {
+                for (actual, expected) in px.iter().zip([200u8, 80, 40]) {
+                    assert!(actual.abs_diff(expected) <= 2, "{px:?}");
+                }
+            }
+        }
+
+        // Edges between colors crawl from one phase to the next
+        let rom = NESFile::new(Path::new("tests/spritecans-2011/spritecans.nes").to_path_buf())
+            .unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..60 {
+            cpu.run_until_frame();
+        }
+        let frame = &cpu.bus.ppu.curr_frame;
+        assert_ne!(frame.apply_ntsc(0), frame.apply_ntsc(1));
+
+        // With rendering on, the skipped dot on odd frames makes the phase alternate between two
+        let phases: Vec<u8> = (0..4)
+            .map(|_| {
+                cpu.run_until_frame();
+                cpu.bus.ppu.color_phase()
+            })
+            .collect();
+        assert_ne!(phases[0], phases[1]);
+        assert_eq!(phases[0..2], phases[2..4]);
+    }
+
+    #[test]
+    fn overclock_keeps_frames_and_audio() {
+        let rom = NESFile::new(Path::new("tests/spritecans-2011/spritecans.nes").to_path_buf())
+            .unwrap();
+        let mut normal = CPU::new(Bus::new(&rom));
+        let mut fast = CPU::new(Bus::new(&rom));
+        fast.set_overclock(2.);
+        assert_eq!(fast.overclock(), 2.);
+        // Frames end as the post-render line starts, so the extra cycles come at the start of
+        // the next one
+        for cpu in [&mut normal, &mut fast] {
+            cpu.reset();
+            cpu.run_until_frame();
+        }
+        let mut samples = [vec![], vec![]];
+        for _ in 0..30 {
+            let mut cycles = [0, 0];
+            for (i, cpu) in [&mut normal, &mut fast].into_iter().enumerate() {
+                let start = cpu.cycle_count;
+                cpu.run_until_frame();
+                cycles[i] = cpu.cycle_count - start;
+                cpu.bus.apu.output_buffer.end_frame(&mut samples[i]);
+            }
+            // The extra cycles don't move the PPU, so every frame still comes out the same
+            assert_eq!(normal.get_frame_hash(), fast.get_frame_hash());
+            assert!(cycles[1].abs_diff(cycles[0] * 2) < 50, "{cycles:?}");
+        }
+        // Nor the APU, so there's no more sound than usual either
+        assert!(samples[0].len().abs_diff(samples[1].len()) <= 2);
+
+        fast.set_overclock(10.);
+        assert_eq!(fast.overclock(), 4.);
+    }
+
+    #[test]
+    fn load_palette() {
+        let default = Palette::default();
+        let bytes = std::fs::read("src/core/ppu/palettes/ntscpalette.pal").unwrap();
+        assert_eq!(
+            Palette::from_bytes(&bytes).unwrap().system_palette,
+            default.system_palette
+        );
+        assert!(Palette::from_bytes(&bytes[..191]).is_none());
+        let err = Palette::from_file("tests/nestest/nestest.nes").err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+
+        // Swapping the palette mid-run recolors the next frame
+        let rom = NESFile::new(Path::new("tests/spritecans-2011/spritecans.nes").to_path_buf())
+            .unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..30 {
+            cpu.run_until_frame();
+        }
+        let inverted: Vec<u8> = bytes.iter().map(|b| 255 - b).collect();
+        cpu.bus.ppu.set_palette(Palette::from_bytes(&inverted).unwrap());
+        cpu.run_until_frame();
+        let colors = &cpu.bus.ppu.palette().system_palette;
+        for px in cpu.bus.ppu.curr_frame.image.chunks(3) {
+            assert!(colors.iter().any(|c| c.0 == px), "{px:?}");
+        }
+    }
+
+    #[test]
+    fn reset_to_runs_from_given_pc() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        // LDX #$42, STX $10
+        for (i, byte) in [0xa2, 0x42, 0x86, 0x10].iter().enumerate() {
+            cpu.bus.write(0x0300 + i as u16, *byte, 0);
+        }
+        cpu.reset_to(0x0300);
+        assert_eq!(cpu.pc, 0x0300);
+        assert_eq!(cpu.sp, 0xFD);
+        assert_eq!(cpu.cycle_count, 7);
+
+        cpu.run();
+        cpu.run();
+        assert_eq!(cpu.pc, 0x0304);
+        assert_eq!(cpu.bus.read(0x10).0, 0x42);
+    }
+
+    #[test]
+    fn reset_to_runs_nestest_automation() {
+        // nestest started at $C000 runs every test without a PPU and leaves error codes in $02/$03
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset_to(0xC000);
+        for _ in 0..8990 {
+            cpu.run();
+        }
+        assert_eq!(cpu.pc, 0xC66E);
+        assert_eq!([cpu.bus.read_trace(0x02), cpu.bus.read_trace(0x03)], [0, 0]);
+    }
+
+    #[test]
+    fn memory_dumps() {
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut bus = Bus::new(&rom);
+        let mut clock = 0;
+        bus.write(0x0805, 0x12, 0);
+        // Vertical mirroring, so $2000 shows up again at $2800
+        for (addr, val) in [(0x2000, 0x34), (0x3f01, 0x16)] {
+            bus.write(0x2006, (addr >> 8) as u8, 0);
+            bus.write(0x2006, addr as u8, 0);
+            step_ppu(&mut bus.ppu, &mut clock, 40);
+            bus.write(0x2007, val, 0);
+        }
+
+        let ram = bus.cpu_ram();
+        assert_eq!(ram.len(), 0x800);
+        assert_eq!(ram[0x05], 0x12);
+        let nametables = bus.ppu.dump_nametables();
+        assert_eq!(nametables[0x000], 0x34);
+        assert_eq!(nametables[0x800], 0x34);
+        assert_eq!(nametables[0x400], 0x00);
+        assert_eq!(bus.ppu.dump_palette()[0x01], 0x16);
+    }
+
+    #[test]
+    fn game_genie_codes() {
+        let decode = |code| Cheat::from_game_genie(code).map(|c| (c.addr, c.value, c.compare));
+        assert_eq!(decode("GOSSIP"), Some((0xD1DD, 0x14, None)));
+        assert_eq!(decode("sxiopo"), Some((0x91D9, 0xAD, None)));
+        assert_eq!(decode("AAAAAAAA"), Some((0x8000, 0x00, Some(0x00))));
+        assert_eq!(decode("NNNNNNNN"), Some((0xFFFF, 0xFF, Some(0xFF))));
+        assert_eq!(decode("GOSSI"), None);
+        assert_eq!(decode("GOSSIPB"), None);
+        assert_eq!(decode("GOSSIQ"), None);
+
+        let rom = NESFile::new(Path::new("tests/holy-mapperel/M0_P32K_C8K_V.nes").to_path_buf())
+            .unwrap();
+        let mut console = Console::new(rom);
+        let original = console.cpu.bus.read(0xD1DD).0;
+        console.add_cheat("GOSSIP").unwrap();
+        assert!(console.add_cheat("GOSSIQ").is_err());
+        assert_eq!(console.cpu.bus.read(0xD1DD).0, 0x14);
+        assert_eq!(console.cpu.bus.read_trace(0xD1DD), 0x14);
+
+        // 8 letter codes leave the byte alone unless it matches the compare value
+        let addr = 0x8123;
+        let rom_byte = console.cpu.bus.read(addr).0;
+        for (compare, expected) in [(rom_byte, 0x42), (rom_byte ^ 0x01, rom_byte)] {
+            console.cpu.bus.add_cheat(Cheat {
+                addr,
+                value: 0x42,
+                compare: Some(compare),
+            });
+            assert_eq!(console.cpu.bus.read(addr).0, expected);
+            console.cpu.bus.clear_cheats();
+        }
+        assert_eq!(console.cpu.bus.read(0xD1DD).0, original);
+    }
+
+    #[test]
+    fn debugger_breakpoints_and_watches() {
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.pc = 0xC000;
+
+        // A watched write halts once the instruction doing it is done
+        cpu.set_mem_watch(0x10);
+        cpu.run_until_frame();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.pc, 0xC5FB);
+        cpu.run();
+        assert_eq!(cpu.pc, 0xC5FB);
+        cpu.clear_mem_watch(0x10);
+
+        cpu.set_pc_breakpoint(0xC72D);
+        cpu.resume();
+        cpu.run_until_frame();
+        assert!(cpu.is_halted());
+        let regs = Registers {
+            pc: 0xC72D,
+            acc: 0,
+            x: 0,
+            y: 0,
+            sp: 0xFB,
+            status: 0x06,
+            cycle: 27,
+        };
+        assert_eq!(cpu.registers(), regs);
+        // Resuming runs the instruction it stopped on instead of breaking again
+        cpu.resume();
+        cpu.run();
+        assert_eq!(cpu.pc, 0xC72E);
+
+        // A callback that doesn't ask for a halt still sees every hit
+        let hits = Arc::new(Mutex::new(Vec::new()));
+        let log = hits.clone();
+        cpu.set_break_callback(Some(Box::new(move |event, regs| {
+            log.lock().unwrap().push((event, regs.pc));
+            false
+        })));
+        cpu.set_pc_breakpoint(0xC72F);
+        while cpu.pc != 0xC735 {
+            cpu.run();
+        }
+        assert!(!cpu.is_halted());
+        assert_eq!(*hits.lock().unwrap(), [(BreakEvent::Execute(0xC72F), 0xC72F)]);
+    }
+
+    #[test]
+    fn string_sink_captures_trace() {
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset_to(0xC000);
+        let trace = cpu.set_string_sink();
+        cpu.enable_logging(TraceFormat::Text);
+        let log = std::fs::read_to_string("tests/nestest/test_pat.txt").unwrap();
+        for (i, expected) in log.lines().take(50).enumerate() {
+            cpu.run();
+            // Readable through the handle while the CPU is still running, one line per instruction
+            let trace = trace.lock().unwrap();
+            let line = trace.lines().nth(i).unwrap();
+            assert_eq!(trace.lines().count(), i + 1);
+            assert_eq!(line[..48], expected[..48]);
+        }
+
+        // Nothing more is written once the trace goes elsewhere
+        let len = trace.lock().unwrap().len();
+        cpu.set_sink(Box::new(std::io::sink()));
+        cpu.run();
+        assert_eq!(trace.lock().unwrap().len(), len);
+    }
+
+    #[test]
+    fn json_trace() {
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset_to(0xC000);
+        let trace = cpu.set_string_sink();
+        cpu.enable_logging(TraceFormat::Json);
+        for _ in 0..3 {
+            cpu.run();
+        }
+
+        // The same instructions and cycle counts as the first lines of nestest.log
+        let trace = trace.lock().unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                concat!(
+                    r#"{"pc":49152,"opcode":76,"operands":[245,197],"a":0,"x":0,"y":0,"p":4,"#,
+                    r#""sp":253,"scanline":0,"dot":26,"cycle":7,"asm":"JMP $C5F5"}"#
+                ),
+                concat!(
+                    r#"{"pc":50677,"opcode":162,"operands":[0],"a":0,"x":0,"y":0,"p":4,"#,
+                    r#""sp":253,"scanline":0,"dot":35,"cycle":10,"asm":"LDX #$00"}"#
+                ),
+                concat!(
+                    r#"{"pc":50679,"opcode":134,"operands":[0],"a":0,"x":0,"y":0,"p":6,"#,
+                    r#""sp":253,"scanline":0,"dot":41,"cycle":12,"asm":"STX $00 = 00"}"#
+                ),
+            ]
+        );
+        assert_eq!(TraceFormat::from_name("json"), Some(TraceFormat::Json));
+        assert_eq!(TraceFormat::from_name("toml"), None);
+    }
+
+    #[test]
+    fn disassemble_matches_nestest_log() {
+        let rom = NESFile::new(Path::new("tests/nestest/nestest.nes").to_path_buf()).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.pc = 0xC000;
+
+        let log = std::fs::read_to_string("tests/nestest/test_pat.txt").unwrap();
+        let mut modes = HashSet::new();
+        for line in log.lines() {
+            assert_eq!(cpu.pc, u16::from_str_radix(&line[..4], 16).unwrap(), "{line}");
+            let (asm, next) = cpu.disassemble(cpu.pc);
+            assert_eq!(asm, line[15..48].trim(), "{line}");
+            let len = line[6..14].split_whitespace().count() as u16;
+            assert_eq!(next, cpu.pc + len, "{line}");
+
+            let op = OPS.iter().find(|op| op.hex == cpu.bus.read_trace(cpu.pc)).unwrap();
+            modes.insert(format!("{:?}", op.addressing_mode));
+            cpu.run();
+        }
+        // Every addressing mode shows up somewhere in nestest, the JMP ($02FF) page wrap included
+        assert_eq!(modes.len(), 16);
+        assert!(log.contains("JMP ($02FF) = 0300"));
+
+        // Bytes that aren't an opcode, and instructions running off the end of memory
+        cpu.memory_write(0x0400, 0x8B);
+        assert_eq!(cpu.disassemble(0x0400), (".DB $8B".to_string(), 0x0401));
+        assert!(cpu.disassemble(0xFFFF).1 < 3);
+    }
+
+    #[test]
+    fn pattern_table_image() {
+        let rom = NESFile::new(Path::new("tests/spritecans-2011/spritecans.nes").to_path_buf())
+            .unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..10 {
+            cpu.run_until_frame();
+        }
+        let ppu = &cpu.bus.ppu;
+
+        // Every pixel is one of the palette's four colors, with color 0 the shared backdrop
+        for palette in 0..8 {
+            let colors: Vec<[u8; 3]> = [0x3f00, 0x3f01, 0x3f02, 0x3f03]
+                .map(|addr| {
+                    let addr = if addr == 0x3f00 { addr } else { addr + palette * 4 };
+                    let idx = ppu.read_ppudata_trace(addr) & 0x3f;
+                    ppu.palette().system_palette[idx as usize].0
+                })
+                .to_vec();
+            let image = ppu.pattern_table_image(1, palette as u8);
+            assert_eq!(image.len(), 128 * 128 * 3);
+            for px in image.chunks(3) {
+                assert!(colors.contains(&[px[0], px[1], px[2]]), "{palette} {px:?}");
+            }
+        }
+
+        // Tile 1's top row comes straight from CHR
+        let image = ppu.pattern_table_image(0, 0);
+        let (low, high) = (ppu.read_ppudata_trace(0x10), ppu.read_ppudata_trace(0x18));
+        let colors: Vec<[u8; 3]> = (0..4)
+            .map(|i| {
+                let idx = ppu.read_ppudata_trace(0x3f00 + i) & 0x3f;
+                ppu.palette().system_palette[idx as usize].0
+            })
+            .collect();
+        for col in 0..8 {
+            let value = (low >> (7 - col)) & 1 | ((high >> (7 - col)) & 1) << 1;
+            let index = (8 + col) * 3;
+            assert_eq!(image[index..index + 3], colors[value as usize]);
+        }
+    }
+
+    #[test]
+    fn nametable_image() {
+        let quadrant = |image: &[u8], nametable: usize| -> Vec<u8> {
+            let (left, top) = ((nametable & 1) * 256, (nametable >> 1) * 240);
+            (top..top + 240)
+                .flat_map(|y| image[(y * 512 + left) * 3..(y * 512 + left + 256) * 3].to_vec())
+                .collect()
+        };
+        for (file, vertical) in [
+            ("tests/holy-mapperel/M0_P32K_C8K_V.nes", true),
+            ("tests/holy-mapperel/M3_P32K_C32K_H.nes", false),
+        ] {
+            let rom = NESFile::new(Path::new(file).to_path_buf()).unwrap();
+            let mut cpu = CPU::new(Bus::new(&rom));
+            cpu.reset();
+            for _ in 0..60 {
+                cpu.run_until_frame();
+            }
+            let ppu = &cpu.bus.ppu;
+            let image = ppu.nametable_image();
+            assert_eq!(image.len(), 512 * 480 * 3);
+
+            assert!(quadrant(&image, 0).chunks(3).any(|px| px != &image[..3]));
+            // Mirrored nametables look the same
+            let mirror = if vertical { 2 } else { 1 };
+            assert_eq!(quadrant(&image, 0), quadrant(&image, mirror), "{file}");
+
+            // With no sprites on screen, the picture is the nametables cut out at the scroll
+            let (scroll_x, scroll_y) = ppu.scroll_position();
+            for y in 0..240 {
+                for x in 0..256 {
+                    let src = ((y + scroll_y) % 480 * 512 + (x + scroll_x) % 512) * 3;
+                    let dst = (y * 256 + x) * 3;
+                    assert_eq!(
+                        image[src..src + 3],
+                        ppu.curr_frame.image[dst..dst + 3],
+                        "{file} {x},{y}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn sprite_inspection() {
+        let rom = NESFile::new(Path::new("tests/spritecans-2011/spritecans.nes").to_path_buf())
+            .unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..60 {
+            cpu.run_until_frame();
+        }
+        // Stop partway down a visible sprite
+        let oam = cpu.bus.ppu.dump_oam();
+        let y = oam.chunks(4).map(|data| data[0]).filter(|&y| y < 200).min().unwrap();
+        while cpu.bus.ppu.scanline() != i16::from(y) + 4 {
+            cpu.run();
+        }
+
+        let ppu = &cpu.bus.ppu;
+        let oam = ppu.dump_oam();
+        let sprites = ppu.sprites();
+        assert_eq!(sprites.len(), 64);
+        for (sprite, data) in sprites.iter().zip(oam.chunks(4)) {
+            assert_eq!([sprite.y, sprite.tile, sprite.attributes, sprite.x], data);
+            assert_eq!(sprite.palette(), (data[2] & 0x03) + 4);
+            let top = i16::from(sprite.y) + 1;
+            assert_eq!(sprite.in_range, (top..top + 8).contains(&ppu.scanline()));
+        }
+        assert!(sprites.iter().any(|sprite| sprite.in_range));
+        assert!(sprites.iter().any(|sprite| !sprite.in_range));
+    }
+
+    // 32K NROM cart that starts running `program` at $8000
+    fn nrom_with_program(program: &[u8]) -> NESFile {
+        let mut bytes = vec![b'N', b'E', b'S', 0x1A, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        prg_rom[0x7FFC..0x7FFE].copy_from_slice(&[0x00, 0x80]);
+        bytes.extend(prg_rom);
+        bytes.extend([0; 0x2000]);
+        NESFile::from_bytes(bytes, false).unwrap()
+    }
+
+    #[test]
+    fn audio_rate_control() {
+        let mut console = Console::new(nrom_with_program(&[0x4c, 0x00, 0x80]));
+        console.set_sample_rate(48000.);
+        console.run_frame();
+        let samples = |console: &mut Console| {
+            (0..20).map(|_| console.run_frame().len()).sum::<usize>()
+        };
+        let nominal = samples(&mut console);
+        assert!(nominal.abs_diff(15974) < 4, "{nominal} samples");
+
+        // Up to half a percent either side, none at half full
+        let mut adjusted = |fill: f64| {
+            console.adjust_audio_rate(fill);
+            samples(&mut console)
+        };
+        assert!(adjusted(0.5).abs_diff(nominal) < 2);
+        let empty = adjusted(0.);
+        assert!((75..=85).contains(&(empty - nominal)), "{empty} samples");
+        let full = adjusted(1.);
+        assert!((75..=85).contains(&(nominal - full)), "{full} samples");
+        // Out of range is the same as the ends of it
+        assert!(adjusted(7.).abs_diff(full) < 2);
+
+        // Setting the rate again starts from the device's own
+        console.set_sample_rate(48000.);
+        console.run_frame();
+        assert!(samples(&mut console).abs_diff(nominal) < 2);
+    }
+
+    #[test]
+    fn turbo_buttons() {
+        let mut console = Console::new(nrom_with_program(&[0x4c, 0x00, 0x80]));
+        console.set_turbo(Buttons::A, Some(15.));
+        let run = |console: &mut Console, frames: usize| {
+            (0..frames)
+                .map(|_| {
+                    console.run_frame();
+                    let buttons = console.cpu.bus.joypad_for(Player::One).buttons;
+                    (buttons.contains(Buttons::A), buttons.contains(Buttons::B))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // Nothing fires until the button is down
+        assert!(run(&mut console, 4).iter().all(|&pressed| pressed == (false, false)));
+
+        // 2 frames down and 2 up at 15 a second, however often the frontend repeats the press
+        console.set_joypad(Player::One, Buttons::A, true);
+        console.set_joypad(Player::One, Buttons::B, true);
+        let mut pattern = vec![];
+        for _ in 0..8 {
+            console.set_joypad(Player::One, Buttons::A, true);
+            pattern.extend(run(&mut console, 1));
+        }
+        let a: Vec<bool> = pattern.iter().map(|&(a, _)| a).collect();
+        assert_eq!(a, [true, true, false, false, true, true, false, false]);
+        assert!(pattern.iter().all(|&(_, b)| b));
+
+        // Letting go stops it straight away, and the next press starts over
+        console.set_joypad(Player::One, Buttons::A, false);
+        assert!(!console.cpu.bus.joypad_for(Player::One).buttons.contains(Buttons::A));
+        assert!(run(&mut console, 3).iter().all(|&(a, _)| !a));
+        console.set_joypad(Player::One, Buttons::A, true);
+        assert_eq!(run(&mut console, 3), [(true, true), (true, true), (false, true)]);
+
+        console.set_turbo(Buttons::A, None);
+        assert!(run(&mut console, 3).iter().all(|&(a, _)| a));
+    }
+
+    #[test]
+    fn run_until_pc() {
+        // INX, INX, then JMP back to the second INX
+        let rom = nrom_with_program(&[0xe8, 0xe8, 0x4c, 0x01, 0x80]);
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        assert!(cpu.run_until_pc(0x8002, 100));
+        assert_eq!(cpu.x, 2);
+        // Already there
+        assert!(cpu.run_until_pc(0x8002, 0));
+        assert_eq!(cpu.x, 2);
+
+        let start = cpu.cycle_count;
+        assert!(!cpu.run_until_pc(0x9000, 1000));
+        assert!((1000..1010).contains(&(cpu.cycle_count - start)));
+    }
+
+    #[test]
+    fn invalid_opcode_behavior() {
+        // $8B (XAA) isn't implemented. INX, INX, then JMP to itself
+        let rom = nrom_with_program(&[0x8b, 0xe8, 0xe8, 0x4c, 0x03, 0x80]);
+
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.run_until_frame();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.x, 2);
+
+        cpu.reset();
+        cpu.set_invalid_opcode_behavior(InvalidOpBehavior::Halt);
+        cpu.run_until_frame();
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.invalid_opcode(), Some((0x8000, 0x8b)));
+        assert_eq!((cpu.pc, cpu.x), (0x8000, 0));
+        // Only a reset gets it going again
+        cpu.resume();
+        cpu.run_until_frame();
+        assert!(cpu.is_halted());
+        cpu.soft_reset();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.invalid_opcode(), None);
+
+        cpu.set_invalid_opcode_behavior(InvalidOpBehavior::Panic);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| cpu.run_until_frame()));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn jam_halts_until_reset() {
+        // INX, then JAM
+        let rom = nrom_with_program(&[0xe8, 0x02, 0xe8]);
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.run_until_frame();
+        assert!(cpu.is_halted() && cpu.is_jammed());
+        assert_eq!((cpu.pc, cpu.x), (0x8001, 1));
+
+        cpu.resume();
+        cpu.run_until_frame();
+        assert!(cpu.is_jammed());
+        assert_eq!((cpu.pc, cpu.x), (0x8001, 1));
+
+        cpu.soft_reset();
+        assert!(!cpu.is_halted() && !cpu.is_jammed());
+        assert_eq!(cpu.pc, 0x8000);
+    }
+
+    #[test]
+    fn unstable_illegal_opcodes() {
+        #[rustfmt::skip]
+        let rom = nrom_with_program(&[
+            0xa9, 0xfb, 0x8d, 0x10, 0x06, // LDA #$FB, STA $0610
+            0xa9, 0x0f, 0xa2, 0x0e,       // LDA #$0F, LDX #$0E
+            0xa0, 0x10, 0x9b, 0x00, 0x07, // LDY #$10, TAS $0700,Y
+            0xc8, 0x9f, 0x00, 0x07,       // INY, AHX $0700,Y
+            0xa0, 0x10, 0xbb, 0x00, 0x06, // LDY #$10, LAS $0600,Y
+            0x02,                         // JAM
+        ]);
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.run_until_frame();
+        assert!(cpu.is_jammed());
+
+        // TAS and AHX store A & X & ($07 + 1) at $0810 and $0811, which mirror $0010 and $0011
+        assert_eq!([cpu.bus.read_trace(0x0010), cpu.bus.read_trace(0x0011)], [0x08, 0x08]);
+        // TAS left A & X = $0E in SP, and LAS loads $FB & SP into A, X and SP
+        assert_eq!((cpu.acc, cpu.x, cpu.sp), (0x0a, 0x0a, 0x0a));
+    }
+
+    #[test]
+    fn zapper_light_and_trigger() {
+        let rom = nrom_with_program(&[0x4c, 0x00, 0x80]);
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.run_until_frame();
+        let mut zapper = Zapper::default();
+        zapper.aim(Some((10, 100)));
+        cpu.bus.zapper = Some(zapper);
+        let run_to_scanline = |cpu: &mut CPU, scanline: i16| {
+            while cpu.bus.ppu.scanline() != scanline {
+                cpu.run();
+            }
+        };
+
+        // Light is only seen for a while after the beam draws the spot
+        assert_eq!(cpu.bus.read(0x4017).0 & 0x18, 0x08);
+        run_to_scanline(&mut cpu, 105);
+        cpu.bus.ppu.curr_frame.set_pixel(10, 100, Rgb([0xff, 0xff, 0xff]));
+        assert_eq!(cpu.bus.read(0x4017).0 & 0x18, 0x00);
+        run_to_scanline(&mut cpu, 125);
+        assert_eq!(cpu.bus.read(0x4017).0 & 0x18, 0x08);
+
+        // A pull holds the trigger down for a few frames
+        cpu.bus.pull_zapper_trigger();
+        assert_eq!(cpu.bus.read(0x4017).0 & 0x10, 0x10);
+        for _ in 0..6 {
+            cpu.run_until_frame();
+        }
+        assert_eq!(cpu.bus.read(0x4017).0 & 0x10, 0x00);
+    }
+
+    #[test]
+    fn frame_cropping() {
+        let mut frame = Frame::new();
+        frame.set_pixel(4, 8, Rgb([1, 2, 3]));
+        frame.set_pixel(251, 231, Rgb([4, 5, 6]));
+
+        let (image, width, height) = frame.cropped(0, 0, 0, 0);
+        assert_eq!((width, height), (256, 240));
+        assert_eq!(image, frame.image);
+
+        let (image, width, height) = frame.cropped(8, 8, 4, 4);
+        assert_eq!((width, height), (248, 224));
+        assert_eq!(image.len(), 248 * 224 * 3);
+        assert_eq!(image[..3], [1, 2, 3]);
+        assert_eq!(image[image.len() - 3..], [4, 5, 6]);
+
+        assert_eq!(frame.cropped(200, 200, 0, 0).2, 0);
+    }
+
+    #[test]
+    fn trainer_loaded_at_7000() {
+        let trainer: Vec<u8> = (0..512).map(|i| (i * 7) as u8).collect();
+        let mut bytes = vec![b'N', b'E', b'S', 0x1A, 1, 1, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        bytes.extend(&trainer);
+        bytes.extend([0; 0x4000]);
+        bytes.extend([0; 0x2000]);
+        let rom = NESFile::from_bytes(bytes, false).unwrap();
+        assert_eq!(rom.trainer.map(Vec::from), Some(trainer.clone()));
+
+        // No PRG-RAM in the header, but the trainer still needs somewhere to go
+        let bus = Bus::new(&rom);
+        let loaded: Vec<u8> = (0x7000..0x7200).map(|addr| bus.read_trace(addr)).collect();
+        assert_eq!(loaded, trainer);
+        assert_eq!(bus.read_trace(0x6FFF), 0);
+        assert_eq!(bus.read_trace(0x7200), 0);
+    }
+
+    #[test]
+    fn power_on_ram_init() {
+        let rom = nrom_with_program(&[0x4c, 0x00, 0x80]);
+        let nametable = |bus: &Bus, idx| bus.mapper.lock().unwrap().read_nametable_idx(idx, 0x123);
+
+        let bus = Bus::new_with_ram_init(&rom, RamInit::Ones);
+        assert_eq!(bus.read_trace(0x0000), 0xFF);
+        assert_eq!(bus.read_trace(0x07FF), 0xFF);
+        assert_eq!(nametable(&bus, 3), 0xFF);
+        assert!(bus.ppu.dump_oam().iter().all(|&b| b == 0xFF));
+
+        let bus = Bus::new_with_ram_init(&rom, RamInit::Pattern(0x5A));
+        assert_eq!(bus.read_trace(0x0400), 0x5A);
+        assert_eq!(nametable(&bus, 0), 0x5A);
+        assert_eq!(bus.ppu.dump_oam()[0x80], 0x5A);
+
+        // The same seed gives the same noise on every power on, a different one doesn't
+        let ram = |bus: &Bus| (0..0x800).map(|addr| bus.read_trace(addr)).collect::<Vec<_>>();
+        let a = Bus::new_with_ram_init(&rom, RamInit::Seeded(1234));
+        let b = Bus::new_with_ram_init(&rom, RamInit::Seeded(1234));
+        let c = Bus::new_with_ram_init(&rom, RamInit::Seeded(5678));
+        assert_eq!(ram(&a), ram(&b));
+        assert_eq!(a.ppu.dump_oam(), b.ppu.dump_oam());
+        assert_ne!(ram(&a), ram(&c));
+        assert!(ram(&a).iter().collect::<HashSet<_>>().len() > 200);
+        assert_ne!(a.ppu.dump_oam(), [0; 0x100]);
+
+        let bus = Bus::new_with_ram_init(&rom, RamInit::Zeros);
+        assert!(ram(&bus).iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn dmc_loop_flag_is_bit_6() {
+        // IRQ enable on its own used to turn looping on as well, so the IRQ never came
+        for (ctrl, looped) in [(0x80, false), (0x40, true), (0xc0, true)] {
+            let mut dmc = DMC::new();
+            dmc.write_ctrl(ctrl);
+            dmc.write_lc(0);
+            dmc.set_enabled(true, 0);
+            dmc.set_dmc_read_buffer(0);
+            assert_eq!(dmc.bytes_remaining > 0, looped, "{ctrl:#04X}");
+            assert_eq!(dmc.irq_flag(), !looped, "{ctrl:#04X}");
+        }
+    }
+
+    #[test]
+    fn dmc_sample_wraparound_and_loop() {
+        // A 65 byte sample from $FFC0 at the fastest rate, so the last byte comes from $8000
+        let run = |ctrl: u8, frames: u64| {
+            #[rustfmt::skip]
+            let rom = nrom_with_program(&[
+                0xa9, 0x40, 0x8d, 0x17, 0x40, // LDA #$40, STA $4017
+                0xa9, ctrl, 0x8d, 0x10, 0x40, // LDA #ctrl, STA $4010
+                0xa9, 0xff, 0x8d, 0x12, 0x40, // LDA #$FF, STA $4012
+                0xa9, 0x04, 0x8d, 0x13, 0x40, // LDA #$04, STA $4013
+                0xa9, 0x10, 0x8d, 0x15, 0x40, // LDA #$10, STA $4015
+            ]);
+            let mut cpu = CPU::new(Bus::new(&rom));
+            cpu.reset();
+            for _ in 0..10 {
+                cpu.run();
+            }
+            let mut addrs = vec![cpu.bus.apu.dmc.current_addr];
+            let mut irqs = 0;
+            while cpu.cycle_count < frames * 29781 {
+                cpu.run();
+                let addr = cpu.bus.apu.dmc.current_addr;
+                if addr != addrs[addrs.len() - 1] {
+                    addrs.push(addr);
+                }
+                // Acknowledged straight away, to catch it going up more than once
+                if cpu.bus.apu.irq_state().dmc_irq {
+                    irqs += 1;
+                    cpu.bus.write(0x4010, ctrl & 0x7f, cpu.cycle_count);
+                    cpu.bus.write(0x4010, ctrl, cpu.cycle_count);
+                }
+            }
+            (cpu, addrs, irqs)
+        };
+
+        let (cpu, addrs, irqs) = run(0x8f, 4);
+        assert_eq!(irqs, 1);
+        assert_eq!(cpu.bus.apu.dmc.bytes_remaining, 0);
+        let expected = (0xffc0..=0xffff).chain([0x8000, 0x8001]).collect::<Vec<u16>>();
+        assert_eq!(addrs, expected);
+
+        // Looping goes back to $FFC0 after the last byte instead, and never raises the IRQ
+        let (cpu, addrs, irqs) = run(0xcf, 4);
+        assert_eq!(irqs, 0);
+        assert!(cpu.bus.apu.dmc.bytes_remaining > 0);
+        let restarts = addrs.windows(2).filter(|pair| pair == &[0x8000, 0xffc0]).count();
+        assert!(restarts >= 3, "{restarts} loops");
+        assert!(addrs.iter().all(|&addr| addr == 0x8000 || addr >= 0xffc0));
+    }
+
+    #[test]
+    fn dmc_dma_stalls_cpu() {
+        // Fastest rate, looping a sample from $C000, then a sled of NOPs
+        #[rustfmt::skip]
+        let program = [
+            0xa9, 0x4f, 0x8d, 0x10, 0x40, // LDA #$4F, STA $4010
+            0xa9, 0x00, 0x8d, 0x12, 0x40, // LDA #$00, STA $4012
+            0xa9, 0xff, 0x8d, 0x13, 0x40, // LDA #$FF, STA $4013
+            0xa9, 0x10, 0x8d, 0x15, 0x40, // LDA #$10, STA $4015
+        ];
+        let nop_cycles = |program: &[u8]| {
+            let rom = nrom_with_program(program);
+            let mut cpu = CPU::new(Bus::new(&rom));
+            cpu.reset();
+            for _ in 0..8 {
+                cpu.run();
+            }
+            (0..5000)
+                .map(|_| {
+                    let start = cpu.cycle_count;
+                    cpu.run();
+                    cpu.cycle_count - start
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert!(nop_cycles(&program[..15]).iter().all(|&cycles| cycles == 2));
+
+        // A byte every 432 cycles, each halting the CPU on its next read for 2 to 4 cycles
+        // depending on where in the get/put cycle the request lands
+        let cycles = nop_cycles(&program);
+        assert!(cycles.iter().all(|&cycles| (2..=6).contains(&cycles)));
+        let stalls = cycles.iter().filter(|&&cycles| cycles > 2).count();
+        assert!((21..=25).contains(&stalls), "{stalls} stalls");
+    }
+
+    #[test]
+    fn dmc_irq_timing() {
+        // Frame IRQ off, a one byte sample with the DMC IRQ on, then wait with interrupts enabled
+        #[rustfmt::skip]
+        let rom = nrom_with_program(&[
+            0xa9, 0x40, 0x8d, 0x17, 0x40, // LDA #$40, STA $4017
+            0xa9, 0x8f, 0x8d, 0x10, 0x40, // LDA #$8F, STA $4010
+            0xa9, 0x00, 0x8d, 0x12, 0x40, // LDA #$00, STA $4012
+            0xa9, 0x00, 0x8d, 0x13, 0x40, // LDA #$00, STA $4013
+            0x58,                         // CLI
+            0xa9, 0x10, 0x8d, 0x15, 0x40, // LDA #$10, STA $4015
+        ]);
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..11 {
+            cpu.run();
+        }
+        let enabled = cpu.cycle_count;
+        assert_eq!(cpu.bus.apu.read_status_trace() & 0x90, 0x10);
+
+        // The flag goes up as the last byte is fetched, a few cycles after the channel starts
+        while !cpu.bus.apu.irq_state().dmc_irq {
+            cpu.run();
+            assert!(cpu.cycle_count - enabled < 20, "no DMC IRQ");
+        }
+        let fired = cpu.cycle_count - enabled;
+        assert!((3..=8).contains(&fired), "DMC IRQ after {fired} cycles");
+
+        // and the CPU takes it on the next instruction
+        cpu.run();
+        assert!(cpu.pc >= 0xeaea);
+
+        // Bit 7 of $4015, which reading doesn't acknowledge
+        assert_eq!(cpu.bus.read(0x4015).0 & 0xc0, 0x80);
+        assert_eq!(cpu.bus.read(0x4015).0 & 0xc0, 0x80);
+        cpu.bus.write(0x4015, 0x00, cpu.cycle_count);
+        assert_eq!(cpu.bus.read(0x4015).0 & 0x80, 0);
+
+        // Turning the IRQ off in $4010 acknowledges it too
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        while !cpu.bus.apu.irq_state().dmc_irq {
+            cpu.run();
+        }
+        cpu.bus.write(0x4010, 0x0f, cpu.cycle_count);
+        assert_eq!(cpu.bus.apu.read_status_trace() & 0x80, 0);
+    }
+
+    #[test]
+    fn apu_irq_state() {
+        // A single byte sample with the IRQ on, then spin with interrupts still disabled from reset
+        #[rustfmt::skip]
+        let rom = nrom_with_program(&[
+            0xa9, 0x8f, 0x8d, 0x10, 0x40, // LDA #$8F, STA $4010
+            0xa9, 0x00, 0x8d, 0x12, 0x40, // LDA #$00, STA $4012
+            0xa9, 0x00, 0x8d, 0x13, 0x40, // LDA #$00, STA $4013
+            0xa9, 0x10, 0x8d, 0x15, 0x40, // LDA #$10, STA $4015
+            0x4c, 0x14, 0x80,             // JMP $8014
+        ]);
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        let none = ApuIrqState {
+            frame_irq: false,
+            dmc_irq: false,
+        };
+        assert_eq!(cpu.bus.apu.irq_state(), none);
+
+        cpu.run_until_frame();
+        cpu.run_until_frame();
+        let both = ApuIrqState {
+            frame_irq: true,
+            dmc_irq: true,
+        };
+        assert_eq!(cpu.bus.apu.irq_state(), both);
+        // Looking doesn't acknowledge anything
+        assert_eq!(cpu.bus.apu.irq_state(), both);
+
+        cpu.bus.read(0x4015);
+        assert_eq!(
+            cpu.bus.apu.irq_state(),
+            ApuIrqState {
+                frame_irq: false,
+                dmc_irq: true,
+            }
+        );
+        cpu.bus.write(0x4015, 0x00, cpu.cycle_count);
+        assert_eq!(cpu.bus.apu.irq_state(), none);
+    }
+
+    #[test]
+    fn frame_counter_region_steps() {
+        // First half frame (length counters) and the first frame IRQ of 4-step mode, which is
+        // PAL's own table and NTSC's for Dendy
+        let expected = [
+            (Region::Ntsc, 14913, 29828),
+            (Region::Pal, 16627, 33252),
+            (Region::Dendy, 14913, 29828),
+        ];
+        for (region, half_frame, irq) in expected {
+            let mut apu = APU::with_region(region);
+            apu.write_status(0x01, 0);
+            apu.write_timer_hi(&AudioChannel::Pulse1, 0x08);
+            // The length is loaded on the next clock
+            apu.clock();
+            let length = apu.debug_state().pulse1.length_counter;
+            let (mut clocked, mut fired) = (None, None);
+            for cycle in 2..40000 {
+                let (irq_pending, _) = apu.clock();
+                if clocked.is_none() && apu.debug_state().pulse1.length_counter != length {
+                    clocked = Some(cycle);
+                }
+                if irq_pending {
+                    fired = Some(cycle);
+                    break;
+                }
+            }
+            assert_eq!(clocked, Some(half_frame), "{region:?}");
+            assert_eq!(fired, Some(irq), "{region:?}");
+        }
+    }
+
+    fn with_timing(file: &str, timing: u8) -> NESFile {
+        let mut bytes = std::fs::read(file).unwrap();
+        // NES 2.0 identifier, then the CPU/PPU timing byte
+        bytes[7] |= 0x08;
+        bytes[12] = timing;
+        NESFile::from_bytes(bytes, false).unwrap()
+    }
+
+    #[test]
+    fn region_frame_length() {
+        let expected = [
+            (0, Region::Ntsc, 29781),
+            (1, Region::Pal, 33248),
+            (2, Region::Ntsc, 29781),
+            (3, Region::Dendy, 35464),
+        ];
+        for (timing, region, cycles_per_frame) in expected {
+            let rom = with_timing("tests/window5/colorwin_ntsc.nes", timing);
+            assert_eq!(rom.region(), region);
+            let mut cpu = CPU::new(Bus::new(&rom));
+            cpu.reset();
+            cpu.run_until_frame();
+            let start = cpu.cycle_count;
+            for _ in 0..4 {
+                cpu.run_until_frame();
+            }
+            let cycles = cpu.cycle_count - start;
+            assert!(
+                cycles.abs_diff(4 * cycles_per_frame) < 8,
+                "{region:?} ran {cycles} cycles in 4 frames"
+            );
+        }
+    }
+
+    #[test]
+    fn emulated_seconds_follow_frame_rate() {
+        for (timing, region) in [(0, Region::Ntsc), (1, Region::Pal)] {
+            for overclock in [1., 2.] {
+                let rom = with_timing("tests/window5/colorwin_ntsc.nes", timing);
+                let mut console = Console::new(rom);
+                // The extra cycles are frozen in time, so they mustn't count
+                console.cpu.set_overclock(overclock);
+                let frames = 120;
+                for _ in 0..frames {
+                    console.step_frame();
+                }
+                let expected = frames as f64 / region.frame_rate();
+                let seconds = console.emulated_seconds();
+                assert!(
+                    (seconds - expected).abs() < 1. / region.frame_rate(),
+                    "{region:?} at {overclock}x ran {seconds}s, expected {expected}s"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn colorwin_greyscale_emphasis() {
+        let mut bytes = std::fs::read("tests/window5/colorwin_ntsc.nes").unwrap();
+        // Every $2001 value that turns rendering on, in the init code, at the end of the window and
+        // in the per-line code the ROM copies to RAM. All get greyscale and green emphasis added
+        for offset in [90, 626, 696] {
+            assert_eq!(bytes[offset], 0x1e);
+            bytes[offset] = 0x5f;
+        }
+        let rom = NESFile::from_bytes(bytes, false).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..60 {
+            cpu.run_until_frame();
+        }
+
+        // The backdrop's $0f shows as grey $00, with red and blue pulled down by the emphasis
+        let grey = Palette::default().system_palette[0x00].0;
+        let idx = (10 * 256 + 10) * 3;
+        let expected = [
+            (grey[0] as f32 * 0.746) as u8,
+            grey[1],
+            (grey[2] as f32 * 0.746) as u8,
+        ];
+        assert_eq!(&cpu.bus.ppu.curr_frame.image[idx..idx + 3], expected);
+        assert_eq!(cpu.get_frame_hash(), 4763301570537347477);
+    }
+
+    #[test]
+    fn pal_colorwin() {
+        let rom = with_timing("tests/window5/colorwin_pal.nes", 1);
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        for _ in 0..60 {
+            cpu.run_until_frame();
+        }
+        assert_eq!(cpu.get_frame_hash(), 12498327664737205374);
+    }
+
+    #[test]
+    fn cnrom_bank_masking_and_bus_conflicts() {
+        let mut prg_rom = vec![0xFF; 0x8000];
+        prg_rom[0] = 0x01;
+        let chr_rom = (0..4u8).flat_map(|bank| [bank; 0x2000]).collect();
+        let mut cnrom = CNROM::new(CartridgeConfig {
+            prg_rom,
+            chr_rom: Some(chr_rom),
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+
+        cnrom.write(0x8001, 0x02);
+        assert_eq!(cnrom.read_chr_rom(0x0000), 2);
+        // $8000 holds 0x01, so the ROM pulls bit 1 low
+        cnrom.write(0x8000, 0x02);
+        assert_eq!(cnrom.read_chr_rom(0x0000), 0);
+        // Only two bank bits are decoded with 32K of CHR
+        cnrom.write(0x8001, 0x07);
+        assert_eq!(cnrom.read_chr_rom(0x1FFF), 3);
+        assert_eq!(cnrom.chr_bank_map(), std::array::from_fn(|i| 24 + i));
+    }
+
+    #[test]
+    fn cnrom_holy_mapperel_chr_banks() {
+        let path = "tests/holy-mapperel/M3_P32K_C32K_H.nes";
+        // Finishes with "32K CHR ROM OK" and a detailed result of 0000
+        assert_eq!(nes::run_headless(path, 80).unwrap(), 12112331729405102634);
+
+        let rom = NESFile::new(Path::new(path).to_path_buf()).unwrap();
+        let chr_rom = rom.chr_rom_area.clone().unwrap();
+        let mut cnrom = MapperFactory::from_file(&rom);
+        // Somewhere the ROM reads $FF, so a write there isn't cut short by a bus conflict
+        let addr = (0x8000..=0xFFFF).find(|&addr| cnrom.read(addr) == 0xFF).unwrap();
+        for bank in 0..8 {
+            cnrom.write(addr, bank);
+            let start = (bank as usize % 4) * 0x2000;
+            let chr: Vec<u8> = (0..0x2000).map(|a| cnrom.read_chr_rom(a)).collect();
+            assert_eq!(chr, chr_rom[start..start + 0x2000], "bank {bank}");
+        }
+    }
+
+    #[test]
+    fn nrom_cnrom_battery_saves() {
+        let new_mappers = |has_battery| -> [Box<dyn Mapper>; 2] {
+            [
+                Box::new(NROM::new(CartridgeConfig {
+                    prg_rom: vec![0; 0x8000],
+                    chr_ram_size: 0x2000,
+                    has_battery,
+                    mirroring: Mirroring::Vertical,
+                    ..Default::default()
+                })),
+                Box::new(CNROM::new(CartridgeConfig {
+                    prg_rom: vec![0; 0x8000],
+                    chr_ram_size: 0x2000,
+                    has_battery,
+                    mirroring: Mirroring::Vertical,
+                    ..Default::default()
+                })),
+            ]
+        };
+
+        for (mut mapper, mut fresh) in new_mappers(true).into_iter().zip(new_mappers(true)) {
+            mapper.write(0x6000, 0x42);
+            mapper.write(0x7FFF, 0x24);
+            fresh.load_save(mapper.dump_save());
+            assert_eq!(fresh.read(0x6000), 0x42);
+            assert_eq!(fresh.read(0x7FFF), 0x24);
+        }
+        for mapper in new_mappers(false) {
+            assert!(mapper.dump_save().is_empty());
+        }
+    }
+
+    #[test]
+    fn fme7_banking_and_irq() {
+        let prg_rom: Vec<u8> = (0..16u8).flat_map(|bank| [bank; 0x2000]).collect();
+        let chr_rom: Vec<u8> = (0..64u8).flat_map(|bank| [bank; 0x400]).collect();
+        let mut fme7 = FME7::new(CartridgeConfig {
+            prg_rom,
+            chr_rom: Some(chr_rom),
+            prg_ram_size: 0x2000,
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+        let write_reg = |fme7: &mut FME7, command: u8, val: u8| {
+            fme7.write(0x8000, command);
+            fme7.write(0xA000, val);
+        };
+
+        for (command, bank) in [(0x8, 2), (0x9, 3), (0xA, 4), (0xB, 5)] {
+            write_reg(&mut fme7, command, bank);
+        }
+        let banks = [0x6000, 0x8000, 0xA000, 0xC000, 0xE000].map(|a| fme7.read(a));
+        assert_eq!(banks, [2, 3, 4, 5, 15]);
+        write_reg(&mut fme7, 0x5, 0x21);
+        assert_eq!(fme7.read_chr_rom(0x1400), 0x21);
+        write_reg(&mut fme7, 0xC, 0x03);
+        assert_eq!(fme7.get_mirroring(), Mirroring::SingleScreenB);
+
+        // $6000 switches to RAM with bit 6, and bit 7 enables it
+        write_reg(&mut fme7, 0x8, 0x40);
+        fme7.write(0x6000, 0x42);
+        assert_eq!(fme7.read(0x6000), 0x60);
+        write_reg(&mut fme7, 0x8, 0xC0);
+        fme7.write(0x6000, 0x42);
+        assert_eq!(fme7.read(0x6000), 0x42);
+
+        // The counter fires when it wraps from 0 to $FFFF
+        write_reg(&mut fme7, 0xE, 0x02);
+        write_reg(&mut fme7, 0xF, 0x00);
+        write_reg(&mut fme7, 0xD, 0x81);
+        assert!(fme7.is_cpu_clocked());
+        fme7.clock_cpu();
+        fme7.clock_cpu();
+        assert!(!fme7.irq_pending());
+        fme7.clock_cpu();
+        assert!(fme7.irq_pending());
+        write_reg(&mut fme7, 0xD, 0x81);
+        assert!(!fme7.irq_pending());
+
+        // With IRQs off the counter still runs but never raises one
+        write_reg(&mut fme7, 0xD, 0x80);
+        for _ in 0..0x10000 {
+            fme7.clock_cpu();
+        }
+        assert!(!fme7.irq_pending());
+    }
+
+    // Shifts a value into an MMC1 register one bit at a time
+    fn mmc1_write(mmc1: &mut MMC1, addr: u16, val: u8) {
+        for i in 0..5 {
+            mmc1.write(addr, val >> i & 1);
+        }
+    }
+
+    #[test]
+    fn mmc1_board_variants() {
+        let prg_rom: Vec<u8> = (0..4u8).flat_map(|bank| [bank; 0x4000]).collect();
+        let new_mmc1 = |prg_ram_size| {
+            MMC1::new(CartridgeConfig {
+                prg_rom: prg_rom.clone(),
+                chr_ram_size: 0x2000,
+                prg_ram_size,
+                mirroring: Mirroring::Vertical,
+                ..Default::default()
+            })
+        };
+
+        let mut mmc1 = new_mmc1(0);
+        mmc1_write(&mut mmc1, 0xE000, 0x01);
+        assert_eq!(mmc1.read(0x8000), 1);
+
+        // SEROM ignores the PRG bank register
+        let mut serom = new_mmc1(0);
+        serom.set_submapper(5);
+        mmc1_write(&mut serom, 0xE000, 0x01);
+        assert_eq!(serom.read(0x8000), 0);
+        assert_eq!(serom.read(0xC000), 1);
+
+        // SOROM picks one of two 8K PRG-RAM banks with bit 3 of the CHR bank
+        let mut sorom = new_mmc1(0x4000);
+        mmc1_write(&mut sorom, 0xA000, 0x08);
+        sorom.write(0x6000, 0xAA);
+        mmc1_write(&mut sorom, 0xA000, 0x00);
+        assert_eq!(sorom.read(0x6000), 0x00);
+        sorom.write(0x6000, 0x55);
+        mmc1_write(&mut sorom, 0xA000, 0x08);
+        assert_eq!(sorom.read(0x6000), 0xAA);
+    }
+
+    #[test]
+    fn mmc1_submapper_overrides_sizes() {
+        let new_mmc1 = |prg_ram_size, submapper| {
+            let mut mmc1 = MMC1::new(CartridgeConfig {
+                prg_rom: vec![0; 0x10000],
+                chr_ram_size: 0x2000,
+                prg_ram_size,
+                mirroring: Mirroring::Vertical,
+                ..Default::default()
+            });
+            mmc1.set_submapper(submapper);
+            mmc1
+        };
+        // 32K of PRG-RAM is taken for SXROM, which banks it with bits 2-3 of the CHR bank. Marked
+        // as SOROM, only bit 3 counts
+        for (submapper, same_bank) in [(0, false), (2, true)] {
+            let mut mmc1 = new_mmc1(0x8000, submapper);
+            mmc1.write(0x6000, 0xAA);
+            mmc1_write(&mut mmc1, 0xA000, 0x04);
+            assert_eq!(mmc1.read(0x6000) == 0xAA, same_bank, "submapper {submapper}");
+        }
+
+        // The MMC1A ignores the WRAM disable bit
+        for (submapper, disabled) in [(0, true), (3, false)] {
+            let mut mmc1 = new_mmc1(0x2000, submapper);
+            mmc1.write(0x6000, 0x42);
+            mmc1_write(&mut mmc1, 0xE000, 0x10);
+            assert_eq!(mmc1.read(0x6000) == 0, disabled, "submapper {submapper}");
+        }
+    }
+
+    #[test]
+    fn mmc1_wram_disable_logging() {
+        static BLOCKED: Mutex<Vec<(u16, bool)>> = Mutex::new(Vec::new());
+        let mut mmc1 = MMC1::new(CartridgeConfig {
+            prg_rom: vec![0; 0x8000],
+            chr_ram_size: 0x2000,
+            prg_ram_size: 0x2000,
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+        mmc1.write(0x6000, 0x42);
+        mmc1_write(&mut mmc1, 0xE000, 0x10);
+        // Blocked accesses read 0 and drop writes either way, quietly unless there's a logger
+        assert_eq!(mmc1.read(0x6000), 0);
+        mmc1.set_wram_logger(Some(|addr, write| BLOCKED.lock().unwrap().push((addr, write))));
+        assert_eq!(mmc1.read(0x6000), 0);
+        mmc1.write(0x7FFF, 0x55);
+        assert_eq!(*BLOCKED.lock().unwrap(), [(0x6000, false), (0x7FFF, true)]);
+
+        mmc1_write(&mut mmc1, 0xE000, 0x00);
+        assert_eq!(mmc1.read(0x6000), 0x42);
+        assert_eq!(mmc1.read(0x7FFF), 0);
+        assert_eq!(BLOCKED.lock().unwrap().len(), 2);
+
+        // Same for a board without PRG-RAM, and for addresses below it
+        let mut mmc1 = MMC1::new(CartridgeConfig {
+            prg_rom: vec![0; 0x8000],
+            chr_ram_size: 0x2000,
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+        mmc1.set_wram_logger(Some(|addr, write| BLOCKED.lock().unwrap().push((addr, write))));
+        assert_eq!(mmc1.read(0x6000), 0);
+        mmc1.write(0x6000, 0x42);
+        mmc1.write(0x5000, 0x42);
+        assert_eq!(BLOCKED.lock().unwrap()[2..], [(0x6000, false), (0x6000, true), (0x5000, true)]);
+    }
+
+    #[test]
+    fn mmc1_ignores_consecutive_writes() {
+        // 64K PRG, each 16K bank tagged with its number at offset $1000
+        let mut bytes = vec![b'N', b'E', b'S', 0x1A, 4, 0, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg_rom = vec![0; 0x10000];
+        for bank in 0..4 {
+            prg_rom[bank * 0x4000 + 0x1000] = bank as u8;
+        }
+        #[rustfmt::skip]
+        let program = [
+            0xee, 0x00, 0xe0, // INC $E000, writes $00 then $01 on back to back cycles
+            0xa9, 0x00,       // LDA #$00
+            0x8d, 0x00, 0xe0, // STA $E000
+            0x8d, 0x00, 0xe0, // STA $E000
+            0x8d, 0x00, 0xe0, // STA $E000
+            0x8d, 0x00, 0xe0, // STA $E000
+            0x4c, 0x11, 0xc0, // JMP $C011
+        ];
+        prg_rom[0xC000..0xC000 + program.len()].copy_from_slice(&program);
+        prg_rom[0xFFFC..0xFFFE].copy_from_slice(&[0x00, 0xc0]);
+        bytes.extend(prg_rom);
+
+        let rom = NESFile::from_bytes(bytes, false).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.run_until_frame();
+        // Only the dummy write shifted in a bit, so the PRG bank is 0 rather than 2
+        assert_eq!(cpu.bus.read_trace(0x9000), 0);
+    }
+
+    #[test]
+    fn mmc2_chr_latches() {
+        let prg_rom: Vec<u8> = (0..16u8).flat_map(|bank| [bank; 0x2000]).collect();
+        let chr_rom: Vec<u8> = (0..16u8).flat_map(|bank| [bank; 0x1000]).collect();
+        let mut mmc2 = MMC2::new(CartridgeConfig {
+            prg_rom,
+            chr_rom: Some(chr_rom),
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+
+        mmc2.write(0xA000, 0x05);
+        assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|a| mmc2.read(a)), [5, 13, 14, 15]);
+
+        mmc2.write(0xB000, 1);
+        mmc2.write(0xC000, 2);
+        mmc2.write(0xD000, 3);
+        mmc2.write(0xE000, 4);
+        // Both latches start on $FE
+        assert_eq!([mmc2.read_chr_rom(0x0000), mmc2.read_chr_rom(0x1000)], [2, 4]);
+
+        // The fetch that flips a latch is still served from the old bank
+        assert_eq!(mmc2.read_chr_rom(0x0FD8), 2);
+        mmc2.notify_chr_fetch(0x0FD8);
+        assert_eq!(mmc2.read_chr_rom(0x0000), 1);
+        // Only $0FD8 and $0FE8 flip the left latch, the right one takes the whole row range
+        mmc2.notify_chr_fetch(0x0FE9);
+        mmc2.notify_chr_fetch(0x1FDD);
+        assert_eq!([mmc2.read_chr_rom(0x0000), mmc2.read_chr_rom(0x1000)], [1, 3]);
+        mmc2.notify_chr_fetch(0x0FE8);
+        mmc2.notify_chr_fetch(0x1FEF);
+        assert_eq!([mmc2.read_chr_rom(0x0000), mmc2.read_chr_rom(0x1000)], [2, 4]);
+        assert_eq!(mmc2.chr_bank_map(), [8, 9, 10, 11, 16, 17, 18, 19]);
+
+        mmc2.write(0xF000, 0x01);
+        assert_eq!(mmc2.get_mirroring(), Mirroring::Horizontal);
+    }
+
+    #[test]
+    fn small_prg_ram_is_mirrored() {
+        // NES 2.0 headers asking for 128 bytes of PRG-RAM on NROM, UxROM, CNROM and AxROM
+        for mapper in [0u8, 2, 3, 7] {
+            let mut bytes = vec![b'N', b'E', b'S', 0x1A, 2, 1, mapper << 4, 0x08, 0, 0, 0x01];
+            bytes.extend([0; 5]);
+            bytes.extend([0xea; 0x8000]);
+            bytes.extend([0; 0x2000]);
+            let rom = NESFile::from_bytes(bytes, false).unwrap();
+            assert_eq!(rom.get_prg_ram_size(), 128);
+
+            let mut bus = Bus::new(&rom);
+            bus.write(0x6000, 0x42, 0);
+            bus.write(0x7FFF, 0x24, 0);
+            assert_eq!(bus.read(0x7F80).0, 0x42, "mapper {mapper}");
+            assert_eq!(bus.read_trace(0x607F), 0x24, "mapper {mapper}");
+        }
+    }
+
+    #[test]
+    fn vrc6_banking_and_irq() {
+        let prg_rom: Vec<u8> = (0..16u8).flat_map(|bank| [bank; 0x2000]).collect();
+        let chr_rom: Vec<u8> = (0..64u8).flat_map(|bank| [bank; 0x400]).collect();
+        let new_vrc6a = || {
+            VRC6a::new(CartridgeConfig {
+                prg_rom: prg_rom.clone(),
+                chr_rom: Some(chr_rom.clone()),
+                mirroring: Mirroring::Vertical,
+                ..Default::default()
+            })
+        };
+        let mut vrc6 = new_vrc6a();
+
+        vrc6.write(0x8000, 0x03);
+        vrc6.write(0xC000, 0x09);
+        assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|a| vrc6.read(a)), [6, 7, 9, 15]);
+
+        vrc6.write(0xD002, 0x21);
+        vrc6.write(0xE001, 0x11);
+        assert_eq!(vrc6.read_chr_rom(0x0800), 0x21);
+        assert_eq!(vrc6.read_chr_rom(0x1400), 0x11);
+        vrc6.write(0xB003, 0x04);
+        assert_eq!(vrc6.get_mirroring(), Mirroring::Horizontal);
+
+        // PRG-RAM only answers once $B003 enables it
+        vrc6.write(0x6000, 0x42);
+        assert_eq!(vrc6.read(0x6000), 0);
+        vrc6.write(0xB003, 0x80);
+        vrc6.write(0x6000, 0x42);
+        assert_eq!(vrc6.read(0x6000), 0x42);
+
+        // VRC6b has A0 and A1 swapped
+        let mut vrc6b = VRC6b::new(CartridgeConfig {
+            prg_rom: prg_rom.clone(),
+            chr_rom: Some(chr_rom.clone()),
+            mirroring: Mirroring::Vertical,
+            ..Default::default()
+        });
+        vrc6b.write(0xD001, 0x05);
+        vrc6b.write(0xD002, 0x06);
+        assert_eq!(vrc6b.read_chr_rom(0x0400), 0x06);
+        assert_eq!(vrc6b.read_chr_rom(0x0800), 0x05);
+
+        // Cycle mode IRQ fires when the counter overflows, then reloads from the latch
+        let mut vrc6 = new_vrc6a();
+        assert!(vrc6.is_cpu_clocked());
+        vrc6.write(0xF000, 0xFD);
+        vrc6.write(0xF001, 0x07);
+        for _ in 0..2 {
+            vrc6.clock_cpu();
+        }
+        assert!(!vrc6.irq_pending());
+        vrc6.clock_cpu();
+        assert!(vrc6.irq_pending());
+        vrc6.write(0xF002, 0x00);
+        assert!(!vrc6.irq_pending());
+        for _ in 0..3 {
+            vrc6.clock_cpu();
+        }
+        assert!(vrc6.irq_pending());
+
+        // A pulse with the duty ignored outputs its volume constantly
+        let mut vrc6 = new_vrc6a();
+        let mut apu = APU::new();
+        vrc6.register_audio(&mut apu);
+        let mut loudest = |vrc6: &mut VRC6a| {
+            let mut samples = vec![];
+            for _ in 0..2000 {
+                vrc6.clock_cpu();
+                apu.clock();
+            }
+            apu.output_buffer.end_frame(&mut samples);
+            samples.into_iter().max().unwrap()
+        };
+        let quiet = loudest(&mut vrc6);
+        vrc6.write(0x9000, 0x8F);
+        vrc6.write(0x9002, 0x80);
+        assert!(loudest(&mut vrc6) > quiet + 300);
+    }
+
+    #[test]
+    fn vrc6_irq_and_audio_on_the_bus() {
+        let mut bytes = vec![b'N', b'E', b'S', 0x1A, 2, 1, 0x80, 0x10, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg_rom = vec![0xEA; 0x8000];
+        #[rustfmt::skip]
+        let program = [
+            0xa9, 0x40, 0x8d, 0x17, 0x40, // LDA #$40, STA $4017 (no frame counter IRQs)
+            0xa9, 0x00, 0x8d, 0x00, 0xf0, // LDA #$00, STA $F000 (IRQ every 256 cycles)
+            0xa9, 0x07, 0x8d, 0x01, 0xf0, // LDA #$07, STA $F001
+            0xa9, 0x7f, 0x8d, 0x00, 0x90, // LDA #$7F, STA $9000 (pulse 1, 50% duty, volume 15)
+            0xa9, 0xff, 0x8d, 0x01, 0x90, // LDA #$FF, STA $9001
+            0xa9, 0x80, 0x8d, 0x02, 0x90, // LDA #$80, STA $9002
+            0x58,                         // CLI
+            0x4c, 0x1f, 0xe0,             // JMP $E01F
+        ];
+        prg_rom[0x6000..0x6000 + program.len()].copy_from_slice(&program);
+        // IRQ handler at $E040: INC $00, acknowledge, RTI
+        prg_rom[0x6040..0x6046].copy_from_slice(&[0xe6, 0x00, 0x8d, 0x02, 0xf0, 0x40]);
+        prg_rom[0x7FFA..].copy_from_slice(&[0x1f, 0xe0, 0x00, 0xe0, 0x40, 0xe0]);
+        bytes.extend(prg_rom);
+        bytes.extend([0; 0x2000]);
+
+        let rom = NESFile::from_bytes(bytes, false).unwrap();
+        let mut cpu = CPU::new(Bus::new(&rom));
+        cpu.reset();
+        cpu.run_until_frame();
+        let start = cpu.bus.read_trace(0x00);
+        let mut samples = vec![];
+        cpu.bus.apu.output_buffer.end_frame(&mut samples);
+        cpu.run_until_frame();
+        cpu.bus.apu.output_buffer.end_frame(&mut samples);
+
+        let irqs = cpu.bus.read_trace(0x00).wrapping_sub(start);
+        assert!((110..=120).contains(&irqs), "{irqs} IRQs in a frame");
+        assert!(samples.iter().any(|&s| s != samples[0]));
+    }
+
+    #[test]
+    fn vrc6_power_cycle_keeps_only_battery_ram() {
+        let console_with = |flags6: u8| {
+            let mut bytes = vec![b'N', b'E', b'S', 0x1A, 2, 1, flags6, 0x10];
+            bytes.extend([0; 8]);
+            let mut prg_rom = vec![0xEA; 0x8000];
+            prg_rom[0x7FFA..].copy_from_slice(&[0x00, 0xe0, 0x00, 0xe0, 0x00, 0xe0]);
+            bytes.extend(prg_rom);
+            bytes.extend([0; 0x2000]);
+            Console::new(NESFile::from_bytes(bytes, false).unwrap())
+        };
+        let ram_after_power_cycle = |mut console: Console| {
+            let bus = &mut console.cpu.bus;
+            bus.write(0xB003, 0x80, 0);
+            bus.write(0x6000, 0x42, 0);
+            bus.write(0x7FFF, 0x24, 0);
+            console.power_cycle();
+            let bus = &mut console.cpu.bus;
+            bus.write(0xB003, 0x80, 0);
+            let after = [bus.read_trace(0x6000), bus.read_trace(0x7FFF)];
+            // Still the full 8K to write to
+            bus.write(0x7FFF, 0x99, 0);
+            assert_eq!(bus.read_trace(0x7FFF), 0x99);
+            after
+        };
+
+        assert_eq!(ram_after_power_cycle(console_with(0x80)), [0, 0]);
+        assert_eq!(ram_after_power_cycle(console_with(0x82)), [0x42, 0x24]);
+
+        // A short save only fills the start of the RAM
+        let mut console = console_with(0x82);
+        console.cpu.bus.mapper.lock().unwrap().load_save(&[1, 2]);
+        let bus = &mut console.cpu.bus;
+        bus.write(0xB003, 0x80, 0);
+        assert_eq!([0x6000, 0x6001, 0x6002].map(|addr| bus.read_trace(addr)), [1, 2, 0]);
+        bus.write(0x7FFF, 0x99, 0);
+        assert_eq!(bus.read_trace(0x7FFF), 0x99);
+    }
+
+    #[test]
+    fn mmc1_power_cycle_keeps_only_battery_ram() {
+        // NES 2.0 with 8K of PRG-RAM, or with 8K of battery-backed PRG-NVRAM
+        let console_with = |flags6: u8, prg_ram_shifts: u8| {
+            let mut bytes = vec![b'N', b'E', b'S', 0x1A, 2, 1, flags6, 0x08, 0, 0, prg_ram_shifts];
+            bytes.extend([0; 5]);
+            let mut prg_rom = vec![0xEA; 0x8000];
+            prg_rom[0x7FFA..].copy_from_slice(&[0x00, 0xc0, 0x00, 0xc0, 0x00, 0xc0]);
+            bytes.extend(prg_rom);
+            bytes.extend([0; 0x2000]);
+            Console::new(NESFile::from_bytes(bytes, false).unwrap())
+        };
+        let ram_after_power_cycle = |mut console: Console| {
+            let bus = &mut console.cpu.bus;
+            bus.write(0x6000, 0x42, 0);
+            bus.write(0x7FFF, 0x24, 0);
+            console.power_cycle();
+            let bus = &mut console.cpu.bus;
+            let after = [bus.read_trace(0x6000), bus.read_trace(0x7FFF)];
+            bus.write(0x7FFF, 0x99, 0);
+            assert_eq!(bus.read_trace(0x7FFF), 0x99);
+            after
+        };
+
+        assert_eq!(ram_after_power_cycle(console_with(0x10, 0x07)), [0, 0]);
+        assert_eq!(ram_after_power_cycle(console_with(0x12, 0x70)), [0x42, 0x24]);
+
+        // A short save only fills the start of the RAM, and one without a battery is ignored
+        let console = console_with(0x12, 0x70);
+        console.cpu.bus.mapper.lock().unwrap().load_save(&[1, 2]);
+        let bus = &console.cpu.bus;
+        assert_eq!([0x6000, 0x6001, 0x6002].map(|addr| bus.read_trace(addr)), [1, 2, 0]);
+        let console = console_with(0x10, 0x07);
+        console.cpu.bus.mapper.lock().unwrap().load_save(&[1, 2]);
+        assert_eq!(console.cpu.bus.read_trace(0x6000), 0);
+    }
+
     // CPU Tests -----------------------------------------------------------------------------------
     // let rom = File::new("tests/cpu_exec_space/test_cpu_exec_space_apu.nes"); // Fails - expected
     // let rom = File::new("tests/cpu_interrupts_v2/cpu_interrupts.nes"); // Fails - expected
@@ -96,8 +2980,4 @@ mod tests {
     // let rom = File::new("tests/window5/colorwin_ntsc.nes"); // Passes
     // let rom = File::new("tests/spritecans-2011/spritecans.nes"); // Passes
     // let rom = File::new("tests/nmi_sync/demo_ntsc.nes"); // Fails
-
-    // Mapper Tests --------------------------------------------------------------------------------
-    // m1_p512k_cr8k_s8k: ("tests/holy-mapperel/M1_P512K_CR8K_S8K.nes", ?, ?); // Fails - expected
-    // m1_p512k_cr8k_w8k: ("tests/holy-mapperel/M1_P512K_CR8K_W8K.nes", ?, ?); // Fails - expected
 }